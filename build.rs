@@ -0,0 +1,7 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Parsed in pure rust, so that building does not need protoc installed
+    let descriptors = protox::compile(["proto/game.proto"], ["proto"])?;
+    tonic_build::configure().compile_fds(descriptors)?;
+    println!("cargo:rerun-if-changed=proto");
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! Looks up users from many threads at once, comparing the sharded map of the users
+//! with the single async mutex around a hash map that it replaced.
+//!
+//! Run with `cargo bench -p pipes-engine --bench user_map`.
+
+use async_mutex::Mutex;
+use futures::executor::block_on;
+use pipes_engine::sharded::ShardedMap;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const USERS: usize = 200;
+const LOOKUPS_PER_THREAD: usize = 100_000;
+
+fn tokens() -> Vec<String> {
+    (0..USERS).map(|n| format!("user-{n}")).collect()
+}
+
+/// Runs `lookup` on every thread for its own users, returns the total time
+fn measure(threads: usize, lookup: impl Fn(&str) + Sync) -> Duration {
+    let tokens = tokens();
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for thread in 0..threads {
+            let (tokens, lookup) = (&tokens, &lookup);
+            scope.spawn(move || {
+                for n in 0..LOOKUPS_PER_THREAD {
+                    lookup(&tokens[(thread + n * threads) % USERS]);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn main() {
+    let single: Mutex<HashMap<String, Arc<u64>>> = Mutex::new(
+        tokens()
+            .into_iter()
+            .map(|token| (token, Arc::new(0)))
+            .collect(),
+    );
+    let sharded: ShardedMap<String, Arc<u64>> = tokens()
+        .into_iter()
+        .map(|token| (token, Arc::new(0)))
+        .collect();
+    let cores = std::thread::available_parallelism().map_or(4, |cores| cores.get());
+    println!("{cores} cores, {USERS} users, {LOOKUPS_PER_THREAD} lookups per thread");
+    println!("threads  single mutex  sharded  speedup");
+    for threads in [1, 2, 4, 8, 16] {
+        let single_time = measure(threads, |token| {
+            let user = block_on(single.lock()).get(token).cloned();
+            assert!(user.is_some());
+        });
+        let sharded_time = measure(threads, |token| {
+            let user = sharded.get(token);
+            assert!(user.is_some());
+        });
+        println!(
+            "{threads:>7}  {:>11.1?}  {:>7.1?}  {:>6.1}x",
+            single_time,
+            sharded_time,
+            single_time.as_secs_f64() / sharded_time.as_secs_f64(),
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Log history kept for late subscribers and history queries
+//!
+//! Only the most recent entries are kept in memory if a limit is configured,
+//! older ones are spilled to an anonymous temporary file and read back on demand.
+//! With a cap the oldest entries are forgotten altogether, indices stay the same.
+
+use crate::model::{LogEntry, PipeId, UserToken};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
+use tracing::{debug, error};
+
+struct Spill {
+    file: File,
+    /// Start of every spilled entry in the file that is still kept
+    offsets: VecDeque<u64>,
+    end: u64,
+}
+
+impl Spill {
+    fn read(&self, index: usize) -> anyhow::Result<LogEntry> {
+        let start = self.offsets[index];
+        let end = self.offsets.get(index + 1).copied().unwrap_or(self.end);
+        let mut buffer = vec![0; (end - start) as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buffer)?;
+        Ok(serde_json::from_slice(&buffer)?)
+    }
+
+    fn write(&mut self, entry: &LogEntry) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(entry)?;
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.end))?;
+        file.write_all(&data)?;
+        self.offsets.push_back(self.end);
+        self.end += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Indices of the log entries about one entity
+#[derive(Default)]
+struct Entries {
+    /// Number of entries forgotten because of the cap
+    forgotten: usize,
+    indices: VecDeque<usize>,
+}
+
+/// All log entries so far, indexed for quick per entity lookups
+pub struct History {
+    memory_limit: Option<usize>,
+    cap: Option<usize>,
+    /// Number of the oldest entries forgotten because of the cap
+    forgotten: usize,
+    spill: Option<Spill>,
+    recent: VecDeque<LogEntry>,
+    by_pipe: HashMap<PipeId, Entries>,
+    by_user: HashMap<UserToken, Entries>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct HistoryResponse {
+    /// Log entries, in the same format as the game log
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<Object>))]
+    pub entries: Vec<LogEntry>,
+    /// Value of `from` to request the following page
+    pub next: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LogsResponse {
+    /// Index of the oldest entry still kept
+    pub first: usize,
+    pub entries: Vec<LogEntry>,
+    /// Value of `offset` to request the following page
+    pub next: usize,
+}
+
+impl History {
+    pub fn new(memory_limit: Option<usize>, cap: Option<usize>) -> Self {
+        Self {
+            memory_limit,
+            cap,
+            forgotten: 0,
+            spill: None,
+            recent: VecDeque::new(),
+            by_pipe: HashMap::new(),
+            by_user: HashMap::new(),
+        }
+    }
+
+    fn spilled(&self) -> usize {
+        self.spill.as_ref().map_or(0, |spill| spill.offsets.len())
+    }
+
+    /// Index of the next entry, forgotten ones included
+    pub fn len(&self) -> usize {
+        self.forgotten + self.spilled() + self.recent.len()
+    }
+
+    /// Index of the oldest entry still kept
+    pub fn first(&self) -> usize {
+        self.forgotten
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, entry: LogEntry) {
+        let index = self.len();
+        if let Some(pipe_id) = entry.msg.pipe_id() {
+            self.by_pipe
+                .entry(pipe_id)
+                .or_default()
+                .indices
+                .push_back(index);
+        }
+        if let Some(user) = entry.msg.user() {
+            self.by_user
+                .entry(user.clone())
+                .or_default()
+                .indices
+                .push_back(index);
+        }
+        self.recent.push_back(entry);
+        if let Some(cap) = self.cap {
+            while self.len() - self.forgotten > cap {
+                self.forget_oldest();
+            }
+        }
+        if let Some(limit) = self.memory_limit {
+            if self.recent.len() > limit {
+                if let Err(e) = self.spill_segment(self.recent.len() - limit / 2) {
+                    error!("Failed to spill log history to disk: {e}");
+                }
+            }
+        }
+    }
+
+    fn spill_segment(&mut self, count: usize) -> anyhow::Result<()> {
+        debug!("Spilling {count} log history entries to disk");
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            spill => spill.insert(Spill {
+                file: tempfile::tempfile()?,
+                offsets: VecDeque::new(),
+                end: 0,
+            }),
+        };
+        for _ in 0..count {
+            let entry = self.recent.front().unwrap();
+            spill.write(entry)?;
+            self.recent.pop_front();
+        }
+        Ok(())
+    }
+
+    fn forget_oldest(&mut self) {
+        let index = self.forgotten;
+        let entry = if self.spilled() > 0 {
+            let entry = self.get(index);
+            self.spill.as_mut().unwrap().offsets.pop_front();
+            entry
+        } else {
+            self.recent.pop_front()
+        };
+        self.forgotten += 1;
+        let Some(entry) = entry else {
+            return;
+        };
+        let forget = |entries: Option<&mut Entries>| {
+            if let Some(entries) = entries.filter(|e| e.indices.front() == Some(&index)) {
+                entries.indices.pop_front();
+                entries.forgotten += 1;
+            }
+        };
+        if let Some(pipe_id) = entry.msg.pipe_id() {
+            forget(self.by_pipe.get_mut(&pipe_id));
+        }
+        if let Some(user) = entry.msg.user() {
+            forget(self.by_user.get_mut(user));
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<LogEntry> {
+        let index = index.checked_sub(self.forgotten)?;
+        let spilled = self.spilled();
+        if index >= spilled {
+            return self.recent.get(index - spilled).cloned();
+        }
+        match self.spill.as_ref().unwrap().read(index) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                error!("Failed to read spilled log history entry {index}: {e}");
+                None
+            }
+        }
+    }
+
+    fn page(&self, entries: Option<&Entries>, from: usize, limit: usize) -> HistoryResponse {
+        let Some(entries) = entries else {
+            return HistoryResponse {
+                entries: Vec::new(),
+                next: from,
+            };
+        };
+        // Positions keep counting the forgotten entries, so that pages stay stable
+        let from = from.max(entries.forgotten);
+        let page: Vec<LogEntry> = entries
+            .indices
+            .iter()
+            .skip(from - entries.forgotten)
+            .take(limit)
+            .filter_map(|&index| self.get(index))
+            .collect();
+        HistoryResponse {
+            next: from + page.len(),
+            entries: page,
+        }
+    }
+
+    pub fn pipe_page(&self, pipe_id: PipeId, from: usize, limit: usize) -> HistoryResponse {
+        self.page(self.by_pipe.get(&pipe_id), from, limit)
+    }
+
+    pub fn user_page(&self, user: &UserToken, from: usize, limit: usize) -> HistoryResponse {
+        self.page(self.by_user.get(user), from, limit)
+    }
+
+    /// Entries of the whole log starting at `offset`, or at the oldest one kept
+    pub fn logs_page(&self, offset: usize, limit: usize) -> LogsResponse {
+        let offset = offset.max(self.forgotten);
+        let entries: Vec<LogEntry> = (offset..self.len())
+            .take(limit)
+            .filter_map(|index| self.get(index))
+            .collect();
+        LogsResponse {
+            first: self.forgotten,
+            next: offset + entries.len(),
+            entries,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::LogMessage;
+
+    #[test]
+    fn test_spill() {
+        let mut history = History::new(Some(4), None);
+        for index in 0..10 {
+            history.push(LogEntry {
+                time: index as f64,
+                msg: LogMessage::GameEnding {
+                    seconds_left: index as f64,
+                },
+            });
+        }
+        assert!(history.spilled() > 0);
+        assert!(history.recent.len() <= 4);
+        assert_eq!(history.len(), 10);
+        for index in 0..10 {
+            assert_eq!(history.get(index).unwrap().time, index as f64);
+        }
+        assert!(history.get(10).is_none());
+    }
+    #[test]
+    fn test_cap() {
+        let mut history = History::new(Some(4), Some(6));
+        for index in 0..10 {
+            history.push(LogEntry {
+                time: index as f64,
+                msg: LogMessage::GameEnding {
+                    seconds_left: index as f64,
+                },
+            });
+        }
+        assert_eq!(history.len(), 10);
+        assert_eq!(history.first(), 4);
+        assert!(history.get(3).is_none());
+        for index in 4..10 {
+            assert_eq!(history.get(index).unwrap().time, index as f64);
+        }
+        let page = history.logs_page(0, 4);
+        assert_eq!(page.first, 4);
+        assert_eq!(page.next, 8);
+        let page = history.logs_page(page.next, 4);
+        assert_eq!(page.entries.len(), 2);
+        assert_eq!(page.next, 10);
+    }
+}
@@ -0,0 +1,15 @@
+//! Game rules and state of the pipes game, independent of the http layer.
+//!
+//! The server binary wraps [`model::App`] into an http api,
+//! other tooling can use the engine and the log format directly.
+
+#[cfg(feature = "runtime")]
+pub mod history;
+#[cfg(feature = "runtime")]
+pub mod metrics;
+pub mod model;
+pub mod ranking;
+pub mod replay;
+pub mod serde_duration;
+pub mod serde_score;
+pub mod sharded;
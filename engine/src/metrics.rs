@@ -0,0 +1,481 @@
+//! Aggregate request metrics for post-mortems
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+    in_flight_requests: AtomicUsize,
+    last_shed: Mutex<Option<Instant>>,
+}
+
+/// Counts the request as in flight until dropped
+pub struct InFlightRequest<'a> {
+    metrics: &'a Metrics,
+    /// Requests in flight including this one
+    pub count: usize,
+}
+
+impl Drop for InFlightRequest<'_> {
+    fn drop(&mut self) {
+        self.metrics
+            .in_flight_requests
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    total_requests: u64,
+    errors: BTreeMap<String, u64>,
+    latencies: BTreeMap<String, Histogram>,
+    requests_by_user: BTreeMap<String, u64>,
+    timings_by_user: BTreeMap<String, UserTimings>,
+    auth_failures_by_ip: BTreeMap<IpAddr, u64>,
+    rate_limits_by_user: BTreeMap<String, RateLimitStats>,
+    next_subscriber: u64,
+    subscribers: BTreeMap<u64, Subscriber>,
+}
+
+impl Inner {
+    fn network_by_user(&self) -> BTreeMap<String, NetworkStats> {
+        self.timings_by_user
+            .iter()
+            .map(|(user, timings)| {
+                let stats = NetworkStats {
+                    mean_round_trip: (timings.round_trips.count > 0)
+                        .then_some(timings.round_trips.mean),
+                    mean_latency: timings.latencies.mean,
+                    mean_request_interval: timings.intervals.mean,
+                    request_interval_jitter: timings.intervals.deviation(),
+                };
+                (user.clone(), stats)
+            })
+            .collect()
+    }
+}
+
+/// Client of the `/logs` websocket, kept after disconnecting for post-mortems
+struct Subscriber {
+    peer: Option<String>,
+    connected: Instant,
+    disconnected: Option<Instant>,
+    messages_sent: u64,
+    bytes_sent: u64,
+}
+
+impl Subscriber {
+    fn stats(&self, id: u64) -> SubscriberStats {
+        SubscriberStats {
+            id,
+            peer: self.peer.clone(),
+            connected: self.disconnected.is_none(),
+            duration: self
+                .disconnected
+                .unwrap_or_else(Instant::now)
+                .duration_since(self.connected)
+                .as_secs_f64(),
+            messages_sent: self.messages_sent,
+            bytes_sent: self.bytes_sent,
+        }
+    }
+}
+
+const BUCKETS: usize = 128;
+const BUCKETS_PER_DOUBLING: f64 = 4.0;
+
+/// Latencies in exponential buckets, so that the memory used stays the same however long the
+/// game runs. Percentiles are accurate to a bucket, about 19%.
+struct Histogram {
+    /// Bucket `i` counts the latencies up to [`Histogram::bound`]`(i)`, the last one also
+    /// everything above
+    buckets: [u64; BUCKETS],
+    count: u64,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            count: 0,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl Histogram {
+    /// Upper bound of the bucket in seconds, starting from a microsecond
+    fn bound(index: usize) -> f64 {
+        1e-6 * 2f64.powf((index + 1) as f64 / BUCKETS_PER_DOUBLING)
+    }
+
+    fn add(&mut self, latency: Duration) {
+        let micros = latency.as_secs_f64() * 1e6;
+        let index = (micros.log2() * BUCKETS_PER_DOUBLING).ceil().max(1.0) as usize - 1;
+        self.buckets[index.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    fn percentile(&self, p: f64) -> f64 {
+        let rank = ((self.count as f64 * p).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank && index < BUCKETS - 1 {
+                return Self::bound(index).min(self.max.as_secs_f64());
+            }
+        }
+        self.max.as_secs_f64()
+    }
+}
+
+#[derive(Default)]
+struct UserTimings {
+    last_request: Option<Instant>,
+    intervals: Aggregate,
+    latencies: Aggregate,
+    round_trips: Aggregate,
+}
+
+/// Count, mean and sum of squared deviations of a series, updated in place with Welford's method
+#[derive(Default)]
+struct Aggregate {
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Aggregate {
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    fn deviation(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        (self.m2 / self.count as f64).sqrt()
+    }
+}
+
+/// Coarse network characteristics of a user as seen by the server, to tell whether a better
+/// connection explains a result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkStats {
+    /// Seconds, averaged over the kernel's estimates for the connections of the user. Only
+    /// measured on Linux.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mean_round_trip: Option<f64>,
+    pub mean_latency: f64,
+    pub mean_request_interval: f64,
+    pub request_interval_jitter: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EndpointStats {
+    pub requests: u64,
+    pub p50_latency: f64,
+    pub p99_latency: f64,
+    pub max_latency: f64,
+}
+
+/// Requests of a user counted against the per token rate limit
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RateLimitStats {
+    pub requests: u64,
+    /// Requests rejected for going over the limit
+    pub rejected: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubscriberStats {
+    pub id: u64,
+    pub peer: Option<String>,
+    /// Whether the subscriber is still connected
+    pub connected: bool,
+    /// Seconds connected so far, or until disconnecting
+    pub duration: f64,
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub total_requests: u64,
+    pub errors: BTreeMap<String, u64>,
+    pub endpoints: BTreeMap<String, EndpointStats>,
+    pub requests_by_user: BTreeMap<String, u64>,
+    pub network_by_user: BTreeMap<String, NetworkStats>,
+    pub auth_failures_by_ip: BTreeMap<IpAddr, u64>,
+    pub log_subscribers: Vec<SubscriberStats>,
+}
+
+impl Snapshot {
+    pub fn map_user(self, f: impl Fn(String) -> String) -> Self {
+        Self {
+            requests_by_user: self
+                .requests_by_user
+                .into_iter()
+                .map(|(user, count)| (f(user), count))
+                .collect(),
+            network_by_user: self
+                .network_by_user
+                .into_iter()
+                .map(|(user, stats)| (f(user), stats))
+                .collect(),
+            ..self
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record(
+        &self,
+        endpoint: &str,
+        user: Option<&str>,
+        error: Option<&str>,
+        latency: Duration,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.total_requests += 1;
+        if let Some(error) = error {
+            *inner.errors.entry(error.to_owned()).or_default() += 1;
+        }
+        inner
+            .latencies
+            .entry(endpoint.to_owned())
+            .or_default()
+            .add(latency);
+        if let Some(user) = user {
+            *inner.requests_by_user.entry(user.to_owned()).or_default() += 1;
+            let timings = inner.timings_by_user.entry(user.to_owned()).or_default();
+            let now = Instant::now();
+            if let Some(last_request) = timings.last_request.replace(now) {
+                timings
+                    .intervals
+                    .add(now.duration_since(last_request).as_secs_f64());
+            }
+            timings.latencies.add(latency.as_secs_f64());
+        }
+    }
+
+    /// Round trip time of the connection a request of the user came over
+    pub fn record_round_trip(&self, user: &str, round_trip: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let timings = inner.timings_by_user.entry(user.to_owned()).or_default();
+        timings.round_trips.add(round_trip.as_secs_f64());
+    }
+
+    pub fn last_request(&self, user: &str) -> Option<Instant> {
+        let inner = self.inner.lock().unwrap();
+        inner.timings_by_user.get(user)?.last_request
+    }
+
+    pub fn start_request(&self) -> InFlightRequest<'_> {
+        let count = self.in_flight_requests.fetch_add(1, Ordering::SeqCst) + 1;
+        InFlightRequest {
+            metrics: self,
+            count,
+        }
+    }
+
+    pub fn in_flight_requests(&self) -> usize {
+        self.in_flight_requests.load(Ordering::SeqCst)
+    }
+
+    pub fn record_shed(&self) {
+        *self.last_shed.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn last_shed(&self) -> Option<Instant> {
+        *self.last_shed.lock().unwrap()
+    }
+
+    pub fn record_auth_failure(&self, ip: IpAddr) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.auth_failures_by_ip.entry(ip).or_default() += 1;
+    }
+
+    pub fn record_rate_limit(&self, user: &str, rejected: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let stats = inner
+            .rate_limits_by_user
+            .entry(user.to_owned())
+            .or_default();
+        stats.requests += 1;
+        if rejected {
+            stats.rejected += 1;
+        }
+    }
+
+    pub fn rate_limits(&self) -> BTreeMap<String, RateLimitStats> {
+        self.inner.lock().unwrap().rate_limits_by_user.clone()
+    }
+
+    /// Returns the id to report sent messages and the disconnect with
+    pub fn subscriber_connected(&self, peer: Option<String>) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_subscriber;
+        inner.next_subscriber += 1;
+        inner.subscribers.insert(
+            id,
+            Subscriber {
+                peer,
+                connected: Instant::now(),
+                disconnected: None,
+                messages_sent: 0,
+                bytes_sent: 0,
+            },
+        );
+        id
+    }
+
+    pub fn record_subscriber_message(&self, id: u64, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(subscriber) = inner.subscribers.get_mut(&id) {
+            subscriber.messages_sent += 1;
+            subscriber.bytes_sent += bytes as u64;
+        }
+    }
+
+    /// Returns the final stats of the subscriber
+    pub fn subscriber_disconnected(&self, id: u64) -> Option<SubscriberStats> {
+        let mut inner = self.inner.lock().unwrap();
+        let subscriber = inner.subscribers.get_mut(&id)?;
+        subscriber.disconnected.get_or_insert_with(Instant::now);
+        Some(subscriber.stats(id))
+    }
+
+    /// Connected subscribers first, the ones sent the most bytes on top
+    pub fn subscribers(&self) -> Vec<SubscriberStats> {
+        let inner = self.inner.lock().unwrap();
+        let mut subscribers: Vec<SubscriberStats> = inner
+            .subscribers
+            .iter()
+            .map(|(&id, subscriber)| subscriber.stats(id))
+            .collect();
+        subscribers.sort_by_key(|stats| (!stats.connected, std::cmp::Reverse(stats.bytes_sent)));
+        subscribers
+    }
+
+    pub fn network_by_user(&self) -> BTreeMap<String, NetworkStats> {
+        self.inner.lock().unwrap().network_by_user()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let inner = self.inner.lock().unwrap();
+        let endpoints = inner
+            .latencies
+            .iter()
+            .map(|(endpoint, latencies)| {
+                let stats = EndpointStats {
+                    requests: latencies.count,
+                    p50_latency: latencies.percentile(0.5),
+                    p99_latency: latencies.percentile(0.99),
+                    max_latency: latencies.max.as_secs_f64(),
+                };
+                (endpoint.clone(), stats)
+            })
+            .collect();
+        Snapshot {
+            total_requests: inner.total_requests,
+            errors: inner.errors.clone(),
+            endpoints,
+            requests_by_user: inner.requests_by_user.clone(),
+            network_by_user: inner.network_by_user(),
+            auth_failures_by_ip: inner.auth_failures_by_ip.clone(),
+            log_subscribers: inner
+                .subscribers
+                .iter()
+                .map(|(&id, subscriber)| subscriber.stats(id))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_latencies() {
+        let metrics = Metrics::default();
+        for millis in 1..=1000 {
+            metrics.record("/api/collect", None, None, Duration::from_millis(millis));
+        }
+        let snapshot = metrics.snapshot();
+        let stats = &snapshot.endpoints["/api/collect"];
+        assert_eq!(stats.requests, 1000);
+        assert_eq!(stats.max_latency, 1.0);
+        let close = |value: f64, expected: f64| (value / expected - 1.0).abs() < 0.2;
+        assert!(close(stats.p50_latency, 0.5), "{stats:?}");
+        assert!(close(stats.p99_latency, 0.99), "{stats:?}");
+        assert!(stats.p99_latency <= stats.max_latency);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let mut aggregate = Aggregate::default();
+        for value in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+            aggregate.add(value);
+        }
+        assert_eq!(aggregate.count, 8);
+        assert!((aggregate.mean - 5.0).abs() < 1e-12);
+        assert!((aggregate.deviation() - 2.0).abs() < 1e-12);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_network_stats() {
+        let metrics = Metrics::default();
+        for _ in 0..3 {
+            metrics.record(
+                "/api/collect",
+                Some("player"),
+                None,
+                Duration::from_millis(10),
+            );
+            metrics.record_round_trip("player", Duration::from_millis(40));
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+        metrics.record(
+            "/api/collect",
+            Some("other"),
+            None,
+            Duration::from_millis(30),
+        );
+        let network = metrics.network_by_user();
+        let player = &network["player"];
+        assert_eq!(player.mean_round_trip, Some(0.04));
+        assert!((player.mean_latency - 0.01).abs() < 1e-12);
+        assert!((player.mean_request_interval - 1.0).abs() < 1e-9);
+        assert!(player.request_interval_jitter < 1e-9);
+        assert_eq!(network["other"].mean_round_trip, None);
+        assert_eq!(network["other"].mean_request_interval, 0.0);
+    }
+
+    #[test]
+    fn test_histogram_extremes() {
+        let mut histogram = Histogram::default();
+        histogram.add(Duration::ZERO);
+        assert_eq!(histogram.buckets[0], 1);
+        histogram.add(Duration::from_secs(1_000_000));
+        assert_eq!(histogram.buckets[BUCKETS - 1], 1);
+        assert_eq!(histogram.percentile(0.5), Histogram::bound(0));
+        assert_eq!(histogram.percentile(1.0), 1_000_000.0);
+    }
+}
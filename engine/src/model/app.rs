@@ -0,0 +1,3869 @@
+//! The running game: users, pipes and the log, shared by all requests
+
+use crate::{
+    history::{History, HistoryResponse, LogsResponse},
+    metrics::{Metrics, NetworkStats},
+    ranking::{RankChange, Ranking},
+    replay,
+    sharded::ShardedMap,
+};
+use async_mutex::Mutex;
+use futures::{
+    channel::mpsc,
+    future::{select, AbortHandle, AbortRegistration, Abortable, Either},
+    SinkExt, Stream, StreamExt,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    future::Future,
+    net::IpAddr,
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::{debug, error, info, warn};
+
+use super::*;
+
+struct UserEntry {
+    /// Order in which users joined, used for pipe assignment
+    index: usize,
+    state: Mutex<User>,
+    actions: std::sync::Mutex<InFlightActions>,
+    profile: std::sync::Mutex<UserProfile>,
+    operations: std::sync::Mutex<Operations>,
+}
+
+/// Finished operations kept per user for polling, the oldest are forgotten first
+const OPERATIONS_KEPT: usize = 100;
+
+/// Actions with ids that can be polled, so that clients can recover from timeouts
+#[derive(Default)]
+struct Operations {
+    next_id: u64,
+    by_id: BTreeMap<u64, Operation>,
+    by_key: HashMap<String, u64>,
+    keys: HashMap<u64, String>,
+}
+
+impl Operations {
+    fn forget_finished(&mut self) {
+        let finished: Vec<u64> = self
+            .by_id
+            .iter()
+            .filter(|(_, operation)| operation.status != OperationStatus::Pending)
+            .map(|(&id, _)| id)
+            .collect();
+        let excess = self.by_id.len().saturating_sub(OPERATIONS_KEPT);
+        for id in finished.into_iter().take(excess) {
+            self.by_id.remove(&id);
+            if let Some(key) = self.keys.remove(&id) {
+                self.by_key.remove(&key);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct InFlightActions {
+    next_id: u64,
+    by_id: BTreeMap<u64, InFlightAction>,
+    aborts: BTreeMap<u64, AbortHandle>,
+    /// Why the actions were aborted, read by the actions themselves
+    abort_reasons: BTreeMap<u64, AbortReason>,
+    /// Game time until which the user is locked out after going bankrupt
+    bankrupt_until: Option<f64>,
+    /// When the user may start the actions again
+    cooldown_until: HashMap<Action, Instant>,
+    /// Errors counted by the misconduct policy within its window
+    misconduct: VecDeque<Instant>,
+    /// Until when the user is locked out after a misconduct penalty
+    misconduct_lockout_until: Option<Instant>,
+}
+
+impl InFlightActions {
+    fn count(&self, action: Option<Action>) -> usize {
+        self.by_id
+            .values()
+            .filter(|in_flight| action.is_none_or(|action| in_flight.action == action))
+            .count()
+    }
+
+    /// Counts the error, returns whether the threshold of the policy is reached
+    fn count_misconduct(&mut self, policy: &MisconductPolicy) -> bool {
+        let now = Instant::now();
+        let window = Duration::from_secs_f64(policy.window_secs);
+        while self
+            .misconduct
+            .front()
+            .is_some_and(|&time| now.duration_since(time) >= window)
+        {
+            self.misconduct.pop_front();
+        }
+        self.misconduct.push_back(now);
+        if self.misconduct.len() < policy.threshold {
+            return false;
+        }
+        self.misconduct.clear();
+        if policy.lockout_secs > 0.0 {
+            self.misconduct_lockout_until =
+                Some(now + Duration::from_secs_f64(policy.lockout_secs));
+        }
+        true
+    }
+
+    /// Until the first of the actions (of the type, if given) is expected to end
+    fn free_in(&self, action: Option<Action>, now: f64) -> Option<Duration> {
+        self.by_id
+            .values()
+            .filter(|in_flight| action.is_none_or(|action| in_flight.action == action))
+            .map(|in_flight| in_flight.expected_completion)
+            .min_by(f64::total_cmp)
+            .map(|end| Duration::from_secs_f64((end - now).max(0.0)))
+    }
+}
+
+impl UserEntry {
+    fn new(index: usize, user: User, profile: UserProfile) -> Self {
+        Self {
+            index,
+            state: Mutex::new(user),
+            actions: Default::default(),
+            profile: std::sync::Mutex::new(profile),
+            operations: Default::default(),
+        }
+    }
+
+    fn abort_action(&self, id: u64, reason: AbortReason) {
+        let mut actions = self.actions.lock().unwrap();
+        let Some(handle) = actions.aborts.get(&id).cloned() else {
+            return;
+        };
+        actions.abort_reasons.insert(id, reason);
+        handle.abort();
+    }
+}
+
+/// Occupies one of the user's action slots until dropped
+struct ActionGuard {
+    user: Arc<UserEntry>,
+    id: u64,
+    abort: Option<AbortRegistration>,
+    /// In turn-based mode, the turn the action resolves in
+    turn: Option<Turn>,
+    /// Cooldown started by the action and when it ends, given back unless the action succeeds
+    cooldown: Option<(Action, Instant)>,
+}
+
+impl ActionGuard {
+    /// Waits unless the action gets aborted.
+    /// Only the first wait can be aborted, unless the action is rearmed for the next one
+    async fn sleep(&mut self, delay: Duration) -> Result<(), AbortReason> {
+        let Some(abort) = self.abort.take() else {
+            sleep(delay).await;
+            return Ok(());
+        };
+        if Abortable::new(sleep(delay), abort).await.is_ok() {
+            return Ok(());
+        }
+        let mut actions = self.user.actions.lock().unwrap();
+        Err(actions
+            .abort_reasons
+            .remove(&self.id)
+            .unwrap_or(AbortReason::Admin))
+    }
+    fn info(&self) -> InFlightAction {
+        self.user.actions.lock().unwrap().by_id[&self.id].clone()
+    }
+    fn user(&self) -> &Mutex<User> {
+        &self.user.state
+    }
+    fn set_expected_completion(&self, time: f64) {
+        let mut actions = self.user.actions.lock().unwrap();
+        actions.by_id.get_mut(&self.id).unwrap().expected_completion = time;
+    }
+    /// Keeps the cooldown the action started
+    fn succeed(&mut self) {
+        self.cooldown = None;
+    }
+    /// Makes the next wait abortable too, actions of turn-based games wait more than once
+    fn rearm(&mut self) {
+        let (handle, abort) = AbortHandle::new_pair();
+        let mut actions = self.user.actions.lock().unwrap();
+        // Aborted in between the waits
+        if actions.abort_reasons.contains_key(&self.id) {
+            handle.abort();
+        }
+        actions.aborts.insert(self.id, handle);
+        self.abort = Some(abort);
+    }
+}
+
+impl Drop for ActionGuard {
+    fn drop(&mut self) {
+        let mut actions = self.user.actions.lock().unwrap();
+        actions.by_id.remove(&self.id);
+        actions.aborts.remove(&self.id);
+        actions.abort_reasons.remove(&self.id);
+        if let Some((action, until)) = self.cooldown {
+            if actions.cooldown_until.get(&action) == Some(&until) {
+                actions.cooldown_until.remove(&action);
+            }
+        }
+    }
+}
+
+/// Place of an action among those resolving at the end of the same tick, see `tick_secs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TurnKey {
+    tick: u64,
+    /// Drawn from the seed for every tick and user, so that nobody always goes first
+    draw: u64,
+    user: usize,
+    action: u64,
+}
+
+/// Actions of turn-based games waiting for the end of their tick or resolving
+#[derive(Default)]
+struct Turns {
+    queue: std::sync::Mutex<BTreeSet<TurnKey>>,
+    /// Woken whenever an action is done with its turn
+    turn_done: tokio::sync::Notify,
+}
+
+/// Queued action, it leaves the queue when dropped
+struct Turn {
+    turns: Arc<Turns>,
+    key: TurnKey,
+    /// Game time at the end of the tick
+    ends_at: Duration,
+}
+
+impl Turn {
+    /// Waits until the actions before this one in the queue are done
+    async fn wait(&self) {
+        loop {
+            let mut done = pin!(self.turns.turn_done.notified());
+            done.as_mut().enable();
+            if self.turns.queue.lock().unwrap().first() == Some(&self.key) {
+                return;
+            }
+            done.await;
+        }
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.turns.queue.lock().unwrap().remove(&self.key);
+        self.turns.turn_done.notify_waiters();
+    }
+}
+
+/// Pipes currently in the game, ids of retired pipes are never reused
+#[derive(Default)]
+struct PipeRegistry {
+    pipes: BTreeMap<PipeId, Arc<Mutex<Pipe>>>,
+    /// One more than the highest id ever used
+    next_id: usize,
+}
+
+impl PipeRegistry {
+    fn new(pipes: impl IntoIterator<Item = (PipeId, Pipe)>, next_id: usize) -> Self {
+        let pipes: BTreeMap<PipeId, Arc<Mutex<Pipe>>> = pipes
+            .into_iter()
+            .map(|(id, pipe)| (id, Arc::new(Mutex::new(pipe))))
+            .collect();
+        let next_id = pipes
+            .keys()
+            .map(|id| id.get() + 1)
+            .fold(next_id.max(1), usize::max);
+        Self { pipes, next_id }
+    }
+
+    fn get(&self, id: PipeId) -> Option<Arc<Mutex<Pipe>>> {
+        self.pipes.get(&id).cloned()
+    }
+
+    /// Ordered by id
+    fn ids(&self) -> Vec<PipeId> {
+        self.pipes.keys().copied().collect()
+    }
+
+    /// Whether the pipe is in the game or was retired
+    fn existed(&self, id: PipeId) -> bool {
+        id.get() < self.next_id
+    }
+
+    fn add(&mut self, pipe: Pipe) -> PipeId {
+        let id = PipeId::new(self.next_id).expect("Pipe ids start from 1");
+        self.next_id += 1;
+        self.pipes.insert(id, Arc::new(Mutex::new(pipe)));
+        id
+    }
+}
+
+/// All game time is read from the tokio clock, so in a runtime with paused time
+/// (`tokio::time::pause`) delays pass instantly and a seeded game plays out deterministically
+pub struct App {
+    /// Not set until the waiting room fills up if `wait_for_players` is set
+    start: tokio::sync::watch::Sender<Option<Instant>>,
+    /// Users that made a request while waiting for players
+    waiting_room: std::sync::Mutex<HashSet<UserToken>>,
+    time_to_run: Option<Duration>,
+    /// Added to `time_to_run` because of ties
+    overtime: std::sync::Mutex<Duration>,
+    /// Set when the operator ends the game early
+    ended: std::sync::atomic::AtomicBool,
+    /// Set once the end is announced, actions are rejected from then on
+    over: std::sync::atomic::AtomicBool,
+    /// Start of the current pause
+    paused_since: std::sync::Mutex<Option<Instant>>,
+    /// Total length of the finished pauses, added to `time_to_run`
+    paused_for: std::sync::Mutex<Duration>,
+    end_requested: tokio::sync::Notify,
+    allow_unknown_users: bool,
+    /// Replaced as a whole by [`App::update_config`], so it is read through [`App::config`]
+    config: std::sync::RwLock<Arc<Config>>,
+    /// Sharded so that requests of different users do not wait for each other
+    users: ShardedMap<UserToken, Arc<UserEntry>>,
+    /// Users added so far, also the index of the next one
+    joined_users: std::sync::atomic::AtomicUsize,
+    pipes: std::sync::RwLock<PipeRegistry>,
+    log_senders: Mutex<Vec<LogSubscriber>>,
+    history: Mutex<History>,
+    metrics: Metrics,
+    auth_failures: std::sync::Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    /// Failed authentication attempts since the last [`App::sweep_rate_limits`]
+    new_auth_failures: std::sync::Mutex<HashMap<IpAddr, usize>>,
+    requests_by_ip: std::sync::Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    connections_by_ip: std::sync::Mutex<HashMap<IpAddr, usize>>,
+    /// Ip each token was first used from, see `bind_tokens_to_ip`
+    token_ips: std::sync::Mutex<HashMap<UserToken, IpAddr>>,
+    requests_by_token: std::sync::Mutex<HashMap<UserToken, VecDeque<Instant>>>,
+    /// Global events started so far, expired ones are dropped when a new one starts
+    global_events: std::sync::Mutex<Vec<GlobalEvent>>,
+    /// Demand surcharges of the modifiers bought so far, with dynamic pricing
+    demand: std::sync::Mutex<BTreeMap<Modifier, Demand>>,
+    last_big_event: std::sync::Mutex<Option<LogEntry>>,
+    /// Last overlay and when it was computed, see `OVERLAY_REFRESH`
+    overlay: std::sync::Mutex<Option<(Instant, Arc<OverlayResponse>)>>,
+    /// Game state rebuilt from the log, readable without waiting for users and pipes
+    state: std::sync::RwLock<replay::State>,
+    ranking: std::sync::Mutex<Ranking<UserToken>>,
+    round: std::sync::Mutex<RoundState>,
+    /// Woken whenever an operation finishes
+    operation_done: tokio::sync::Notify,
+    turns: Arc<Turns>,
+    seed: u64,
+    /// Seeded like a `StdRng`, it is the same generator, but can be saved in checkpoints
+    rng: std::sync::Mutex<ChaCha12Rng>,
+}
+
+impl App {
+    pub async fn pipe_history(
+        &self,
+        pipe_id: PipeId,
+        from: usize,
+        limit: usize,
+    ) -> Result<HistoryResponse> {
+        // History of retired pipes stays available
+        if !self.pipes.read().unwrap().existed(pipe_id) {
+            return Err(Error::PipeNotFound {
+                pipe_id: Some(pipe_id),
+            });
+        }
+        Ok(self.history.lock().await.pipe_page(pipe_id, from, limit))
+    }
+
+    pub async fn user_history(
+        &self,
+        user_token: &UserToken,
+        from: usize,
+        limit: usize,
+    ) -> Result<HistoryResponse> {
+        Ok(self.history.lock().await.user_page(user_token, from, limit))
+    }
+
+    /// Page of the whole log, for clients that poll instead of subscribing
+    pub async fn logs_page(&self, offset: usize, limit: usize) -> LogsResponse {
+        self.history.lock().await.logs_page(offset, limit)
+    }
+}
+
+impl App {
+    async fn log(&self, msg: LogMessage) {
+        let entry = LogEntry {
+            time: self.elapsed().as_secs_f64(),
+            msg,
+        };
+        // Locked before the senders like in `register_logs`, so that new subscribers miss nothing.
+        // The read model is applied under it as well, so it follows the order of the log.
+        let mut history = self.history.lock().await;
+        if entry.msg.is_big_event() {
+            *self.last_big_event.lock().unwrap() = Some(entry.clone());
+        }
+        self.state
+            .write()
+            .unwrap()
+            .apply(&entry.clone().map_user(|token| token.0));
+        self.log_senders
+            .lock()
+            .await
+            .retain_mut(|sender| sender.offer(entry.clone()));
+        history.push(entry);
+    }
+    /// Every change of a user has to be logged, replays and the read model only know the log
+    async fn log_user(&self, token: &UserToken, user: &User, change: Option<ScoreChange>) {
+        self.log(LogMessage::UpdateUser {
+            user: token.clone(),
+            state: user.clone(),
+            change,
+        })
+        .await;
+        let changes = self.ranking.lock().unwrap().update(token, Some(user.score));
+        self.log_rank_changes(changes).await;
+    }
+    async fn log_rank_changes(&self, changes: Vec<RankChange<UserToken>>) {
+        for change in changes {
+            self.log(LogMessage::RankChanged {
+                user: change.user,
+                old_rank: change.old_rank,
+                new_rank: change.new_rank,
+            })
+            .await;
+        }
+    }
+    /// Every change of a pipe has to be logged, replays and the read model only know the log
+    async fn log_pipe(&self, id: PipeId, pipe: &Pipe) {
+        self.log(LogMessage::UpdatePipe {
+            id,
+            state: pipe.clone(),
+        })
+        .await;
+    }
+    async fn log_observation(&self, token: &UserToken, pipe_id: PipeId, observation: Observation) {
+        if self.config().log_observations {
+            self.log(LogMessage::ValueObserved {
+                user: token.clone(),
+                pipe_id,
+                observation,
+            })
+            .await;
+        }
+    }
+    /// Bounded channel for a network subscriber, so that it can not hold up the game
+    pub fn log_channel(&self, overflow: Option<LogOverflow>) -> (LogSubscriber, LogReceiver) {
+        let (sender, receiver) = mpsc::channel(self.config().log_subscriber_buffer);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let subscriber = LogSubscriber::Bounded {
+            sender,
+            overflow: overflow.unwrap_or(self.config().log_subscriber_overflow),
+            dropped: 0,
+            queued: queued.clone(),
+        };
+        (subscriber, LogReceiver { receiver, queued })
+    }
+    pub async fn register_logs(&self, sender: impl Into<LogSubscriber>) {
+        let mut sender = sender.into();
+        let mut next = 0;
+        // The history is replayed in chunks, a slow subscriber must not keep it locked
+        loop {
+            let chunk: Vec<LogEntry> = {
+                let history = self.history.lock().await;
+                next = next.max(history.first());
+                if next == history.len() {
+                    self.log_senders.lock().await.push(sender);
+                    return;
+                }
+                let end = history.len().min(next + LOG_REPLAY_CHUNK);
+                let chunk = (next..end).filter_map(|index| history.get(index)).collect();
+                next = end;
+                chunk
+            };
+            for entry in chunk {
+                if let Err(e) = sender.send(entry).await {
+                    error!("{e}");
+                    return;
+                }
+            }
+        }
+    }
+    /// Subscribes to new log entries only, without replaying the history
+    pub async fn register_live_logs(&self, sender: impl Into<LogSubscriber>) {
+        self.log_senders.lock().await.push(sender.into());
+    }
+    pub async fn history_len(&self) -> usize {
+        self.history.lock().await.len()
+    }
+    pub async fn log_subscribers(&self) -> usize {
+        self.log_senders.lock().await.len()
+    }
+    /// Entries waiting to be read by each network subscriber,
+    /// the unbounded subscribers in the server are expected to keep up
+    pub async fn log_backlogs(&self) -> Vec<usize> {
+        self.log_senders
+            .lock()
+            .await
+            .iter()
+            .filter_map(|sender| match sender {
+                LogSubscriber::Unbounded(_) => None,
+                LogSubscriber::Bounded { queued, .. } => Some(queued.load(Ordering::Relaxed)),
+            })
+            .collect()
+    }
+    pub async fn unregister_logs(&self, sender: impl Into<LogSubscriber>) {
+        let sender = sender.into();
+        self.log_senders
+            .lock()
+            .await
+            .retain(|s| !s.same_receiver(&sender));
+    }
+}
+
+/// Log entries replayed to a new subscriber per lock of the history
+const LOG_REPLAY_CHUNK: usize = 256;
+
+/// Sending end of a log subscription
+#[derive(Clone)]
+pub enum LogSubscriber {
+    /// Gets every entry, for consumers in the server that keep up such as the log file
+    Unbounded(mpsc::UnboundedSender<LogEntry>),
+    /// Network clients, which may fall behind
+    Bounded {
+        sender: mpsc::Sender<LogEntry>,
+        overflow: LogOverflow,
+        /// Entries skipped so far because of `LogOverflow::Drop`
+        dropped: usize,
+        /// Entries in the channel, shared with the [`LogReceiver`]
+        queued: Arc<AtomicUsize>,
+    },
+}
+
+/// Receiving end of a bounded log subscription, counts the entries it has yet to yield
+pub struct LogReceiver {
+    receiver: mpsc::Receiver<LogEntry>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl LogReceiver {
+    pub fn try_recv(&mut self) -> std::result::Result<LogEntry, mpsc::TryRecvError> {
+        let entry = self.receiver.try_recv()?;
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        Ok(entry)
+    }
+}
+
+impl Stream for LogReceiver {
+    type Item = LogEntry;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<LogEntry>> {
+        let entry = ready!(self.receiver.poll_next_unpin(cx));
+        if entry.is_some() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        Poll::Ready(entry)
+    }
+}
+
+impl From<mpsc::UnboundedSender<LogEntry>> for LogSubscriber {
+    fn from(sender: mpsc::UnboundedSender<LogEntry>) -> Self {
+        LogSubscriber::Unbounded(sender)
+    }
+}
+
+impl LogSubscriber {
+    fn same_receiver(&self, other: &LogSubscriber) -> bool {
+        match (self, other) {
+            (LogSubscriber::Unbounded(a), LogSubscriber::Unbounded(b)) => a.same_receiver(b),
+            (
+                LogSubscriber::Bounded { sender: a, .. },
+                LogSubscriber::Bounded { sender: b, .. },
+            ) => a.same_receiver(b),
+            _ => false,
+        }
+    }
+
+    /// Waits for room, only used outside of the game path
+    async fn send(&mut self, entry: LogEntry) -> std::result::Result<(), mpsc::SendError> {
+        match self {
+            LogSubscriber::Unbounded(sender) => sender.send(entry).await,
+            LogSubscriber::Bounded { sender, queued, .. } => {
+                // Counted before sending, so that the receiver never counts below zero
+                queued.fetch_add(1, Ordering::Relaxed);
+                let result = sender.send(entry).await;
+                if result.is_err() {
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                }
+                result
+            }
+        }
+    }
+
+    /// Hands over an entry without waiting, false if the subscriber is gone or has to go
+    fn offer(&mut self, entry: LogEntry) -> bool {
+        let (sender, overflow, dropped, queued) = match self {
+            LogSubscriber::Unbounded(sender) => return sender.unbounded_send(entry).is_ok(),
+            LogSubscriber::Bounded {
+                sender,
+                overflow,
+                dropped,
+                queued,
+            } => (sender, overflow, dropped, queued),
+        };
+        queued.fetch_add(1, Ordering::Relaxed);
+        let result = sender.try_send(entry);
+        if result.is_err() {
+            queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        match result {
+            Ok(()) => true,
+            Err(e) if e.is_disconnected() => false,
+            Err(_) => match overflow {
+                LogOverflow::Drop => {
+                    if *dropped == 0 {
+                        warn!("Log subscriber fell behind, dropping entries");
+                    }
+                    *dropped += 1;
+                    true
+                }
+                LogOverflow::Disconnect => {
+                    warn!("Log subscriber fell behind, disconnecting it");
+                    false
+                }
+            },
+        }
+    }
+}
+
+impl App {
+    /// Sets the time after which the server is going to be stopped
+    pub fn set_time_to_run(&mut self, time_to_run: Option<Duration>) {
+        self.time_to_run = time_to_run;
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replaces the config of the running game, actions started from now on use the new values.
+    /// Fields that are only read at the start have to stay the same.
+    pub fn update_config(&self, mut config: Config) -> Result<Arc<Config>, ConfigUpdateError> {
+        let errors = config.validate();
+        if !errors.is_empty() {
+            return Err(ConfigUpdateError::Invalid(errors));
+        }
+        config.prepare();
+        let mut current = self.config.write().unwrap();
+        let updated = current.with_mutable_fields(config.clone());
+        let updated = serde_json::to_value(updated).expect("Config should serialize");
+        let serde_json::Value::Object(new) =
+            serde_json::to_value(&config).expect("Config should serialize")
+        else {
+            unreachable!("Config should serialize to an object");
+        };
+        let changed: Vec<_> = new
+            .into_iter()
+            .filter(|(field, value)| updated.get(field) != Some(value))
+            .map(|(field, _)| field)
+            .collect();
+        if !changed.is_empty() {
+            return Err(ConfigUpdateError::Immutable(changed));
+        }
+        info!("Config updated: {config:#?}");
+        *current = Arc::new(config);
+        Ok(current.clone())
+    }
+
+    /// Seed the game was started with, reproduces the pipe layout when set in the config
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Order in which the user joined, unlike the token it can be shown to others
+    pub fn user_index(&self, token: &UserToken) -> Option<usize> {
+        self.users.get(token).map(|user| user.index)
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Start of the game, unknown while waiting for players
+    fn started_at(&self) -> Option<Instant> {
+        *self.start.borrow()
+    }
+
+    /// Time since the start of the game, zero before it
+    fn elapsed(&self) -> Duration {
+        self.started_at()
+            .map_or(Duration::ZERO, |start| start.elapsed())
+    }
+
+    /// Seconds since the start of the game, as used in the log
+    pub fn game_time(&self) -> f64 {
+        self.elapsed().as_secs_f64()
+    }
+
+    /// Zero while waiting for players, see [`App::is_waiting_for_players`]
+    pub fn time_until_start(&self) -> Duration {
+        self.started_at().map_or(Duration::ZERO, |start| {
+            start.saturating_duration_since(Instant::now())
+        })
+    }
+
+    /// Whether the game waits for `wait_for_players` users or an admin to start
+    pub fn is_waiting_for_players(&self) -> bool {
+        self.started_at().is_none()
+    }
+
+    /// Resolves once the game has started
+    pub async fn wait_for_start(&self) {
+        let mut start = self.start.subscribe();
+        let start = *start
+            .wait_for(Option::is_some)
+            .await
+            .expect("The start is owned by the app");
+        if let Some(start) = start {
+            sleep_until(start).await;
+        }
+    }
+
+    /// Starts the clock of a game waiting for players, returns whether it was waiting.
+    /// Held requests are released after `start_delay_secs`.
+    pub fn start_game(&self) -> bool {
+        let start = Instant::now() + Duration::from_secs_f64(self.config().start_delay_secs);
+        let started = self.start.send_if_modified(|current| {
+            if current.is_some() {
+                return false;
+            }
+            *current = Some(start);
+            true
+        });
+        if started {
+            info!("Starting the game");
+        }
+        started
+    }
+
+    /// Counts the user into `wait_for_players` and starts the game once enough have come
+    fn enter_waiting_room(&self, token: &UserToken) {
+        let Some(needed) = self.config().wait_for_players else {
+            return;
+        };
+        if !self.is_waiting_for_players() {
+            return;
+        }
+        let arrived = {
+            let mut waiting_room = self.waiting_room.lock().unwrap();
+            waiting_room.insert(token.clone());
+            waiting_room.len()
+        };
+        debug!("{token:?} is waiting for the start, {arrived} of {needed} users are here");
+        if arrived >= needed {
+            self.start_game();
+        }
+    }
+
+    pub fn time_left(&self) -> Option<Duration> {
+        if self.ended.load(std::sync::atomic::Ordering::Relaxed) {
+            return Some(Duration::ZERO);
+        }
+        if let Some(rounds) = &self.config().rounds {
+            // Pauses only extend the round they happen in, so the end is counted from it
+            let later_rounds = rounds
+                .count
+                .saturating_sub(self.round.lock().unwrap().index + 1);
+            let later = Duration::from_secs_f64(rounds.duration_secs) * later_rounds as u32;
+            return self
+                .time_to_run
+                .and(self.round_time_left())
+                .map(|time| time + later);
+        }
+        let extension = *self.overtime.lock().unwrap() + self.paused_duration();
+        self.time_to_run
+            .map(|time| (time + extension).saturating_sub(self.elapsed()))
+    }
+
+    /// Time left in the current round, `None` if the game has no rounds
+    pub fn round_time_left(&self) -> Option<Duration> {
+        let config = self.config();
+        let rounds = config.rounds.as_ref()?;
+        if self.ended.load(std::sync::atomic::Ordering::Relaxed) {
+            return Some(Duration::ZERO);
+        }
+        let round = self.round.lock().unwrap();
+        if round.ended {
+            return Some(Duration::ZERO);
+        }
+        let paused = self.paused_duration().as_secs_f64() - round.paused_secs_at_start;
+        let end = round.started_at + rounds.duration_secs + paused;
+        Some(Duration::from_secs_f64((end - self.game_time()).max(0.0)))
+    }
+
+    /// Final scores of the finished rounds
+    pub fn round_scores(&self) -> Vec<Results> {
+        self.round.lock().unwrap().scores.clone()
+    }
+
+    /// Ends the current round and starts the next one unless it was the last,
+    /// returns whether a new round started
+    pub async fn end_round(&self) -> bool {
+        let Some(rounds) = &self.config().rounds else {
+            return false;
+        };
+        let index = {
+            let round = self.round.lock().unwrap();
+            if round.ended {
+                return false;
+            }
+            round.index
+        };
+        let scores = self.scores().await;
+        info!("Round {} ended with scores {scores:?}", index + 1);
+        {
+            let mut round = self.round.lock().unwrap();
+            round.scores.push(scores);
+            round.ended = true;
+        }
+        self.log(LogMessage::RoundEnd { round: index + 1 }).await;
+        if self.ended.load(std::sync::atomic::Ordering::Relaxed) || index + 1 >= rounds.count {
+            return false;
+        }
+        self.start_round(index + 1, rounds.duration_secs).await;
+        true
+    }
+
+    /// Actions in flight at the end of a round finish in the new one
+    async fn start_round(&self, index: usize, duration_secs: f64) {
+        info!("Round {} started", index + 1);
+        {
+            let mut round = self.round.lock().unwrap();
+            round.index = index;
+            round.started_at = self.game_time();
+            round.paused_secs_at_start = self.paused_duration().as_secs_f64();
+            round.ended = false;
+        }
+        // Every round starts from the base prices, like from the initial scores
+        self.demand.lock().unwrap().clear();
+        self.log(LogMessage::RoundStart {
+            round: index + 1,
+            duration_secs,
+        })
+        .await;
+        let ids = self.pipes.read().unwrap().ids();
+        // Generated up front in a fixed order, so that seeded games stay reproducible
+        let new_pipes: Vec<(PipeId, Pipe)> = {
+            let mut rng = self.rng.lock().unwrap();
+            ids.into_iter()
+                .map(|id| (id, self.config().random_pipe(&mut *rng)))
+                .collect()
+        };
+        for (id, new_pipe) in new_pipes {
+            // Retired in the meantime
+            let Some(pipe) = self.pipes.read().unwrap().get(id) else {
+                continue;
+            };
+            let mut pipe = pipe.lock().await;
+            *pipe = new_pipe;
+            self.log_pipe(id, &pipe).await;
+        }
+        let users: Vec<(UserToken, Arc<UserEntry>)> = self.users.entries();
+        for (token, entry) in users {
+            entry.actions.lock().unwrap().bankrupt_until = None;
+            let mut user = entry.state.lock().await;
+            let initial = self.config().initial_user(&token).score;
+            let change = ScoreChange {
+                delta: initial - user.score,
+                reason: ScoreReason::RoundStart { round: index + 1 },
+            };
+            user.score = initial;
+            self.log_user(&token, &user, Some(change)).await;
+        }
+    }
+
+    /// Total time the game spent paused, including the current pause
+    fn paused_duration(&self) -> Duration {
+        let current = self
+            .paused_since
+            .lock()
+            .unwrap()
+            .map_or(Duration::ZERO, |since| since.elapsed());
+        *self.paused_for.lock().unwrap() + current
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_since.lock().unwrap().is_some()
+    }
+
+    /// Stops the countdown and rejects new actions until [`App::resume`],
+    /// actions already in flight finish normally
+    pub async fn pause(&self) {
+        {
+            let mut paused_since = self.paused_since.lock().unwrap();
+            if paused_since.is_some() {
+                return;
+            }
+            *paused_since = Some(Instant::now());
+        }
+        info!("Game paused");
+        self.log(LogMessage::GamePaused).await;
+    }
+
+    pub async fn resume(&self) {
+        let paused_for = {
+            let Some(since) = self.paused_since.lock().unwrap().take() else {
+                return;
+            };
+            let paused_for = since.elapsed();
+            *self.paused_for.lock().unwrap() += paused_for;
+            paused_for
+        };
+        info!("Game resumed after {paused_for:?}");
+        self.log(LogMessage::GameResumed {
+            paused_secs: paused_for.as_secs_f64(),
+        })
+        .await;
+    }
+
+    /// Ends the game now, the server stops once it notices with [`App::wait_for_end`]
+    pub async fn end_game(&self) {
+        if self.ended.swap(true, std::sync::atomic::Ordering::Relaxed) {
+            return;
+        }
+        info!("Game ended early");
+        self.log(LogMessage::GameEnding { seconds_left: 0.0 }).await;
+        self.end_requested.notify_one();
+    }
+
+    /// Marks the end of the game in the log and runs the podium ceremony if enabled,
+    /// the results are final by now
+    pub async fn announce_end(&self) {
+        self.over.store(true, std::sync::atomic::Ordering::Relaxed);
+        self.log(LogMessage::GameEnd).await;
+        let results = self.results().await;
+        let mut standings: Vec<Standing> = results
+            .iter()
+            .map(|(user, &score)| Standing {
+                // Ties share a rank
+                rank: 1 + results.values().filter(|&&other| other > score).count(),
+                user: UserToken(user.clone()),
+                score,
+            })
+            .collect();
+        standings.sort_by(|a, b| (a.rank, &a.user).cmp(&(b.rank, &b.user)));
+        if self.config().podium_ceremony {
+            let delay = Duration::from_secs_f64(self.config().podium_delay_secs);
+            let mut podium: Vec<&Standing> = standings
+                .iter()
+                .filter(|standing| standing.rank <= self.config().podium_size)
+                .collect();
+            podium.sort_by_key(|standing| (std::cmp::Reverse(standing.rank), &standing.user));
+            for standing in podium {
+                sleep(delay).await;
+                info!(
+                    "Place {}: {:?} with {}",
+                    standing.rank, standing.user, standing.score
+                );
+                self.log(LogMessage::Podium {
+                    rank: standing.rank,
+                    user: standing.user.clone(),
+                    score: standing.score,
+                })
+                .await;
+            }
+        }
+        self.log(LogMessage::GameOver { results: standings }).await;
+    }
+
+    /// Whether the end of the game was announced
+    pub fn is_over(&self) -> bool {
+        self.over.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Whether the game was ended with [`App::end_game`] before the time ran out
+    pub fn is_ended_early(&self) -> bool {
+        self.ended.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Resolves once the game was ended with [`App::end_game`]
+    pub async fn wait_for_end(&self) {
+        self.end_requested.notified().await;
+    }
+
+    /// Extends the game if the leaders are tied and overtime is enabled,
+    /// returns whether the game goes on
+    pub async fn start_overtime(&self) -> bool {
+        if self.ended.load(std::sync::atomic::Ordering::Relaxed) {
+            return false;
+        }
+        let Some(overtime_secs) = self
+            .config()
+            .overtime_secs
+            .filter(|_| self.config().rounds.is_none())
+        else {
+            return false;
+        };
+        let increment = Duration::from_secs_f64(overtime_secs);
+        if *self.overtime.lock().unwrap() + increment
+            > Duration::from_secs_f64(self.config().max_overtime_secs)
+        {
+            info!("Maximum overtime reached");
+            return false;
+        }
+        let mut scores: Vec<Score> = self.results().await.into_values().collect();
+        scores.sort_unstable_by(|a, b| b.cmp(a));
+        let [first, second, ..] = scores[..] else {
+            return false;
+        };
+        if first != second {
+            return false;
+        }
+        *self.overtime.lock().unwrap() += increment;
+        info!("Top scores are tied at {first}, overtime for {increment:?}");
+        self.log(LogMessage::Overtime {
+            tied_score: first,
+            seconds_left: self.time_left().unwrap_or_default().as_secs_f64(),
+        })
+        .await;
+        true
+    }
+
+    /// Best effort results without waiting for locks, for use when the game can't proceed.
+    /// Users whose state is currently locked are left out.
+    pub fn try_results(&self) -> Option<Results> {
+        let users = self.users.try_entries()?;
+        let scores = users
+            .iter()
+            .filter_map(|(token, user)| Some((token.0.clone(), user.state.try_lock()?.score)))
+            .collect();
+        Some(self.aggregate_rounds(scores))
+    }
+
+    /// Statistics of the users by token
+    pub async fn player_stats(&self) -> BTreeMap<String, PlayerStats> {
+        let mut stats = BTreeMap::new();
+        for (token, user) in self.users.entries() {
+            stats.insert(token.0.clone(), user.state.lock().await.stats.clone());
+        }
+        stats
+    }
+
+    pub async fn profiles(&self) -> BTreeMap<String, UserProfile> {
+        collect_profiles(&self.users.entries())
+    }
+
+    /// Results with the statistics of the users
+    pub async fn detailed_results(&self) -> DetailedResults {
+        DetailedResults {
+            scores: self.results().await,
+            players: self.player_stats().await,
+            profiles: self.profiles().await,
+            network: self.metrics().network_by_user(),
+        }
+    }
+
+    /// Same as [`App::detailed_results`], but gives up instead of waiting for locked users
+    pub fn try_detailed_results(&self) -> Option<DetailedResults> {
+        let users = self.users.try_entries()?;
+        let players = users
+            .iter()
+            .map(|(token, user)| Some((token.0.clone(), user.state.try_lock()?.stats.clone())))
+            .collect::<Option<_>>()?;
+        let profiles = collect_profiles(&users);
+        Some(DetailedResults {
+            scores: self.try_results()?,
+            players,
+            profiles,
+            network: self.metrics().network_by_user(),
+        })
+    }
+
+    /// Combines the current scores with the finished rounds, if the game has rounds
+    fn aggregate_rounds(&self, scores: Results) -> Results {
+        let Some(rounds) = &self.config().rounds else {
+            return scores;
+        };
+        let round = self.round.lock().unwrap();
+        let mut played = round.scores.clone();
+        if !round.ended {
+            played.push(scores);
+        }
+        rounds.aggregation.aggregate(&played)
+    }
+
+    /// Judged by the requests seen so far, so only meaningful for a server handling requests
+    pub fn user_status(&self, token: &UserToken) -> UserStatus {
+        let timeout = Duration::from_secs_f64(self.config().inactivity_timeout_secs);
+        match self.metrics.last_request(&token.0) {
+            None => UserStatus::NeverConnected,
+            Some(time) if time.elapsed() > timeout => UserStatus::TimedOut,
+            Some(_) => UserStatus::Active,
+        }
+    }
+
+    /// Final results, aggregated over the rounds if the game has them
+    pub async fn results(&self) -> Results {
+        let scores = self.scores().await;
+        self.aggregate_rounds(scores)
+    }
+
+    /// Current scores of the users
+    async fn scores(&self) -> Results {
+        let mut result = BTreeMap::new();
+        for (token, user) in self.users.entries() {
+            result.insert(token.0.clone(), user.state.lock().await.score);
+        }
+        let logged = &self.state.read().unwrap().scores;
+        for (user, score) in &result {
+            if logged.get(user) != Some(score) {
+                error!(
+                    "Score of {user:?} is {score}, but the log says {:?}",
+                    logged.get(user)
+                );
+            }
+        }
+        result
+    }
+}
+
+fn collect_profiles(users: &[(UserToken, Arc<UserEntry>)]) -> BTreeMap<String, UserProfile> {
+    users
+        .iter()
+        .filter_map(|(token, user)| {
+            let profile = user.profile.lock().unwrap().clone();
+            let empty = profile.name.is_none()
+                && profile.team.is_none()
+                && profile.color.is_none()
+                && !profile.ghost;
+            (!empty).then(|| (token.0.clone(), profile))
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DetailedResults {
+    pub scores: Results,
+    pub players: BTreeMap<String, PlayerStats>,
+    /// Profiles of the users that have one
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, UserProfile>,
+    /// Network characteristics of the users that made requests
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub network: BTreeMap<String, NetworkStats>,
+}
+
+impl App {
+    /// Queues the action for the end of the tick in which `delay` is over, in turn-based games
+    fn queue_turn(&self, action: &ActionGuard, delay: Duration) -> Option<Turn> {
+        let tick_secs = self.config().tick_secs?;
+        // Locked while reading the clock, so that no action of a tick can be queued
+        // after the first ones of it resolved
+        let mut queue = self.turns.queue.lock().unwrap();
+        let tick = ((self.elapsed() + delay).as_secs_f64() / tick_secs).floor() as u64 + 1;
+        let user = action.user.index;
+        let draw = StdRng::seed_from_u64(self.seed ^ (tick << 20) ^ user as u64).gen();
+        let key = TurnKey {
+            tick,
+            draw,
+            user,
+            action: action.id,
+        };
+        queue.insert(key);
+        Some(Turn {
+            turns: self.turns.clone(),
+            key,
+            ends_at: Duration::from_secs_f64(tick as f64 * tick_secs),
+        })
+    }
+
+    /// Waits for the action, logging it if aborted. In turn-based games the action waits
+    /// until the end of the tick in which the delay is over, and then for its turn
+    async fn action_sleep(
+        &self,
+        user_token: &UserToken,
+        action: &mut ActionGuard,
+        delay: Duration,
+    ) -> Result<()> {
+        action.turn = None;
+        let turn = self.queue_turn(action, delay);
+        let delay = match &turn {
+            Some(turn) => {
+                action.set_expected_completion(turn.ends_at.as_secs_f64());
+                turn.ends_at.saturating_sub(self.elapsed())
+            }
+            None => delay,
+        };
+        debug!("Sleeping for {delay:?}");
+        let start = Instant::now();
+        let result = action.sleep(delay).await;
+        action.user().lock().await.stats.time_blocked += start.elapsed().as_secs_f64();
+        let Err(reason) = result else {
+            if let Some(turn) = turn {
+                turn.wait().await;
+                debug!("Resolving in turn {:?}", turn.key);
+                action.turn = Some(turn);
+                action.rearm();
+            }
+            return Ok(());
+        };
+        let info = action.info();
+        info!(
+            "{:?} of {user_token:?} on pipe {} aborted: {reason:?}",
+            info.action, info.pipe_id
+        );
+        // Logged as rolled back by the collect instead
+        if reason == AbortReason::Disconnected {
+            return Err(Error::ActionAborted);
+        }
+        self.log(LogMessage::ActionAborted {
+            user: user_token.clone(),
+            action: info.action,
+            pipe_id: info.pipe_id,
+            reason,
+        })
+        .await;
+        Err(Error::ActionAborted)
+    }
+
+    /// Interrupts all waiting actions of the user, returns how many actions were in flight
+    pub async fn abort_actions(
+        &self,
+        user_token: &UserToken,
+        reason: AbortReason,
+    ) -> Result<AbortActionsResponse> {
+        let user = self.user_entry(user_token).await?;
+        let mut actions = user.actions.lock().unwrap();
+        let actions = &mut *actions;
+        for (&id, handle) in &actions.aborts {
+            actions.abort_reasons.insert(id, reason);
+            handle.abort();
+        }
+        Ok(AbortActionsResponse {
+            aborted: actions.aborts.len(),
+        })
+    }
+
+    /// Counts the outcome of the action in the user's stats,
+    /// logging failures if enabled in config
+    async fn record_result<T>(
+        &self,
+        user_token: &UserToken,
+        action: Action,
+        pipe_id: PipeId,
+        result: &Result<T>,
+    ) {
+        // Requests with invalid tokens are not made by any user
+        if let Err(
+            Error::UserNotFound
+            | Error::TooManyAuthFailures { .. }
+            | Error::SpectatorOnly
+            | Error::TokenBoundToOtherIp,
+        ) = result
+        {
+            return;
+        }
+        if let Some(entry) = self.users.get(user_token) {
+            let stats = &mut entry.state.lock().await.stats;
+            if result.is_ok() {
+                stats.last_success_secs = Some(self.game_time());
+                stats.error_streak = 0;
+            } else {
+                stats.errors += 1;
+                stats.error_streak += 1;
+            }
+        }
+        if let (Err(error), Some(policy)) = (result, &self.config().misconduct_policy) {
+            if policy.errors.contains(&error.code()) {
+                self.count_misconduct(user_token, policy).await;
+            }
+        }
+        let Err(reason) = result else {
+            return;
+        };
+        if !self.config().log_failed_actions {
+            return;
+        }
+        self.log(LogMessage::ActionFailed {
+            user: user_token.clone(),
+            action,
+            pipe_id,
+            reason: reason.code(),
+        })
+        .await;
+    }
+}
+
+impl App {
+    async fn count_misconduct(&self, user_token: &UserToken, policy: &MisconductPolicy) {
+        let Some(entry) = self.users.get(user_token) else {
+            return;
+        };
+        let penalized = entry.actions.lock().unwrap().count_misconduct(policy);
+        let mut user = entry.state.lock().await;
+        user.stats.misconduct += 1;
+        if !penalized {
+            return;
+        }
+        user.stats.misconduct_penalties += 1;
+        info!(
+            "{user_token:?} made {} invalid requests within {}s, taking {} score",
+            policy.threshold, policy.window_secs, policy.penalty
+        );
+        let (bankrupt, change) =
+            self.change_score(&mut user, -policy.penalty, ScoreReason::Misconduct);
+        self.log_user(user_token, &user, Some(change)).await;
+        if bankrupt {
+            self.bankrupt(user_token, &entry, user.score).await;
+        }
+    }
+}
+
+/// Window of `max_auth_failures_per_minute`
+const AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Window of `max_requests_per_ip_per_minute`
+const IP_REQUEST_WINDOW: Duration = Duration::from_secs(60);
+
+/// Window of `max_requests_per_token_per_second`
+const TOKEN_REQUEST_WINDOW: Duration = Duration::from_secs(1);
+
+fn prune_requests(times: &mut VecDeque<Instant>, window: Duration) {
+    while times.front().is_some_and(|time| time.elapsed() > window) {
+        times.pop_front();
+    }
+}
+
+/// Drops the keys without requests in the window
+fn sweep_requests<K>(requests: &mut HashMap<K, VecDeque<Instant>>, window: Duration) {
+    requests.retain(|_, times| {
+        prune_requests(times, window);
+        !times.is_empty()
+    });
+}
+
+/// Times of the recent requests of `key`, those older than `window` dropped. The entries of
+/// the other keys are swept when a new key shows up, so that keys seen once don't stay forever
+fn recent_requests<K: std::hash::Hash + Eq>(
+    requests: &mut HashMap<K, VecDeque<Instant>>,
+    key: K,
+    window: Duration,
+) -> &mut VecDeque<Instant> {
+    if !requests.contains_key(&key) {
+        sweep_requests(requests, window);
+    }
+    let times = requests.entry(key).or_default();
+    prune_requests(times, window);
+    times
+}
+
+impl App {
+    async fn user_entry(&self, token: &UserToken) -> Result<Arc<UserEntry>> {
+        if self.is_spectator(token) {
+            return Err(Error::SpectatorOnly);
+        }
+        if self.allow_unknown_users && self.config().implicit_users {
+            // Create new user on demand
+            let (user, created) = self.users.get_or_insert_with(token.to_owned(), || {
+                info!("Unknown user detected, creating {token:?}");
+                let index = self
+                    .joined_users
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let user = self.config().initial_user(token);
+                let profile = self.config().initial_profile(token);
+                Arc::new(UserEntry::new(index, user, profile))
+            });
+            if created {
+                // Logged as it is under the lock, a change that came first is not undone
+                let state = user.state.lock().await;
+                self.log_user(token, &state, None).await;
+            }
+            Ok(user)
+        } else {
+            self.users.get(token).ok_or_else(|| {
+                warn!("Someone tried to use the api with incorrect token: {token:?}");
+                Error::UserNotFound
+            })
+        }
+    }
+
+    async fn begin_action(
+        &self,
+        token: &UserToken,
+        action: Action,
+        pipe_id: PipeId,
+        duration: Duration,
+    ) -> Result<ActionGuard> {
+        let user = self.user_entry(token).await?;
+        if self.is_over() {
+            return Err(Error::GameOver);
+        }
+        if self.is_paused() {
+            return Err(Error::GamePaused);
+        }
+        let (abort_handle, abort) = AbortHandle::new_pair();
+        let (id, cooldown) = {
+            let mut actions = user.actions.lock().unwrap();
+            if let Some(bankrupt_until) = actions.bankrupt_until {
+                let now = self.game_time();
+                if now < bankrupt_until {
+                    debug!("{token:?} is bankrupt until {bankrupt_until}");
+                    let retry_after = Duration::from_secs_f64(bankrupt_until - now);
+                    return Err(Error::Bankrupt { retry_after });
+                }
+                actions.bankrupt_until = None;
+            }
+            if let Some(until) = actions.misconduct_lockout_until {
+                let now = Instant::now();
+                if now < until {
+                    debug!(
+                        "{token:?} is locked out for misconduct for {:?}",
+                        until - now
+                    );
+                    return Err(Error::MisconductLockout {
+                        retry_after: until - now,
+                    });
+                }
+                actions.misconduct_lockout_until = None;
+            }
+            let total = actions.count(None);
+            if self
+                .config()
+                .max_concurrent_actions
+                .is_some_and(|limit| total >= limit)
+            {
+                debug!("{token:?} already has {total} actions in flight");
+                return Err(Error::UserBusy {
+                    retry_after: actions.free_in(None, self.game_time()),
+                });
+            }
+            if let Some(&limit) = self.config().max_concurrent_actions_by_type.get(&action) {
+                let current = actions.count(Some(action));
+                if current >= limit {
+                    debug!("{token:?} already has {current} {action:?} actions in flight");
+                    return Err(Error::UserBusy {
+                        retry_after: actions.free_in(Some(action), self.game_time()),
+                    });
+                }
+            }
+            let now = Instant::now();
+            if let Some(&until) = actions.cooldown_until.get(&action) {
+                if now < until {
+                    debug!(
+                        "{token:?} has to wait {:?} for the next {action:?}",
+                        until - now
+                    );
+                    return Err(Error::Cooldown {
+                        retry_after: until - now,
+                    });
+                }
+            }
+            let cooldown = self
+                .config()
+                .action_cooldowns_secs
+                .get(&action)
+                .map(|&secs| (action, now + Duration::from_secs_f64(secs)));
+            if let Some((action, until)) = cooldown {
+                actions.cooldown_until.insert(action, until);
+            }
+            let id = actions.next_id;
+            actions.next_id += 1;
+            actions.aborts.insert(id, abort_handle);
+            let started_at = self.elapsed().as_secs_f64();
+            actions.by_id.insert(
+                id,
+                InFlightAction {
+                    action,
+                    pipe_id,
+                    started_at,
+                    expected_completion: started_at + duration.as_secs_f64(),
+                },
+            );
+            (id, cooldown)
+        };
+        Ok(ActionGuard {
+            user,
+            id,
+            abort: Some(abort),
+            turn: None,
+            cooldown,
+        })
+    }
+
+    /// Time left until the user may start the action again
+    pub async fn cooldown_left(&self, token: &UserToken, action: Action) -> Option<Duration> {
+        let user = self.users.get(token)?;
+        let until = *user.actions.lock().unwrap().cooldown_until.get(&action)?;
+        Some(until.saturating_duration_since(Instant::now())).filter(|left| !left.is_zero())
+    }
+
+    /// Spectators pass authentication, but are never users
+    pub fn is_spectator(&self, token: &UserToken) -> bool {
+        self.config().spectator_tokens.contains(token)
+    }
+
+    /// Checks the token, keeping track of failed attempts per ip,
+    /// and counts the user into the waiting room
+    pub async fn authenticate(&self, token: &UserToken, ip: Option<IpAddr>) -> Result<()> {
+        if self.is_spectator(token) {
+            return Ok(());
+        }
+        self.check_credentials(token, ip).await?;
+        if let Some(ip) = ip {
+            self.check_token_ip(token, ip)?;
+        }
+        self.enter_waiting_room(token);
+        Ok(())
+    }
+
+    /// Binds the token to the ip on first use if `bind_tokens_to_ip` is set
+    fn check_token_ip(&self, token: &UserToken, ip: IpAddr) -> Result<()> {
+        if !self.config().bind_tokens_to_ip {
+            return Ok(());
+        }
+        let mut token_ips = self.token_ips.lock().unwrap();
+        let bound = *token_ips.entry(token.clone()).or_insert_with(|| {
+            info!("Binding {token:?} to {ip}");
+            ip
+        });
+        if bound != ip {
+            warn!("{token:?} bound to {bound} was used from {ip}");
+            return Err(Error::TokenBoundToOtherIp);
+        }
+        Ok(())
+    }
+
+    /// Lets the token be bound to the next ip it is used from, returns the ip it was bound to
+    pub fn unbind_token_ip(&self, token: &UserToken) -> Option<IpAddr> {
+        let ip = self.token_ips.lock().unwrap().remove(token);
+        info!("Released {token:?} from {ip:?}");
+        ip
+    }
+
+    /// Counts a new connection from the ip, returns whether it is within
+    /// `max_connections_per_ip`. Every call has to be matched by [`Self::close_connection`].
+    pub fn open_connection(&self, ip: IpAddr) -> bool {
+        let mut connections = self.connections_by_ip.lock().unwrap();
+        let count = connections.entry(ip).or_default();
+        *count += 1;
+        let allowed = self
+            .config()
+            .max_connections_per_ip
+            .is_none_or(|limit| *count <= limit);
+        if !allowed {
+            debug!("Turning away connection {count} from {ip}");
+        }
+        allowed
+    }
+
+    pub fn close_connection(&self, ip: IpAddr) {
+        let mut connections = self.connections_by_ip.lock().unwrap();
+        if let Some(count) = connections.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&ip);
+            }
+        }
+    }
+
+    async fn check_credentials(&self, token: &UserToken, ip: Option<IpAddr>) -> Result<()> {
+        const WINDOW: Duration = AUTH_FAILURE_WINDOW;
+        let Some(ip) = ip else {
+            return self.user_entry(token).await.map(|_| ());
+        };
+        {
+            let mut auth_failures = self.auth_failures.lock().unwrap();
+            if let Some(failures) = auth_failures.get_mut(&ip) {
+                prune_requests(failures, WINDOW);
+                if failures.is_empty() {
+                    auth_failures.remove(&ip);
+                } else if failures.len() >= self.config().max_auth_failures_per_minute {
+                    debug!("Rejecting {ip} because of too many failed attempts");
+                    let retry_after = failures
+                        .front()
+                        .map_or(WINDOW, |time| WINDOW.saturating_sub(time.elapsed()));
+                    return Err(Error::TooManyAuthFailures { retry_after });
+                }
+            }
+        }
+        let result = self.user_entry(token).await;
+        if let Err(Error::UserNotFound) = result {
+            let mut auth_failures = self.auth_failures.lock().unwrap();
+            recent_requests(&mut auth_failures, ip, WINDOW).push_back(Instant::now());
+            *self
+                .new_auth_failures
+                .lock()
+                .unwrap()
+                .entry(ip)
+                .or_default() += 1;
+            self.metrics.record_auth_failure(ip);
+        }
+        result.map(|_| ())
+    }
+
+    /// Drops the rate limit entries of ips and tokens that went quiet, which otherwise only
+    /// happens when new ones show up. Returns the failed authentication attempts of every ip
+    /// since the last sweep, for the audit log.
+    pub fn sweep_rate_limits(&self) -> Vec<(IpAddr, usize)> {
+        sweep_requests(&mut self.requests_by_ip.lock().unwrap(), IP_REQUEST_WINDOW);
+        sweep_requests(
+            &mut self.requests_by_token.lock().unwrap(),
+            TOKEN_REQUEST_WINDOW,
+        );
+        sweep_requests(&mut self.auth_failures.lock().unwrap(), AUTH_FAILURE_WINDOW);
+        let mut failures: Vec<_> = std::mem::take(&mut *self.new_auth_failures.lock().unwrap())
+            .into_iter()
+            .collect();
+        failures.sort();
+        failures
+    }
+
+    /// Counts the request against `max_requests_per_ip_per_minute`
+    pub fn check_request_rate(&self, ip: IpAddr) -> Result<()> {
+        const WINDOW: Duration = IP_REQUEST_WINDOW;
+        let Some(limit) = self.config().max_requests_per_ip_per_minute else {
+            return Ok(());
+        };
+        let mut requests = self.requests_by_ip.lock().unwrap();
+        let requests = recent_requests(&mut requests, ip, WINDOW);
+        if requests.len() >= limit {
+            debug!("Rejecting {ip} because of too many requests");
+            let retry_after = requests
+                .front()
+                .map(|time| WINDOW.saturating_sub(time.elapsed()));
+            return Err(Error::RateLimited { retry_after });
+        }
+        requests.push_back(Instant::now());
+        Ok(())
+    }
+
+    /// Counts a game api request made with the token against `max_requests_per_token_per_second`
+    pub fn check_token_request_rate(&self, token: &UserToken) -> Result<()> {
+        const WINDOW: Duration = TOKEN_REQUEST_WINDOW;
+        let Some(limit) = self.config().max_requests_per_token_per_second else {
+            return Ok(());
+        };
+        // Made up tokens are left to the authentication, they must not take up memory here
+        if !self.users.contains_key(token) {
+            return Ok(());
+        }
+        let mut requests = self.requests_by_token.lock().unwrap();
+        let requests = recent_requests(&mut requests, token.clone(), WINDOW);
+        let rejected = requests.len() >= limit;
+        self.metrics.record_rate_limit(&token.0, rejected);
+        if rejected {
+            debug!("Rejecting {token:?} because of too many requests");
+            let retry_after = requests
+                .front()
+                .map(|time| WINDOW.saturating_sub(time.elapsed()));
+            return Err(Error::RateLimited { retry_after });
+        }
+        requests.push_back(Instant::now());
+        Ok(())
+    }
+
+    fn pipe(&self, id: PipeId) -> Result<Arc<Mutex<Pipe>>> {
+        self.pipes
+            .read()
+            .unwrap()
+            .get(id)
+            .ok_or(Error::PipeNotFound { pipe_id: Some(id) })
+    }
+}
+
+impl App {
+    pub fn init(mut config: Config, users: impl IntoIterator<Item = UserToken>) -> Self {
+        let users: Vec<UserToken> = users.into_iter().collect();
+        debug!("Initializing app...");
+        config.prepare();
+        info!("Config: {config:#?}");
+        let allow_unknown_users = users.is_empty() && config.allow_unknown_users;
+        if allow_unknown_users {
+            info!("No users specified, so everyone is welcome");
+        } else if users.is_empty() {
+            warn!("No users specified and unknown users are not allowed, nobody can play");
+        } else {
+            info!("Users: {users:#?}");
+        }
+        let seed = config.seed.unwrap_or_else(|| thread_rng().gen());
+        info!("Seed: {seed}");
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        let mut history = History::new(config.history_memory_limit, config.history_limit);
+        let mut ranking = Ranking::default();
+        let joined_users = users.len().into();
+        let users = users
+            .into_iter()
+            .enumerate()
+            .map(|(index, token)| {
+                let user = config.initial_user(&token);
+                ranking.update(&token, Some(user.score));
+                history.push(LogEntry {
+                    time: 0.0,
+                    msg: LogMessage::UpdateUser {
+                        user: token.clone(),
+                        state: user.clone(),
+                        change: None,
+                    },
+                });
+                let profile = config.initial_profile(&token);
+                (token, Arc::new(UserEntry::new(index, user, profile)))
+            })
+            .collect();
+        if let Some(rounds) = &config.rounds {
+            info!(
+                "Playing {} rounds of {}s",
+                rounds.count, rounds.duration_secs
+            );
+            history.push(LogEntry {
+                time: 0.0,
+                msg: LogMessage::RoundStart {
+                    round: 1,
+                    duration_secs: rounds.duration_secs,
+                },
+            });
+        }
+        let pipes = (1..=config.pipe_count)
+            .filter_map(PipeId::new)
+            .map(|id| {
+                let pipe = config.random_pipe(&mut rng);
+                debug!("Pipe #{id}: {pipe:#?}");
+                history.push(LogEntry {
+                    time: 0.0,
+                    msg: LogMessage::UpdatePipe {
+                        id,
+                        state: pipe.clone(),
+                    },
+                });
+                (id, pipe)
+            })
+            .collect::<Vec<_>>();
+        let mut state = replay::State::default();
+        for entry in (0..history.len()).filter_map(|index| history.get(index)) {
+            state.apply(&entry.map_user(|token| token.0));
+        }
+        Self {
+            start: tokio::sync::watch::Sender::new(
+                config
+                    .wait_for_players
+                    .is_none()
+                    .then(|| Instant::now() + Duration::from_secs_f64(config.start_delay_secs)),
+            ),
+            waiting_room: Default::default(),
+            time_to_run: config.time_to_run.map(Duration::from_secs_f64),
+            overtime: Default::default(),
+            ended: Default::default(),
+            paused_since: Default::default(),
+            paused_for: Default::default(),
+            end_requested: Default::default(),
+            over: Default::default(),
+            allow_unknown_users,
+            users,
+            joined_users,
+            pipes: std::sync::RwLock::new(PipeRegistry::new(pipes, 1)),
+            config: std::sync::RwLock::new(Arc::new(config)),
+            log_senders: Default::default(),
+            history: Mutex::new(history),
+            metrics: Default::default(),
+            auth_failures: Default::default(),
+            new_auth_failures: Default::default(),
+            requests_by_ip: Default::default(),
+            connections_by_ip: Default::default(),
+            token_ips: Default::default(),
+            requests_by_token: Default::default(),
+            global_events: Default::default(),
+            demand: Default::default(),
+            last_big_event: Default::default(),
+            overlay: Default::default(),
+            state: std::sync::RwLock::new(state),
+            ranking: std::sync::Mutex::new(ranking),
+            round: Default::default(),
+            operation_done: Default::default(),
+            turns: Default::default(),
+            seed,
+            rng: std::sync::Mutex::new(rng),
+        }
+    }
+}
+
+impl App {
+    /// Snapshot of the game, actions in flight are not included
+    pub async fn checkpoint(&self) -> Checkpoint {
+        let time = self.game_time();
+        let mut users = Vec::new();
+        let entries: Vec<(UserToken, Arc<UserEntry>)> = self.users.entries();
+        for (token, user) in entries {
+            let state = user.state.lock().await.clone();
+            users.push(CheckpointUser {
+                token,
+                index: user.index,
+                stats: state.stats.clone(),
+                last_collect_secs: state.last_collect_secs,
+                state,
+                profile: user.profile.lock().unwrap().clone(),
+                bankrupt_until: user.actions.lock().unwrap().bankrupt_until,
+            });
+        }
+        users.sort_by_key(|user| user.index);
+        let mut pipes = BTreeMap::new();
+        let registered: Vec<(PipeId, Arc<Mutex<Pipe>>)> = self
+            .pipes
+            .read()
+            .unwrap()
+            .pipes
+            .iter()
+            .map(|(&id, pipe)| (id, pipe.clone()))
+            .collect();
+        for (id, pipe) in registered {
+            let pipe = pipe.lock().await;
+            pipes.insert(
+                id,
+                CheckpointPipe {
+                    insurance: pipe.insurance.clone(),
+                    state: pipe.clone(),
+                },
+            );
+        }
+        let ends_at_unix_secs = self
+            .time_left()
+            .map(|time_left| unix_time() + time_left.as_secs_f64());
+        let history = self.history.lock().await;
+        Checkpoint {
+            time,
+            seed: self.seed,
+            rng: Some(self.rng.lock().unwrap().clone()),
+            overtime_secs: self.overtime.lock().unwrap().as_secs_f64(),
+            paused_secs: self.paused_duration().as_secs_f64(),
+            ends_at_unix_secs,
+            users,
+            pipes,
+            history: (history.first()..history.len())
+                .filter_map(|index| history.get(index))
+                .collect(),
+            round: self.round.lock().unwrap().clone(),
+        }
+    }
+
+    /// Continues the game from the checkpoint, must be called before the server starts.
+    /// Random choices go on where the checkpointed game left off,
+    /// and the game ends at the same wall clock time as it would have.
+    pub fn restore(&mut self, checkpoint: Checkpoint) {
+        info!(
+            "Restoring the game at {:.1}s with {} users",
+            checkpoint.time,
+            checkpoint.users.len()
+        );
+        let now = Instant::now();
+        let start = now
+            .checked_sub(Duration::from_secs_f64(checkpoint.time))
+            .unwrap_or(now);
+        self.start.send_replace(Some(start));
+        self.seed = checkpoint.seed;
+        *self.rng.get_mut().unwrap() = checkpoint
+            .rng
+            .unwrap_or_else(|| ChaCha12Rng::seed_from_u64(checkpoint.seed));
+        *self.overtime.get_mut().unwrap() = Duration::from_secs_f64(checkpoint.overtime_secs);
+        *self.paused_for.get_mut().unwrap() = Duration::from_secs_f64(checkpoint.paused_secs);
+        let mut history = History::new(
+            self.config().history_memory_limit,
+            self.config().history_limit,
+        );
+        let mut state = replay::State::default();
+        let mut last_big_event = None;
+        // Ids of pipes retired before the checkpoint are only known from the log
+        let next_pipe_id = checkpoint
+            .history
+            .iter()
+            .filter_map(|entry| entry.msg.pipe_id())
+            .map(|id| id.get() + 1)
+            .max()
+            .unwrap_or(1);
+        for entry in checkpoint.history {
+            state.apply(&entry.clone().map_user(|token| token.0));
+            if entry.msg.is_big_event() {
+                last_big_event = Some(entry.clone());
+            }
+            history.push(entry);
+        }
+        *self.ranking.get_mut().unwrap() = Ranking::new(
+            checkpoint
+                .users
+                .iter()
+                .map(|user| (user.token.clone(), user.state.score)),
+        );
+        let joined_users = checkpoint.users.iter().map(|user| user.index + 1).max();
+        *self.joined_users.get_mut() = joined_users.unwrap_or_default();
+        self.users = checkpoint
+            .users
+            .into_iter()
+            .map(|mut user| {
+                // The checkpoint may have been taken before the change was logged
+                state.scores.insert(user.token.0.clone(), user.state.score);
+                user.state.stats = user.stats;
+                user.state.last_collect_secs = user.last_collect_secs;
+                let entry = UserEntry::new(user.index, user.state, user.profile);
+                entry.actions.lock().unwrap().bankrupt_until = user.bankrupt_until;
+                (user.token, Arc::new(entry))
+            })
+            .collect();
+        let pipes = checkpoint.pipes.into_iter().map(|(id, pipe)| {
+            let mut restored = pipe.state;
+            restored.insurance = pipe.insurance;
+            (id, restored)
+        });
+        *self.pipes.get_mut().unwrap() = PipeRegistry::new(pipes, next_pipe_id);
+        *self.history.get_mut() = history;
+        *self.state.get_mut().unwrap() = state;
+        *self.last_big_event.get_mut().unwrap() = last_big_event;
+        *self.round.get_mut().unwrap() = checkpoint.round;
+        if let Some(ends_at) = checkpoint.ends_at_unix_secs {
+            self.skip_downtime(ends_at);
+        }
+    }
+
+    /// Moves the start back by the time since the checkpoint, so the game ends at `ends_at`.
+    /// A clock that went backwards does not extend the game.
+    fn skip_downtime(&mut self, ends_at: f64) {
+        let Some(time_left) = self.time_left() else {
+            return;
+        };
+        let left_now = Duration::from_secs_f64((ends_at - unix_time()).max(0.0));
+        let downtime = time_left.saturating_sub(left_now);
+        if downtime.is_zero() {
+            return;
+        }
+        info!("The game was down for {downtime:?}, {left_now:?} left");
+        let start = self
+            .started_at()
+            .map(|start| start.checked_sub(downtime).unwrap_or(start));
+        self.start.send_replace(start);
+    }
+}
+
+/// Seconds since the unix epoch, checkpoints outlive the process so `Instant` does not do
+fn unix_time() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long load shedding is reported after the last rejected request
+const SHEDDING_REPORT_TIME: Duration = Duration::from_secs(1);
+
+impl App {
+    pub fn load(&self) -> f64 {
+        self.metrics.in_flight_requests() as f64 / self.config().max_in_flight_requests as f64
+    }
+
+    pub fn status(&self) -> StatusResponse {
+        StatusResponse {
+            load: self.load(),
+            shedding: self
+                .metrics
+                .last_shed()
+                .is_some_and(|time| time.elapsed() < SHEDDING_REPORT_TIME),
+        }
+    }
+}
+
+impl App {
+    pub fn progress(&self) -> GameProgress {
+        if self.is_over() || self.time_left().is_some_and(|time| time.is_zero()) {
+            GameProgress::Ended
+        } else if self.is_waiting_for_players() || !self.time_until_start().is_zero() {
+            GameProgress::Starting
+        } else if self.is_paused() {
+            GameProgress::Paused
+        } else {
+            GameProgress::Running
+        }
+    }
+
+    pub fn game(&self) -> GameResponse {
+        GameResponse {
+            state: self.progress(),
+            time: self.time(),
+        }
+    }
+
+    pub async fn health(&self) -> HealthResponse {
+        let until_start = self.time_until_start();
+        let time_left = self.time_left();
+        let state = self.progress();
+        HealthResponse {
+            state,
+            time: if until_start.is_zero() {
+                self.game_time()
+            } else {
+                -until_start.as_secs_f64()
+            },
+            time_left: time_left.map(|time| time.as_secs_f64()),
+            players: self.users.len(),
+            pipes: self.pipes.read().unwrap().pipes.len(),
+            ready: state != GameProgress::Ended && !self.status().shedding,
+        }
+    }
+}
+
+impl App {
+    pub fn time(&self) -> TimeResponse {
+        let time = self.game_time();
+        TimeResponse {
+            time,
+            time_left: self.time_left().map(|time| time.as_secs_f64()),
+            phase: self.phase(),
+            next_phase_secs: self
+                .config()
+                .phases
+                .iter()
+                .map(|phase| phase.start_secs)
+                .filter(|&start| start > time)
+                .min_by(f64::total_cmp),
+            paused: self.is_paused(),
+            round: self
+                .config()
+                .rounds
+                .as_ref()
+                .map(|_| self.round.lock().unwrap().index + 1),
+            round_time_left: self.round_time_left().map(|time| time.as_secs_f64()),
+        }
+    }
+}
+
+impl App {
+    #[tracing::instrument(
+        name = "pipe_value",
+        skip_all,
+        fields(user = %user_token.digest(), pipe_id = pipe_id.get())
+    )]
+    pub async fn pipe_value(
+        &self,
+        user_token: &UserToken,
+        pipe_id: PipeId,
+    ) -> Result<PipeValueResponse> {
+        if self.is_spectator(user_token) {
+            let pipe = self.pipe(pipe_id)?;
+            let pipe = pipe.lock().await;
+            return Ok(PipeValueResponse {
+                value: pipe.value,
+                owner: pipe.owner_info(user_token, self.game_time()),
+            });
+        }
+        let result = self.pipe_value_inner(user_token, pipe_id).await;
+        self.record_result(user_token, Action::PipeValue, pipe_id, &result)
+            .await;
+        result
+    }
+
+    async fn pipe_value_inner(
+        &self,
+        user_token: &UserToken,
+        pipe_id: PipeId,
+    ) -> Result<PipeValueResponse> {
+        let delay = Duration::from_secs_f64(self.config().pipe_value_delay_secs);
+        let mut action = self
+            .begin_action(user_token, Action::PipeValue, pipe_id, delay)
+            .await?;
+        let pipe = self.pipe(pipe_id)?;
+        info!("User {user_token:?} is finding out value of pipe {pipe_id}");
+        self.action_sleep(user_token, &mut action, delay).await?;
+        let (value, owner) = {
+            let pipe = pipe.lock().await;
+            (pipe.value, pipe.owner_info(user_token, self.game_time()))
+        };
+        debug!("Sleep finished, {user_token:?} now knows pipe {pipe_id} value: {value}");
+        self.log_observation(user_token, pipe_id, Observation::PipeValue { value })
+            .await;
+        action.succeed();
+        Ok(PipeValueResponse { value, owner })
+    }
+}
+
+impl App {
+    /// Values of several pipes in a single action, taking as long as `pipe_values_delay` says
+    #[tracing::instrument(
+        name = "pipe_values",
+        skip_all,
+        fields(user = %user_token.digest(), pipe_ids = ?pipe_ids)
+    )]
+    pub async fn pipe_values(
+        &self,
+        user_token: &UserToken,
+        pipe_ids: &[PipeId],
+    ) -> Result<PipeValuesResponse> {
+        let Some(&first) = pipe_ids.first() else {
+            return Ok(PipeValuesResponse { values: Vec::new() });
+        };
+        if self.is_spectator(user_token) {
+            let mut values = Vec::with_capacity(pipe_ids.len());
+            for &pipe_id in pipe_ids {
+                let value = self.pipe(pipe_id)?.lock().await.value;
+                values.push(PipeValue { pipe_id, value });
+            }
+            return Ok(PipeValuesResponse { values });
+        }
+        let result = self.pipe_values_inner(user_token, first, pipe_ids).await;
+        self.record_result(user_token, Action::PipeValue, first, &result)
+            .await;
+        result
+    }
+
+    async fn pipe_values_inner(
+        &self,
+        user_token: &UserToken,
+        first: PipeId,
+        pipe_ids: &[PipeId],
+    ) -> Result<PipeValuesResponse> {
+        let delay = Duration::from_secs_f64(self.config().pipe_value_delay_secs);
+        let delay = match self.config().pipe_values_delay {
+            BatchDelay::PerPipe => delay * pipe_ids.len() as u32,
+            BatchDelay::Once => delay,
+        };
+        let mut action = self
+            .begin_action(user_token, Action::PipeValue, first, delay)
+            .await?;
+        let pipes = pipe_ids
+            .iter()
+            .map(|&pipe_id| Ok((pipe_id, self.pipe(pipe_id)?)))
+            .collect::<Result<Vec<_>>>()?;
+        info!("User {user_token:?} is finding out values of pipes {pipe_ids:?}");
+        self.action_sleep(user_token, &mut action, delay).await?;
+        let mut values = Vec::with_capacity(pipes.len());
+        for (pipe_id, pipe) in pipes {
+            let value = pipe.lock().await.value;
+            self.log_observation(user_token, pipe_id, Observation::PipeValue { value })
+                .await;
+            values.push(PipeValue { pipe_id, value });
+        }
+        debug!(
+            "Sleep finished, {user_token:?} now knows {} pipe values",
+            values.len()
+        );
+        action.succeed();
+        Ok(PipeValuesResponse { values })
+    }
+}
+
+impl App {
+    /// Cheaper and faster than [`App::pipe_value`], but only tells the rough value
+    #[tracing::instrument(
+        name = "peek",
+        skip_all,
+        fields(user = %user_token.digest(), pipe_id = pipe_id.get())
+    )]
+    pub async fn peek(&self, user_token: &UserToken, pipe_id: PipeId) -> Result<PeekResponse> {
+        let result = self.peek_inner(user_token, pipe_id).await;
+        self.record_result(user_token, Action::Peek, pipe_id, &result)
+            .await;
+        result
+    }
+
+    async fn peek_inner(&self, user_token: &UserToken, pipe_id: PipeId) -> Result<PeekResponse> {
+        let delay = Duration::from_secs_f64(self.config().peek_delay_secs);
+        let mut action = self
+            .begin_action(user_token, Action::Peek, pipe_id, delay)
+            .await?;
+        let pipe = self.pipe(pipe_id)?;
+        info!("User {user_token:?} is peeking at pipe {pipe_id}");
+        {
+            let mut user = action.user().lock().await;
+            if user.score < self.config().peek_cost {
+                debug!("Not enough score to pay for peeking");
+                return Err(Error::NotEnoughScore {
+                    required: self.config().peek_cost,
+                    score: user.score,
+                });
+            }
+            let (bankrupt, change) = self.change_score(
+                &mut user,
+                -self.config().peek_cost,
+                ScoreReason::Peek { pipe_id },
+            );
+            self.log_user(user_token, &user, Some(change)).await;
+            if bankrupt {
+                self.bankrupt(user_token, &action.user, user.score).await;
+            }
+        }
+        if let Err(e) = self.action_sleep(user_token, &mut action, delay).await {
+            let mut user = action.user().lock().await;
+            debug!("Refunding the peek");
+            let (_, change) = self.change_score(
+                &mut user,
+                self.config().peek_cost,
+                ScoreReason::PeekRefund { pipe_id },
+            );
+            self.log_user(user_token, &user, Some(change)).await;
+            return Err(e);
+        }
+        let pipe = pipe.lock().await;
+        let value = if pipe.value >= self.config().peek_high_from {
+            ValueBucket::High
+        } else if pipe.value >= self.config().peek_medium_from {
+            ValueBucket::Medium
+        } else {
+            ValueBucket::Low
+        };
+        debug!("{user_token:?} peeked {value:?} value at pipe {pipe_id}");
+        let modifiers = pipe.modifiers.len();
+        drop(pipe);
+        self.log_observation(user_token, pipe_id, Observation::Peek { bucket: value })
+            .await;
+        action.succeed();
+        Ok(PeekResponse { value, modifiers })
+    }
+}
+
+impl App {
+    pub async fn collect(
+        &self,
+        user_token: &UserToken,
+        pipe_id: PipeId,
+    ) -> Result<CollectResponse> {
+        self.collect_until(user_token, pipe_id, futures::future::pending())
+            .await
+    }
+
+    /// Collect for a client that may go away, `client_gone` completes when it does.
+    /// The collect is then rolled back unless `commit_abandoned_collects` is set,
+    /// either way the future has to be polled to the end for the outcome to be logged.
+    #[tracing::instrument(
+        name = "collect",
+        skip_all,
+        fields(user = %user_token.digest(), pipe_id = pipe_id.get())
+    )]
+    pub async fn collect_until(
+        &self,
+        user_token: &UserToken,
+        pipe_id: PipeId,
+        client_gone: impl Future<Output = ()>,
+    ) -> Result<CollectResponse> {
+        let result = self.collect_inner(user_token, pipe_id, client_gone).await;
+        self.record_result(user_token, Action::Collect, pipe_id, &result)
+            .await;
+        if result.is_err() {
+            self.break_streak(user_token).await;
+        }
+        result
+    }
+
+    /// Counts a successful collect into the user's streak and multiplies the gain by it
+    fn extend_streak(&self, user: &mut User, gain: Score) -> Score {
+        let (Some(step), Some(streak)) = (self.config().streak_step, user.streak) else {
+            return gain;
+        };
+        let time = self.elapsed().as_secs_f64();
+        let timed_out = match (self.config().streak_timeout_secs, user.last_collect_secs) {
+            (Some(timeout), Some(last_collect)) => time - last_collect > timeout,
+            _ => false,
+        };
+        let streak = if timed_out {
+            debug!("Streak of {streak} timed out");
+            0
+        } else {
+            streak
+        };
+        let multiplier = (1.0 + step * streak as f64).min(self.config().max_streak_multiplier);
+        user.streak = Some(streak + 1);
+        user.last_collect_secs = Some(time);
+        debug!(
+            "Streak of {} multiplies the gain by {multiplier}",
+            streak + 1
+        );
+        (gain as f64 * multiplier).round() as Score
+    }
+
+    /// Time left until the pipe may be collected again, see `post_collect_lockout_secs`
+    fn lockout_left(&self, pipe: &Pipe) -> Option<Duration> {
+        let now = self.elapsed().as_secs_f64();
+        pipe.locked_until
+            .filter(|&locked_until| now < locked_until)
+            .map(|locked_until| Duration::from_secs_f64(locked_until - now))
+    }
+
+    /// Gives back the modifiers used by starting a collect that did not happen, because the
+    /// client went away or the pipe got locked in the meantime
+    async fn roll_back_collect(
+        &self,
+        user_token: &UserToken,
+        pipe_id: PipeId,
+        used_modifiers: Vec<(Modifier, Option<ModifierApplication<UserToken>>)>,
+    ) {
+        let mut restored_modifiers = Vec::new();
+        if let Ok(pipe) = self.pipe(pipe_id) {
+            let mut pipe = pipe.lock().await;
+            for (modifier, application) in used_modifiers {
+                pipe.restore_modifier(modifier, application);
+                restored_modifiers.push(modifier);
+            }
+            if !restored_modifiers.is_empty() {
+                self.log_pipe(pipe_id, &pipe).await;
+            }
+        }
+        info!("Collect of pipe {pipe_id} by {user_token:?} rolled back");
+        self.log(LogMessage::CollectAborted {
+            user: user_token.clone(),
+            pipe_id,
+            restored_modifiers,
+        })
+        .await;
+    }
+
+    async fn break_streak(&self, user_token: &UserToken) {
+        let Some(entry) = self.users.get(user_token) else {
+            return;
+        };
+        let mut user = entry.state.lock().await;
+        if user.streak.is_some_and(|streak| streak > 0) {
+            debug!("{user_token:?} lost a streak of {:?}", user.streak);
+            user.streak = Some(0);
+            self.log_user(user_token, &user, None).await;
+        }
+    }
+
+    async fn collect_inner(
+        &self,
+        user_token: &UserToken,
+        pipe_id: PipeId,
+        client_gone: impl Future<Output = ()>,
+    ) -> Result<CollectResponse> {
+        let mut action = self
+            .begin_action(user_token, Action::Collect, pipe_id, Duration::ZERO)
+            .await?;
+        if self.config().tick_secs.is_some() {
+            // Starts at the end of the tick, the collect then ends in a later one
+            self.action_sleep(user_token, &mut action, Duration::ZERO)
+                .await?;
+        }
+        let pipe = self.pipe(pipe_id)?;
+        if let Some(assignment) = self.assignment(&action.user) {
+            if assignment.pipe_id != pipe_id {
+                debug!("{user_token:?} is assigned to pipe {}", assignment.pipe_id);
+                return Err(Error::PipeNotAssigned {
+                    pipe_id,
+                    assigned_pipe_id: assignment.pipe_id,
+                });
+            }
+        }
+        info!("User {user_token:?} is trying to collect pipe {pipe_id}");
+        debug!("Pipe state: {:#?}", pipe.lock().await);
+        let mut used_modifiers = Vec::new();
+        let delay = {
+            let mut pipe = pipe.lock().await;
+            if let Some(retry_after) = self.lockout_left(&pipe) {
+                debug!("Pipe is locked for {retry_after:?}");
+                return Err(Error::PipeLocked {
+                    pipe_id,
+                    retry_after,
+                });
+            }
+            let mut use_modifier = |pipe: &mut Pipe, modifier| {
+                let application = pipe.applied_by.get(&modifier).cloned();
+                let used = pipe.use_modifier(modifier);
+                if used {
+                    used_modifiers.push((modifier, application));
+                }
+                used
+            };
+            let mut delay = pipe.base_delay;
+            if use_modifier(&mut pipe, Modifier::Slow) {
+                delay *= 2;
+            }
+            for (modifier, effect) in self.custom_effects(&pipe) {
+                if let ModifierEffect::DelayMultiplier { multiplier } = effect {
+                    if use_modifier(&mut pipe, modifier) {
+                        delay = delay.mul_f64(multiplier);
+                    }
+                }
+            }
+            delay = delay.mul_f64(self.global_delay_multiplier());
+            if use_modifier(&mut pipe, Modifier::Shield) {
+                debug!("Pipe {pipe_id} is shielded for one collect less");
+            }
+            self.log_pipe(pipe_id, &pipe).await;
+            delay
+        };
+        action.set_expected_completion((self.elapsed() + delay).as_secs_f64());
+        self.log(LogMessage::CollectStart {
+            user: user_token.clone(),
+            pipe_id,
+            delay,
+        })
+        .await;
+        // Modifiers used up by starting the collect stay used, unless the client goes away
+        let (user, action_id) = (action.user.clone(), action.id);
+        let mut disconnected = false;
+        let result = {
+            let sleep = pin!(self.action_sleep(user_token, &mut action, delay));
+            if self.config().commit_abandoned_collects {
+                sleep.await
+            } else {
+                match select(sleep, pin!(client_gone)).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(((), sleep)) => {
+                        user.abort_action(action_id, AbortReason::Disconnected);
+                        disconnected = true;
+                        sleep.await
+                    }
+                }
+            }
+        };
+        if let Err(error) = result {
+            if disconnected {
+                self.roll_back_collect(user_token, pipe_id, used_modifiers)
+                    .await;
+            }
+            return Err(error);
+        }
+        // Held from the check on, so that no other collect can lock the pipe in between
+        let mut pipe = pipe.lock().await;
+        if let Some(retry_after) = self.lockout_left(&pipe) {
+            debug!("Pipe {pipe_id} got locked while {user_token:?} was collecting it");
+            drop(pipe);
+            self.roll_back_collect(user_token, pipe_id, used_modifiers)
+                .await;
+            return Err(Error::PipeLocked {
+                pipe_id,
+                retry_after,
+            });
+        }
+        self.log(LogMessage::CollectEnd {
+            user: user_token.clone(),
+            pipe_id,
+        })
+        .await;
+        if self.pipe(pipe_id).is_err() {
+            debug!("Pipe {pipe_id} was retired while {user_token:?} was collecting it");
+            return Err(Error::PipeNotFound {
+                pipe_id: Some(pipe_id),
+            });
+        }
+        debug!(
+            "Sleep finished, {user_token:?} is now going to collect from pipe {pipe_id}: {:#?}",
+            *pipe,
+        );
+        let score = {
+            let mut score = pipe.value;
+            let policy = self.config().double_min_policy;
+            let min = pipe.use_modifier(Modifier::Min);
+            let double = match policy {
+                DoubleMinPolicy::KeepDouble if min => false,
+                _ => pipe.use_modifier(Modifier::Double),
+            };
+            if min {
+                score = self.config().min_value;
+            }
+            if double && (!min || policy == DoubleMinPolicy::DoubleMin) {
+                score *= 2;
+            }
+            // Overrides go first, so that custom multipliers apply to them like double does
+            let mut custom_effects = self.custom_effects(&pipe);
+            custom_effects
+                .sort_by_key(|(_, effect)| !matches!(effect, ModifierEffect::ValueOverride { .. }));
+            for (modifier, effect) in custom_effects {
+                match effect {
+                    ModifierEffect::ValueOverride { value } if pipe.use_modifier(modifier) => {
+                        score = value;
+                    }
+                    ModifierEffect::ValueMultiplier { multiplier }
+                        if pipe.use_modifier(modifier) =>
+                    {
+                        score = (score as f64 * multiplier).round() as Score;
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(phase) = self.phase() {
+                score = (score as f64 * phase.value_multiplier).round() as Score;
+            }
+            score = (score as f64 * self.global_value_multiplier()).round() as Score;
+            score
+        };
+        debug!("Score retrieved from the pipe: {score}");
+        let theft = match pipe.applied_by.get(&Modifier::Steal) {
+            Some(application) if application.user != *user_token => {
+                let thief = application.user.clone();
+                // Nothing is stolen from negative values, so the thief can't go bankrupt
+                let stolen = (score as f64 * self.config().steal_ratio).round().max(0.0) as Score;
+                assert!(pipe.use_modifier(Modifier::Steal));
+                debug!("{thief:?} steals {stolen} of it");
+                Some((thief, stolen))
+            }
+            _ => None,
+        };
+        let score = score - theft.as_ref().map_or(0, |(_, stolen)| *stolen);
+        let score = self.claim_pipe(&mut pipe, user_token, score);
+        let refund = self.settle_insurance(&mut pipe, user_token, score);
+        let gain = match self.config().handicaps.get(user_token) {
+            Some(multiplier) => {
+                let gain = (score as f64 * multiplier).round() as Score;
+                debug!("Handicap x{multiplier} turns {score} into {gain}");
+                gain
+            }
+            None => score,
+        };
+        let mut user = action.user().lock().await;
+        let gain = self.extend_streak(&mut user, gain);
+        user.stats.collects += 1;
+        let (bankrupt, change) =
+            self.change_score(&mut user, gain + refund, ScoreReason::Collect { pipe_id });
+        debug!("User's score is now {}", user.score);
+        pipe.value += match pipe.direction {
+            PipeDirection::Up => 1,
+            PipeDirection::Down => -1,
+        };
+        if pipe.value < self.config().min_value {
+            pipe.value = self.config().max_value;
+        } else if pipe.value > self.config().max_value {
+            pipe.value = self.config().min_value;
+        }
+        debug!("Next pipe value will be {}", pipe.value);
+        if self.config().post_collect_lockout_secs > 0.0 {
+            let locked_until =
+                self.elapsed().as_secs_f64() + self.config().post_collect_lockout_secs;
+            debug!("Locking the pipe until {locked_until}");
+            pipe.locked_until = Some(locked_until);
+        }
+        self.log_pipe(pipe_id, &pipe).await;
+        if refund != 0 {
+            self.log(LogMessage::InsurancePayout {
+                user: user_token.clone(),
+                pipe_id,
+                refund,
+            })
+            .await;
+        }
+        self.log_user(user_token, &user, Some(change)).await;
+        if bankrupt {
+            self.bankrupt(user_token, &action.user, user.score).await;
+        }
+        // Only one user is locked at a time, two thieves could deadlock otherwise
+        drop(user);
+        drop(pipe);
+        if let Some((thief, stolen)) = theft {
+            self.pay_stolen(&thief, user_token, pipe_id, stolen).await;
+        }
+        action.succeed();
+        Ok(CollectResponse {
+            value: gain,
+            refund,
+        })
+    }
+
+    /// Effects of the custom modifiers on the pipe, in the order they are defined
+    fn custom_effects(&self, pipe: &Pipe) -> Vec<(Modifier, ModifierEffect)> {
+        self.config()
+            .custom_modifiers
+            .iter()
+            .map(|custom| (Modifier::Custom(custom.name), custom.effect))
+            .filter(|(modifier, _)| pipe.modifiers.contains_key(modifier))
+            .collect()
+    }
+
+    /// Makes the user the owner of the pipe, returns the part of the score the user gets
+    fn claim_pipe(&self, pipe: &mut Pipe, user_token: &UserToken, score: Score) -> Score {
+        let Some(ownership) = &self.config().ownership else {
+            return score;
+        };
+        let now = self.game_time();
+        let score = match &pipe.owner {
+            Some(owner) if owner.user != *user_token && now < owner.until && score > 0 => {
+                let share = (score as f64 * ownership.share).round() as Score;
+                debug!(
+                    "Pipe is owned by {:?}, {user_token:?} gets {share}",
+                    owner.user
+                );
+                share
+            }
+            _ => score,
+        };
+        pipe.owner = Some(PipeOwner {
+            user: user_token.clone(),
+            until: now + ownership.secs,
+        });
+        score
+    }
+
+    async fn pay_stolen(
+        &self,
+        thief: &UserToken,
+        victim: &UserToken,
+        pipe_id: PipeId,
+        stolen: Score,
+    ) {
+        let Some(entry) = self.users.get(thief) else {
+            debug!("{thief:?} was removed, {stolen} stolen from {victim:?} is lost");
+            return;
+        };
+        let mut user = entry.state.lock().await;
+        let (_, change) = self.change_score(&mut user, stolen, ScoreReason::Steal { pipe_id });
+        self.log(LogMessage::ScoreStolen {
+            user: thief.clone(),
+            victim: victim.clone(),
+            pipe_id,
+            amount: stolen,
+        })
+        .await;
+        self.log_user(thief, &user, Some(change)).await;
+    }
+
+    /// Changes the score within the configured limits,
+    /// returns whether the user went bankrupt and the change to be logged
+    fn change_score(
+        &self,
+        user: &mut User,
+        delta: Score,
+        reason: ScoreReason,
+    ) -> (bool, ScoreChange) {
+        let score = user.score + delta;
+        let bankrupt = self
+            .config()
+            .min_user_score
+            .is_some_and(|min| score <= min && user.score > min);
+        let limited = self.config().limit_score(score);
+        let change = ScoreChange {
+            delta: limited - user.score,
+            reason,
+        };
+        if change.delta > 0 {
+            user.stats.earned += change.delta;
+        } else {
+            user.stats.spent -= change.delta;
+        }
+        user.score = limited;
+        (bankrupt, change)
+    }
+
+    async fn bankrupt(&self, user_token: &UserToken, user: &UserEntry, score: Score) {
+        let locked_until = (self.config().bankruptcy_lockout_secs > 0.0)
+            .then(|| self.game_time() + self.config().bankruptcy_lockout_secs);
+        info!("User {user_token:?} went bankrupt, locked until {locked_until:?}");
+        user.actions.lock().unwrap().bankrupt_until = locked_until;
+        self.log(LogMessage::Bankrupt {
+            user: user_token.clone(),
+            score,
+            locked_until,
+        })
+        .await;
+    }
+
+    /// Uses up the user's insurance on the pipe, returning the refund for this payout
+    fn settle_insurance(&self, pipe: &mut Pipe, user_token: &UserToken, payout: Score) -> Score {
+        let Some(uses_left) = pipe.insurance.get_mut(user_token) else {
+            return 0;
+        };
+        *uses_left -= 1;
+        debug!("Using insurance, {uses_left} uses left now");
+        if *uses_left == 0 {
+            pipe.insurance.remove(user_token);
+        }
+        if payout >= self.config().insurance_threshold {
+            return 0;
+        }
+        let loss = (pipe.value - payout).max(0);
+        let refund = (loss as f64 * self.config().insurance_refund_ratio).round() as Score;
+        debug!("Bad payout {payout} insured, refunding {refund}");
+        refund
+    }
+}
+
+impl App {
+    #[tracing::instrument(
+        name = "apply_modifier",
+        skip_all,
+        fields(user = %user_token.digest(), pipe_id = pipe_id.get(), ?modifier)
+    )]
+    pub async fn apply_modifier(
+        &self,
+        user_token: &UserToken,
+        pipe_id: PipeId,
+        modifier: Modifier,
+    ) -> Result<ApplyModifierResponse> {
+        let result = self
+            .apply_modifier_inner(user_token, pipe_id, modifier)
+            .await;
+        self.record_result(user_token, Action::ApplyModifier, pipe_id, &result)
+            .await;
+        result
+    }
+
+    async fn apply_modifier_inner(
+        &self,
+        user_token: &UserToken,
+        pipe_id: PipeId,
+        modifier: Modifier,
+    ) -> Result<ApplyModifierResponse> {
+        if !self.config().is_available(modifier) {
+            debug!("User {user_token:?} tried to apply disabled {modifier:?} modifier");
+            return Err(Error::ModifierDisabled { modifier });
+        }
+        let mut action = self
+            .begin_action(user_token, Action::ApplyModifier, pipe_id, Duration::ZERO)
+            .await?;
+        if self.config().tick_secs.is_some() {
+            self.action_sleep(user_token, &mut action, Duration::ZERO)
+                .await?;
+        }
+        let pipe = self.pipe(pipe_id)?;
+        let mut pipe = pipe.lock().await;
+        let mut user = action.user().lock().await;
+        info!(
+            "User {user_token:?}: {user:?} is trying apply {modifier:?} modifier to pipe {pipe_id}"
+        );
+        debug!("Pipe state: {pipe:#?}");
+        if let Some(shield) = pipe.applied_by.get(&Modifier::Shield) {
+            let expired = self
+                .config()
+                .shield_secs
+                .is_some_and(|secs| self.game_time() >= shield.time + secs);
+            if expired {
+                debug!("Shield of pipe {pipe_id} expired");
+                pipe.modifiers.remove(&Modifier::Shield);
+                pipe.applied_by.remove(&Modifier::Shield);
+                self.log_pipe(pipe_id, &pipe).await;
+            } else if shield.user != *user_token {
+                debug!("Pipe is shielded by {:?}", shield.user);
+                return Err(Error::PipeShielded { pipe_id });
+            }
+        }
+        let cost = self.modifier_cost(modifier);
+        if user.score < cost {
+            debug!("Not enough score to pay for modification");
+            return Err(Error::NotEnoughScore {
+                required: cost,
+                score: user.score,
+            });
+        }
+        match modifier {
+            Modifier::Slow
+            | Modifier::Double
+            | Modifier::Min
+            | Modifier::Shield
+            | Modifier::Steal => {
+                let uses = match modifier {
+                    Modifier::Slow => self.config().slow_uses,
+                    Modifier::Double => self.config().double_uses,
+                    Modifier::Min => self.config().min_uses,
+                    Modifier::Shield => self.config().shield_uses,
+                    // Only the next collect
+                    Modifier::Steal => 1,
+                    _ => unreachable!("Well, we just checked its one of these"),
+                };
+                self.attach_modifier(&mut pipe, pipe_id, modifier, uses, user_token)?;
+            }
+            Modifier::Custom(name) => {
+                let config = self.config();
+                let custom = config
+                    .custom_modifier(name)
+                    .expect("Only available modifiers get here");
+                match custom.effect {
+                    ModifierEffect::DirectionFlip => {
+                        pipe.direction = pipe.direction.inverse();
+                        debug!("Pipe's new direction is {:?}", pipe.direction);
+                    }
+                    ModifierEffect::DelayReroll => {
+                        pipe.base_delay = config.random_pipe_delay(&mut *self.rng.lock().unwrap());
+                        debug!("Pipe's base delay changed to {:?}", pipe.base_delay);
+                    }
+                    _ => {
+                        self.attach_modifier(&mut pipe, pipe_id, modifier, custom.uses, user_token)?
+                    }
+                }
+            }
+            Modifier::Shuffle => {
+                pipe.base_delay = self
+                    .config()
+                    .random_pipe_delay(&mut *self.rng.lock().unwrap());
+                debug!("Pipe's base delay changed to {:?}", pipe.base_delay);
+            }
+            Modifier::Reverse => {
+                pipe.direction = pipe.direction.inverse();
+                debug!("Pipe's new direction is {:?}", pipe.direction);
+            }
+            Modifier::Insurance => {
+                if pipe.insurance.contains_key(user_token) {
+                    debug!("User already has insurance on this pipe");
+                    return Err(Error::ModifierAlreadyApplied { pipe_id, modifier });
+                }
+                let uses = self.config().insurance_uses;
+                debug!("Insuring {user_token:?} on pipe {pipe_id} for {uses} collects");
+                pipe.insurance.insert(user_token.clone(), uses);
+            }
+        }
+        let (bankrupt, change) = self.change_score(
+            &mut user,
+            -cost,
+            ScoreReason::Modifier { pipe_id, modifier },
+        );
+        user.stats.modifiers_bought += 1;
+        debug!("User's score is now {}", user.score);
+        self.log_user(user_token, &user, Some(change)).await;
+        self.log_pipe(pipe_id, &pipe).await;
+        if self.raise_price(modifier) {
+            self.log(LogMessage::UpdatePrices {
+                prices: self.prices(),
+            })
+            .await;
+        }
+        if bankrupt {
+            self.bankrupt(user_token, &action.user, user.score).await;
+        }
+        drop(user);
+        action.succeed();
+        Ok(ApplyModifierResponse {})
+    }
+}
+
+impl App {
+    /// Puts a modifier that lasts for some collects on the pipe
+    fn attach_modifier(
+        &self,
+        pipe: &mut Pipe,
+        pipe_id: PipeId,
+        modifier: Modifier,
+        uses: usize,
+        user_token: &UserToken,
+    ) -> Result<()> {
+        if pipe.modifiers.contains_key(&modifier) {
+            debug!("Modifier already applied");
+            return Err(Error::ModifierAlreadyApplied { pipe_id, modifier });
+        }
+        debug!("Adding {modifier:?} modifier to pipe {pipe_id} with {uses} uses");
+        pipe.modifiers.insert(modifier, uses);
+        pipe.applied_by.insert(
+            modifier,
+            ModifierApplication {
+                user: user_token.clone(),
+                time: self.game_time(),
+            },
+        );
+        Ok(())
+    }
+}
+
+struct Assignment {
+    pipe_id: PipeId,
+    until: f64,
+}
+
+impl App {
+    fn assignment(&self, user: &UserEntry) -> Option<Assignment> {
+        let period = self.config().round_robin_secs?;
+        let rotation = (self.game_time() / period).floor();
+        let ids = self.pipes.read().unwrap().ids();
+        let index = (user.index + rotation as usize).checked_rem(ids.len())?;
+        Some(Assignment {
+            pipe_id: ids[index],
+            until: (rotation + 1.0) * period,
+        })
+    }
+
+    pub async fn assigned_pipe(&self, user_token: &UserToken) -> Result<AssignedPipeResponse> {
+        let user = self.user_entry(user_token).await?;
+        let assignment = self.assignment(&user);
+        Ok(AssignedPipeResponse {
+            pipe_id: assignment.as_ref().map(|assignment| assignment.pipe_id),
+            until: assignment.map(|assignment| assignment.until),
+        })
+    }
+}
+
+/// Modifiers active on the pipe as seen by the user, including the user's own insurance
+fn visible_modifiers(pipe: &Pipe, user_token: &UserToken) -> Vec<PipeModifier> {
+    let mut modifiers: Vec<PipeModifier> = pipe
+        .modifiers
+        .iter()
+        .map(|(&modifier, &uses)| {
+            let own = pipe
+                .applied_by
+                .get(&modifier)
+                .is_some_and(|application| &application.user == user_token);
+            PipeModifier {
+                modifier,
+                uses_left: own.then_some(uses),
+            }
+        })
+        .collect();
+    if let Some(&uses) = pipe.insurance.get(user_token) {
+        modifiers.push(PipeModifier {
+            modifier: Modifier::Insurance,
+            uses_left: Some(uses),
+        });
+    }
+    modifiers.sort_by_key(|modifier| modifier.modifier);
+    modifiers
+}
+
+impl App {
+    pub async fn pipe_modifiers(
+        &self,
+        user_token: &UserToken,
+        pipe_id: PipeId,
+    ) -> Result<PipeModifiersResponse> {
+        self.user_entry(user_token).await?;
+        let pipe = self.pipe(pipe_id)?;
+        let pipe = pipe.lock().await;
+        Ok(PipeModifiersResponse {
+            modifiers: visible_modifiers(&pipe, user_token),
+        })
+    }
+}
+
+impl App {
+    /// All pipes ordered by id, with the modifiers the user can see on them
+    pub async fn list_pipes(&self, user_token: &UserToken) -> Result<PipesResponse> {
+        self.user_entry(user_token).await?;
+        let registered: Vec<(PipeId, Arc<Mutex<Pipe>>)> = self
+            .pipes
+            .read()
+            .unwrap()
+            .pipes
+            .iter()
+            .map(|(&id, pipe)| (id, pipe.clone()))
+            .collect();
+        let mut pipes = Vec::with_capacity(registered.len());
+        for (id, pipe) in registered {
+            let pipe = pipe.lock().await;
+            pipes.push(PipeSummary {
+                id,
+                modifiers: visible_modifiers(&pipe, user_token),
+            });
+        }
+        Ok(PipesResponse { pipes })
+    }
+}
+
+impl App {
+    pub async fn user_actions(&self, user_token: &UserToken) -> Result<UserActionsResponse> {
+        let user = self.user_entry(user_token).await?;
+        let actions = user
+            .actions
+            .lock()
+            .unwrap()
+            .by_id
+            .values()
+            .cloned()
+            .collect();
+        Ok(UserActionsResponse { actions })
+    }
+
+    /// Current state of the user as kept by the server
+    pub async fn user_state(&self, user_token: &UserToken) -> Result<User> {
+        let user = self.user_entry(user_token).await?;
+        let state = user.state.lock().await.clone();
+        Ok(state)
+    }
+}
+
+impl App {
+    /// Registers a collect as an operation, requests with a known idempotency key
+    /// get the operation started by the first one instead
+    pub async fn begin_operation(
+        &self,
+        user_token: &UserToken,
+        pipe_id: PipeId,
+        key: Option<String>,
+    ) -> Result<OperationStart> {
+        let user = self.user_entry(user_token).await?;
+        let mut operations = user.operations.lock().unwrap();
+        if let Some(&id) = key.as_ref().and_then(|key| operations.by_key.get(key)) {
+            if operations.by_id[&id].pipe_id != pipe_id {
+                return Err(Error::IdempotencyKeyReused);
+            }
+            return Ok(OperationStart::Existing(id));
+        }
+        operations.next_id += 1;
+        let id = operations.next_id;
+        operations.by_id.insert(
+            id,
+            Operation {
+                id,
+                action: Action::Collect,
+                pipe_id,
+                started_at: self.game_time(),
+                status: OperationStatus::Pending,
+            },
+        );
+        if let Some(key) = key {
+            operations.by_key.insert(key.clone(), id);
+            operations.keys.insert(id, key);
+        }
+        operations.forget_finished();
+        Ok(OperationStart::New(id))
+    }
+
+    /// Runs a collect registered with [`Self::begin_operation`], see [`Self::collect_until`]
+    #[tracing::instrument(
+        name = "operation",
+        skip_all,
+        fields(user = %user_token.digest(), id = id)
+    )]
+    pub async fn run_operation(
+        &self,
+        user_token: &UserToken,
+        id: u64,
+        pipe_id: PipeId,
+        client_gone: impl Future<Output = ()>,
+    ) -> Result<CollectResponse> {
+        let result = self.collect_until(user_token, pipe_id, client_gone).await;
+        if let Some(user) = self.users.get(user_token) {
+            let mut operations = user.operations.lock().unwrap();
+            if let Some(operation) = operations.by_id.get_mut(&id) {
+                operation.status = match &result {
+                    Ok(response) => OperationStatus::Done {
+                        response: response.clone(),
+                    },
+                    Err(error) => OperationStatus::Failed { error: *error },
+                };
+            }
+        }
+        self.operation_done.notify_waiters();
+        result
+    }
+
+    pub async fn operation(&self, user_token: &UserToken, id: u64) -> Result<Operation> {
+        let user = self.user_entry(user_token).await?;
+        let operations = user.operations.lock().unwrap();
+        operations
+            .by_id
+            .get(&id)
+            .cloned()
+            .ok_or(Error::OperationNotFound)
+    }
+
+    /// Waits until the operation is no longer pending
+    pub async fn wait_for_operation(&self, user_token: &UserToken, id: u64) -> Result<Operation> {
+        loop {
+            // Created before checking, so that a finish in between is not missed
+            let done = self.operation_done.notified();
+            let operation = self.operation(user_token, id).await?;
+            if operation.status != OperationStatus::Pending {
+                return Ok(operation);
+            }
+            done.await;
+        }
+    }
+}
+
+impl App {
+    /// Adds users while the game is running, existing users only get their profile replaced
+    pub async fn import_users(&self, records: Vec<UserRecord>) -> ImportUsersResponse {
+        let mut response = ImportUsersResponse {
+            added: 0,
+            updated: 0,
+        };
+        for UserRecord { token, profile } in records {
+            let (entry, added) = self.users.get_or_insert_with(token.clone(), || {
+                info!("Importing user {token:?}");
+                let mut user = self.config().initial_user(&token);
+                user.show_profile(&profile);
+                let index = self
+                    .joined_users
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Arc::new(UserEntry::new(index, user, profile.clone()))
+            });
+            let mut user = entry.state.lock().await;
+            if added {
+                response.added += 1;
+            } else {
+                user.show_profile(&profile);
+                *entry.profile.lock().unwrap() = profile;
+                response.updated += 1;
+            }
+            self.log_user(&token, &user, None).await;
+        }
+        response
+    }
+
+    /// Hands out a new random token, only when anyone may play.
+    /// Names are trimmed, blank ones are dropped.
+    pub async fn register(&self, request: RegisterRequest) -> Option<RegisterResponse> {
+        if !self.allow_unknown_users {
+            return None;
+        }
+        let name = request
+            .name
+            .map(|name| name.trim().to_owned())
+            .filter(|name| !name.is_empty());
+        let profile = UserProfile {
+            name: name.clone(),
+            team: None,
+            color: request.color,
+            ghost: false,
+        };
+        let (token, user) = loop {
+            let token = UserToken(format!("{:032x}", thread_rng().gen::<u128>()));
+            let mut user = self.config().initial_user(&token);
+            user.show_profile(&profile);
+            let (_, added) = self.users.get_or_insert_with(token.clone(), || {
+                let index = self
+                    .joined_users
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Arc::new(UserEntry::new(index, user.clone(), profile.clone()))
+            });
+            if added {
+                break (token, user);
+            }
+        };
+        info!("Registered {token:?} as {name:?}");
+        self.log(LogMessage::UserRegistered {
+            user: token.clone(),
+            name: name.clone(),
+        })
+        .await;
+        self.log_user(&token, &user, None).await;
+        Some(RegisterResponse { token, name })
+    }
+
+    /// Takes the user out of the game, their actions in flight are aborted
+    pub async fn remove_user(&self, token: &UserToken) -> Result<()> {
+        self.abort_actions(token, AbortReason::Admin).await?;
+        if self.users.remove(token).is_none() {
+            return Err(Error::UserNotFound);
+        }
+        info!("Removed user {token:?}");
+        self.log(LogMessage::UserRemoved {
+            user: token.clone(),
+        })
+        .await;
+        let changes = self.ranking.lock().unwrap().update(token, None);
+        self.log_rank_changes(changes).await;
+        Ok(())
+    }
+
+    /// Changes the pipe as asked by the operator
+    pub async fn update_pipe(&self, id: PipeId, update: PipeUpdate) -> Result<Pipe> {
+        let pipe = self.pipe(id)?;
+        let mut pipe = pipe.lock().await;
+        if let Some(value) = update.value {
+            pipe.value = value;
+        }
+        if let Some(direction) = update.direction {
+            pipe.direction = direction;
+        }
+        info!("Pipe {id} changed by the operator: {pipe:?}");
+        self.log_pipe(id, &pipe).await;
+        Ok(pipe.clone())
+    }
+
+    /// Everything known about the game, including values hidden from users
+    pub fn game_state(&self) -> replay::State {
+        self.state.read().unwrap().clone()
+    }
+
+    /// All users in the order they joined
+    pub async fn export_users(&self) -> Vec<ExportedUser> {
+        let mut users: Vec<(UserToken, Arc<UserEntry>)> = self.users.entries();
+        users.sort_by_key(|(_, user)| user.index);
+        let mut exported = Vec::with_capacity(users.len());
+        for (token, user) in users {
+            exported.push(ExportedUser {
+                score: user.state.lock().await.score,
+                user: UserRecord {
+                    token,
+                    profile: user.profile.lock().unwrap().clone(),
+                },
+            });
+        }
+        exported
+    }
+}
+
+impl App {
+    /// Current cost of the modifier, taking active market events into account
+    pub fn modifier_cost(&self, modifier: Modifier) -> Score {
+        let time = self.elapsed().as_secs_f64();
+        let multiplier: f64 = self
+            .config()
+            .market_events
+            .iter()
+            .filter(|event| event.modifier == modifier && event.is_active(time))
+            .map(|event| event.cost_multiplier)
+            .product();
+        let phase_multiplier = self.phase().map_or(1.0, |phase| phase.cost_multiplier);
+        (self.config().modifier_cost(modifier) as f64
+            * multiplier
+            * phase_multiplier
+            * self.demand_multiplier(modifier))
+        .round() as Score
+    }
+
+    /// Surcharge of the modifier because of recent purchases, 1 without dynamic pricing
+    pub fn demand_multiplier(&self, modifier: Modifier) -> f64 {
+        let Some(pricing) = &self.config().dynamic_pricing else {
+            return 1.0;
+        };
+        self.demand
+            .lock()
+            .unwrap()
+            .get(&modifier)
+            .map_or(1.0, |demand| demand.at(pricing, self.game_time()))
+    }
+
+    /// Makes the next purchase of the modifier more expensive, returns whether prices changed
+    fn raise_price(&self, modifier: Modifier) -> bool {
+        let Some(pricing) = &self.config().dynamic_pricing else {
+            return false;
+        };
+        let time = self.game_time();
+        let mut demand = self.demand.lock().unwrap();
+        let current = demand
+            .get(&modifier)
+            .map_or(1.0, |demand| demand.at(pricing, time));
+        let multiplier = pricing
+            .max_multiplier
+            .map_or(current * pricing.purchase_multiplier, |max| {
+                (current * pricing.purchase_multiplier).min(max)
+            });
+        debug!("Demand multiplier of {modifier:?} is now {multiplier}");
+        demand.insert(modifier, Demand { multiplier, time });
+        true
+    }
+
+    /// Current prices of the enabled modifiers
+    pub fn prices(&self) -> BTreeMap<Modifier, ModifierPrice> {
+        self.config()
+            .available_modifiers()
+            .map(|modifier| {
+                let price = ModifierPrice {
+                    cost: self.modifier_cost(modifier),
+                    demand_multiplier: self.demand_multiplier(modifier),
+                };
+                (modifier, price)
+            })
+            .collect()
+    }
+
+    /// Phase of the game active right now
+    pub fn phase(&self) -> Option<Phase> {
+        let time = self.game_time();
+        self.config()
+            .phases
+            .iter()
+            .filter(|phase| phase.start_secs <= time)
+            .max_by(|a, b| a.start_secs.total_cmp(&b.start_secs))
+            .cloned()
+    }
+
+    /// Runs timed game events, should be running for the whole duration of the game
+    pub async fn run_scheduler(&self) {
+        self.wait_for_start().await;
+        info!("Game started");
+        let players = self.users.len();
+        self.log(LogMessage::GameStart { players }).await;
+        futures::join!(
+            self.run_market_events(),
+            self.run_phases(),
+            self.run_global_events(),
+            self.run_value_drift(),
+            self.announce_game_end(),
+            self.report_idle_users(),
+            self.run_pipe_events(),
+        );
+    }
+
+    /// Adds and retires pipes at random every `pipe_events_secs`
+    async fn run_pipe_events(&self) {
+        let Some(interval) = self.config().pipe_events_secs.map(Duration::from_secs_f64) else {
+            return;
+        };
+        while self
+            .time_left()
+            .is_none_or(|time_left| !time_left.is_zero())
+        {
+            sleep(interval).await;
+            if self.is_paused() {
+                continue;
+            }
+            let (spawn, retire) = {
+                let mut rng = self.rng.lock().unwrap();
+                (
+                    rng.gen_bool(self.config().pipe_spawn_probability.clamp(0.0, 1.0)),
+                    rng.gen_bool(self.config().pipe_retire_probability.clamp(0.0, 1.0)),
+                )
+            };
+            if spawn {
+                self.spawn_pipe().await;
+            }
+            if retire {
+                self.retire_pipe().await;
+            }
+        }
+    }
+
+    /// Moves every pipe value by `value_drift_amount` every `value_drift_interval_secs`
+    async fn run_value_drift(&self) {
+        let Some(interval) = self
+            .config()
+            .value_drift_interval_secs
+            .map(Duration::from_secs_f64)
+        else {
+            return;
+        };
+        if self.config().value_drift_amount == 0 {
+            return;
+        }
+        while self
+            .time_left()
+            .is_none_or(|time_left| !time_left.is_zero())
+        {
+            sleep(interval).await;
+            if self.is_paused() {
+                continue;
+            }
+            let ids = self.pipes.read().unwrap().ids();
+            for id in ids {
+                let Some(pipe) = self.pipes.read().unwrap().get(id) else {
+                    continue;
+                };
+                let mut pipe = pipe.lock().await;
+                let value = (pipe.value + self.config().value_drift_amount)
+                    .clamp(self.config().min_value, self.config().max_value);
+                if value != pipe.value {
+                    pipe.value = value;
+                    debug!("Pipe {id} value drifted to {value}");
+                    self.log_pipe(id, &pipe).await;
+                }
+            }
+        }
+    }
+
+    async fn spawn_pipe(&self) {
+        let (id, pipe) = {
+            let mut pipes = self.pipes.write().unwrap();
+            if self
+                .config()
+                .max_pipe_count
+                .is_some_and(|max| pipes.pipes.len() >= max)
+            {
+                debug!("Not adding a pipe, there are {} already", pipes.pipes.len());
+                return;
+            }
+            let pipe = self.config().random_pipe(&mut *self.rng.lock().unwrap());
+            (pipes.add(pipe.clone()), pipe)
+        };
+        info!("Pipe {id} appeared: {pipe:?}");
+        self.log_pipe(id, &pipe).await;
+    }
+
+    async fn retire_pipe(&self) {
+        let id = {
+            let mut pipes = self.pipes.write().unwrap();
+            if pipes.pipes.len() <= self.config().min_pipe_count {
+                debug!("Not retiring a pipe, only {} left", pipes.pipes.len());
+                return;
+            }
+            let ids = pipes.ids();
+            let id = *ids
+                .choose(&mut *self.rng.lock().unwrap())
+                .expect("There are more pipes than the minimum");
+            pipes.pipes.remove(&id);
+            id
+        };
+        info!("Pipe {id} retired");
+        self.log(LogMessage::RemovePipe { id }).await;
+    }
+
+    /// Logs a `UserIdle` event once for every silence longer than `idle_notice_secs`
+    async fn report_idle_users(&self) {
+        let Some(notice) = self.config().idle_notice_secs.map(Duration::from_secs_f64) else {
+            return;
+        };
+        // Last request of every user when it was reported, so each silence is reported once
+        let mut reported: HashMap<UserToken, Option<Instant>> = HashMap::new();
+        while self
+            .time_left()
+            .is_none_or(|time_left| !time_left.is_zero())
+        {
+            sleep(IDLE_CHECK_INTERVAL).await;
+            let tokens = self.users.keys();
+            for token in tokens {
+                let last_request = self.metrics.last_request(&token.0);
+                let idle = last_request.map_or(self.elapsed(), |time| time.elapsed());
+                if idle < notice || reported.get(&token) == Some(&last_request) {
+                    continue;
+                }
+                info!("{token:?} is idle for {idle:?}");
+                reported.insert(token.clone(), last_request);
+                self.log(LogMessage::UserIdle {
+                    user: token,
+                    idle_secs: idle.as_secs_f64(),
+                })
+                .await;
+            }
+        }
+    }
+
+    async fn run_phases(&self) {
+        let mut phases = self.config().phases.clone();
+        phases.sort_by(|a, b| a.start_secs.total_cmp(&b.start_secs));
+        for phase in phases {
+            let start = Duration::from_secs_f64(phase.start_secs);
+            if let Some(wait) = start.checked_sub(self.elapsed()) {
+                sleep(wait).await;
+            }
+            info!("Phase {:?} started", phase.name);
+            self.log(LogMessage::PhaseStarted { phase }).await;
+        }
+    }
+
+    /// Starts random global events, the schedule comes from a generator of its own so that
+    /// it only depends on the seed and not on what the players do
+    async fn run_global_events(&self) {
+        let Some(events) = &self.config().global_events else {
+            return;
+        };
+        if events.effects.is_empty() {
+            return;
+        }
+        let mut rng = StdRng::seed_from_u64(self.seed ^ GLOBAL_EVENTS_SEED_SALT);
+        while self
+            .time_left()
+            .is_none_or(|time_left| !time_left.is_zero())
+        {
+            // Exponentially distributed gaps, so that events can not be predicted
+            let gap = -events.mean_interval_secs * (1.0 - rng.gen::<f64>()).ln();
+            let effect = match events
+                .effects
+                .choose_weighted(&mut rng, |effect| effect.weight)
+            {
+                Ok(effect) => effect.clone(),
+                Err(e) => {
+                    error!("Can not pick a global event: {e}");
+                    return;
+                }
+            };
+            sleep(Duration::from_secs_f64(gap)).await;
+            if self.is_paused() {
+                continue;
+            }
+            let event = GlobalEvent {
+                effect,
+                start_secs: self.elapsed().as_secs_f64(),
+            };
+            info!("Global event started: {event:?}");
+            {
+                let mut active = self.global_events.lock().unwrap();
+                active.retain(|active| active.is_active(event.start_secs));
+                active.push(event.clone());
+            }
+            self.log(LogMessage::GlobalEvent { event }).await;
+        }
+    }
+
+    /// Global events in effect right now
+    pub fn active_global_events(&self) -> Vec<GlobalEvent> {
+        let time = self.elapsed().as_secs_f64();
+        self.global_events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.is_active(time))
+            .cloned()
+            .collect()
+    }
+
+    fn global_value_multiplier(&self) -> f64 {
+        self.active_global_events()
+            .iter()
+            .map(|event| event.effect.value_multiplier)
+            .product()
+    }
+
+    fn global_delay_multiplier(&self) -> f64 {
+        self.active_global_events()
+            .iter()
+            .map(|event| event.effect.delay_multiplier)
+            .product()
+    }
+
+    async fn announce_game_end(&self) {
+        let notice = Duration::from_secs_f64(self.config().game_ending_notice_secs);
+        // The end moves away while the game is paused
+        let time_left = loop {
+            let Some(time_left) = self.time_left() else {
+                return;
+            };
+            match time_left.checked_sub(notice) {
+                Some(wait) if !wait.is_zero() => sleep(wait).await,
+                _ => break time_left,
+            }
+        };
+        info!("Game ends in {time_left:?}");
+        self.log(LogMessage::GameEnding {
+            seconds_left: time_left.as_secs_f64(),
+        })
+        .await;
+    }
+
+    async fn run_market_events(&self) {
+        let config = self.config();
+        // Ends go before starts at the same time, like `MarketEvent::is_active` has it
+        let mut boundaries: Vec<_> = config
+            .market_events
+            .iter()
+            .flat_map(|event| {
+                [
+                    (event.start_secs, true, event),
+                    (event.end_secs(), false, event),
+                ]
+            })
+            .collect();
+        boundaries.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        for (time, started, event) in boundaries {
+            if let Some(wait) = Duration::from_secs_f64(time).checked_sub(self.elapsed()) {
+                sleep(wait).await;
+            }
+            if started {
+                info!("Market event started: {event:?}");
+                self.log(LogMessage::MarketEvent {
+                    modifier: event.modifier,
+                    cost_multiplier: event.cost_multiplier,
+                    cost: self.modifier_cost(event.modifier),
+                    duration: Duration::from_secs_f64(event.duration_secs),
+                })
+                .await;
+            } else {
+                info!("Market event ended: {event:?}");
+                self.log(LogMessage::MarketEventEnd {
+                    modifier: event.modifier,
+                    cost: self.modifier_cost(event.modifier),
+                })
+                .await;
+            }
+        }
+    }
+}
+
+/// Mixed into the seed for the global event schedule
+const GLOBAL_EVENTS_SEED_SALT: u64 = 0x9e37_79b9_7f4a_7c15;
+
+const OVERLAY_TOP_PLAYERS: usize = 5;
+
+/// How often the overlay is recomputed at most
+const OVERLAY_REFRESH: Duration = Duration::from_secs(1);
+
+impl App {
+    /// Game summary taken from the state rebuilt from the log,
+    /// so frequent polling never waits for users or pipes.
+    /// It is recomputed at most every `OVERLAY_REFRESH`, requests coming meanwhile share it
+    pub fn overlay(&self) -> Arc<OverlayResponse> {
+        let mut cached = self.overlay.lock().unwrap();
+        if let Some((computed, overlay)) = &*cached {
+            if computed.elapsed() < OVERLAY_REFRESH {
+                return overlay.clone();
+            }
+        }
+        let mut top = self.standings();
+        top.truncate(OVERLAY_TOP_PLAYERS);
+        let last_event = self.last_big_event.lock().unwrap().clone();
+        let overlay = Arc::new(OverlayResponse {
+            top,
+            time_left: self.time_left().map(|time| time.as_secs_f64()),
+            last_event: last_event.map(|entry| entry.map_user(|user| user.digest())),
+        });
+        *cached = Some((Instant::now(), overlay.clone()));
+        overlay
+    }
+
+    /// All users from the leader down, taken from the log like the overlay
+    pub fn scoreboard(&self) -> Vec<ScoreboardEntry> {
+        let mut scoreboard: Vec<ScoreboardEntry> = Vec::new();
+        for (index, player) in self.standings().into_iter().enumerate() {
+            let rank = match scoreboard.last() {
+                Some(last) if last.player.score == player.score => last.rank,
+                _ => index + 1,
+            };
+            scoreboard.push(ScoreboardEntry { rank, player });
+        }
+        scoreboard
+    }
+
+    fn standings(&self) -> Vec<OverlayPlayer> {
+        let state = self.state.read().unwrap();
+        let mut players: Vec<OverlayPlayer> = state
+            .scores
+            .iter()
+            .map(|(user, &score)| OverlayPlayer {
+                user: UserToken(user.clone()).digest(),
+                name: state.names.get(user).cloned(),
+                color: state.colors.get(user).cloned(),
+                score,
+            })
+            .collect();
+        players.sort_by_key(|player| std::cmp::Reverse(player.score));
+        players
+    }
+}
+
+/// Surcharge of a modifier as of its last purchase
+#[derive(Debug, Clone, Copy)]
+struct Demand {
+    multiplier: f64,
+    /// Game time of the last purchase
+    time: f64,
+}
+
+impl Demand {
+    /// The surcharge wears off exponentially towards the base price
+    fn at(&self, pricing: &DynamicPricing, time: f64) -> f64 {
+        let decay = 0.5f64.powf((time - self.time).max(0.0) / pricing.half_life_secs);
+        1.0 + (self.multiplier - 1.0) * decay
+    }
+}
+
+impl App {
+    pub fn shop(&self) -> ShopResponse {
+        let time = self.elapsed().as_secs_f64();
+        let modifiers = self
+            .config()
+            .available_modifiers()
+            .map(|modifier| {
+                let item = ShopItem {
+                    cost: self.modifier_cost(modifier),
+                    base_cost: self.config().modifier_cost(modifier),
+                };
+                (modifier, item)
+            })
+            .collect();
+        let events = self
+            .config()
+            .market_events
+            .iter()
+            .filter(|event| event.end_secs() > time)
+            .cloned()
+            .collect();
+        ShopResponse {
+            modifiers,
+            events,
+            peek_cost: self.config().peek_cost,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_requests() {
+        let window = Duration::from_millis(1);
+        let mut requests = HashMap::new();
+        recent_requests(&mut requests, "a", window).push_back(Instant::now());
+        recent_requests(&mut requests, "a", window).push_back(Instant::now());
+        assert!(!recent_requests(&mut requests, "a", Duration::MAX).is_empty());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(recent_requests(&mut requests, "a", window).is_empty());
+        // Keys without recent requests go away once another key shows up
+        recent_requests(&mut requests, "b", window).push_back(Instant::now());
+        assert_eq!(requests.len(), 1);
+        assert!(requests.contains_key("b"));
+        // Also when no other key shows up
+        std::thread::sleep(Duration::from_millis(5));
+        sweep_requests(&mut requests, window);
+        assert!(requests.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_applied_by() {
+        let [alice, bob] = ["alice", "bob"].map(|token| UserToken::from(token.to_owned()));
+        let app = App::init(
+            Config {
+                double_uses: 2,
+                slow_uses: 5,
+                start_delay_secs: 0.0,
+                initial_score: 1000,
+                ..Default::default()
+            },
+            [alice.clone(), bob.clone()],
+        );
+        let id = PipeId::new(1).unwrap();
+        let applied = |app: &App| -> Vec<(Modifier, String, f64)> {
+            let mut applied: Vec<_> = app.game_state().pipes[&id]
+                .applied_by
+                .iter()
+                .map(|(modifier, application)| {
+                    (*modifier, application.user.clone(), application.time)
+                })
+                .collect();
+            applied.sort_by_key(|(modifier, ..)| format!("{modifier:?}"));
+            applied
+        };
+        app.apply_modifier(&alice, id, Modifier::Double)
+            .await
+            .unwrap();
+        app.apply_modifier(&alice, id, Modifier::Insurance)
+            .await
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(2)).await;
+        app.apply_modifier(&bob, id, Modifier::Slow).await.unwrap();
+        // Insurance is private to the insured, it is not attributed in the public state
+        assert_eq!(
+            applied(&app),
+            [
+                (Modifier::Double, "alice".to_owned(), 0.0),
+                (Modifier::Slow, "bob".to_owned(), 2.0),
+            ]
+        );
+        assert!(app.game_state().pipes[&id].insurance.is_empty());
+
+        // The application goes away with the last use of the modifier
+        app.collect(&bob, id).await.unwrap();
+        assert_eq!(applied(&app).len(), 2);
+        app.collect(&bob, id).await.unwrap();
+        assert_eq!(applied(&app), [(Modifier::Slow, "bob".to_owned(), 2.0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_locked_mid_collect() {
+        let [alice, bob] = ["alice", "bob"].map(|token| UserToken::from(token.to_owned()));
+        let app = App::init(
+            Config {
+                min_delay_secs: 1.0,
+                max_delay_secs: 1.0,
+                post_collect_lockout_secs: 3.0,
+                slow_uses: 1,
+                start_delay_secs: 0.0,
+                initial_score: 1000,
+                ..Default::default()
+            },
+            [alice.clone(), bob.clone()],
+        );
+        let id = PipeId::new(1).unwrap();
+        app.apply_modifier(&alice, id, Modifier::Slow)
+            .await
+            .unwrap();
+        // Bob starts later, but finishes first and locks the pipe
+        let slow = app.collect(&alice, id);
+        let fast = async {
+            sleep(Duration::from_millis(500)).await;
+            app.collect(&bob, id).await
+        };
+        let (slow, fast) = tokio::join!(slow, fast);
+        fast.unwrap();
+        assert!(matches!(slow, Err(Error::PipeLocked { .. })), "{slow:?}");
+        // The collect did not happen, so the slow modifier it used is back
+        assert_eq!(app.game_state().pipes[&id].modifiers[&Modifier::Slow], 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_implicit_user_logged() {
+        let carol = UserToken::from("carol".to_owned());
+        let app = App::init(
+            Config {
+                allow_unknown_users: true,
+                implicit_users: true,
+                start_delay_secs: 0.0,
+                initial_score: 50,
+                ..Default::default()
+            },
+            [],
+        );
+        app.pipe_value(&carol, PipeId::new(1).unwrap())
+            .await
+            .unwrap();
+        // The new user is in the log, the read model only knows about it from there
+        let history = app.checkpoint().await.history;
+        assert!(history.iter().any(|entry| matches!(
+            &entry.msg,
+            LogMessage::UpdateUser { user, state, change: None }
+                if *user == carol && state.score == 50
+        )));
+        assert_eq!(app.game_state().scores["carol"], 50);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restore_deadline() {
+        let config = || Config {
+            time_to_run: Some(100.0),
+            start_delay_secs: 0.0,
+            ..Default::default()
+        };
+        let users = || [UserToken::from("alice".to_owned())];
+        let app = App::init(config(), users());
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let mut checkpoint = app.checkpoint().await;
+        let ends_at = checkpoint.ends_at_unix_secs.unwrap();
+        assert!((ends_at - unix_time() - 90.0).abs() < 1.0, "{ends_at}");
+
+        // The server was down for 30 seconds before the restore
+        checkpoint.ends_at_unix_secs = Some(ends_at - 30.0);
+        let mut restored = App::init(config(), users());
+        restored.restore(checkpoint);
+        let time_left = restored.time_left().unwrap().as_secs_f64();
+        assert!((time_left - 60.0).abs() < 1.0, "{time_left}");
+        assert!((restored.game_time() - 40.0).abs() < 1.0);
+
+        // A deadline that passed while the server was down ends the game right away
+        let mut checkpoint = app.checkpoint().await;
+        checkpoint.ends_at_unix_secs = Some(unix_time() - 1.0);
+        let mut restored = App::init(config(), users());
+        restored.restore(checkpoint);
+        assert_eq!(restored.time_left(), Some(Duration::ZERO));
+    }
+
+    /// Collects of the same pipe submitted 0.1s and 0.6s into the game, with the gains and
+    /// the game times they finished at
+    async fn collect_in_turns(early: &str, late: &str) -> BTreeMap<String, (Score, f64)> {
+        let app = App::init(
+            Config {
+                tick_secs: Some(1.0),
+                min_delay_secs: 0.2,
+                max_delay_secs: 0.5,
+                start_delay_secs: 0.0,
+                seed: Some(7),
+                ..Default::default()
+            },
+            ["alice", "bob"].map(|token| UserToken::from(token.to_owned())),
+        );
+        let id = PipeId::new(1).unwrap();
+        let collect = |token: &str, after: f64| {
+            let (app, token) = (&app, UserToken::from(token.to_owned()));
+            async move {
+                sleep(Duration::from_secs_f64(after)).await;
+                let gain = app.collect(&token, id).await.unwrap().value;
+                (token.0.clone(), (gain, app.game_time()))
+            }
+        };
+        let (early, late) = tokio::join!(collect(early, 0.1), collect(late, 0.6));
+        [early, late].into()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_turns() {
+        let results = collect_in_turns("alice", "bob").await;
+        // Both started at the end of the first tick and ended at the end of the second one
+        for (_, time) in results.values() {
+            assert!((time - 2.0).abs() < 0.01, "{results:?}");
+        }
+        let gains: Vec<_> = results.values().map(|(gain, _)| *gain).collect();
+        assert_eq!((gains[0] - gains[1]).abs(), 1, "{results:?}");
+        // Being first within the tick made no difference
+        assert_eq!(collect_in_turns("bob", "alice").await, results);
+    }
+
+    #[test]
+    fn test_token_debug() {
+        let token = UserToken::from("secret-token".to_owned());
+        let config = Config {
+            spectator_tokens: vec![token.clone()],
+            ..Default::default()
+        };
+        assert_eq!(format!("{token:?}"), format!("token#{}", token.digest()));
+        assert!(!format!("{config:?}").contains("secret-token"));
+    }
+}
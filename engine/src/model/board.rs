@@ -0,0 +1,309 @@
+//! Pipes and the modifiers applied to them
+
+use super::*;
+
+/// Pipes are numbered from 1, so zero is rejected already when parsing.
+/// Whether the pipe exists is checked against the config by the [`App`].
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(transparent)]
+#[cfg_attr(feature = "openapi", schema(value_type = usize))]
+pub struct PipeId(NonZeroUsize);
+
+impl PipeId {
+    pub fn new(id: usize) -> Option<Self> {
+        NonZeroUsize::new(id).map(Self)
+    }
+
+    pub fn get(self) -> usize {
+        self.0.get()
+    }
+}
+
+impl Debug for PipeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+
+impl std::fmt::Display for PipeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl FromStr for PipeId {
+    type Err = <NonZeroUsize as FromStr>::Err;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PipeDirection {
+    Up,
+    Down,
+}
+impl PipeDirection {
+    pub fn inverse(&self) -> Self {
+        match self {
+            Self::Up => Self::Down,
+            Self::Down => Self::Up,
+        }
+    }
+
+    pub fn random(rng: &mut impl Rng) -> PipeDirection {
+        *[Self::Up, Self::Down].choose(rng).unwrap()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Modifier {
+    Slow,
+    Double,
+    Min,
+    Shuffle,
+    Reverse,
+    Insurance,
+    /// Other users can not apply modifiers to the pipe
+    Shield,
+    /// Part of the next collect by another user goes to the owner
+    Steal,
+    /// Defined in [`Config::custom_modifiers`]
+    #[serde(untagged)]
+    Custom(ModifierName),
+}
+
+impl Modifier {
+    pub const ALL: [Self; 8] = [
+        Self::Slow,
+        Self::Double,
+        Self::Min,
+        Self::Shuffle,
+        Self::Reverse,
+        Self::Insurance,
+        Self::Shield,
+        Self::Steal,
+    ];
+}
+
+/// Name of a custom modifier, kept inline so that modifiers stay `Copy`
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ModifierName {
+    // Zero padded, so that names are ordered like strings
+    bytes: [u8; Self::MAX_LEN],
+    len: u8,
+}
+
+impl ModifierName {
+    pub const MAX_LEN: usize = 31;
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len as usize])
+            .expect("Names are checked to be ASCII")
+    }
+}
+
+impl TryFrom<&str> for ModifierName {
+    type Error = String;
+
+    fn try_from(name: &str) -> std::result::Result<Self, String> {
+        let valid = !name.is_empty()
+            && name.len() <= Self::MAX_LEN
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_');
+        if !valid {
+            return Err(format!(
+                "modifier names have 1 to {} lowercase letters, digits or underscores, got {name:?}",
+                Self::MAX_LEN,
+            ));
+        }
+        let mut bytes = [0; Self::MAX_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(Self {
+            bytes,
+            len: name.len() as u8,
+        })
+    }
+}
+
+#[cfg(feature = "openapi")]
+impl utoipa::PartialSchema for ModifierName {
+    fn schema() -> utoipa::openapi::RefOr<utoipa::openapi::schema::Schema> {
+        String::schema()
+    }
+}
+
+#[cfg(feature = "openapi")]
+impl utoipa::ToSchema for ModifierName {}
+
+impl std::fmt::Debug for ModifierName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl std::fmt::Display for ModifierName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for ModifierName {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ModifierName {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let name = std::borrow::Cow::<str>::deserialize(deserializer)?;
+        Self::try_from(name.as_ref()).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Modifier defined by the organizers, with one of the generic effects
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomModifier {
+    pub name: ModifierName,
+    pub cost: Score,
+    /// Collects the modifier lasts for, effects that change the pipe right away have no uses
+    #[serde(default = "default_custom_modifier_uses")]
+    pub uses: usize,
+    #[serde(flatten)]
+    pub effect: ModifierEffect,
+}
+
+fn default_custom_modifier_uses() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "effect", rename_all = "snake_case")]
+pub enum ModifierEffect {
+    /// Collects take this many times as long
+    DelayMultiplier { multiplier: f64 },
+    /// Collected values are multiplied and rounded
+    ValueMultiplier { multiplier: f64 },
+    /// Collects get this value instead of the pipe's
+    ValueOverride {
+        #[serde(with = "serde_score")]
+        value: Score,
+    },
+    /// The pipe's values go the other way from now on, like [`Modifier::Reverse`]
+    DirectionFlip,
+    /// The pipe gets a new random delay, like [`Modifier::Shuffle`]
+    DelayReroll,
+}
+
+impl ModifierEffect {
+    /// Whether the effect stays on the pipe for the next collects
+    pub fn lasts(&self) -> bool {
+        !matches!(self, Self::DirectionFlip | Self::DelayReroll)
+    }
+}
+
+/// User who collected the pipe last, see [`Config::ownership`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipeOwner<U> {
+    pub user: U,
+    /// Game time when the ownership ends
+    pub until: f64,
+}
+
+/// Who put an active modifier on a pipe
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModifierApplication<U> {
+    pub user: U,
+    /// Game time of the application
+    pub time: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "U: Deserialize<'de>"))]
+pub struct Pipe<U = UserToken> {
+    #[serde(with = "serde_score")]
+    pub value: Score,
+    #[serde(with = "serde_duration")]
+    pub base_delay: Duration,
+    pub direction: PipeDirection,
+    pub modifiers: HashMap<Modifier, usize>,
+    /// Applications of the modifiers currently in `modifiers`
+    #[serde(default)]
+    pub applied_by: HashMap<Modifier, ModifierApplication<U>>,
+    /// Insured users and their covered collects left, kept private to the owners
+    #[serde(skip)]
+    pub insurance: HashMap<U, usize>,
+    /// Game time until which the pipe can not be collected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked_until: Option<f64>,
+    /// Kept after the ownership ends, until the next collect
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<PipeOwner<U>>,
+}
+
+impl<U> Pipe<U> {
+    #[must_use]
+    pub fn use_modifier(&mut self, modifier: Modifier) -> bool {
+        let Some(uses_left) = self.modifiers.get_mut(&modifier) else {
+            return false;
+        };
+        assert_ne!(*uses_left, 0);
+        *uses_left -= 1;
+        debug!("Using {modifier:?} modifier, {uses_left} uses left now");
+        if *uses_left == 0 {
+            debug!("{modifier:?} is now removed from the pipe");
+            self.modifiers.remove(&modifier);
+            self.applied_by.remove(&modifier);
+        }
+        true
+    }
+
+    /// Gives back a use taken by [`Self::use_modifier`], along with the application
+    /// it had if the modifier was removed in the meantime
+    pub fn restore_modifier(
+        &mut self,
+        modifier: Modifier,
+        application: Option<ModifierApplication<U>>,
+    ) {
+        *self.modifiers.entry(modifier).or_default() += 1;
+        if let Some(application) = application {
+            self.applied_by.entry(modifier).or_insert(application);
+        }
+    }
+
+    /// Converts users in the public part of the state, insurance is not carried over
+    pub fn map_user<V>(self, f: impl Fn(U) -> V) -> Pipe<V> {
+        Pipe {
+            value: self.value,
+            base_delay: self.base_delay,
+            direction: self.direction,
+            modifiers: self.modifiers,
+            applied_by: self
+                .applied_by
+                .into_iter()
+                .map(|(modifier, application)| {
+                    let application = ModifierApplication {
+                        user: f(application.user),
+                        time: application.time,
+                    };
+                    (modifier, application)
+                })
+                .collect(),
+            insurance: HashMap::new(),
+            locked_until: self.locked_until,
+            owner: self.owner.map(|owner| PipeOwner {
+                user: f(owner.user),
+                until: owner.until,
+            }),
+        }
+    }
+}
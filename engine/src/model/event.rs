@@ -0,0 +1,512 @@
+//! Entries of the game log, everything the game reports to players and spectators
+
+use super::*;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum LogMessage<U = UserToken> {
+    CollectStart {
+        user: U,
+        pipe_id: PipeId,
+        #[serde(with = "serde_duration")]
+        delay: Duration,
+    },
+    UpdatePipe {
+        id: PipeId,
+        #[serde(flatten)]
+        state: Pipe<U>,
+    },
+    CollectEnd {
+        user: U,
+        pipe_id: PipeId,
+    },
+    /// The pipe was retired, actions on it fail from now on
+    RemovePipe {
+        id: PipeId,
+    },
+    UpdateUser {
+        user: U,
+        #[serde(flatten)]
+        state: User,
+        /// What changed the score, missing for the initial state and in older logs
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        change: Option<ScoreChange>,
+    },
+    InsurancePayout {
+        user: U,
+        pipe_id: PipeId,
+        #[serde(with = "serde_score")]
+        refund: Score,
+    },
+    MarketEvent {
+        modifier: Modifier,
+        cost_multiplier: f64,
+        #[serde(with = "serde_score")]
+        cost: Score,
+        #[serde(with = "serde_duration")]
+        duration: Duration,
+    },
+    /// Cost of the modifier after a market event ended
+    MarketEventEnd {
+        modifier: Modifier,
+        #[serde(with = "serde_score")]
+        cost: Score,
+    },
+    /// Modifier prices changed because of demand
+    UpdatePrices {
+        prices: BTreeMap<Modifier, ModifierPrice>,
+    },
+    GameEnding {
+        seconds_left: f64,
+    },
+    Bankrupt {
+        user: U,
+        #[serde(with = "serde_score")]
+        score: Score,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        locked_until: Option<f64>,
+    },
+    ActionFailed {
+        user: U,
+        action: Action,
+        pipe_id: PipeId,
+        reason: ErrorCode,
+    },
+    ActionAborted {
+        user: U,
+        action: Action,
+        pipe_id: PipeId,
+        reason: AbortReason,
+    },
+    PhaseStarted {
+        #[serde(flatten)]
+        phase: Phase,
+    },
+    GlobalEvent {
+        #[serde(flatten)]
+        event: GlobalEvent,
+    },
+    Overtime {
+        /// Score shared by the leaders
+        #[serde(with = "serde_score")]
+        tied_score: Score,
+        seconds_left: f64,
+    },
+    UserIdle {
+        user: U,
+        /// Time since the last request, or since the start if there was none
+        idle_secs: f64,
+    },
+    ValueObserved {
+        user: U,
+        pipe_id: PipeId,
+        #[serde(flatten)]
+        observation: Observation,
+    },
+    UserRemoved {
+        user: U,
+    },
+    /// Collect rolled back because the client went away, modifiers used by it are given back
+    CollectAborted {
+        user: U,
+        pipe_id: PipeId,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        restored_modifiers: Vec<Modifier>,
+    },
+    /// Token handed out by `/api/register`
+    UserRegistered {
+        user: U,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    GamePaused,
+    GameResumed {
+        paused_secs: f64,
+    },
+    /// Pipes are randomized again and scores reset right after, rounds are numbered from 1
+    RoundStart {
+        round: usize,
+        duration_secs: f64,
+    },
+    RoundEnd {
+        round: usize,
+    },
+    /// The game clock starts, players are the users known at this point
+    GameStart {
+        players: usize,
+    },
+    /// Nothing happens in the game after this, only the podium and the final standings follow
+    GameEnd,
+    /// Final place of the user, announced from the last place of the podium to the first
+    Podium {
+        rank: usize,
+        user: U,
+        #[serde(with = "serde_score")]
+        score: Score,
+    },
+    /// Final standings from the first place, the last message of the game.
+    /// Log subscribers are disconnected after it.
+    GameOver {
+        results: Vec<Standing<U>>,
+    },
+    /// Position of the user in the leaderboard changed, ties share a rank
+    RankChanged {
+        user: U,
+        old_rank: usize,
+        new_rank: usize,
+    },
+    /// Part of the victim's collect was paid to the user through the steal modifier
+    ScoreStolen {
+        user: U,
+        victim: U,
+        pipe_id: PipeId,
+        #[serde(with = "serde_score")]
+        amount: Score,
+    },
+}
+
+/// Final place of a user, ties share a rank
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Standing<U = UserToken> {
+    pub rank: usize,
+    pub user: U,
+    #[serde(with = "serde_score")]
+    pub score: Score,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ScoreChange {
+    /// Difference to the previous score, after score limits were applied
+    #[serde(with = "serde_score")]
+    pub delta: Score,
+    #[serde(flatten)]
+    pub reason: ScoreReason,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum ScoreReason {
+    /// Value of the pipe with insurance refunds and handicaps
+    Collect {
+        pipe_id: PipeId,
+    },
+    Modifier {
+        pipe_id: PipeId,
+        modifier: Modifier,
+    },
+    Peek {
+        pipe_id: PipeId,
+    },
+    PeekRefund {
+        pipe_id: PipeId,
+    },
+    /// Part of someone else's collect through the steal modifier
+    Steal {
+        pipe_id: PipeId,
+    },
+    /// Scores are reset to the initial ones for every round
+    RoundStart {
+        round: usize,
+    },
+    /// Penalty of the misconduct policy
+    Misconduct,
+}
+
+/// What a user learned about the value of a pipe
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(tag = "via", rename_all = "snake_case")]
+pub enum Observation {
+    PipeValue {
+        #[serde(with = "serde_score")]
+        value: Score,
+    },
+    Peek {
+        bucket: ValueBucket,
+    },
+}
+
+impl<U> LogMessage<U> {
+    /// Events shown to everyone in the game status
+    #[cfg(feature = "runtime")]
+    pub(super) fn is_big_event(&self) -> bool {
+        matches!(
+            self,
+            LogMessage::InsurancePayout { .. }
+                | LogMessage::ScoreStolen { .. }
+                | LogMessage::MarketEvent { .. }
+                | LogMessage::MarketEventEnd { .. }
+                | LogMessage::GameEnding { .. }
+                | LogMessage::Bankrupt { .. }
+                | LogMessage::PhaseStarted { .. }
+                | LogMessage::GlobalEvent { .. }
+                | LogMessage::Overtime { .. }
+                | LogMessage::GamePaused
+                | LogMessage::GameResumed { .. }
+                | LogMessage::RoundStart { .. }
+                | LogMessage::RoundEnd { .. }
+                | LogMessage::GameStart { .. }
+                | LogMessage::GameEnd
+                | LogMessage::Podium { .. }
+                | LogMessage::GameOver { .. }
+        )
+    }
+
+    /// User this message is about
+    pub fn user(&self) -> Option<&U> {
+        match self {
+            LogMessage::CollectStart { user, .. }
+            | LogMessage::CollectEnd { user, .. }
+            | LogMessage::UpdateUser { user, .. }
+            | LogMessage::InsurancePayout { user, .. }
+            | LogMessage::ScoreStolen { user, .. }
+            | LogMessage::RankChanged { user, .. }
+            | LogMessage::Bankrupt { user, .. }
+            | LogMessage::ActionFailed { user, .. }
+            | LogMessage::ActionAborted { user, .. }
+            | LogMessage::CollectAborted { user, .. }
+            | LogMessage::UserIdle { user, .. }
+            | LogMessage::ValueObserved { user, .. }
+            | LogMessage::Podium { user, .. }
+            | LogMessage::UserRegistered { user, .. }
+            | LogMessage::UserRemoved { user } => Some(user),
+            LogMessage::UpdatePipe { .. }
+            | LogMessage::RemovePipe { .. }
+            | LogMessage::MarketEvent { .. }
+            | LogMessage::MarketEventEnd { .. }
+            | LogMessage::UpdatePrices { .. }
+            | LogMessage::GameEnding { .. }
+            | LogMessage::PhaseStarted { .. }
+            | LogMessage::GlobalEvent { .. }
+            | LogMessage::Overtime { .. }
+            | LogMessage::GamePaused
+            | LogMessage::GameResumed { .. }
+            | LogMessage::RoundStart { .. }
+            | LogMessage::RoundEnd { .. }
+            | LogMessage::GameStart { .. }
+            | LogMessage::GameEnd
+            | LogMessage::GameOver { .. } => None,
+        }
+    }
+
+    /// Pipe this message is about
+    pub fn pipe_id(&self) -> Option<PipeId> {
+        match *self {
+            LogMessage::CollectStart { pipe_id, .. }
+            | LogMessage::CollectEnd { pipe_id, .. }
+            | LogMessage::InsurancePayout { pipe_id, .. }
+            | LogMessage::ScoreStolen { pipe_id, .. }
+            | LogMessage::ActionFailed { pipe_id, .. }
+            | LogMessage::ActionAborted { pipe_id, .. }
+            | LogMessage::CollectAborted { pipe_id, .. }
+            | LogMessage::ValueObserved { pipe_id, .. } => Some(pipe_id),
+            LogMessage::UpdatePipe { id, .. } | LogMessage::RemovePipe { id } => Some(id),
+            LogMessage::UpdateUser { .. }
+            | LogMessage::MarketEvent { .. }
+            | LogMessage::MarketEventEnd { .. }
+            | LogMessage::UpdatePrices { .. }
+            | LogMessage::GameEnding { .. }
+            | LogMessage::Bankrupt { .. }
+            | LogMessage::PhaseStarted { .. }
+            | LogMessage::GlobalEvent { .. }
+            | LogMessage::Overtime { .. }
+            | LogMessage::UserIdle { .. }
+            | LogMessage::UserRemoved { .. }
+            | LogMessage::UserRegistered { .. }
+            | LogMessage::RankChanged { .. }
+            | LogMessage::GamePaused
+            | LogMessage::GameResumed { .. }
+            | LogMessage::RoundStart { .. }
+            | LogMessage::RoundEnd { .. }
+            | LogMessage::GameStart { .. }
+            | LogMessage::GameEnd
+            | LogMessage::Podium { .. }
+            | LogMessage::GameOver { .. } => None,
+        }
+    }
+
+    pub fn map_user<V>(self, f: impl Fn(U) -> V) -> LogMessage<V> {
+        match self {
+            LogMessage::CollectStart {
+                user,
+                pipe_id,
+                delay,
+            } => LogMessage::CollectStart {
+                user: f(user),
+                pipe_id,
+                delay,
+            },
+            LogMessage::UpdatePipe { id, state } => LogMessage::UpdatePipe {
+                id,
+                state: state.map_user(f),
+            },
+            LogMessage::CollectEnd { user, pipe_id } => LogMessage::CollectEnd {
+                user: f(user),
+                pipe_id,
+            },
+            LogMessage::RemovePipe { id } => LogMessage::RemovePipe { id },
+            LogMessage::UpdateUser {
+                user,
+                state,
+                change,
+            } => LogMessage::UpdateUser {
+                user: f(user),
+                state,
+                change,
+            },
+            LogMessage::InsurancePayout {
+                user,
+                pipe_id,
+                refund,
+            } => LogMessage::InsurancePayout {
+                user: f(user),
+                pipe_id,
+                refund,
+            },
+            LogMessage::MarketEvent {
+                modifier,
+                cost_multiplier,
+                cost,
+                duration,
+            } => LogMessage::MarketEvent {
+                modifier,
+                cost_multiplier,
+                cost,
+                duration,
+            },
+            LogMessage::MarketEventEnd { modifier, cost } => {
+                LogMessage::MarketEventEnd { modifier, cost }
+            }
+            LogMessage::UpdatePrices { prices } => LogMessage::UpdatePrices { prices },
+            LogMessage::GameEnding { seconds_left } => LogMessage::GameEnding { seconds_left },
+            LogMessage::Bankrupt {
+                user,
+                score,
+                locked_until,
+            } => LogMessage::Bankrupt {
+                user: f(user),
+                score,
+                locked_until,
+            },
+            LogMessage::ActionFailed {
+                user,
+                action,
+                pipe_id,
+                reason,
+            } => LogMessage::ActionFailed {
+                user: f(user),
+                action,
+                pipe_id,
+                reason,
+            },
+            LogMessage::ActionAborted {
+                user,
+                action,
+                pipe_id,
+                reason,
+            } => LogMessage::ActionAborted {
+                user: f(user),
+                action,
+                pipe_id,
+                reason,
+            },
+            LogMessage::CollectAborted {
+                user,
+                pipe_id,
+                restored_modifiers,
+            } => LogMessage::CollectAborted {
+                user: f(user),
+                pipe_id,
+                restored_modifiers,
+            },
+            LogMessage::PhaseStarted { phase } => LogMessage::PhaseStarted { phase },
+            LogMessage::GlobalEvent { event } => LogMessage::GlobalEvent { event },
+            LogMessage::Overtime {
+                tied_score,
+                seconds_left,
+            } => LogMessage::Overtime {
+                tied_score,
+                seconds_left,
+            },
+            LogMessage::UserIdle { user, idle_secs } => LogMessage::UserIdle {
+                user: f(user),
+                idle_secs,
+            },
+            LogMessage::ValueObserved {
+                user,
+                pipe_id,
+                observation,
+            } => LogMessage::ValueObserved {
+                user: f(user),
+                pipe_id,
+                observation,
+            },
+            LogMessage::UserRemoved { user } => LogMessage::UserRemoved { user: f(user) },
+            LogMessage::UserRegistered { user, name } => LogMessage::UserRegistered {
+                user: f(user),
+                name,
+            },
+            LogMessage::GamePaused => LogMessage::GamePaused,
+            LogMessage::GameResumed { paused_secs } => LogMessage::GameResumed { paused_secs },
+            LogMessage::RoundStart {
+                round,
+                duration_secs,
+            } => LogMessage::RoundStart {
+                round,
+                duration_secs,
+            },
+            LogMessage::RoundEnd { round } => LogMessage::RoundEnd { round },
+            LogMessage::GameStart { players } => LogMessage::GameStart { players },
+            LogMessage::GameEnd => LogMessage::GameEnd,
+            LogMessage::Podium { rank, user, score } => LogMessage::Podium {
+                rank,
+                user: f(user),
+                score,
+            },
+            LogMessage::GameOver { results } => LogMessage::GameOver {
+                results: results
+                    .into_iter()
+                    .map(|standing| Standing {
+                        rank: standing.rank,
+                        user: f(standing.user),
+                        score: standing.score,
+                    })
+                    .collect(),
+            },
+            LogMessage::RankChanged {
+                user,
+                old_rank,
+                new_rank,
+            } => LogMessage::RankChanged {
+                user: f(user),
+                old_rank,
+                new_rank,
+            },
+            LogMessage::ScoreStolen {
+                user,
+                victim,
+                pipe_id,
+                amount,
+            } => LogMessage::ScoreStolen {
+                user: f(user),
+                victim: f(victim),
+                pipe_id,
+                amount,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogEntry<U = UserToken> {
+    pub time: f64,
+    pub msg: LogMessage<U>,
+}
+
+impl<U> LogEntry<U> {
+    pub fn map_user<V>(self, f: impl Fn(U) -> V) -> LogEntry<V> {
+        LogEntry {
+            time: self.time,
+            msg: self.msg.map_user(f),
+        }
+    }
+}
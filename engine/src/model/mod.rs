@@ -0,0 +1,820 @@
+use crate::{serde_duration, serde_score};
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    num::NonZeroUsize,
+    str::FromStr,
+    time::Duration,
+};
+use tracing::debug;
+
+#[cfg(feature = "runtime")]
+mod app;
+mod board;
+mod event;
+mod rules;
+
+#[cfg(feature = "runtime")]
+pub use app::*;
+pub use board::*;
+pub use event::*;
+pub use rules::*;
+
+pub type Score = i64;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UserToken(String);
+
+impl From<String> for UserToken {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl std::borrow::Borrow<String> for UserToken {
+    fn borrow(&self) -> &String {
+        &self.0
+    }
+}
+
+impl UserToken {
+    /// Short stable digest (FNV-1a) of the token, to tell users apart in traces
+    /// without writing the tokens themselves out
+    pub fn digest(&self) -> String {
+        let hash = self.0.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+        format!("{:08x}", hash >> 32)
+    }
+}
+
+/// Only the digest, so that tokens don't end up in logs and traces
+impl Debug for UserToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "token#{}", self.digest())
+    }
+}
+
+impl FromStr for UserToken {
+    type Err = <String as FromStr>::Err;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Self(String::from_str(s)?))
+    }
+}
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct User {
+    #[cfg_attr(feature = "openapi", schema(value_type = i64))]
+    #[serde(with = "serde_score")]
+    pub score: Score,
+    /// Successful collects in a row, missing when there are no streaks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub streak: Option<usize>,
+    /// Display name shown to spectators instead of the token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Any CSS color, for viewers to draw the user with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// Built-in bot filling an empty slot, not a real player
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ghost: bool,
+    /// Game time of the last successful collect
+    #[serde(skip)]
+    pub last_collect_secs: Option<f64>,
+    #[serde(skip)]
+    pub stats: PlayerStats,
+}
+
+/// What the user did during the game, reported with the results
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub collects: u64,
+    /// Seconds spent waiting for the delays of actions
+    pub time_blocked: f64,
+    pub modifiers_bought: u64,
+    /// Sum of all score gains
+    #[serde(with = "serde_score")]
+    pub earned: Score,
+    /// Sum of all score losses, modifier costs included
+    #[serde(with = "serde_score")]
+    pub spent: Score,
+    /// Failed actions
+    pub errors: u64,
+    /// Game time of the last successful action
+    pub last_success_secs: Option<f64>,
+    /// Actions failed in a row since the last successful one
+    pub error_streak: u64,
+    /// Failed actions counted by the misconduct policy
+    #[serde(default)]
+    pub misconduct: u64,
+    /// Penalties given by the misconduct policy
+    #[serde(default)]
+    pub misconduct_penalties: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Collect,
+    PipeValue,
+    ApplyModifier,
+    Peek,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct InFlightAction {
+    #[serde(rename = "type")]
+    pub action: Action,
+    pub pipe_id: PipeId,
+    pub started_at: f64,
+    pub expected_completion: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AbortReason {
+    /// Cancelled by the user
+    Cancelled,
+    /// Aborted by the operator
+    Admin,
+    /// The client went away before getting the response
+    Disconnected,
+}
+
+pub type Results = BTreeMap<String, Score>;
+
+/// Participation of a user, ordered from the most active
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum UserStatus {
+    Active,
+    TimedOut,
+    NeverConnected,
+}
+
+/// Stable name of an [`Error`], sent to clients as its `code`.
+/// Statuses of errors are configured by it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub enum ErrorCode {
+    UserNotFound,
+    TooManyAuthFailures,
+    UserBusy,
+    PipeNotFound,
+    PipeLocked,
+    NotEnoughScore,
+    ModifierAlreadyApplied,
+    Bankrupt,
+    PipeNotAssigned,
+    ServerOverloaded,
+    ActionAborted,
+    GamePaused,
+    PipeShielded,
+    ModifierDisabled,
+    RateLimited,
+    Cooldown,
+    GameOver,
+    SpectatorOnly,
+    OperationNotFound,
+    IdempotencyKeyReused,
+    MisconductLockout,
+    TokenBoundToOtherIp,
+}
+
+/// Serialized as its [`ErrorCode`], the context is in [`Error::details`]
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Error {
+    #[error("User not found")]
+    UserNotFound,
+    #[error("Too many failed authentication attempts")]
+    TooManyAuthFailures {
+        /// Until the oldest failure counted leaves the window
+        retry_after: Duration,
+    },
+    #[error("User is already processing another request")]
+    UserBusy {
+        /// Until the first action in flight is expected to end
+        retry_after: Option<Duration>,
+    },
+    #[error("Pipe not found")]
+    PipeNotFound {
+        /// Not set for ids that are not valid at all
+        pipe_id: Option<PipeId>,
+    },
+    #[error("Pipe {pipe_id} was collected recently and is locked")]
+    PipeLocked {
+        pipe_id: PipeId,
+        retry_after: Duration,
+    },
+    #[error("Not enough score, {required} needed but only {score} left")]
+    NotEnoughScore { required: Score, score: Score },
+    #[error("Modifier {modifier:?} is already applied to pipe {pipe_id}")]
+    ModifierAlreadyApplied { pipe_id: PipeId, modifier: Modifier },
+    #[error("User went bankrupt and is locked out")]
+    Bankrupt { retry_after: Duration },
+    #[error("Pipe {pipe_id} is currently assigned to someone else, yours is {assigned_pipe_id}")]
+    PipeNotAssigned {
+        pipe_id: PipeId,
+        assigned_pipe_id: PipeId,
+    },
+    #[error("Server is overloaded, slow down")]
+    ServerOverloaded,
+    #[error("Action was aborted")]
+    ActionAborted,
+    #[error("Game is paused")]
+    GamePaused,
+    #[error("Pipe {pipe_id} is shielded by another user")]
+    PipeShielded { pipe_id: PipeId },
+    #[error("Modifier {modifier:?} is disabled in this game")]
+    ModifierDisabled { modifier: Modifier },
+    #[error("Too many requests, slow down")]
+    RateLimited {
+        /// Until the oldest request counted leaves the window
+        retry_after: Option<Duration>,
+    },
+    #[error("This action is cooling down, try again later")]
+    Cooldown { retry_after: Duration },
+    #[error("The game is over")]
+    GameOver,
+    #[error("Spectators can only watch the game")]
+    SpectatorOnly,
+    #[error("Operation not found")]
+    OperationNotFound,
+    #[error("Idempotency key was already used for a different request")]
+    IdempotencyKeyReused,
+    #[error("Locked out for making too many invalid requests")]
+    MisconductLockout { retry_after: Duration },
+    #[error("Token is in use from another ip")]
+    TokenBoundToOtherIp,
+}
+
+/// Context of an error, the fields that do not apply to it are left out
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ErrorDetails {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<usize>))]
+    pub pipe_id: Option<PipeId>,
+    /// Pipe the user has to collect instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<usize>))]
+    pub assigned_pipe_id: Option<PipeId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modifier: Option<Modifier>,
+    /// Cost of the action
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serde_score::serialize_option"
+    )]
+    pub required_score: Option<Score>,
+    /// Score of the user
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serde_score::serialize_option"
+    )]
+    pub score: Option<Score>,
+    /// How long until the request may succeed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<f64>,
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::UserNotFound => ErrorCode::UserNotFound,
+            Error::TooManyAuthFailures { .. } => ErrorCode::TooManyAuthFailures,
+            Error::UserBusy { .. } => ErrorCode::UserBusy,
+            Error::PipeNotFound { .. } => ErrorCode::PipeNotFound,
+            Error::PipeLocked { .. } => ErrorCode::PipeLocked,
+            Error::NotEnoughScore { .. } => ErrorCode::NotEnoughScore,
+            Error::ModifierAlreadyApplied { .. } => ErrorCode::ModifierAlreadyApplied,
+            Error::Bankrupt { .. } => ErrorCode::Bankrupt,
+            Error::PipeNotAssigned { .. } => ErrorCode::PipeNotAssigned,
+            Error::ServerOverloaded => ErrorCode::ServerOverloaded,
+            Error::ActionAborted => ErrorCode::ActionAborted,
+            Error::GamePaused => ErrorCode::GamePaused,
+            Error::PipeShielded { .. } => ErrorCode::PipeShielded,
+            Error::ModifierDisabled { .. } => ErrorCode::ModifierDisabled,
+            Error::RateLimited { .. } => ErrorCode::RateLimited,
+            Error::Cooldown { .. } => ErrorCode::Cooldown,
+            Error::GameOver => ErrorCode::GameOver,
+            Error::SpectatorOnly => ErrorCode::SpectatorOnly,
+            Error::OperationNotFound => ErrorCode::OperationNotFound,
+            Error::IdempotencyKeyReused => ErrorCode::IdempotencyKeyReused,
+            Error::MisconductLockout { .. } => ErrorCode::MisconductLockout,
+            Error::TokenBoundToOtherIp => ErrorCode::TokenBoundToOtherIp,
+        }
+    }
+
+    /// How long until the request may succeed, when the server knows
+    pub fn retry_after(&self) -> Option<Duration> {
+        match *self {
+            Error::UserBusy { retry_after } | Error::RateLimited { retry_after } => retry_after,
+            Error::PipeLocked { retry_after, .. }
+            | Error::Bankrupt { retry_after }
+            | Error::Cooldown { retry_after }
+            | Error::TooManyAuthFailures { retry_after }
+            | Error::MisconductLockout { retry_after } => Some(retry_after),
+            _ => None,
+        }
+    }
+
+    pub fn details(&self) -> ErrorDetails {
+        let mut details = ErrorDetails {
+            retry_after_secs: self.retry_after().map(|wait| wait.as_secs_f64()),
+            ..Default::default()
+        };
+        match *self {
+            Error::PipeNotFound { pipe_id } => details.pipe_id = pipe_id,
+            Error::PipeLocked { pipe_id, .. } | Error::PipeShielded { pipe_id } => {
+                details.pipe_id = Some(pipe_id)
+            }
+            Error::NotEnoughScore { required, score } => {
+                details.required_score = Some(required);
+                details.score = Some(score);
+            }
+            Error::ModifierAlreadyApplied { pipe_id, modifier } => {
+                details.pipe_id = Some(pipe_id);
+                details.modifier = Some(modifier);
+            }
+            Error::PipeNotAssigned {
+                pipe_id,
+                assigned_pipe_id,
+            } => {
+                details.pipe_id = Some(pipe_id);
+                details.assigned_pipe_id = Some(assigned_pipe_id);
+            }
+            Error::ModifierDisabled { modifier } => details.modifier = Some(modifier),
+            _ => {}
+        }
+        details
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        self.code().serialize(serializer)
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AbortActionsResponse {
+    pub aborted: usize,
+}
+
+/// Everything needed to continue a game after the server restarts
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Game time when the checkpoint was taken
+    pub time: f64,
+    pub seed: u64,
+    /// State of the random generator, restored games reseed from `seed` without it
+    #[serde(default)]
+    pub rng: Option<rand_chacha::ChaCha12Rng>,
+    pub overtime_secs: f64,
+    pub paused_secs: f64,
+    /// Wall clock end of the game in seconds since the unix epoch,
+    /// so that the time a crashed server was down counts against the deadline
+    #[serde(default)]
+    pub ends_at_unix_secs: Option<f64>,
+    pub users: Vec<CheckpointUser>,
+    pub pipes: BTreeMap<PipeId, CheckpointPipe>,
+    pub history: Vec<LogEntry>,
+    #[serde(default)]
+    pub round: RoundState,
+}
+
+/// Progress through the configured rounds
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoundState {
+    /// Zero based, rounds are numbered from 1 in the log
+    pub index: usize,
+    /// Game time when the current round started
+    pub started_at: f64,
+    /// Total pause time when the current round started, pauses extend the round
+    pub paused_secs_at_start: f64,
+    /// Set once the current round is over, stays set after the last round
+    pub ended: bool,
+    /// Final scores of the finished rounds
+    pub scores: Vec<Results>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CheckpointUser {
+    pub token: UserToken,
+    pub index: usize,
+    #[serde(flatten)]
+    pub state: User,
+    pub profile: UserProfile,
+    pub bankrupt_until: Option<f64>,
+    /// Not part of the user state in the log
+    #[serde(default)]
+    pub stats: PlayerStats,
+    #[serde(default)]
+    pub last_collect_secs: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CheckpointPipe {
+    #[serde(flatten)]
+    pub state: Pipe,
+    /// Not part of the pipe state in the log
+    pub insurance: HashMap<UserToken, usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct StatusResponse {
+    /// Requests in flight relative to the limit, requests are rejected at 1
+    pub load: f64,
+    /// Whether requests were rejected because of load just now
+    pub shedding: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum GameProgress {
+    /// Waiting for the start, requests wait for it as well
+    Starting,
+    Running,
+    Paused,
+    /// Time is up, actions fail with `GameOver`
+    Ended,
+}
+
+/// Clock of the game for bots that plan around the end
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct GameResponse {
+    pub state: GameProgress,
+    #[serde(flatten)]
+    pub time: TimeResponse,
+}
+
+/// Answer to health probes, readable without a token
+#[derive(Serialize, Deserialize)]
+pub struct HealthResponse {
+    pub state: GameProgress,
+    /// Seconds since the start of the game, negative before it
+    pub time: f64,
+    pub time_left: Option<f64>,
+    pub players: usize,
+    pub pipes: usize,
+    /// Whether the server should get traffic, it is not once the game ended or under overload
+    pub ready: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct TimeResponse {
+    /// Seconds since the start of the game
+    pub time: f64,
+    pub time_left: Option<f64>,
+    pub phase: Option<Phase>,
+    /// Start of the following phase
+    pub next_phase_secs: Option<f64>,
+    /// New actions are rejected while the game is paused
+    #[serde(default)]
+    pub paused: bool,
+    /// Number of the current round from 1, if the game has rounds
+    #[serde(default)]
+    pub round: Option<usize>,
+    #[serde(default)]
+    pub round_time_left: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PipeValueResponse {
+    #[cfg_attr(feature = "openapi", schema(value_type = i64))]
+    #[serde(with = "serde_score")]
+    pub value: Score,
+    /// Set while the pipe is owned by someone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<OwnerInfo>,
+}
+
+/// Ownership of a pipe as seen by a player, other players are not named
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct OwnerInfo {
+    /// Whether the user asking owns the pipe
+    pub yours: bool,
+    /// Game time when the ownership ends
+    pub until: f64,
+}
+
+#[cfg(feature = "runtime")]
+impl Pipe {
+    fn owner_info(&self, user_token: &UserToken, now: f64) -> Option<OwnerInfo> {
+        self.owner
+            .as_ref()
+            .filter(|owner| now < owner.until)
+            .map(|owner| OwnerInfo {
+                yours: owner.user == *user_token,
+                until: owner.until,
+            })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PipeValue {
+    pub pipe_id: PipeId,
+    #[cfg_attr(feature = "openapi", schema(value_type = i64))]
+    #[serde(with = "serde_score")]
+    pub value: Score,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PipeValuesResponse {
+    /// In the order the pipes were asked for
+    pub values: Vec<PipeValue>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ValueBucket {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PeekResponse {
+    pub value: ValueBucket,
+    /// Number of modifiers active on the pipe
+    pub modifiers: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct CollectResponse {
+    /// What the collect paid, not counting the insurance refund
+    #[cfg_attr(feature = "openapi", schema(value_type = i64))]
+    #[serde(with = "serde_score")]
+    pub value: Score,
+    /// Paid on top of the value when insurance covered a bad payout.
+    /// It is not affected by handicaps or streaks
+    #[cfg_attr(feature = "openapi", schema(value_type = i64))]
+    #[serde(default, with = "serde_score")]
+    pub refund: Score,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApplyModifierResponse {}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AssignedPipeResponse {
+    /// Pipe the user may collect, any pipe if not set
+    pub pipe_id: Option<PipeId>,
+    /// Game time of the next rotation
+    pub until: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PipeModifier {
+    #[serde(rename = "type")]
+    pub modifier: Modifier,
+    /// Only told to the user who applied the modifier
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uses_left: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PipeModifiersResponse {
+    pub modifiers: Vec<PipeModifier>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PipeSummary {
+    pub id: PipeId,
+    pub modifiers: Vec<PipeModifier>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PipesResponse {
+    pub pipes: Vec<PipeSummary>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct UserActionsResponse {
+    pub actions: Vec<InFlightAction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Operation {
+    /// Counted per user from 1
+    pub id: u64,
+    pub action: Action,
+    #[cfg_attr(feature = "openapi", schema(value_type = usize))]
+    pub pipe_id: PipeId,
+    /// Game time of the start
+    pub started_at: f64,
+    #[serde(flatten)]
+    pub status: OperationStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum OperationStatus {
+    Pending,
+    Done {
+        response: CollectResponse,
+    },
+    Failed {
+        #[cfg_attr(feature = "openapi", schema(value_type = ErrorCode))]
+        error: Error,
+    },
+}
+
+impl OperationStatus {
+    /// Outcome of a finished operation
+    pub fn result(&self) -> Option<Result<CollectResponse>> {
+        match self {
+            OperationStatus::Pending => None,
+            OperationStatus::Done { response } => Some(Ok(response.clone())),
+            OperationStatus::Failed { error } => Some(Err(*error)),
+        }
+    }
+}
+
+/// Whether an operation has to be run or another request already started it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationStart {
+    New(u64),
+    /// Started before with the same idempotency key
+    Existing(u64),
+}
+
+/// Details of a user, only the name, color and ghost flag are shown to spectators
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UserProfile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// Played by the server itself, see `ghost_players`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ghost: bool,
+}
+
+impl User {
+    /// Copies the public part of the profile, to be logged with the next update
+    fn show_profile(&mut self, profile: &UserProfile) {
+        self.name = profile.name.clone();
+        self.color = profile.color.clone();
+        self.ghost = profile.ghost;
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserRecord {
+    pub token: UserToken,
+    #[serde(flatten)]
+    pub profile: UserProfile,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PipeUpdate {
+    #[serde(default)]
+    pub value: Option<Score>,
+    #[serde(default)]
+    pub direction: Option<PipeDirection>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct RegisterRequest {
+    /// Shown instead of the token in results and viewers
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Any CSS color, for viewers to draw the user with
+    #[serde(default)]
+    pub color: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct RegisterResponse {
+    /// Secret token to authorize the game requests with
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
+    pub token: UserToken,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ImportUsersResponse {
+    pub added: usize,
+    pub updated: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportedUser {
+    #[serde(flatten)]
+    pub user: UserRecord,
+    #[serde(with = "serde_score")]
+    pub score: Score,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OverlayPlayer {
+    /// Digest of the token, the token itself is a secret
+    pub user: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(with = "serde_score")]
+    pub score: Score,
+}
+
+/// Line of the full standings, users with equal scores share a rank
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScoreboardEntry {
+    pub rank: usize,
+    #[serde(flatten)]
+    pub player: OverlayPlayer,
+}
+
+/// Small summary of the game for broadcast graphics
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OverlayResponse {
+    pub top: Vec<OverlayPlayer>,
+    pub time_left: Option<f64>,
+    /// Users are digests of their tokens, like in [`OverlayPlayer`]
+    pub last_event: Option<LogEntry<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ModifierPrice {
+    /// What the modifier costs right now, with every multiplier applied
+    #[cfg_attr(feature = "openapi", schema(value_type = i64))]
+    #[serde(with = "serde_score")]
+    pub cost: Score,
+    /// Part of the cost because of recent purchases, 1 when there were none
+    pub demand_multiplier: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PricesResponse {
+    /// Whether prices depend on demand, otherwise the multipliers are all 1
+    pub dynamic: bool,
+    pub prices: BTreeMap<Modifier, ModifierPrice>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ShopItem {
+    #[cfg_attr(feature = "openapi", schema(value_type = i64))]
+    #[serde(with = "serde_score")]
+    pub cost: Score,
+    #[cfg_attr(feature = "openapi", schema(value_type = i64))]
+    #[serde(with = "serde_score")]
+    pub base_cost: Score,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ShopResponse {
+    pub modifiers: BTreeMap<Modifier, ShopItem>,
+    pub events: Vec<MarketEvent>,
+    #[cfg_attr(feature = "openapi", schema(value_type = i64))]
+    #[serde(with = "serde_score")]
+    pub peek_cost: Score,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct GlobalEventsResponse {
+    pub events: Vec<GlobalEvent>,
+}
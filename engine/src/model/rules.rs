@@ -0,0 +1,1266 @@
+//! Game configuration: costs, delays and the optional rules of a game
+
+use super::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub reverse_cost: Score,
+    pub double_cost: Score,
+    pub double_uses: usize,
+    pub slow_cost: Score,
+    pub slow_uses: usize,
+    pub shuffle_cost: Score,
+    pub min_cost: Score,
+    pub min_uses: usize,
+    /// What a collect yields when both Double and Min are on the pipe
+    #[serde(default)]
+    pub double_min_policy: DoubleMinPolicy,
+    #[serde(default = "default_insurance_cost")]
+    pub insurance_cost: Score,
+    /// How many of the owner's collects are covered
+    #[serde(default = "default_insurance_uses")]
+    pub insurance_uses: usize,
+    /// Payouts below this value are considered bad and get refunded
+    #[serde(default = "default_insurance_threshold")]
+    pub insurance_threshold: Score,
+    /// Part of the loss (pipe value minus payout) that is refunded
+    #[serde(default = "default_insurance_refund_ratio")]
+    pub insurance_refund_ratio: f64,
+    #[serde(default = "default_shield_cost")]
+    pub shield_cost: Score,
+    /// How many collects of the pipe the shield lasts
+    #[serde(default = "default_shield_uses")]
+    pub shield_uses: usize,
+    /// The shield also ends after this long
+    #[serde(default)]
+    pub shield_secs: Option<f64>,
+    #[serde(default = "default_steal_cost")]
+    pub steal_cost: Score,
+    /// Part of the collected value that goes to the owner of the steal modifier
+    #[serde(default = "default_steal_ratio")]
+    pub steal_ratio: f64,
+    /// Collecting a pipe makes the user its owner for a while, others collecting it then
+    /// get only a share of the value. Pipes have no owners if not set.
+    #[serde(default)]
+    pub ownership: Option<Ownership>,
+    /// Modifiers that can be bought in this game, the others are rejected and left out of the shop
+    #[serde(default = "default_enabled_modifiers")]
+    pub enabled_modifiers: Vec<Modifier>,
+    /// Modifiers sold in addition to the enabled built-in ones
+    #[serde(default)]
+    pub custom_modifiers: Vec<CustomModifier>,
+    /// Pipes at the start of the game, pipe events may add and retire pipes later
+    pub pipe_count: usize,
+    /// How often pipes may appear or be retired, pipes stay the same if not set
+    #[serde(default)]
+    pub pipe_events_secs: Option<f64>,
+    /// Chance of a new pipe appearing at every pipe event
+    #[serde(default)]
+    pub pipe_spawn_probability: f64,
+    /// Chance of a random pipe being retired at every pipe event
+    #[serde(default)]
+    pub pipe_retire_probability: f64,
+    /// Pipes are only retired while there are more of them
+    #[serde(default = "default_min_pipe_count")]
+    pub min_pipe_count: usize,
+    /// No pipes appear while there are this many
+    #[serde(default)]
+    pub max_pipe_count: Option<usize>,
+    pub min_value: Score,
+    pub max_value: Score,
+    pub min_delay_secs: f64,
+    pub max_delay_secs: f64,
+    pub pipe_value_delay_secs: f64,
+    /// How long asking for the values of several pipes at once takes
+    #[serde(default)]
+    pub pipe_values_delay: BatchDelay,
+    /// How often pipe values drift on their own, they only change on collects if not set
+    #[serde(default)]
+    pub value_drift_interval_secs: Option<f64>,
+    /// Change of every pipe value per drift, positive values regenerate pipes towards
+    /// `max_value` and negative ones decay them towards `min_value`
+    #[serde(default)]
+    pub value_drift_amount: Score,
+    pub time_to_run: Option<f64>,
+    /// Requests received during this time after launch are held and released together
+    /// when the game starts, so that connection setup does not give anyone a head start
+    #[serde(default)]
+    pub start_delay_secs: f64,
+    /// The game clock does not start before this many users made a request
+    /// or an admin starts the game, `start_delay_secs` then counts from that moment
+    #[serde(default)]
+    pub wait_for_players: Option<usize>,
+    /// How many actions a single user may have in flight at once, unlimited if null
+    #[serde(default = "default_max_concurrent_actions")]
+    pub max_concurrent_actions: Option<usize>,
+    /// Additional per action type limits, unlisted actions are only limited by the total
+    #[serde(default)]
+    pub max_concurrent_actions_by_type: HashMap<Action, usize>,
+    /// Time a user has to wait between starting two actions of the type, unlisted actions
+    /// can be started as soon as the concurrency limits allow
+    #[serde(default)]
+    pub action_cooldowns_secs: HashMap<Action, f64>,
+    /// Nobody can start collecting a pipe for this long after it was collected
+    #[serde(default)]
+    pub post_collect_lockout_secs: f64,
+    /// Collects of clients that go away mid-way still award the score,
+    /// instead of being rolled back
+    #[serde(default)]
+    pub commit_abandoned_collects: bool,
+    /// Turn-based mode: actions submitted during a tick of this length resolve together at
+    /// its end, one after another in an order drawn from the seed, so that being faster
+    /// within a tick gains nothing. Delays are rounded up to whole ticks. Actions resolve
+    /// as soon as their delay is over if not set
+    #[serde(default)]
+    pub tick_secs: Option<f64>,
+    /// How long before the end of the game to announce it
+    #[serde(default = "default_game_ending_notice_secs")]
+    pub game_ending_notice_secs: f64,
+    /// Announce the top players one by one in the log once the game ends
+    #[serde(default)]
+    pub podium_ceremony: bool,
+    /// Places announced in the podium ceremony
+    #[serde(default = "default_podium_size")]
+    pub podium_size: usize,
+    /// Real time between the podium announcements
+    #[serde(default = "default_podium_delay_secs")]
+    pub podium_delay_secs: f64,
+    /// Real time the server keeps answering `GameOver` after the end
+    /// instead of closing connections right away
+    #[serde(default = "default_game_over_grace_secs")]
+    pub game_over_grace_secs: f64,
+    /// Whether anyone may play when no users are specified
+    #[serde(default = "default_allow_unknown_users")]
+    pub allow_unknown_users: bool,
+    /// Whether unknown tokens become users on their first request when anyone may play,
+    /// turn off to only let in tokens handed out by `/api/register`
+    #[serde(default = "default_implicit_users")]
+    pub implicit_users: bool,
+    /// Fills the game up to this many players with built-in bots playing the simulator
+    /// strategies, flagged as ghosts in the log and the results
+    #[serde(default)]
+    pub ghost_players: Option<usize>,
+    /// Tokens that may look at the pipe values without the delay, but not play
+    #[serde(default)]
+    pub spectator_tokens: Vec<UserToken>,
+    /// Failed authentication attempts allowed from a single ip per minute
+    #[serde(default = "default_max_auth_failures_per_minute")]
+    pub max_auth_failures_per_minute: usize,
+    /// Game api requests allowed from a single ip per minute, for servers open to the public
+    #[serde(default)]
+    pub max_requests_per_ip_per_minute: Option<usize>,
+    /// Connections open at the same time from a single ip, further ones are turned away
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+    /// Tokens only work from the ip they were first used from, against shared tokens.
+    /// The operator can release a token with `DELETE /api/admin/user/{token}/ip`.
+    #[serde(default)]
+    pub bind_tokens_to_ip: bool,
+    /// Game api requests allowed with a single token per second,
+    /// so that a misbehaving bot can not starve the others
+    #[serde(default)]
+    pub max_requests_per_token_per_second: Option<usize>,
+    /// Maximum number of log entries kept in memory, older ones are moved to disk
+    #[serde(default)]
+    pub history_memory_limit: Option<usize>,
+    /// Maximum number of log entries kept at all, older ones are only in the saved log
+    #[serde(default)]
+    pub history_limit: Option<usize>,
+    /// Log entries buffered for a network subscriber before `log_subscriber_overflow` applies
+    #[serde(default = "default_log_subscriber_buffer")]
+    pub log_subscriber_buffer: usize,
+    /// What happens to network log subscribers that fall behind, unless they ask otherwise
+    #[serde(default)]
+    pub log_subscriber_overflow: LogOverflow,
+    /// Scheduled discounts and surges of modifier costs
+    #[serde(default)]
+    pub market_events: Vec<MarketEvent>,
+    /// Modifier costs rising with every purchase, fixed if not set
+    #[serde(default)]
+    pub dynamic_pricing: Option<DynamicPricing>,
+    /// Effects hitting every pipe at random times, drawn from the seed
+    #[serde(default)]
+    pub global_events: Option<GlobalEvents>,
+    /// Urls notified when the user's score crosses one of the milestones
+    #[serde(default)]
+    pub webhooks: HashMap<UserToken, String>,
+    #[serde(default)]
+    pub score_milestones: Vec<Score>,
+    /// Secret for signing webhook payloads
+    #[serde(default)]
+    pub webhook_secret: Option<String>,
+    /// Users making no requests for this long during the game are reported as idle
+    #[serde(default)]
+    pub idle_notice_secs: Option<f64>,
+    /// Url notified when a user becomes idle
+    #[serde(default)]
+    pub idle_webhook: Option<String>,
+    /// Lowest score a user can have, reaching it makes the user bankrupt
+    #[serde(default)]
+    pub min_user_score: Option<Score>,
+    #[serde(default)]
+    pub max_user_score: Option<Score>,
+    /// What happens to scores beyond the limits
+    #[serde(default)]
+    pub score_limit_mode: ScoreLimitMode,
+    /// Bankrupt users can not start new actions for this long
+    #[serde(default)]
+    pub bankruptcy_lockout_secs: f64,
+    /// Every user may only collect the pipe assigned to them,
+    /// assignments move to the next pipe this often
+    #[serde(default)]
+    pub round_robin_secs: Option<f64>,
+    /// Multipliers of the users' collects, for closer races between players of different skill
+    #[serde(default)]
+    pub handicaps: HashMap<UserToken, f64>,
+    /// Growth of the collect multiplier with every successful collect in a row,
+    /// there are no streaks if not set
+    #[serde(default)]
+    pub streak_step: Option<f64>,
+    /// Highest multiplier a streak can reach
+    #[serde(default = "default_max_streak_multiplier")]
+    pub max_streak_multiplier: f64,
+    /// Streaks end when the user collects nothing for this long
+    #[serde(default)]
+    pub streak_timeout_secs: Option<f64>,
+    /// Score every user starts with
+    #[serde(default)]
+    pub initial_score: Score,
+    /// Starting scores of specific users, overriding `initial_score`
+    #[serde(default)]
+    pub initial_scores: HashMap<UserToken, Score>,
+    /// Display names and colors of specific users
+    #[serde(default)]
+    pub user_profiles: HashMap<UserToken, UserProfile>,
+    #[serde(default = "default_peek_cost")]
+    pub peek_cost: Score,
+    #[serde(default = "default_peek_delay_secs")]
+    pub peek_delay_secs: f64,
+    /// Lowest pipe value peeked as medium
+    #[serde(default = "default_peek_medium_from")]
+    pub peek_medium_from: Score,
+    /// Lowest pipe value peeked as high
+    #[serde(default = "default_peek_high_from")]
+    pub peek_high_from: Score,
+    /// Log failed actions, useful for analysis but makes logs much longer
+    #[serde(default)]
+    pub log_failed_actions: bool,
+    /// Log every pipe value learned by users, to see how information affects the results
+    #[serde(default)]
+    pub log_observations: bool,
+    /// Users silent for this long at the end of the game are reported as timed out
+    #[serde(default = "default_inactivity_timeout_secs")]
+    pub inactivity_timeout_secs: f64,
+    /// Parts of the game with different rules, each lasting until the next one starts
+    #[serde(default)]
+    pub phases: Vec<Phase>,
+    /// If the top scores are tied at the end, the game is extended by this much
+    /// until the tie breaks
+    #[serde(default)]
+    pub overtime_secs: Option<f64>,
+    /// Limit of the total overtime
+    #[serde(default)]
+    pub max_overtime_secs: f64,
+    /// Splits the game into rounds with freshly randomized pipes and reset scores.
+    /// The game then lasts for all the rounds instead of `time_to_run` and has no overtime
+    #[serde(default)]
+    pub rounds: Option<Rounds>,
+    /// Penalties for tokens that keep making invalid requests, off if not set
+    #[serde(default)]
+    pub misconduct_policy: Option<MisconductPolicy>,
+    /// Faults injected into the api, for testing bots against an imperfect network
+    #[serde(default)]
+    pub chaos: Option<Chaos>,
+    /// Game api requests handled at once, more are rejected until the load goes down
+    #[serde(default = "default_max_in_flight_requests")]
+    pub max_in_flight_requests: usize,
+    /// How scores are written in api responses and logs,
+    /// clients can override it for their requests with the `Accept` header
+    #[serde(default)]
+    pub score_format: serde_score::Format,
+    /// Origins of externally hosted pages allowed to call the api and subscribe to logs,
+    /// `*` allows any origin
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+    /// Seed of the pipe layout and all other random choices, random if not set
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Http statuses of game errors that differ from the defaults
+    #[serde(default)]
+    pub error_statuses: HashMap<ErrorCode, u16>,
+    /// Multiplier of all game timings, e.g. 0.1 plays a game ten times faster
+    /// with the same relative timings
+    #[serde(default = "default_multiplier")]
+    pub time_scale: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Phase {
+    pub name: String,
+    pub start_secs: f64,
+    /// Multiplier of all modifier costs
+    #[serde(default = "default_multiplier")]
+    pub cost_multiplier: f64,
+    /// Multiplier of collected values
+    #[serde(default = "default_multiplier")]
+    pub value_multiplier: f64,
+}
+
+fn default_podium_size() -> usize {
+    3
+}
+
+fn default_log_subscriber_buffer() -> usize {
+    1024
+}
+
+fn default_podium_delay_secs() -> f64 {
+    1.0
+}
+
+fn default_game_over_grace_secs() -> f64 {
+    5.0
+}
+
+fn default_max_streak_multiplier() -> f64 {
+    2.0
+}
+
+fn default_multiplier() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchDelay {
+    /// `pipe_value_delay_secs` for every pipe in the batch
+    #[default]
+    PerPipe,
+    /// `pipe_value_delay_secs` once for the whole batch
+    Once,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogOverflow {
+    /// Skip entries until the subscriber catches up
+    Drop,
+    /// Close the stream, the subscriber can reconnect and get the history again
+    #[default]
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DoubleMinPolicy {
+    /// Both are used up and the collect yields the minimum value
+    #[default]
+    MinWins,
+    /// The collect yields the minimum value and Double stays for the next collect
+    KeepDouble,
+    /// Both are used up and the collect yields twice the minimum value
+    DoubleMin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rounds {
+    pub count: usize,
+    pub duration_secs: f64,
+    /// How the final result is made up from the scores at the end of every round
+    #[serde(default)]
+    pub aggregation: RoundAggregation,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundAggregation {
+    #[default]
+    Sum,
+    Best,
+}
+
+impl RoundAggregation {
+    /// Users missing from some rounds are judged by the rounds they played
+    pub fn aggregate(self, rounds: &[Results]) -> Results {
+        let mut total = Results::new();
+        for scores in rounds {
+            for (user, &score) in scores {
+                total
+                    .entry(user.clone())
+                    .and_modify(|total| {
+                        *total = match self {
+                            Self::Sum => *total + score,
+                            Self::Best => (*total).max(score),
+                        }
+                    })
+                    .or_insert(score);
+            }
+        }
+        total
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreLimitMode {
+    /// Scores stop at the limits
+    #[default]
+    Clamp,
+    /// Scores continue from the opposite limit, like pipe values do.
+    /// Only used when both limits are set
+    Wrap,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct MarketEvent {
+    pub modifier: Modifier,
+    pub cost_multiplier: f64,
+    pub start_secs: f64,
+    pub duration_secs: f64,
+}
+
+impl MarketEvent {
+    pub fn end_secs(&self) -> f64 {
+        self.start_secs + self.duration_secs
+    }
+    pub fn is_active(&self, time: f64) -> bool {
+        self.start_secs <= time && time < self.end_secs()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ownership {
+    /// How long a collect keeps the pipe owned, collecting it again extends the ownership
+    pub secs: f64,
+    /// Part of a positive value that users other than the owner get
+    #[serde(default = "default_ownership_share")]
+    pub share: f64,
+}
+
+fn default_ownership_share() -> f64 {
+    0.5
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DynamicPricing {
+    /// Every purchase multiplies the price of the modifier by this
+    #[serde(default = "default_purchase_multiplier")]
+    pub purchase_multiplier: f64,
+    /// Time for half of the surcharge to wear off
+    #[serde(default = "default_price_half_life_secs")]
+    pub half_life_secs: f64,
+    /// Prices never rise above the base ones times this
+    #[serde(default)]
+    pub max_multiplier: Option<f64>,
+}
+
+fn default_purchase_multiplier() -> f64 {
+    1.1
+}
+
+fn default_price_half_life_secs() -> f64 {
+    60.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MisconductPolicy {
+    /// Failed actions that count as misconduct
+    #[serde(default = "default_misconduct_errors")]
+    pub errors: Vec<ErrorCode>,
+    /// Counted errors within `window_secs` that bring a penalty, the count then starts over
+    pub threshold: usize,
+    #[serde(default = "default_misconduct_window_secs")]
+    pub window_secs: f64,
+    /// Score taken for every penalty
+    #[serde(default, with = "serde_score")]
+    pub penalty: Score,
+    /// How long the user may not start actions after a penalty
+    #[serde(default)]
+    pub lockout_secs: f64,
+}
+
+fn default_misconduct_errors() -> Vec<ErrorCode> {
+    vec![
+        ErrorCode::PipeNotFound,
+        ErrorCode::NotEnoughScore,
+        ErrorCode::UserBusy,
+    ]
+}
+
+fn default_misconduct_window_secs() -> f64 {
+    10.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chaos {
+    /// Chance of a game api response being delayed
+    #[serde(default)]
+    pub delay_probability: f64,
+    /// Delays are drawn evenly up to this, in real time
+    #[serde(default = "default_chaos_max_delay_secs")]
+    pub max_delay_secs: f64,
+    /// Chance of a game api request failing without reaching the game
+    #[serde(default)]
+    pub error_probability: f64,
+    #[serde(default = "default_chaos_error_status")]
+    pub error_status: u16,
+    /// Chance of a message to a `/logs` websocket subscriber being left out
+    #[serde(default)]
+    pub drop_log_probability: f64,
+    /// Seed of the injected faults, the game seed if not set
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+fn default_chaos_max_delay_secs() -> f64 {
+    1.0
+}
+
+fn default_chaos_error_status() -> u16 {
+    503
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GlobalEvents {
+    /// Average time between events, the actual gaps are random
+    pub mean_interval_secs: f64,
+    pub effects: Vec<GlobalEffect>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct GlobalEffect {
+    pub name: String,
+    pub duration_secs: f64,
+    /// Multiplier of collected values while the effect lasts
+    #[serde(default = "default_multiplier")]
+    pub value_multiplier: f64,
+    /// Multiplier of collect delays while the effect lasts
+    #[serde(default = "default_multiplier")]
+    pub delay_multiplier: f64,
+    /// How likely this effect is to be picked compared to the others
+    #[serde(default = "default_effect_weight")]
+    pub weight: f64,
+}
+
+fn default_effect_weight() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct GlobalEvent {
+    #[serde(flatten)]
+    pub effect: GlobalEffect,
+    pub start_secs: f64,
+}
+
+impl GlobalEvent {
+    pub fn end_secs(&self) -> f64 {
+        self.start_secs + self.effect.duration_secs
+    }
+    pub fn is_active(&self, time: f64) -> bool {
+        self.start_secs <= time && time < self.end_secs()
+    }
+}
+
+/// Config field with a value the game can't run with
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{field}: {message}")]
+pub struct ConfigError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigUpdateError {
+    #[error("Can't change {} while the game is running", .0.join(", "))]
+    Immutable(Vec<String>),
+    #[error("Invalid config: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+    Invalid(Vec<ConfigError>),
+}
+
+impl Config {
+    /// Finds values that would make the game fail while running, also for configs
+    /// that did not come from a file checked against the schema
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let mut check = |valid: bool, field: &'static str, message: String| {
+            if !valid {
+                errors.push(ConfigError { field, message });
+            }
+        };
+        check(
+            self.pipe_count > 0,
+            "pipe_count",
+            "there has to be at least one pipe".to_owned(),
+        );
+        if let Some(max) = self.max_pipe_count {
+            check(
+                max >= self.pipe_count.max(self.min_pipe_count),
+                "max_pipe_count",
+                format!("{max} is below pipe_count or min_pipe_count"),
+            );
+        }
+        check(
+            self.min_value <= self.max_value,
+            "min_value",
+            format!("{} is above max_value {}", self.min_value, self.max_value),
+        );
+        for (field, cost) in [
+            ("reverse_cost", self.reverse_cost),
+            ("double_cost", self.double_cost),
+            ("slow_cost", self.slow_cost),
+            ("shuffle_cost", self.shuffle_cost),
+            ("min_cost", self.min_cost),
+            ("insurance_cost", self.insurance_cost),
+            ("shield_cost", self.shield_cost),
+            ("steal_cost", self.steal_cost),
+            ("peek_cost", self.peek_cost),
+        ] {
+            check(
+                cost >= 0,
+                field,
+                format!("costs can't be negative, got {cost}"),
+            );
+        }
+        for (field, secs) in [
+            ("min_delay_secs", self.min_delay_secs),
+            ("max_delay_secs", self.max_delay_secs),
+            ("pipe_value_delay_secs", self.pipe_value_delay_secs),
+            ("peek_delay_secs", self.peek_delay_secs),
+            ("start_delay_secs", self.start_delay_secs),
+            ("post_collect_lockout_secs", self.post_collect_lockout_secs),
+            ("game_ending_notice_secs", self.game_ending_notice_secs),
+            ("bankruptcy_lockout_secs", self.bankruptcy_lockout_secs),
+            ("max_overtime_secs", self.max_overtime_secs),
+            ("podium_delay_secs", self.podium_delay_secs),
+            ("game_over_grace_secs", self.game_over_grace_secs),
+            ("inactivity_timeout_secs", self.inactivity_timeout_secs),
+        ]
+        .into_iter()
+        .chain(
+            self.action_cooldowns_secs
+                .values()
+                .map(|&secs| ("action_cooldowns_secs", secs)),
+        )
+        .chain(self.phases.iter().map(|phase| ("phases", phase.start_secs)))
+        .chain(self.market_events.iter().flat_map(|event| {
+            [
+                ("market_events", event.start_secs),
+                ("market_events", event.duration_secs),
+            ]
+        }))
+        .chain(self.global_events.iter().flat_map(|events| {
+            events
+                .effects
+                .iter()
+                .map(|effect| ("global_events", effect.duration_secs))
+        }))
+        .chain(
+            self.misconduct_policy
+                .iter()
+                .map(|policy| ("misconduct_policy", policy.lockout_secs)),
+        ) {
+            check(
+                secs.is_finite() && secs >= 0.0,
+                field,
+                format!("has to be a duration of 0 or more seconds, got {secs}"),
+            );
+        }
+        check(
+            self.min_delay_secs <= self.max_delay_secs,
+            "min_delay_secs",
+            format!(
+                "{} is above max_delay_secs {}",
+                self.min_delay_secs, self.max_delay_secs
+            ),
+        );
+        if let Some(secs) = self.time_to_run {
+            check(
+                secs.is_finite() && secs >= 0.0,
+                "time_to_run",
+                format!("has to be a duration of 0 or more seconds, got {secs}"),
+            );
+        }
+        // Timers that fire again and again, zero would make them spin
+        for (field, secs) in [
+            ("pipe_events_secs", self.pipe_events_secs),
+            ("value_drift_interval_secs", self.value_drift_interval_secs),
+            ("tick_secs", self.tick_secs),
+            ("round_robin_secs", self.round_robin_secs),
+            ("shield_secs", self.shield_secs),
+            ("streak_timeout_secs", self.streak_timeout_secs),
+            ("overtime_secs", self.overtime_secs),
+            ("idle_notice_secs", self.idle_notice_secs),
+            (
+                "global_events",
+                self.global_events
+                    .as_ref()
+                    .map(|events| events.mean_interval_secs),
+            ),
+            (
+                "misconduct_policy",
+                self.misconduct_policy
+                    .as_ref()
+                    .map(|policy| policy.window_secs),
+            ),
+        ] {
+            if let Some(secs) = secs {
+                check(
+                    secs.is_finite() && secs > 0.0,
+                    field,
+                    format!("has to be a positive duration if set, got {secs}"),
+                );
+            }
+        }
+        check(
+            self.time_scale.is_finite() && self.time_scale > 0.0,
+            "time_scale",
+            format!("has to be positive, got {}", self.time_scale),
+        );
+        for (code, &status) in &self.error_statuses {
+            check(
+                (400..600).contains(&status),
+                "error_statuses",
+                format!("{code:?} has to map to an error status, got {status}"),
+            );
+        }
+        for (field, ratio) in [
+            ("pipe_spawn_probability", self.pipe_spawn_probability),
+            ("pipe_retire_probability", self.pipe_retire_probability),
+            ("steal_ratio", self.steal_ratio),
+            ("insurance_refund_ratio", self.insurance_refund_ratio),
+        ] {
+            check(
+                (0.0..=1.0).contains(&ratio),
+                field,
+                format!("has to be between 0 and 1, got {ratio}"),
+            );
+        }
+        // Negative costs would pay the buyer, negative or zero delays break the collects
+        for (field, multiplier) in self
+            .phases
+            .iter()
+            .flat_map(|phase| {
+                [
+                    ("phases", phase.cost_multiplier),
+                    ("phases", phase.value_multiplier),
+                ]
+            })
+            .chain(
+                self.market_events
+                    .iter()
+                    .map(|event| ("market_events", event.cost_multiplier)),
+            )
+            .chain(self.global_events.iter().flat_map(|events| {
+                events.effects.iter().flat_map(|effect| {
+                    [
+                        ("global_events", effect.value_multiplier),
+                        ("global_events", effect.weight),
+                    ]
+                })
+            }))
+            .chain(
+                self.handicaps
+                    .values()
+                    .map(|&multiplier| ("handicaps", multiplier)),
+            )
+        {
+            check(
+                multiplier.is_finite() && multiplier >= 0.0,
+                field,
+                format!("has to be 0 or more, got {multiplier}"),
+            );
+        }
+        for effect in self.global_events.iter().flat_map(|events| &events.effects) {
+            check(
+                effect.delay_multiplier.is_finite() && effect.delay_multiplier > 0.0,
+                "global_events",
+                format!(
+                    "delay_multiplier of {} has to be positive, got {}",
+                    effect.name, effect.delay_multiplier
+                ),
+            );
+        }
+        if let (Some(min), Some(max)) = (self.min_user_score, self.max_user_score) {
+            check(
+                min <= max,
+                "min_user_score",
+                format!("{min} is above max_user_score {max}"),
+            );
+        }
+        for (i, custom) in self.custom_modifiers.iter().enumerate() {
+            let name = custom.name;
+            check(
+                !Modifier::ALL.iter().any(|builtin| {
+                    serde_json::to_value(builtin).ok() == Some(name.as_str().into())
+                }),
+                "custom_modifiers",
+                format!("{name} is the name of a built-in modifier"),
+            );
+            check(
+                !self.custom_modifiers[..i]
+                    .iter()
+                    .any(|other| other.name == name),
+                "custom_modifiers",
+                format!("{name} is defined more than once"),
+            );
+            check(
+                custom.cost >= 0,
+                "custom_modifiers",
+                format!("cost of {name} can't be negative, got {}", custom.cost),
+            );
+            check(
+                custom.uses > 0 || !custom.effect.lasts(),
+                "custom_modifiers",
+                format!("{name} has to last for at least one collect"),
+            );
+            if let ModifierEffect::DelayMultiplier { multiplier }
+            | ModifierEffect::ValueMultiplier { multiplier } = custom.effect
+            {
+                check(
+                    multiplier.is_finite() && multiplier > 0.0,
+                    "custom_modifiers",
+                    format!("multiplier of {name} has to be positive, got {multiplier}"),
+                );
+            }
+        }
+        // Webhooks are called with the plain http client of the server
+        for (field, url) in self
+            .webhooks
+            .values()
+            .map(|url| ("webhooks", url))
+            .chain(self.idle_webhook.iter().map(|url| ("idle_webhook", url)))
+        {
+            check(
+                url.starts_with("http://"),
+                field,
+                format!("only http:// urls are supported, got {url}"),
+            );
+        }
+        if let Some(ownership) = &self.ownership {
+            check(
+                ownership.secs > 0.0,
+                "ownership",
+                format!("secs has to be positive, got {}", ownership.secs),
+            );
+            check(
+                (0.0..=1.0).contains(&ownership.share),
+                "ownership",
+                format!("share has to be between 0 and 1, got {}", ownership.share),
+            );
+        }
+        if let Some(pricing) = &self.dynamic_pricing {
+            check(
+                pricing.purchase_multiplier >= 1.0,
+                "dynamic_pricing",
+                format!(
+                    "purchase_multiplier can't lower prices, got {}",
+                    pricing.purchase_multiplier
+                ),
+            );
+            check(
+                pricing.half_life_secs > 0.0,
+                "dynamic_pricing",
+                format!(
+                    "half_life_secs has to be positive, got {}",
+                    pricing.half_life_secs
+                ),
+            );
+            if let Some(max) = pricing.max_multiplier {
+                check(
+                    max >= 1.0,
+                    "dynamic_pricing",
+                    format!("max_multiplier can't be below 1, got {max}"),
+                );
+            }
+        }
+        if let Some(chaos) = &self.chaos {
+            for (field, probability) in [
+                ("chaos", chaos.delay_probability),
+                ("chaos", chaos.error_probability),
+                ("chaos", chaos.drop_log_probability),
+            ] {
+                check(
+                    (0.0..=1.0).contains(&probability),
+                    field,
+                    format!("probabilities have to be between 0 and 1, got {probability}"),
+                );
+            }
+            check(
+                chaos.max_delay_secs.is_finite() && chaos.max_delay_secs >= 0.0,
+                "chaos",
+                format!(
+                    "max_delay_secs has to be 0 or more, got {}",
+                    chaos.max_delay_secs
+                ),
+            );
+            check(
+                (500..600).contains(&chaos.error_status),
+                "chaos",
+                format!(
+                    "error_status has to be a server error, got {}",
+                    chaos.error_status
+                ),
+            );
+        }
+        if let Some(rounds) = &self.rounds {
+            check(
+                rounds.count > 0,
+                "rounds",
+                "there has to be at least one round".to_owned(),
+            );
+            check(
+                rounds.duration_secs.is_finite() && rounds.duration_secs > 0.0,
+                "rounds",
+                format!(
+                    "rounds have to last a positive duration, got {}",
+                    rounds.duration_secs
+                ),
+            );
+        }
+        errors
+    }
+
+    /// This config with the values of `other` for the fields a running game reads whenever it
+    /// needs them. The rest is only read at the start and keeps its values.
+    #[cfg(feature = "runtime")]
+    pub(super) fn with_mutable_fields(&self, other: Config) -> Config {
+        Config {
+            reverse_cost: other.reverse_cost,
+            double_cost: other.double_cost,
+            double_uses: other.double_uses,
+            slow_cost: other.slow_cost,
+            slow_uses: other.slow_uses,
+            shuffle_cost: other.shuffle_cost,
+            min_cost: other.min_cost,
+            min_uses: other.min_uses,
+            double_min_policy: other.double_min_policy,
+            insurance_cost: other.insurance_cost,
+            insurance_uses: other.insurance_uses,
+            insurance_threshold: other.insurance_threshold,
+            insurance_refund_ratio: other.insurance_refund_ratio,
+            shield_cost: other.shield_cost,
+            shield_uses: other.shield_uses,
+            shield_secs: other.shield_secs,
+            steal_cost: other.steal_cost,
+            steal_ratio: other.steal_ratio,
+            ownership: other.ownership,
+            enabled_modifiers: other.enabled_modifiers,
+            pipe_spawn_probability: other.pipe_spawn_probability,
+            pipe_retire_probability: other.pipe_retire_probability,
+            min_pipe_count: other.min_pipe_count,
+            max_pipe_count: other.max_pipe_count,
+            min_value: other.min_value,
+            max_value: other.max_value,
+            min_delay_secs: other.min_delay_secs,
+            max_delay_secs: other.max_delay_secs,
+            pipe_value_delay_secs: other.pipe_value_delay_secs,
+            pipe_values_delay: other.pipe_values_delay,
+            value_drift_amount: other.value_drift_amount,
+            max_concurrent_actions: other.max_concurrent_actions,
+            max_concurrent_actions_by_type: other.max_concurrent_actions_by_type,
+            action_cooldowns_secs: other.action_cooldowns_secs,
+            post_collect_lockout_secs: other.post_collect_lockout_secs,
+            commit_abandoned_collects: other.commit_abandoned_collects,
+            game_ending_notice_secs: other.game_ending_notice_secs,
+            podium_ceremony: other.podium_ceremony,
+            podium_size: other.podium_size,
+            podium_delay_secs: other.podium_delay_secs,
+            game_over_grace_secs: other.game_over_grace_secs,
+            spectator_tokens: other.spectator_tokens,
+            max_auth_failures_per_minute: other.max_auth_failures_per_minute,
+            max_requests_per_ip_per_minute: other.max_requests_per_ip_per_minute,
+            max_connections_per_ip: other.max_connections_per_ip,
+            bind_tokens_to_ip: other.bind_tokens_to_ip,
+            max_requests_per_token_per_second: other.max_requests_per_token_per_second,
+            log_subscriber_buffer: other.log_subscriber_buffer,
+            log_subscriber_overflow: other.log_subscriber_overflow,
+            dynamic_pricing: other.dynamic_pricing,
+            min_user_score: other.min_user_score,
+            max_user_score: other.max_user_score,
+            score_limit_mode: other.score_limit_mode,
+            bankruptcy_lockout_secs: other.bankruptcy_lockout_secs,
+            round_robin_secs: other.round_robin_secs,
+            handicaps: other.handicaps,
+            streak_step: other.streak_step,
+            max_streak_multiplier: other.max_streak_multiplier,
+            streak_timeout_secs: other.streak_timeout_secs,
+            initial_score: other.initial_score,
+            initial_scores: other.initial_scores,
+            user_profiles: other.user_profiles,
+            peek_cost: other.peek_cost,
+            peek_delay_secs: other.peek_delay_secs,
+            peek_medium_from: other.peek_medium_from,
+            peek_high_from: other.peek_high_from,
+            log_failed_actions: other.log_failed_actions,
+            log_observations: other.log_observations,
+            inactivity_timeout_secs: other.inactivity_timeout_secs,
+            overtime_secs: other.overtime_secs,
+            max_overtime_secs: other.max_overtime_secs,
+            misconduct_policy: other.misconduct_policy,
+            max_in_flight_requests: other.max_in_flight_requests,
+            score_format: other.score_format,
+            error_statuses: other.error_statuses,
+            ..self.clone()
+        }
+    }
+
+    /// Brings the config into the shape the game runs with
+    #[cfg(feature = "runtime")]
+    pub(super) fn prepare(&mut self) {
+        self.scale_time();
+        if let Some(rounds) = &self.rounds {
+            self.time_to_run = Some(rounds.count as f64 * rounds.duration_secs);
+        }
+    }
+
+    /// Applies `time_scale` to the game timings, the start delay and timeouts of
+    /// clients are real time and stay as they are
+    #[cfg(feature = "runtime")]
+    fn scale_time(&mut self) {
+        let scale = self.time_scale;
+        for secs in [
+            &mut self.min_delay_secs,
+            &mut self.max_delay_secs,
+            &mut self.pipe_value_delay_secs,
+            &mut self.peek_delay_secs,
+            &mut self.post_collect_lockout_secs,
+            &mut self.game_ending_notice_secs,
+            &mut self.bankruptcy_lockout_secs,
+            &mut self.max_overtime_secs,
+        ]
+        .into_iter()
+        .chain(self.action_cooldowns_secs.values_mut())
+        {
+            *secs *= scale;
+        }
+        for secs in [
+            &mut self.time_to_run,
+            &mut self.round_robin_secs,
+            &mut self.overtime_secs,
+            &mut self.shield_secs,
+            &mut self.pipe_events_secs,
+            &mut self.value_drift_interval_secs,
+            &mut self.streak_timeout_secs,
+            &mut self.tick_secs,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *secs *= scale;
+        }
+        for phase in &mut self.phases {
+            phase.start_secs *= scale;
+        }
+        if let Some(rounds) = &mut self.rounds {
+            rounds.duration_secs *= scale;
+        }
+        for event in &mut self.market_events {
+            event.start_secs *= scale;
+            event.duration_secs *= scale;
+        }
+        if let Some(pricing) = &mut self.dynamic_pricing {
+            pricing.half_life_secs *= scale;
+        }
+        if let Some(ownership) = &mut self.ownership {
+            ownership.secs *= scale;
+        }
+        if let Some(events) = &mut self.global_events {
+            events.mean_interval_secs *= scale;
+            for effect in &mut events.effects {
+                effect.duration_secs *= scale;
+            }
+        }
+        // Already applied, a prepared config is prepared again as it is
+        self.time_scale = 1.0;
+    }
+
+    pub fn initial_user(&self, token: &UserToken) -> User {
+        let mut user = User {
+            score: *self
+                .initial_scores
+                .get(token)
+                .unwrap_or(&self.initial_score),
+            streak: self.streak_step.map(|_| 0),
+            name: None,
+            color: None,
+            ghost: false,
+            last_collect_secs: None,
+            stats: PlayerStats::default(),
+        };
+        user.show_profile(&self.initial_profile(token));
+        user
+    }
+
+    pub fn initial_profile(&self, token: &UserToken) -> UserProfile {
+        self.user_profiles.get(token).cloned().unwrap_or_default()
+    }
+
+    /// Brings the score within `min_user_score` and `max_user_score`
+    pub fn limit_score(&self, score: Score) -> Score {
+        match (
+            self.score_limit_mode,
+            self.min_user_score,
+            self.max_user_score,
+        ) {
+            (ScoreLimitMode::Wrap, Some(min), Some(max)) if min <= max => {
+                min + (score - min).rem_euclid(max - min + 1)
+            }
+            (_, min, max) => {
+                let score = min.map_or(score, |min| score.max(min));
+                max.map_or(score, |max| score.min(max))
+            }
+        }
+    }
+}
+
+fn default_max_concurrent_actions() -> Option<usize> {
+    Some(1)
+}
+
+fn default_allow_unknown_users() -> bool {
+    true
+}
+
+fn default_implicit_users() -> bool {
+    true
+}
+
+fn default_max_auth_failures_per_minute() -> usize {
+    10
+}
+
+fn default_game_ending_notice_secs() -> f64 {
+    10.0
+}
+
+fn default_insurance_cost() -> Score {
+    20
+}
+
+fn default_insurance_uses() -> usize {
+    3
+}
+
+fn default_insurance_threshold() -> Score {
+    3
+}
+
+fn default_insurance_refund_ratio() -> f64 {
+    0.5
+}
+
+fn default_shield_cost() -> Score {
+    30
+}
+
+fn default_shield_uses() -> usize {
+    5
+}
+
+fn default_steal_cost() -> Score {
+    40
+}
+
+fn default_steal_ratio() -> f64 {
+    0.5
+}
+
+fn default_enabled_modifiers() -> Vec<Modifier> {
+    Modifier::ALL.to_vec()
+}
+
+fn default_min_pipe_count() -> usize {
+    1
+}
+
+fn default_max_in_flight_requests() -> usize {
+    1000
+}
+
+fn default_inactivity_timeout_secs() -> f64 {
+    30.0
+}
+
+fn default_peek_cost() -> Score {
+    2
+}
+
+fn default_peek_delay_secs() -> f64 {
+    0.3
+}
+
+fn default_peek_medium_from() -> Score {
+    4
+}
+
+fn default_peek_high_from() -> Score {
+    8
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        serde_json::from_str(include_str!("../../../config.json"))
+            .expect("Failed to parse default config")
+    }
+}
+
+impl Config {
+    pub fn modifier_cost(&self, modifier: Modifier) -> Score {
+        match modifier {
+            Modifier::Slow => self.slow_cost,
+            Modifier::Double => self.double_cost,
+            Modifier::Min => self.min_cost,
+            Modifier::Shuffle => self.shuffle_cost,
+            Modifier::Reverse => self.reverse_cost,
+            Modifier::Insurance => self.insurance_cost,
+            Modifier::Shield => self.shield_cost,
+            Modifier::Steal => self.steal_cost,
+            Modifier::Custom(name) => self.custom_modifier(name).map_or(0, |custom| custom.cost),
+        }
+    }
+
+    pub fn custom_modifier(&self, name: ModifierName) -> Option<&CustomModifier> {
+        self.custom_modifiers
+            .iter()
+            .find(|custom| custom.name == name)
+    }
+
+    /// Modifiers that can be bought, the enabled built-in ones and the custom ones
+    pub fn available_modifiers(&self) -> impl Iterator<Item = Modifier> + '_ {
+        let custom = self
+            .custom_modifiers
+            .iter()
+            .map(|custom| Modifier::Custom(custom.name));
+        self.enabled_modifiers.iter().copied().chain(custom)
+    }
+
+    pub fn is_available(&self, modifier: Modifier) -> bool {
+        match modifier {
+            Modifier::Custom(name) => self.custom_modifier(name).is_some(),
+            modifier => self.enabled_modifiers.contains(&modifier),
+        }
+    }
+    pub fn random_pipe_delay(&self, rng: &mut impl Rng) -> Duration {
+        Duration::from_secs_f64(rng.gen_range(self.min_delay_secs..=self.max_delay_secs))
+    }
+    pub fn random_pipe_value(&self, rng: &mut impl Rng) -> Score {
+        rng.gen_range(self.min_value..=self.max_value)
+    }
+    pub fn random_pipe(&self, rng: &mut impl Rng) -> Pipe {
+        Pipe {
+            value: self.random_pipe_value(rng),
+            base_delay: self.random_pipe_delay(rng),
+            direction: PipeDirection::random(rng),
+            modifiers: HashMap::new(),
+            applied_by: HashMap::new(),
+            insurance: HashMap::new(),
+            locked_until: None,
+            owner: None,
+        }
+    }
+}
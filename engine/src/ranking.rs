@@ -0,0 +1,146 @@
+//! Leaderboard positions kept up to date on every score change,
+//! so that overtakes can be logged without sorting all users each time
+
+use crate::model::Score;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound::{Excluded, Unbounded},
+};
+
+/// Rank is one more than the number of users with a higher score, so ties share a rank
+#[derive(Debug)]
+pub struct Ranking<U> {
+    scores: BTreeMap<U, Score>,
+    by_score: BTreeMap<Score, BTreeSet<U>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RankChange<U> {
+    pub user: U,
+    pub old_rank: usize,
+    pub new_rank: usize,
+}
+
+impl<U> Default for Ranking<U> {
+    fn default() -> Self {
+        Self {
+            scores: BTreeMap::new(),
+            by_score: BTreeMap::new(),
+        }
+    }
+}
+
+impl<U: Ord + Clone> Ranking<U> {
+    pub fn new(scores: impl IntoIterator<Item = (U, Score)>) -> Self {
+        let mut ranking = Self::default();
+        for (user, score) in scores {
+            ranking.update(&user, Some(score));
+        }
+        ranking
+    }
+
+    fn rank_of_score(&self, score: Score) -> usize {
+        1 + self
+            .by_score
+            .range((Excluded(score), Unbounded))
+            .map(|(_, users)| users.len())
+            .sum::<usize>()
+    }
+
+    pub fn rank(&self, user: &U) -> Option<usize> {
+        self.scores
+            .get(user)
+            .map(|&score| self.rank_of_score(score))
+    }
+
+    /// Sets the score of the user, `None` removes the user.
+    /// Returns the ranks that changed, users joining or leaving are not included themselves.
+    pub fn update(&mut self, user: &U, score: Option<Score>) -> Vec<RankChange<U>> {
+        let old = self.scores.get(user).copied();
+        if old == score {
+            return Vec::new();
+        }
+        // Only users between the old and the new score move, a missing score is below everyone
+        let low = old.min(score).unwrap_or(Score::MIN);
+        let high = old.max(score).expect("Old and new score differ");
+        let mut affected: Vec<(U, usize)> = self
+            .by_score
+            .range(low..high)
+            .flat_map(|(&score, users)| {
+                let rank = self.rank_of_score(score);
+                users.iter().map(move |other| (other.clone(), rank))
+            })
+            .filter(|(other, _)| other != user)
+            .collect();
+        if let Some(old) = old {
+            affected.push((user.clone(), self.rank_of_score(old)));
+            let users = self.by_score.get_mut(&old).expect("Scores are indexed");
+            users.remove(user);
+            if users.is_empty() {
+                self.by_score.remove(&old);
+            }
+            self.scores.remove(user);
+        }
+        if let Some(score) = score {
+            self.by_score.entry(score).or_default().insert(user.clone());
+            self.scores.insert(user.clone(), score);
+        }
+        affected
+            .into_iter()
+            .filter_map(|(user, old_rank)| {
+                let new_rank = self.rank(&user)?;
+                (new_rank != old_rank).then_some(RankChange {
+                    user,
+                    old_rank,
+                    new_rank,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overtake() {
+        let mut ranking = Ranking::new([("a", 30), ("b", 20), ("c", 10)]);
+        assert_eq!(ranking.rank(&"c"), Some(3));
+        let changes = ranking.update(&"c", Some(25));
+        assert_eq!(
+            changes,
+            [
+                RankChange {
+                    user: "b",
+                    old_rank: 2,
+                    new_rank: 3
+                },
+                RankChange {
+                    user: "c",
+                    old_rank: 3,
+                    new_rank: 2
+                },
+            ]
+        );
+        // Reaching a tie does not move the leader
+        let changes = ranking.update(&"c", Some(30));
+        assert_eq!(
+            changes,
+            [RankChange {
+                user: "c",
+                old_rank: 2,
+                new_rank: 1
+            }]
+        );
+        let changes = ranking.update(&"a", None);
+        assert_eq!(
+            changes,
+            [RankChange {
+                user: "b",
+                old_rank: 3,
+                new_rank: 2
+            }]
+        );
+    }
+}
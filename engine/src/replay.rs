@@ -0,0 +1,121 @@
+//! Reading saved game logs and reconstructing the game state from them
+
+use crate::model::{Action, LogEntry, LogMessage, Pipe, PipeId, Score};
+use anyhow::Context;
+use serde::Serialize;
+use std::{collections::BTreeMap, io::BufRead, path::Path};
+
+/// Bumped on incompatible changes of the log format,
+/// viewers declare the version they understand in their manifest
+pub const LOG_SCHEMA_VERSION: u32 = 1;
+
+/// Users are tokens in local logs and numeric ids in codehub logs,
+/// so both are read as strings
+pub type User = String;
+
+/// Parses a single log entry as saved to a log file or sent to the `/logs` stream
+pub fn parse_entry(json: &str) -> anyhow::Result<LogEntry<User>> {
+    let entry: LogEntry<serde_json::Value> = serde_json::from_str(json)?;
+    Ok(entry.map_user(|user| match user {
+        serde_json::Value::String(user) => user,
+        user => user.to_string(),
+    }))
+}
+
+pub fn read_log(path: impl AsRef<Path>) -> anyhow::Result<Vec<LogEntry<User>>> {
+    let file = std::fs::File::open(path).context("Failed to open log file")?;
+    let mut entries = Vec::new();
+    for (index, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = parse_entry(&line)
+            .with_context(|| format!("Failed to parse log line {}", index + 1))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Game state as seen by log consumers
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct State {
+    pub time: f64,
+    pub pipes: BTreeMap<PipeId, Pipe<User>>,
+    pub scores: BTreeMap<User, Score>,
+    /// Pipe currently being collected by the user
+    pub collecting: BTreeMap<User, PipeId>,
+    /// Display names of the users that have one
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub names: BTreeMap<User, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub colors: BTreeMap<User, String>,
+}
+
+impl State {
+    pub fn apply(&mut self, entry: &LogEntry<User>) {
+        self.time = entry.time;
+        match &entry.msg {
+            LogMessage::CollectStart { user, pipe_id, .. } => {
+                self.collecting.insert(user.clone(), *pipe_id);
+            }
+            LogMessage::CollectEnd { user, .. }
+            | LogMessage::CollectAborted { user, .. }
+            | LogMessage::ActionAborted {
+                user,
+                action: Action::Collect,
+                ..
+            } => {
+                self.collecting.remove(user);
+            }
+            LogMessage::UpdatePipe { id, state } => {
+                self.pipes.insert(*id, state.clone());
+            }
+            LogMessage::RemovePipe { id } => {
+                self.pipes.remove(id);
+            }
+            LogMessage::UpdateUser { user, state, .. } => {
+                self.scores.insert(user.clone(), state.score);
+                set_or_remove(&mut self.names, user, state.name.as_ref());
+                set_or_remove(&mut self.colors, user, state.color.as_ref());
+            }
+            LogMessage::UserRemoved { user } => {
+                self.scores.remove(user);
+                self.collecting.remove(user);
+                self.names.remove(user);
+                self.colors.remove(user);
+            }
+            LogMessage::InsurancePayout { .. }
+            | LogMessage::ScoreStolen { .. }
+            | LogMessage::RankChanged { .. }
+            | LogMessage::MarketEvent { .. }
+            | LogMessage::MarketEventEnd { .. }
+            | LogMessage::UpdatePrices { .. }
+            | LogMessage::GameEnding { .. }
+            | LogMessage::Bankrupt { .. }
+            | LogMessage::ActionFailed { .. }
+            | LogMessage::ActionAborted { .. }
+            | LogMessage::PhaseStarted { .. }
+            | LogMessage::GlobalEvent { .. }
+            | LogMessage::Overtime { .. }
+            | LogMessage::UserIdle { .. }
+            | LogMessage::ValueObserved { .. }
+            | LogMessage::GamePaused
+            | LogMessage::GameResumed { .. }
+            | LogMessage::RoundStart { .. }
+            | LogMessage::RoundEnd { .. }
+            | LogMessage::GameStart { .. }
+            | LogMessage::UserRegistered { .. }
+            | LogMessage::GameEnd
+            | LogMessage::GameOver { .. }
+            | LogMessage::Podium { .. } => {}
+        }
+    }
+}
+
+fn set_or_remove(map: &mut BTreeMap<User, String>, user: &User, value: Option<&String>) {
+    match value {
+        Some(value) => map.insert(user.clone(), value.clone()),
+        None => map.remove(user),
+    };
+}
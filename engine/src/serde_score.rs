@@ -0,0 +1,73 @@
+//! Serialization of [`Score`] fields either as json numbers or as strings.
+//!
+//! Some client languages parse every json number as a double and lose precision on large
+//! scores. The format is chosen per serialization with [`with_format`] or [`scope`],
+//! deserialization accepts both.
+
+use crate::model::Score;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{cell::Cell, future::Future};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Number,
+    String,
+}
+
+thread_local! {
+    static FORMAT: Cell<Format> = const { Cell::new(Format::Number) };
+}
+
+/// Runs `f` with scores serialized in the given format
+pub fn with_format<T>(format: Format, f: impl FnOnce() -> T) -> T {
+    let previous = FORMAT.replace(format);
+    let result = f();
+    FORMAT.set(previous);
+    result
+}
+
+/// Polls the future with scores serialized in the given format.
+/// Other tasks on the same thread are not affected.
+pub fn scope<F: Future>(format: Format, future: F) -> impl Future<Output = F::Output> {
+    let mut future = Box::pin(future);
+    std::future::poll_fn(move |cx| with_format(format, || future.as_mut().poll(cx)))
+}
+
+pub fn serialize<S>(score: &Score, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match FORMAT.get() {
+        Format::Number => serializer.serialize_i64(*score),
+        Format::String => serializer.collect_str(score),
+    }
+}
+
+/// Like [`serialize`], for optional scores
+pub fn serialize_option<S>(score: &Option<Score>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match score {
+        Some(score) => serialize(score, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Score, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Number(Score),
+        String(String),
+    }
+    match Repr::deserialize(deserializer)? {
+        Repr::Number(score) => Ok(score),
+        Repr::String(score) => score.parse().map_err(serde::de::Error::custom),
+    }
+}
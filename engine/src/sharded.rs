@@ -0,0 +1,174 @@
+//! Concurrent hash map split into independently locked shards,
+//! so that lookups of unrelated keys do not wait for each other.
+//!
+//! Locks are only held within the methods, never across an await,
+//! so values are handed out as clones (usually an `Arc`).
+
+use std::{
+    borrow::Borrow,
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash},
+    sync::RwLock,
+};
+
+const SHARDS: usize = 32;
+
+pub struct ShardedMap<K, V> {
+    hasher: RandomState,
+    shards: Box<[RwLock<HashMap<K, V>>]>,
+}
+
+impl<K, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, V: Clone> ShardedMap<K, V> {
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> &RwLock<HashMap<K, V>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    pub fn get<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    pub fn contains_key<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.shard(key).read().unwrap().contains_key(key)
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().unwrap().insert(key, value)
+    }
+
+    pub fn remove<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    /// Returns the value and whether it was just inserted.
+    /// `insert` runs with the shard locked, so it must not touch the map itself.
+    pub fn get_or_insert_with(&self, key: K, insert: impl FnOnce() -> V) -> (V, bool) {
+        let shard = self.shard(&key);
+        if let Some(value) = shard.read().unwrap().get(&key) {
+            return (value.clone(), false);
+        }
+        let mut shard = shard.write().unwrap();
+        // Someone else may have inserted it between the locks
+        if let Some(value) = shard.get(&key) {
+            return (value.clone(), false);
+        }
+        let value = insert();
+        shard.insert(key, value.clone());
+        (value, true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Clone, V: Clone> ShardedMap<K, V> {
+    /// Copy of all entries, shards are locked one at a time so it is not an atomic snapshot
+    pub fn entries(&self) -> Vec<(K, V)> {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            entries.extend(
+                shard
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+        }
+        entries
+    }
+
+    /// Like [`Self::entries`], but gives up instead of waiting for a locked shard
+    pub fn try_entries(&self) -> Option<Vec<(K, V)>> {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let Ok(shard) = shard.try_read() else {
+                return None;
+            };
+            entries.extend(
+                shard
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+        }
+        Some(entries)
+    }
+
+    pub fn keys(&self) -> Vec<K> {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            keys.extend(shard.read().unwrap().keys().cloned());
+        }
+        keys
+    }
+}
+
+impl<K: Hash + Eq, V: Clone> FromIterator<(K, V)> for ShardedMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let map = Self::default();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sharded_map() {
+        let map: ShardedMap<String, usize> = (0..100).map(|n| (n.to_string(), n)).collect();
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.get("42"), Some(42));
+        assert_eq!(map.get_or_insert_with("42".to_owned(), || 0), (42, false));
+        assert_eq!(
+            map.get_or_insert_with("100".to_owned(), || 100),
+            (100, true)
+        );
+        assert_eq!(map.remove("0"), Some(0));
+        assert!(!map.contains_key("0"));
+        let mut keys = map.keys();
+        keys.sort_by_key(|key| key.parse::<usize>().unwrap());
+        assert_eq!(keys.len(), 100);
+        assert_eq!(keys[0], "1");
+        assert_eq!(map.try_entries().unwrap().len(), 100);
+
+        let inserted = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| map.get_or_insert_with("new".to_owned(), || 7).1))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .filter(|&inserted| inserted)
+                .count()
+        });
+        assert_eq!(inserted, 1);
+    }
+}
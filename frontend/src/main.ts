@@ -32,7 +32,7 @@ let playersGraph = new Chart(document.querySelector<HTMLCanvasElement>("#players
     }
 });
 
-type Modifier = "slow" | "double" | "min" | "shuffle" | "reverse";
+type Modifier = "slow" | "double" | "min" | "shuffle" | "reverse" | "shield" | "steal";
 
 type LogMessage =
     | {
@@ -48,11 +48,15 @@ type LogMessage =
         direction: "Up" | "Down",
         modifiers: {
             [mod in Modifier]?: number
+        },
+        applied_by?: {
+            [mod in Modifier]?: { user: string, time: number }
         }
     }
     | {
         type: "CollectEnd",
         user: string,
+        pipe_id: number,
     }
     | {
         type: "UpdateUser",
@@ -163,7 +167,9 @@ if (replayUrl) {
             ws_url = "ws://";
         }
         ws_url += location.host;
-        ws_url += "/logs";
+        // Lobby games serve the viewer at /api/games/{id}/viewer/, next to their logs
+        const game = location.pathname.match(/^\/api\/games\/[^/]+\//);
+        ws_url += game ? game[0] + "logs" : "/logs";
         return ws_url;
     }
     let ws = new WebSocket(ws_url());
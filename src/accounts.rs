@@ -0,0 +1,476 @@
+//! Ladder accounts kept in the results database, so that players keep their name over many
+//! games. Players sign in with a username and password for a token of the running game,
+//! they get the same token again for as long as it is in the game. Bots exchange a
+//! long-lived API key of the account for it instead, so that no password has to be deployed.
+
+use crate::{model, results_db::ResultsDb};
+use actix_web::rt::task::spawn_blocking;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use model::UserToken;
+use rand::{thread_rng, Rng};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Borrow,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS accounts (
+    id INTEGER PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    password_hash TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS match_tokens (
+    token TEXT PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES accounts(id) ON DELETE CASCADE,
+    issued_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS match_tokens_by_account ON match_tokens(account_id);
+CREATE TABLE IF NOT EXISTS api_keys (
+    key_hash TEXT PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES accounts(id) ON DELETE CASCADE,
+    created_at INTEGER NOT NULL
+);
+";
+
+const MAX_USERNAME_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AccountError {
+    #[error("Usernames are 1 to {MAX_USERNAME_LEN} letters, digits, '-' or '_'")]
+    InvalidUsername,
+    #[error("Passwords need at least {MIN_PASSWORD_LEN} characters")]
+    WeakPassword,
+    #[error("Username is taken")]
+    UsernameTaken,
+    #[error("Wrong username or password")]
+    WrongCredentials,
+    #[error("Token was not issued to an account")]
+    UnknownToken,
+    #[error("Unknown API key")]
+    UnknownApiKey,
+    #[error(transparent)]
+    Database(#[from] anyhow::Error),
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+pub struct Credentials {
+    /// Not case sensitive, shown as the name of the player
+    pub username: String,
+    pub password: String,
+}
+
+/// Shown only when created, the database keeps a hash of it
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct ApiKeyResponse {
+    pub key: String,
+}
+
+/// Recorded game played with a token of the account
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct AccountGame {
+    pub game_id: i64,
+    /// Unix time in seconds
+    pub finished_at: i64,
+    pub rank: usize,
+    #[schema(value_type = i64)]
+    #[serde(with = "crate::serde_score")]
+    pub score: model::Score,
+}
+
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct AccountResponse {
+    pub username: String,
+    /// Unix time in seconds
+    pub created_at: i64,
+    /// The latest first
+    pub games: Vec<AccountGame>,
+}
+
+/// Shares the connection of the results database, games of an account are looked up by the
+/// tokens it was issued
+pub struct Accounts {
+    connection: Arc<Mutex<Connection>>,
+}
+
+fn now() -> anyhow::Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+fn check_username(username: &str) -> Result<(), AccountError> {
+    let valid = (1..=MAX_USERNAME_LEN).contains(&username.len())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(AccountError::InvalidUsername);
+    }
+    Ok(())
+}
+
+/// Keys are random, so a plain hash is enough to keep them from being read off the database
+fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::encode_b64(&thread_rng().gen::<[u8; 16]>())
+        .map_err(|e| anyhow::anyhow!("Failed to encode the salt: {e}"))?;
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("Failed to hash the password: {e}"))?;
+    Ok(hash.to_string())
+}
+
+/// Id and username of the account if the password is right. The database is not locked while
+/// the password is checked, that takes a while on purpose.
+fn sign_in(
+    connection: &Mutex<Connection>,
+    credentials: &Credentials,
+) -> Result<(i64, String), AccountError> {
+    let account = connection
+        .lock()
+        .unwrap()
+        .query_row(
+            "SELECT id, username, password_hash FROM accounts WHERE username = ?1",
+            [&credentials.username],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?)),
+        )
+        .optional()
+        .map_err(anyhow::Error::from)?;
+    let Some((id, username, hash)) = account else {
+        return Err(AccountError::WrongCredentials);
+    };
+    let hash = PasswordHash::new(&hash)
+        .map_err(|e| anyhow::anyhow!("Stored password hash of {username:?} is broken: {e}"))?;
+    Argon2::default()
+        .verify_password(credentials.password.as_bytes(), &hash)
+        .map_err(|_| AccountError::WrongCredentials)?;
+    Ok((id, username))
+}
+
+impl Accounts {
+    pub fn new(results_db: &ResultsDb) -> anyhow::Result<Self> {
+        let connection = results_db.connection();
+        connection.lock().unwrap().execute_batch(SCHEMA)?;
+        Ok(Self { connection })
+    }
+
+    pub async fn create(&self, credentials: Credentials) -> Result<AccountResponse, AccountError> {
+        check_username(&credentials.username)?;
+        if credentials.password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(AccountError::WeakPassword);
+        }
+        let connection = self.connection.clone();
+        spawn_blocking(move || {
+            let hash = hash_password(&credentials.password)?;
+            let created_at = now()?;
+            let inserted = connection.lock().unwrap().execute(
+                "INSERT INTO accounts (username, password_hash, created_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT DO NOTHING",
+                params![credentials.username, hash, created_at],
+            );
+            match inserted.map_err(anyhow::Error::from)? {
+                0 => Err(AccountError::UsernameTaken),
+                _ => {
+                    info!("Created account {:?}", credentials.username);
+                    Ok(AccountResponse {
+                        username: credentials.username,
+                        created_at,
+                        games: Vec::new(),
+                    })
+                }
+            }
+        })
+        .await
+        .map_err(anyhow::Error::from)?
+    }
+
+    /// Token of the account in the game, a new one is issued and added to the game
+    /// with the username as the name if the account has none in it yet
+    pub async fn issue_token(
+        &self,
+        app: &model::App,
+        credentials: Credentials,
+    ) -> Result<model::RegisterResponse, AccountError> {
+        let connection = self.connection.clone();
+        let (id, username) = spawn_blocking(move || sign_in(&connection, &credentials))
+            .await
+            .map_err(anyhow::Error::from)??;
+        self.game_token(app, id, username).await
+    }
+
+    /// Creates another API key of the account, the older ones keep working
+    pub async fn create_api_key(
+        &self,
+        credentials: Credentials,
+    ) -> Result<ApiKeyResponse, AccountError> {
+        let connection = self.connection.clone();
+        spawn_blocking(move || {
+            let (id, username) = sign_in(&connection, &credentials)?;
+            let key = format!("key-{:032x}", thread_rng().gen::<u128>());
+            connection
+                .lock()
+                .unwrap()
+                .execute(
+                    "INSERT INTO api_keys (key_hash, account_id, created_at) VALUES (?1, ?2, ?3)",
+                    params![hash_api_key(&key), id, now()?],
+                )
+                .map_err(anyhow::Error::from)?;
+            info!("Created an API key for account {username:?}");
+            Ok(ApiKeyResponse { key })
+        })
+        .await
+        .map_err(anyhow::Error::from)?
+    }
+
+    /// Same as [`Accounts::issue_token`], signed in with an API key of the account
+    pub async fn exchange_api_key(
+        &self,
+        app: &model::App,
+        key: &str,
+    ) -> Result<model::RegisterResponse, AccountError> {
+        let key_hash = hash_api_key(key);
+        let connection = self.connection.clone();
+        let account = spawn_blocking(move || {
+            connection
+                .lock()
+                .unwrap()
+                .query_row(
+                    "SELECT accounts.id, username FROM api_keys
+                     JOIN accounts ON accounts.id = api_keys.account_id
+                     WHERE key_hash = ?1",
+                    [key_hash],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+        })
+        .await
+        .map_err(anyhow::Error::from)?
+        .map_err(anyhow::Error::from)?;
+        let Some((id, username)) = account else {
+            return Err(AccountError::UnknownApiKey);
+        };
+        self.game_token(app, id, username).await
+    }
+
+    /// Token of a signed in account in the game, see [`Accounts::issue_token`]
+    async fn game_token(
+        &self,
+        app: &model::App,
+        id: i64,
+        username: String,
+    ) -> Result<model::RegisterResponse, AccountError> {
+        let connection = self.connection.clone();
+        let tokens = spawn_blocking(move || {
+            connection
+                .lock()
+                .unwrap()
+                .prepare(
+                    "SELECT token FROM match_tokens WHERE account_id = ?1
+                     ORDER BY issued_at DESC, rowid DESC",
+                )
+                .and_then(|mut statement| {
+                    statement
+                        .query_map([id], |row| row.get::<_, String>(0))?
+                        .collect::<rusqlite::Result<Vec<_>>>()
+                })
+        })
+        .await
+        .map_err(anyhow::Error::from)?
+        .map_err(anyhow::Error::from)?;
+        let name = Some(username);
+        if let Some(token) = tokens
+            .into_iter()
+            .map(UserToken::from)
+            .find(|token| app.user_index(token).is_some())
+        {
+            return Ok(model::RegisterResponse { token, name });
+        }
+
+        let token = format!("{:032x}", thread_rng().gen::<u128>());
+        let connection = self.connection.clone();
+        let token = spawn_blocking(move || {
+            connection.lock().unwrap().execute(
+                "INSERT INTO match_tokens (token, account_id, issued_at) VALUES (?1, ?2, ?3)",
+                params![token, id, now()?],
+            )?;
+            anyhow::Ok(UserToken::from(token))
+        })
+        .await
+        .map_err(anyhow::Error::from)??;
+        info!("Issued {token:?} to account {name:?}");
+        app.import_users(vec![model::UserRecord {
+            token: token.clone(),
+            profile: model::UserProfile {
+                name: name.clone(),
+                ..Default::default()
+            },
+        }])
+        .await;
+        Ok(model::RegisterResponse { token, name })
+    }
+
+    /// The account a token was issued to, with the recorded games of all its tokens
+    pub async fn account(&self, token: &UserToken) -> Result<AccountResponse, AccountError> {
+        let token: &String = token.borrow();
+        let token = token.clone();
+        let connection = self.connection.clone();
+        spawn_blocking(move || {
+            let connection = connection.lock().unwrap();
+            let account = connection
+                .query_row(
+                    "SELECT accounts.id, username, created_at FROM match_tokens
+                     JOIN accounts ON accounts.id = match_tokens.account_id
+                     WHERE token = ?1",
+                    [token],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .optional()
+                .map_err(anyhow::Error::from)?;
+            let Some((id, username, created_at)) = account else {
+                return Err(AccountError::UnknownToken);
+            };
+            let games = connection
+                .prepare(
+                    "SELECT games.id, finished_at, rank, score FROM match_tokens
+                     JOIN players ON players.user = match_tokens.token
+                     JOIN games ON games.id = players.game_id
+                     WHERE account_id = ?1 ORDER BY games.id DESC",
+                )
+                .and_then(|mut statement| {
+                    statement
+                        .query_map([id], |row| {
+                            Ok(AccountGame {
+                                game_id: row.get(0)?,
+                                finished_at: row.get(1)?,
+                                rank: row.get(2)?,
+                                score: row.get(3)?,
+                            })
+                        })?
+                        .collect::<rusqlite::Result<_>>()
+                })
+                .map_err(anyhow::Error::from)?;
+            Ok(AccountResponse {
+                username,
+                created_at,
+                games,
+            })
+        })
+        .await
+        .map_err(anyhow::Error::from)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_accounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let results_db = ResultsDb::open(&dir.path().join("results.sqlite")).unwrap();
+        let accounts = Accounts::new(&results_db).unwrap();
+        accounts
+            .create(credentials("alice", "correct horse"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            accounts
+                .create(credentials("ALICE", "battery staple"))
+                .await,
+            Err(AccountError::UsernameTaken)
+        ));
+        assert!(matches!(
+            accounts.create(credentials("bob", "short")).await,
+            Err(AccountError::WeakPassword)
+        ));
+        assert!(matches!(
+            accounts
+                .create(credentials("bob smith", "long enough"))
+                .await,
+            Err(AccountError::InvalidUsername)
+        ));
+
+        let app = model::App::init(model::Config::default(), []);
+        assert!(matches!(
+            accounts
+                .issue_token(&app, credentials("alice", "wrong horse"))
+                .await,
+            Err(AccountError::WrongCredentials)
+        ));
+        let first = accounts
+            .issue_token(&app, credentials("Alice", "correct horse"))
+            .await
+            .unwrap();
+        assert_eq!(first.name.as_deref(), Some("alice"));
+        assert!(app.user_index(&first.token).is_some());
+        let again = accounts
+            .issue_token(&app, credentials("alice", "correct horse"))
+            .await
+            .unwrap();
+        assert_eq!(again.token, first.token);
+
+        // A new game gets a new token, games of both are listed
+        results_db.record(&app, None).await.unwrap();
+        let next = model::App::init(model::Config::default(), []);
+        let second = accounts
+            .issue_token(&next, credentials("alice", "correct horse"))
+            .await
+            .unwrap();
+        assert_ne!(second.token, first.token);
+        results_db.record(&next, None).await.unwrap();
+        let account = accounts.account(&first.token).await.unwrap();
+        assert_eq!(account.username, "alice");
+        let games: Vec<_> = account.games.iter().map(|game| game.game_id).collect();
+        assert_eq!(games, [2, 1]);
+        assert_eq!(account.games[0].score, next.config().initial_score);
+
+        assert!(matches!(
+            accounts
+                .account(&UserToken::from("stranger".to_owned()))
+                .await,
+            Err(AccountError::UnknownToken)
+        ));
+
+        // Keys get the token of the game like the password does
+        let key = accounts
+            .create_api_key(credentials("alice", "correct horse"))
+            .await
+            .unwrap()
+            .key;
+        let exchanged = accounts.exchange_api_key(&next, &key).await.unwrap();
+        assert_eq!(exchanged.token, second.token);
+        let third = model::App::init(model::Config::default(), []);
+        let exchanged = accounts.exchange_api_key(&third, &key).await.unwrap();
+        assert_ne!(exchanged.token, second.token);
+        assert!(third.user_index(&exchanged.token).is_some());
+        assert!(next.user_index(&exchanged.token).is_none());
+        assert!(matches!(
+            accounts.exchange_api_key(&third, "key-made-up").await,
+            Err(AccountError::UnknownApiKey)
+        ));
+        assert!(matches!(
+            accounts
+                .create_api_key(credentials("alice", "wrong horse"))
+                .await,
+            Err(AccountError::WrongCredentials)
+        ));
+    }
+}
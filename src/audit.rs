@@ -0,0 +1,271 @@
+//! Audit log of every api call, the authoritative record for settling disputes about
+//! what a bot did and what the server answered
+
+use crate::{model, server::GameError};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::AUTHORIZATION,
+    middleware::Next,
+    web,
+};
+use anyhow::Context;
+use serde::Serialize;
+use std::{
+    io::Write,
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+    time::{Instant, SystemTime},
+};
+use tracing::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditFormat {
+    JsonLines,
+    Csv,
+}
+
+/// Single api call as written to the audit log
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    /// Seconds since the unix epoch when the request was received
+    pub unix_time: f64,
+    /// Game time when the request was received
+    pub game_time: Option<f64>,
+    pub ip: Option<String>,
+    pub token: Option<String>,
+    pub method: String,
+    /// Route the request matched, e.g. `/api/pipe/{n}/collect`
+    pub endpoint: Option<String>,
+    /// Path with the query string
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<serde_json::Value>,
+    pub status: u16,
+    /// Code of the game error the request failed with
+    pub error: Option<model::ErrorCode>,
+    pub latency_ms: f64,
+}
+
+impl AuditRecord {
+    const CSV_HEADER: &'static str =
+        "unix_time,game_time,ip,token,method,endpoint,path,body,status,error,latency_ms";
+
+    fn write_csv(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        let fields = [
+            self.unix_time.to_string(),
+            optional(self.game_time.map(|time| time.to_string())),
+            optional(self.ip.clone()),
+            optional(self.token.clone()),
+            self.method.clone(),
+            optional(self.endpoint.clone()),
+            self.path.clone(),
+            optional(self.body.as_ref().map(ToString::to_string)),
+            self.status.to_string(),
+            optional(self.error.map(|code| format!("{code:?}"))),
+            self.latency_ms.to_string(),
+        ];
+        let fields: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+        writeln!(writer, "{}", fields.join(","))
+    }
+}
+
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+pub struct AuditLog {
+    format: AuditFormat,
+    writer: Mutex<std::io::BufWriter<std::fs::File>>,
+}
+
+impl AuditLog {
+    /// Appends to the file, CSV if it has the `.csv` extension and JSON lines otherwise
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let format = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => AuditFormat::Csv,
+            _ => AuditFormat::JsonLines,
+        };
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("Failed to open the audit log")?;
+        let is_empty = file.metadata()?.len() == 0;
+        let mut writer = std::io::BufWriter::new(file);
+        if format == AuditFormat::Csv && is_empty {
+            writeln!(writer, "{}", AuditRecord::CSV_HEADER)?;
+            writer.flush()?;
+        }
+        Ok(Self {
+            format,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Every record is flushed right away, so that nothing is lost if the server goes down
+    fn write(&self, record: &AuditRecord) -> anyhow::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        match self.format {
+            AuditFormat::JsonLines => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writeln!(writer)?;
+            }
+            AuditFormat::Csv => record.write_csv(&mut *writer)?,
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl AuditLog {
+    /// Summarizes the failed authentication attempts since the last summary, one record per ip
+    /// with the `SUMMARY` method and the number of attempts in the body
+    pub fn write_auth_failures(
+        &self,
+        game_time: Option<f64>,
+        failures: &[(IpAddr, usize)],
+    ) -> anyhow::Result<()> {
+        let unix_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        for &(ip, attempts) in failures {
+            self.write(&AuditRecord {
+                unix_time,
+                game_time,
+                ip: Some(ip.to_string()),
+                token: None,
+                method: "SUMMARY".to_owned(),
+                endpoint: Some("auth_failures".to_owned()),
+                path: String::new(),
+                body: Some(serde_json::json!({ "attempts": attempts })),
+                status: 401,
+                error: Some(model::ErrorCode::UserNotFound),
+                latency_ms: 0.0,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Middleware writing every api call to the [`AuditLog`] if one is registered,
+/// including the ones turned away before reaching the game
+pub async fn audit_requests(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let audit_log = req.app_data::<web::Data<AuditLog>>().cloned();
+    let Some(audit_log) = audit_log.filter(|_| req.path().starts_with("/api/")) else {
+        return next.call(req).await;
+    };
+    let start = Instant::now();
+    let unix_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let game_time = req
+        .app_data::<web::Data<model::App>>()
+        .map(|state| state.game_time());
+    let body = req.extract::<web::Bytes>().await?;
+    req.set_payload(actix_web::dev::Payload::from(body.clone()));
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(str::to_owned);
+    let method = req.method().to_string();
+    let path = req.uri().to_string();
+    let response = next.call(req).await?;
+    let record = AuditRecord {
+        unix_time,
+        game_time,
+        ip,
+        token,
+        method,
+        endpoint: response.request().match_pattern(),
+        path,
+        body: serde_json::from_slice(&body).ok(),
+        status: response.status().as_u16(),
+        error: response
+            .response()
+            .extensions()
+            .get::<GameError>()
+            .map(|error| error.0.code()),
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+    };
+    if let Err(e) = audit_log.write(&record) {
+        error!("Failed to write the audit log: {e}");
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, middleware::from_fn, test, App};
+    use actix_web_httpauth::headers::authorization::Bearer;
+
+    #[actix_web::test]
+    async fn test_audit_log() {
+        crate::logger::init_for_tests();
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["audit.jsonl", "audit.csv"] {
+            let path = dir.path().join(name);
+            let state = web::Data::new(model::App::init(
+                model::Config {
+                    initial_score: 1000,
+                    ..Default::default()
+                },
+                vec![model::UserToken::from("player".to_owned())],
+            ));
+            let app = test::init_service(
+                App::new()
+                    .wrap(from_fn(audit_requests))
+                    .app_data(web::Data::new(AuditLog::open(&path).unwrap()))
+                    .configure(|config| crate::server::configure(config, state)),
+            )
+            .await;
+            let req = test::TestRequest::post()
+                .uri("/api/pipe/999/modifier")
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .set_json(serde_json::json!({"type": "reverse"}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+            let req = test::TestRequest::get().uri("/api/time").to_request();
+            test::call_service(&app, req).await;
+
+            let log = std::fs::read_to_string(&path).unwrap();
+            let lines: Vec<_> = log.lines().collect();
+            if name.ends_with(".csv") {
+                assert_eq!(lines.len(), 3, "{log}");
+                assert_eq!(lines[0], AuditRecord::CSV_HEADER);
+                assert!(lines[1].contains(
+                    ",player,POST,/api/pipe/{n}/modifier,/api/pipe/999/modifier,\
+                     \"{\"\"type\"\":\"\"reverse\"\"}\",404,PipeNotFound,"
+                ));
+            } else {
+                assert_eq!(lines.len(), 2, "{log}");
+                let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+                assert_eq!(record["token"], "player");
+                assert_eq!(record["endpoint"], "/api/pipe/{n}/modifier");
+                assert_eq!(record["body"]["type"], "reverse");
+                assert_eq!(record["status"], 404);
+                assert_eq!(record["error"], "PipeNotFound");
+                let record: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+                assert_eq!(record["token"], serde_json::Value::Null);
+                assert_eq!(record["status"], 200);
+            }
+        }
+    }
+}
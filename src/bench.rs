@@ -0,0 +1,244 @@
+//! Load test of a running server through its http api, every client hammers it without pauses
+
+use crate::{http_client::Client, model};
+use rand::{seq::SliceRandom, thread_rng, Rng};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+pub struct Options {
+    pub url: String,
+    pub clients: usize,
+    pub duration: Duration,
+    /// Given to the clients in turn, `bench-<n>` tokens are used if empty
+    pub tokens: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EndpointSummary {
+    pub requests: usize,
+    /// Requests that got no response at all
+    pub failed: usize,
+    /// Responses by status code
+    pub statuses: BTreeMap<u16, usize>,
+    /// Share of requests that failed or got a status of 400 or more
+    pub error_rate: f64,
+    pub p50_latency: f64,
+    pub p90_latency: f64,
+    pub p99_latency: f64,
+    pub max_latency: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub clients: usize,
+    pub duration_secs: f64,
+    pub requests: usize,
+    pub requests_per_sec: f64,
+    pub error_rate: f64,
+    pub endpoints: BTreeMap<&'static str, EndpointSummary>,
+}
+
+#[derive(Default)]
+struct Samples {
+    latencies: Vec<Duration>,
+    failed: usize,
+    statuses: BTreeMap<u16, usize>,
+}
+
+impl Samples {
+    fn merge(&mut self, other: Samples) {
+        self.latencies.extend(other.latencies);
+        self.failed += other.failed;
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+    }
+
+    fn errors(&self) -> usize {
+        let error_responses: usize = self.statuses.range(400..).map(|(_, count)| count).sum();
+        self.failed + error_responses
+    }
+
+    fn summary(mut self) -> EndpointSummary {
+        self.latencies.sort();
+        let percentile = |p: f64| {
+            let index = ((self.latencies.len() - 1) as f64 * p).round() as usize;
+            self.latencies[index].as_secs_f64()
+        };
+        EndpointSummary {
+            requests: self.latencies.len(),
+            failed: self.failed,
+            error_rate: self.errors() as f64 / self.latencies.len() as f64,
+            p50_latency: percentile(0.5),
+            p90_latency: percentile(0.9),
+            p99_latency: percentile(0.99),
+            max_latency: self.latencies.last().unwrap().as_secs_f64(),
+            statuses: self.statuses,
+        }
+    }
+}
+
+type ClientSamples = BTreeMap<&'static str, Samples>;
+
+/// Runs the clients on their own threads until the duration is over
+pub fn run(options: &Options) -> anyhow::Result<Summary> {
+    anyhow::ensure!(options.clients > 0, "No clients to run");
+    let tokens: Vec<String> = (0..options.clients)
+        .map(|index| {
+            if options.tokens.is_empty() {
+                format!("bench-{}", index + 1)
+            } else {
+                options.tokens[index % options.tokens.len()].clone()
+            }
+        })
+        .collect();
+    info!(
+        "Running {} clients against {} for {:?}",
+        options.clients, options.url, options.duration
+    );
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    let threads: Vec<_> = tokens
+        .into_iter()
+        .map(|token| {
+            let client = Client::new(&options.url, Some(token));
+            thread::spawn(move || run_client(&client, deadline))
+        })
+        .collect();
+    let mut samples = ClientSamples::new();
+    for thread in threads {
+        let client_samples = thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("Bench client panicked"))?;
+        for (endpoint, endpoint_samples) in client_samples {
+            samples.entry(endpoint).or_default().merge(endpoint_samples);
+        }
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let requests: usize = samples
+        .values()
+        .map(|samples| samples.latencies.len())
+        .sum();
+    let errors: usize = samples.values().map(Samples::errors).sum();
+    let endpoints = samples
+        .into_iter()
+        .filter(|(_, samples)| !samples.latencies.is_empty())
+        .map(|(endpoint, samples)| (endpoint, samples.summary()))
+        .collect();
+    Ok(Summary {
+        clients: options.clients,
+        duration_secs: elapsed,
+        requests,
+        requests_per_sec: requests as f64 / elapsed,
+        error_rate: errors as f64 / requests.max(1) as f64,
+        endpoints,
+    })
+}
+
+fn request(
+    client: &Client,
+    samples: &mut ClientSamples,
+    endpoint: &'static str,
+    method: &str,
+    path: &str,
+) -> Option<String> {
+    let samples = samples.entry(endpoint).or_default();
+    let started = Instant::now();
+    let result = client.request(method, path, None);
+    samples.latencies.push(started.elapsed());
+    match result {
+        Ok(response) => {
+            *samples.statuses.entry(response.status).or_default() += 1;
+            (response.status < 400).then_some(response.body)
+        }
+        Err(e) => {
+            debug!("Request {method} {path} failed: {e:#}");
+            samples.failed += 1;
+            None
+        }
+    }
+}
+
+fn run_client(client: &Client, deadline: Instant) -> ClientSamples {
+    let mut rng = thread_rng();
+    let mut samples = ClientSamples::new();
+    let mut pipes = Vec::new();
+    while Instant::now() < deadline {
+        let Some(&pipe_id) = pipes.choose(&mut rng) else {
+            let body = request(client, &mut samples, "GET /api/pipes", "GET", "/api/pipes");
+            pipes = body
+                .and_then(|body| serde_json::from_str::<model::PipesResponse>(&body).ok())
+                .map(|response| response.pipes.into_iter().map(|pipe| pipe.id).collect())
+                .unwrap_or_default();
+            continue;
+        };
+        match rng.gen_range(0..3) {
+            0 => request(
+                client,
+                &mut samples,
+                "PUT /api/pipe/{n}",
+                "PUT",
+                &format!("/api/pipe/{pipe_id}"),
+            ),
+            1 => request(
+                client,
+                &mut samples,
+                "GET /api/pipe/{n}/value",
+                "GET",
+                &format!("/api/pipe/{pipe_id}/value"),
+            ),
+            _ => request(client, &mut samples, "GET /api/user", "GET", "/api/user"),
+        };
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::GameServer;
+    use actix_web::rt::task::spawn_blocking;
+
+    #[actix_web::test]
+    async fn test_bench() {
+        crate::logger::init_for_tests();
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                start_delay_secs: 0.0,
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                pipe_value_delay_secs: 0.0,
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let options = Options {
+            url: server.addr().to_string(),
+            clients: 4,
+            duration: Duration::from_millis(500),
+            tokens: Vec::new(),
+        };
+        let summary = spawn_blocking(move || run(&options).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(summary.clients, 4);
+        assert!(summary.requests > 0);
+        let pipes = &summary.endpoints["GET /api/pipes"];
+        assert_eq!(pipes.requests, 4);
+        assert_eq!(pipes.statuses[&200], 4);
+        let user = &summary.endpoints["GET /api/user"];
+        assert_eq!(user.error_rate, 0.0);
+        assert!(user.p50_latency <= user.p99_latency);
+        assert!(user.p99_latency <= user.max_latency);
+        let app = server.stop().await.unwrap();
+        assert!(app.results().await.contains_key("bench-4"));
+    }
+}
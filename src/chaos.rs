@@ -0,0 +1,163 @@
+//! Faults injected on purpose, so that bots can be tested against slow and flaky servers.
+//! Everything injected is logged with the `chaos` target, to tell it apart from real problems.
+
+use crate::model;
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{HeaderName, HeaderValue},
+        StatusCode,
+    },
+    middleware::Next,
+    rt::time::sleep,
+    web, HttpResponse,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{sync::Mutex, time::Duration};
+use tracing::{debug, info};
+
+/// Header marking responses of failures that were injected
+pub const CHAOS_HEADER: HeaderName = HeaderName::from_static("x-chaos");
+
+/// What to do with a single request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    Delay(Duration),
+    Error(StatusCode),
+}
+
+/// Draws the faults from its own rng, so that the game plays out the same with chaos on
+pub struct ChaosInjector {
+    chaos: model::Chaos,
+    rng: Mutex<StdRng>,
+}
+
+impl ChaosInjector {
+    /// Seeded with the seed of the chaos config, or the given game seed if it has none
+    pub fn new(chaos: model::Chaos, game_seed: u64) -> Self {
+        let rng = StdRng::seed_from_u64(chaos.seed.unwrap_or(game_seed));
+        Self {
+            chaos,
+            rng: Mutex::new(rng),
+        }
+    }
+
+    /// Fault to inject into the next api request, if any
+    pub fn next_fault(&self) -> Option<Fault> {
+        let mut rng = self.rng.lock().unwrap();
+        if rng.gen_bool(self.chaos.error_probability) {
+            let status = StatusCode::from_u16(self.chaos.error_status)
+                .unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+            return Some(Fault::Error(status));
+        }
+        if rng.gen_bool(self.chaos.delay_probability) {
+            let delay = rng.gen_range(0.0..=self.chaos.max_delay_secs);
+            return Some(Fault::Delay(Duration::from_secs_f64(delay)));
+        }
+        None
+    }
+
+    /// Whether the next message to a log subscriber should be left out
+    pub fn drop_log_message(&self) -> bool {
+        self.rng
+            .lock()
+            .unwrap()
+            .gen_bool(self.chaos.drop_log_probability)
+    }
+}
+
+/// Delays or fails game api requests at random, admin requests are left alone
+pub async fn inject_chaos(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let injector = req.app_data::<web::Data<ChaosInjector>>().cloned();
+    let fault = injector
+        .filter(|_| req.path().starts_with("/api/") && !req.path().starts_with("/api/admin/"))
+        .and_then(|injector| injector.next_fault());
+    match fault {
+        Some(Fault::Error(status)) => {
+            info!(target: "chaos", "Failing {} {} with {status}", req.method(), req.path());
+            let response = HttpResponse::build(status)
+                .insert_header((CHAOS_HEADER, HeaderValue::from_static("error")))
+                .body("Injected failure");
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+        Some(Fault::Delay(delay)) => {
+            debug!(target: "chaos", "Delaying {} {} by {delay:?}", req.method(), req.path());
+            sleep(delay).await;
+        }
+        None => {}
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, App};
+
+    fn chaos(seed: Option<u64>) -> model::Chaos {
+        model::Chaos {
+            delay_probability: 0.3,
+            max_delay_secs: 2.0,
+            error_probability: 0.2,
+            error_status: 502,
+            drop_log_probability: 0.5,
+            seed,
+        }
+    }
+
+    #[test]
+    fn test_seeded_faults() {
+        let faults = |injector: ChaosInjector| {
+            (0..100)
+                .map(|_| (injector.next_fault(), injector.drop_log_message()))
+                .collect::<Vec<_>>()
+        };
+        let first = faults(ChaosInjector::new(chaos(None), 42));
+        assert_eq!(first, faults(ChaosInjector::new(chaos(None), 42)));
+        assert_eq!(first, faults(ChaosInjector::new(chaos(Some(42)), 7)));
+        assert_ne!(first, faults(ChaosInjector::new(chaos(None), 7)));
+
+        let errors = first
+            .iter()
+            .filter(|(fault, _)| *fault == Some(Fault::Error(StatusCode::BAD_GATEWAY)))
+            .count();
+        assert!((5..40).contains(&errors), "{errors} errors");
+        assert!(first.iter().all(|(fault, _)| match fault {
+            Some(Fault::Delay(delay)) => *delay <= Duration::from_secs(2),
+            _ => true,
+        }));
+    }
+
+    #[actix_web::test]
+    async fn test_injected_errors() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(Default::default(), vec![]));
+        let chaos = model::Chaos {
+            error_probability: 1.0,
+            ..chaos(Some(1))
+        };
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(from_fn(inject_chaos))
+                .app_data(web::Data::new(ChaosInjector::new(chaos, 0)))
+                .configure(|config| crate::server::configure(config, state)),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/api/time")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(resp.headers().get(CHAOS_HEADER).unwrap(), "error");
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/api/admin/game")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(resp.headers().get(CHAOS_HEADER).is_none());
+    }
+}
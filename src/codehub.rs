@@ -1,17 +1,18 @@
 //! Codehub specific logic
 
-use crate::model;
+use crate::{json, model};
+use anyhow::Context;
 use futures::future::LocalBoxFuture;
-use log::info;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     path::{Path, PathBuf},
 };
+use tracing::info;
 
 type UserId = i64;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     pub summary_path: PathBuf,
     pub time_to_run: Option<f64>,
@@ -23,12 +24,7 @@ pub fn detect() -> Option<Config> {
     if let Some(summary_path) = std::env::var_os("GAME_LOG_LOCATION") {
         let summary_path = summary_path.into();
         let clients_json = std::env::var("CLIENTS_JSON").expect("CLIENTS_JSON env var expected");
-        let client_tokens: HashMap<UserId, model::UserToken> =
-            serde_json::from_str(&clients_json).expect("Failed to parse CLIENTS_JSON");
-        let user_id_by_token = client_tokens
-            .into_iter()
-            .map(|(id, token)| (token, id))
-            .collect();
+        let user_id_by_token = parse_clients(&clients_json).expect("Failed to parse CLIENTS_JSON");
         let config = Config {
             summary_path,
             user_id_by_token,
@@ -43,6 +39,27 @@ pub fn detect() -> Option<Config> {
     }
 }
 
+/// Users by their tokens from `CLIENTS_JSON`, a user has either one token or a list of them
+fn parse_clients(json: &str) -> serde_json::Result<HashMap<model::UserToken, UserId>> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Seats {
+        Single(model::UserToken),
+        Multiple(Vec<model::UserToken>),
+    }
+    let client_tokens: HashMap<UserId, Seats> = serde_json::from_str(json)?;
+    Ok(client_tokens
+        .into_iter()
+        .flat_map(|(id, seats)| {
+            let tokens = match seats {
+                Seats::Single(token) => vec![token],
+                Seats::Multiple(tokens) => tokens,
+            };
+            tokens.into_iter().map(move |token| (token, id))
+        })
+        .collect())
+}
+
 // Reports "user" errors to game log if running on codehub
 pub async fn wrapper<F>(f: F) -> anyhow::Result<()>
 where
@@ -76,9 +93,149 @@ where
     }
 }
 
+/// How scores of several seats of one user are combined
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum SeatCombiner {
+    Sum,
+    Max,
+}
+
+impl Config {
+    pub fn user_results(
+        &self,
+        results: model::Results,
+        combiner: SeatCombiner,
+    ) -> HashMap<UserId, f64> {
+        let mut user_results = HashMap::new();
+        for (token, score) in results {
+            let id = self.user_id_by_token[&token];
+            let score = score as f64;
+            user_results
+                .entry(id)
+                .and_modify(|total: &mut f64| match combiner {
+                    SeatCombiner::Sum => *total += score,
+                    SeatCombiner::Max => *total = total.max(score),
+                })
+                .or_insert(score);
+        }
+        user_results
+    }
+}
+
+impl Config {
+    /// Per player results explaining the scores: whether the player took part at all,
+    /// whether a handicap applied and what the player did, summed over the seats
+    pub fn players(
+        &self,
+        app: &model::App,
+        stats: &BTreeMap<String, model::PlayerStats>,
+        profiles: &BTreeMap<String, model::UserProfile>,
+    ) -> HashMap<UserId, PlayerResult> {
+        let handicaps = &app.config().handicaps;
+        let mut statuses: HashMap<UserId, model::UserStatus> = HashMap::new();
+        let mut comments: HashMap<UserId, Vec<String>> = HashMap::new();
+        let mut names: HashMap<UserId, String> = HashMap::new();
+        for (token, profile) in profiles {
+            if let (Some(&id), Some(name)) = (self.user_id_by_token.get(token), &profile.name) {
+                names.entry(id).or_insert_with(|| name.clone());
+            }
+        }
+        let mut totals: HashMap<UserId, model::PlayerStats> = HashMap::new();
+        for (token, stats) in stats {
+            let Some(&id) = self.user_id_by_token.get(token) else {
+                continue;
+            };
+            let total = match totals.entry(id) {
+                Entry::Vacant(entry) => {
+                    entry.insert(stats.clone());
+                    continue;
+                }
+                Entry::Occupied(entry) => entry.into_mut(),
+            };
+            total.collects += stats.collects;
+            total.time_blocked += stats.time_blocked;
+            total.modifiers_bought += stats.modifiers_bought;
+            total.earned += stats.earned;
+            total.spent += stats.spent;
+            total.errors += stats.errors;
+            // The seat doing best represents the player, same as with the statuses
+            total.last_success_secs = match (total.last_success_secs, stats.last_success_secs) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            };
+            total.error_streak = total.error_streak.min(stats.error_streak);
+        }
+        for (token, &id) in &self.user_id_by_token {
+            // The most active seat represents the player
+            let status = app.user_status(token);
+            statuses
+                .entry(id)
+                .and_modify(|best| *best = status.min(*best))
+                .or_insert(status);
+            if let Some(multiplier) = handicaps.get(token) {
+                comments
+                    .entry(id)
+                    .or_default()
+                    .push(format!("Handicap: collects multiplied by {multiplier}"));
+            }
+        }
+        statuses
+            .into_iter()
+            .map(|(id, status)| {
+                let mut comment = comments.remove(&id).unwrap_or_default();
+                match status {
+                    model::UserStatus::Active => {}
+                    model::UserStatus::TimedOut => {
+                        comment.insert(0, "Stopped sending requests".to_owned())
+                    }
+                    model::UserStatus::NeverConnected => {
+                        comment.insert(0, "Never connected".to_owned())
+                    }
+                }
+                let total = totals.remove(&id);
+                if let Some(total) = total.as_ref().filter(|total| total.error_streak > 0) {
+                    comment.push(format!("Failed the last {} actions", total.error_streak));
+                }
+                if let Some(total) = &total {
+                    comment.push(format!(
+                        "{} collects, {} modifiers bought, earned {}, spent {}, {} errors",
+                        total.collects,
+                        total.modifiers_bought,
+                        total.earned,
+                        total.spent,
+                        total.errors
+                    ));
+                }
+                // Bots that connected and went silent long before the end crashed at their
+                // last successful action, idle ones never connected
+                let crashed = status == model::UserStatus::TimedOut;
+                let crash_tick = crashed.then(|| {
+                    total
+                        .as_ref()
+                        .and_then(|total| total.last_success_secs)
+                        .map_or(0, |secs| secs as usize)
+                });
+                let player = PlayerResult {
+                    name: names.remove(&id),
+                    crashed,
+                    crash_tick,
+                    // Time spent waiting for the game, there is no way to measure the bot itself
+                    time_used: total.map(|total| total.time_blocked),
+                    comment: (!comment.is_empty()).then(|| comment.join(". ")),
+                };
+                (id, player)
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug, serde::Serialize)]
 pub struct PlayerResult {
+    /// Display name of the user, taken from one of the seats
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub crashed: bool,
+    /// Game second of the last successful action of a crashed player
     pub crash_tick: Option<usize>,
     pub time_used: Option<f64>,
     pub comment: Option<String>,
@@ -89,15 +246,75 @@ pub struct Results {
     pub players: Option<HashMap<UserId, PlayerResult>>,
     pub results: HashMap<UserId, f64>,
     pub seed: Option<u64>,
+    /// Set when the game was terminated abnormally and the results are not final
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub incomplete: bool,
+    /// Digest of the game log, when the server was asked to compute it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_digest: Option<crate::log_digest::Digest>,
+}
+
+/// Additional file to reference from the summary
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    pub name: String,
+    pub path: PathBuf,
+    pub private: bool,
+}
+
+/// Keys of the summary that are not artifacts
+const RESERVED_NAMES: [&str; 2] = ["visio", "scores"];
+
+impl Artifact {
+    /// Named after the file stem, which has to be valid UTF-8
+    pub fn private(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .with_context(|| format!("Artifact {path:?} has no UTF-8 file name"))?
+            .to_owned();
+        Ok(Self {
+            name,
+            path,
+            private: true,
+        })
+    }
+
+    pub fn public(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        Ok(Self {
+            private: false,
+            ..Self::private(path)?
+        })
+    }
+}
+
+/// Fails if two artifacts would end up under the same key of the summary
+pub fn check_artifact_names<'a>(
+    artifacts: impl IntoIterator<Item = &'a Artifact>,
+) -> anyhow::Result<()> {
+    let mut names: HashSet<&str> = RESERVED_NAMES.into();
+    for artifact in artifacts {
+        anyhow::ensure!(
+            names.insert(&artifact.name),
+            "Artifact {:?} is named {:?} like another file of the summary",
+            artifact.path,
+            artifact.name,
+        );
+    }
+    Ok(())
 }
 
-pub fn write_game_log(config: &Config, game_log_path: impl AsRef<Path>, results: Results) {
-    let results_path = "results.json";
-    serde_json::to_writer_pretty(
-        std::fs::File::create(results_path).expect("Failed to create results file"),
-        &results,
-    )
-    .expect("Failed to write results");
+/// `game_log` has several files if the log was rotated, the viewer gets the first one
+pub fn write_game_log(
+    config: &Config,
+    game_log: &[PathBuf],
+    results_path: impl AsRef<Path>,
+    artifacts: &[Artifact],
+    results: Results,
+    format: json::Format,
+) -> anyhow::Result<()> {
+    json::write_atomic(&results_path, &results, format).context("Failed to write results")?;
 
     #[derive(Debug, Serialize)]
     struct File {
@@ -106,27 +323,124 @@ pub fn write_game_log(config: &Config, game_log_path: impl AsRef<Path>, results:
         is_private: bool,
     }
     impl File {
-        fn new(path: impl AsRef<Path>, private: bool) -> Self {
+        fn new(path: impl AsRef<Path>, private: bool) -> anyhow::Result<Self> {
             let path = path.as_ref();
-            Self {
-                filename: path.file_name().unwrap().to_str().unwrap().to_owned(),
-                location: path.canonicalize().unwrap(),
+            Ok(Self {
+                filename: path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .with_context(|| format!("{path:?} has no UTF-8 file name"))?
+                    .to_owned(),
+                location: path
+                    .canonicalize()
+                    .with_context(|| format!("Failed to find {path:?}"))?,
                 is_private: private,
-            }
+            })
         }
     }
     #[derive(Debug, Serialize)]
     struct Summary {
         visio: File,
         scores: File,
+        #[serde(flatten)]
+        artifacts: BTreeMap<String, File>,
     }
+    let artifacts = game_log[1..]
+        .iter()
+        .map(Artifact::public)
+        .chain(artifacts.iter().cloned().map(Ok))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    check_artifact_names(&artifacts)?;
     let results = Summary {
-        visio: File::new(game_log_path, false),
-        scores: File::new(results_path, false),
+        visio: File::new(&game_log[0], false)?,
+        scores: File::new(results_path, false)?,
+        artifacts: artifacts
+            .into_iter()
+            .map(|artifact| {
+                let file = File::new(&artifact.path, artifact.private)?;
+                Ok((artifact.name, file))
+            })
+            .collect::<anyhow::Result<_>>()?,
     };
-    serde_json::to_writer_pretty(
-        std::fs::File::create(&config.summary_path).expect("Failed to create summary file"),
-        &results,
-    )
-    .expect("Failed to write summary");
+    json::write(&config.summary_path, &results, format).context("Failed to write summary")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seats() {
+        let config = Config {
+            summary_path: PathBuf::new(),
+            time_to_run: None,
+            user_id_by_token: parse_clients(r#"{"1": "solo", "2": ["first", "second"]}"#).unwrap(),
+        };
+        assert_eq!(config.user_id_by_token.len(), 3);
+        let results: model::Results = [("solo", 10), ("first", 5), ("second", 7)]
+            .map(|(token, score)| (token.to_owned(), score))
+            .into();
+        let sums = config.user_results(results.clone(), SeatCombiner::Sum);
+        assert_eq!(sums, [(1, 10.0), (2, 12.0)].into());
+        let maxima = config.user_results(results, SeatCombiner::Max);
+        assert_eq!(maxima, [(1, 10.0), (2, 7.0)].into());
+
+        assert!(parse_clients(r#"{"1": 5}"#).is_err());
+    }
+
+    #[test]
+    fn test_artifacts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, "{}").unwrap();
+            path
+        };
+        let config = Config {
+            summary_path: dir.path().join("summary.json"),
+            time_to_run: None,
+            user_id_by_token: HashMap::new(),
+        };
+        let results = || Results {
+            players: None,
+            results: HashMap::new(),
+            seed: None,
+            incomplete: false,
+            log_digest: None,
+        };
+        let log = [path("game_log.0001.jsonl"), path("game_log.0002.jsonl")];
+        let notes = Artifact::private(path("notes.txt")).unwrap();
+        let write = |artifacts: &[Artifact]| {
+            let results_path = dir.path().join("results.json");
+            write_game_log(
+                &config,
+                &log,
+                results_path,
+                artifacts,
+                results(),
+                json::Format::Pretty,
+            )
+        };
+        write(std::slice::from_ref(&notes)).unwrap();
+        let summary: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&config.summary_path).unwrap()).unwrap();
+        assert_eq!(summary["visio"]["filename"], "game_log.0001.jsonl");
+        assert_eq!(summary["game_log.0002"]["is_private"], false);
+        assert_eq!(summary["notes"]["is_private"], true);
+
+        let other_notes = Artifact::private(path("notes.md")).unwrap();
+        let error = write(&[notes, other_notes]).unwrap_err();
+        assert!(error.to_string().contains("notes"), "{error}");
+        let scores = Artifact::private(path("scores.json")).unwrap();
+        assert!(write(&[scores]).is_err());
+        assert!(write(&[Artifact::private(dir.path().join("missing.txt")).unwrap()]).is_err());
+
+        assert!(Artifact::private("/").is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let invalid = std::ffi::OsStr::from_bytes(b"\xff.txt");
+            assert!(Artifact::private(invalid).is_err());
+        }
+    }
 }
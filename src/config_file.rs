@@ -0,0 +1,117 @@
+//! Reading config files in JSON, TOML or YAML, layered on top of each other with `extends`
+
+use anyhow::Context;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// Key naming the config a file is based on, relative to the file
+const EXTENDS: &str = "extends";
+
+/// Reads a config file (or JSON from stdin if the path is `-`), the format is chosen by
+/// the extension. The fields of the file override those of the config it extends.
+pub fn read(path: &Path) -> anyhow::Result<Value> {
+    read_layers(path, &mut Vec::new())
+}
+
+fn read_layers(path: &Path, seen: &mut Vec<PathBuf>) -> anyhow::Result<Value> {
+    if path.to_str() != Some("-") {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Failed to find config {path:?}"))?;
+        anyhow::ensure!(!seen.contains(&canonical), "Config {path:?} extends itself");
+        seen.push(canonical);
+    }
+    let mut config = parse(path).with_context(|| format!("Failed to read config {path:?}"))?;
+    let Some(base) = config
+        .as_object_mut()
+        .and_then(|config| config.remove(EXTENDS))
+    else {
+        return Ok(config);
+    };
+    let base = base
+        .as_str()
+        .with_context(|| format!("{EXTENDS:?} of {path:?} should be a path"))?;
+    let base = match path.parent() {
+        Some(dir) if path.to_str() != Some("-") => dir.join(base),
+        _ => base.into(),
+    };
+    let mut merged = read_layers(&base, seen)?;
+    merge(&mut merged, config);
+    Ok(merged)
+}
+
+fn parse(path: &Path) -> anyhow::Result<Value> {
+    if path.to_str() == Some("-") {
+        return Ok(serde_json::from_reader(std::io::stdin().lock())?);
+    }
+    let text = std::fs::read_to_string(path)?;
+    Ok(
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(&text)?,
+            Some("yaml" | "yml") => serde_yaml::from_str(&text)?,
+            _ => serde_json::from_str(&text)?,
+        },
+    )
+}
+
+/// Objects are merged field by field, anything else in the overrides replaces the base
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_extends() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("base.json"), include_str!("../config.json")).unwrap();
+        std::fs::create_dir(dir.path().join("stages")).unwrap();
+        std::fs::write(
+            dir.path().join("stages/final.toml"),
+            "extends = \"../base.json\"\n\
+             pipe_count = 3\n\
+             [rounds]\n\
+             count = 2\n\
+             duration_secs = 60\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("stages/final-fast.yaml"),
+            "extends: final.toml\ntime_scale: 0.5\nrounds:\n  count: 4\n",
+        )
+        .unwrap();
+
+        let config = read(&dir.path().join("stages/final-fast.yaml")).unwrap();
+        let base: Value = serde_json::from_str(include_str!("../config.json")).unwrap();
+        assert_eq!(config["pipe_count"], 3);
+        assert_eq!(config["time_scale"], 0.5);
+        assert_eq!(config["rounds"], json!({"count": 4, "duration_secs": 60}));
+        assert_eq!(config["min_value"], base["min_value"]);
+        assert!(config.get(EXTENDS).is_none());
+        assert!(itonecup_mobile::config_schema::validate(&config).is_empty());
+    }
+
+    #[test]
+    fn test_extends_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.yaml"), "extends: b.json\n").unwrap();
+        std::fs::write(dir.path().join("b.json"), r#"{"extends": "a.yaml"}"#).unwrap();
+        let error = read(&dir.path().join("a.yaml")).unwrap_err();
+        assert!(format!("{error:#}").contains("extends itself"), "{error:#}");
+    }
+}
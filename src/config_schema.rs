@@ -0,0 +1,331 @@
+//! JSON Schema of the game config and validation of config files against it
+//!
+//! Only the subset of JSON Schema used by `config.schema.json` and by the api description is
+//! supported.
+
+use serde_json::Value;
+
+pub const SCHEMA: &str = include_str!("../config.schema.json");
+
+fn type_matches(value: &Value, expected: &str) -> bool {
+    match expected {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => false,
+    }
+}
+
+/// Follows a local reference like `#/components/schemas/Name`
+fn resolve<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    let pointer = reference.strip_prefix('#')?;
+    root.pointer(pointer)
+}
+
+fn check(root: &Value, schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    if let Some(reference) = schema["$ref"].as_str() {
+        match resolve(root, reference) {
+            Some(schema) => check(root, schema, value, path, errors),
+            None => errors.push(format!("{path}: unresolved reference {reference}")),
+        }
+        return;
+    }
+    for schema in schema["allOf"].as_array().into_iter().flatten() {
+        check(root, schema, value, path, errors);
+    }
+    if let Some(variants) = schema["oneOf"].as_array().or(schema["anyOf"].as_array()) {
+        let matches = variants.iter().any(|variant| {
+            let mut variant_errors = Vec::new();
+            check(root, variant, value, path, &mut variant_errors);
+            variant_errors.is_empty()
+        });
+        if !matches {
+            errors.push(format!("{path}: matches none of the variants, got {value}"));
+            return;
+        }
+    }
+    match &schema["type"] {
+        Value::String(expected) if !type_matches(value, expected) => {
+            errors.push(format!("{path}: expected {expected}, got {value}"));
+            return;
+        }
+        Value::Array(expected)
+            if !expected
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|expected| type_matches(value, expected)) =>
+        {
+            errors.push(format!("{path}: expected one of {expected:?}, got {value}"));
+            return;
+        }
+        _ => {}
+    }
+    if let Some(variants) = schema["enum"].as_array() {
+        if !variants.contains(value) {
+            errors.push(format!("{path}: expected one of {variants:?}, got {value}"));
+        }
+    }
+    if let (Some(minimum), Some(number)) = (schema["minimum"].as_f64(), value.as_f64()) {
+        if number < minimum {
+            errors.push(format!("{path}: must be at least {minimum}, got {number}"));
+        }
+    }
+    if let (Some(minimum), Some(number)) = (schema["exclusiveMinimum"].as_f64(), value.as_f64()) {
+        if number <= minimum {
+            errors.push(format!(
+                "{path}: must be greater than {minimum}, got {number}"
+            ));
+        }
+    }
+    if let Some(object) = value.as_object() {
+        for required in schema["required"].as_array().into_iter().flatten() {
+            let required = required.as_str().unwrap_or_default();
+            if !object.contains_key(required) {
+                errors.push(format!("{path}: missing field {required:?}"));
+            }
+        }
+        for (key, field) in object {
+            let field_path = format!("{path}.{key}");
+            if schema.get("propertyNames").is_some() {
+                check(
+                    root,
+                    &schema["propertyNames"],
+                    &Value::String(key.clone()),
+                    &field_path,
+                    errors,
+                );
+            }
+            match (
+                schema["properties"].get(key),
+                &schema["additionalProperties"],
+            ) {
+                (Some(field_schema), _) => check(root, field_schema, field, &field_path, errors),
+                (None, Value::Bool(false)) => errors.push(format!("{field_path}: unknown field")),
+                (None, field_schema @ Value::Object(_)) => {
+                    check(root, field_schema, field, &field_path, errors)
+                }
+                (None, _) => {}
+            }
+        }
+    }
+    if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+        for (index, item) in array.iter().enumerate() {
+            check(root, items, item, &format!("{path}[{index}]"), errors);
+        }
+    }
+}
+
+/// Returns a description of every place where the config does not match the schema
+pub fn validate(config: &Value) -> Vec<String> {
+    let schema: Value = serde_json::from_str(SCHEMA).expect("Failed to parse config schema");
+    validate_against(&schema, &schema, config)
+}
+
+/// Same as [`validate`] for any schema, `root` is the document its references point into
+pub fn validate_against(root: &Value, schema: &Value, value: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    check(root, schema, value, "$", &mut errors);
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config_is_valid() {
+        let config: Value = serde_json::from_str(include_str!("../config.json")).unwrap();
+        assert_eq!(validate(&config), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_error_paths() {
+        let mut config: Value = serde_json::from_str(include_str!("../config.json")).unwrap();
+        config["pipe_count"] = "three".into();
+        config["market_events"] = serde_json::json!([{
+            "modifier": "teleport",
+            "cost_multiplier": 0.5,
+            "start_secs": 10,
+        }]);
+        config["pipe_cuont"] = 3.into();
+        let errors = validate(&config);
+        assert_eq!(errors.len(), 4, "{errors:#?}");
+        assert!(errors[0].starts_with("$.market_events[0]: missing field \"duration_secs\""));
+        assert!(errors[1].starts_with("$.market_events[0].modifier: expected one of"));
+        assert!(errors[2].starts_with("$.pipe_count: expected integer"));
+        assert!(errors[3].starts_with("$.pipe_cuont: unknown field"));
+    }
+
+    #[test]
+    fn test_config_can_be_played() {
+        let config: Value = serde_json::from_str(include_str!("../config.json")).unwrap();
+        let config: crate::model::Config = serde_json::from_value(config).unwrap();
+        assert_eq!(config.validate(), Vec::new());
+        assert_eq!(crate::model::Config::default().validate(), Vec::new());
+
+        let config = crate::model::Config {
+            pipe_count: 0,
+            min_value: 10,
+            max_value: 5,
+            min_delay_secs: 2.0,
+            max_delay_secs: 1.0,
+            shuffle_cost: -1,
+            steal_ratio: 1.5,
+            pipe_events_secs: Some(0.0),
+            ..config
+        };
+        let fields: Vec<_> = config.validate().iter().map(|error| error.field).collect();
+        assert_eq!(
+            fields,
+            [
+                "pipe_count",
+                "min_value",
+                "shuffle_cost",
+                "min_delay_secs",
+                "pipe_events_secs",
+                "steal_ratio",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_multipliers() {
+        use crate::model::{
+            Config, GlobalEffect, GlobalEvents, MarketEvent, Modifier, Phase, UserToken,
+        };
+        fn effect(change: impl FnOnce(&mut GlobalEffect)) -> impl FnOnce(&mut Config) {
+            move |config| {
+                let mut effect = GlobalEffect {
+                    name: "storm".to_owned(),
+                    duration_secs: 10.0,
+                    value_multiplier: 1.0,
+                    delay_multiplier: 1.0,
+                    weight: 1.0,
+                };
+                change(&mut effect);
+                config.global_events = Some(GlobalEvents {
+                    mean_interval_secs: 60.0,
+                    effects: vec![effect],
+                });
+            }
+        }
+        fn phase(change: impl FnOnce(&mut Phase)) -> impl FnOnce(&mut Config) {
+            move |config| {
+                let mut phase = Phase {
+                    name: "rush".to_owned(),
+                    start_secs: 10.0,
+                    cost_multiplier: 1.0,
+                    value_multiplier: 1.0,
+                };
+                change(&mut phase);
+                config.phases = vec![phase];
+            }
+        }
+        fn market_event(cost_multiplier: f64) -> impl FnOnce(&mut Config) {
+            move |config| {
+                config.market_events = vec![MarketEvent {
+                    modifier: Modifier::Double,
+                    cost_multiplier,
+                    start_secs: 10.0,
+                    duration_secs: 10.0,
+                }];
+            }
+        }
+        fn handicap(multiplier: f64) -> impl FnOnce(&mut Config) {
+            move |config| {
+                config.handicaps = [(UserToken::from("player".to_owned()), multiplier)].into();
+            }
+        }
+        type Change = Box<dyn FnOnce(&mut Config)>;
+        let cases: Vec<(&str, Change)> = vec![
+            (
+                "global_events",
+                Box::new(effect(|e| e.delay_multiplier = -1.0)),
+            ),
+            (
+                "global_events",
+                Box::new(effect(|e| e.delay_multiplier = 0.0)),
+            ),
+            (
+                "global_events",
+                Box::new(effect(|e| e.delay_multiplier = f64::NAN)),
+            ),
+            (
+                "global_events",
+                Box::new(effect(|e| e.value_multiplier = -1.0)),
+            ),
+            ("global_events", Box::new(effect(|e| e.weight = f64::NAN))),
+            (
+                "global_events",
+                Box::new(effect(|e| e.duration_secs = -1.0)),
+            ),
+            ("phases", Box::new(phase(|p| p.cost_multiplier = -0.5))),
+            (
+                "phases",
+                Box::new(phase(|p| p.value_multiplier = f64::INFINITY)),
+            ),
+            ("phases", Box::new(phase(|p| p.start_secs = f64::NAN))),
+            ("market_events", Box::new(market_event(-1.0))),
+            ("market_events", Box::new(market_event(f64::NAN))),
+            ("handicaps", Box::new(handicap(-2.0))),
+            ("handicaps", Box::new(handicap(f64::NAN))),
+        ];
+        for (i, (field, change)) in cases.into_iter().enumerate() {
+            let mut config = Config::default();
+            change(&mut config);
+            let fields: Vec<_> = config.validate().iter().map(|error| error.field).collect();
+            assert_eq!(fields, [field], "case {i}");
+        }
+        // Zero is fine where it can't break anything
+        let mut config = Config::default();
+        effect(|e| e.value_multiplier = 0.0)(&mut config);
+        phase(|p| p.cost_multiplier = 0.0)(&mut config);
+        handicap(0.0)(&mut config);
+        assert_eq!(config.validate(), Vec::new());
+    }
+
+    #[test]
+    fn test_webhook_urls() {
+        let config = crate::model::Config {
+            webhooks: [(
+                crate::model::UserToken::from("player".to_owned()),
+                "https://example.com/hook".to_owned(),
+            )]
+            .into(),
+            idle_webhook: Some("example.com/idle".to_owned()),
+            ..Default::default()
+        };
+        let fields: Vec<_> = config.validate().iter().map(|error| error.field).collect();
+        assert_eq!(fields, ["webhooks", "idle_webhook"]);
+    }
+
+    #[test]
+    fn test_references() {
+        let root = serde_json::json!({
+            "components": {"schemas": {
+                "Score": {"oneOf": [{"type": "integer"}, {"type": "string"}]},
+                "User": {
+                    "type": "object",
+                    "required": ["score"],
+                    "properties": {"score": {"$ref": "#/components/schemas/Score"}},
+                },
+            }},
+        });
+        let schema = serde_json::json!({"$ref": "#/components/schemas/User"});
+        let check = |value| validate_against(&root, &schema, &value);
+        assert!(check(serde_json::json!({"score": 5})).is_empty());
+        assert!(check(serde_json::json!({"score": "5"})).is_empty());
+        let errors = check(serde_json::json!({"score": 0.5}));
+        assert!(errors[0].starts_with("$.score: matches none of the variants"));
+        let missing = serde_json::json!({"$ref": "#/components/schemas/Missing"});
+        let errors = validate_against(&root, &missing, &Value::Null);
+        assert_eq!(
+            errors,
+            ["$: unresolved reference #/components/schemas/Missing"]
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Public demo server where anyone can try the api, restarted with a fresh game regularly
+
+use itonecup_mobile::{model, server};
+use std::{net::SocketAddr, time::Duration};
+use tracing::info;
+
+#[derive(Debug, Clone, clap::Args)]
+#[group(id = "demo_options")]
+pub struct Options {
+    /// Run a public demo: anyone may play, requests are limited per ip and the game restarts
+    /// every --demo-reset-mins until ended with Ctrl-C or the admin api
+    #[clap(long = "demo")]
+    pub enabled: bool,
+    #[clap(long = "demo-reset-mins", default_value = "10")]
+    pub reset_mins: f64,
+    #[clap(long = "demo-max-requests-per-minute", default_value = "120")]
+    pub max_requests_per_minute: usize,
+    /// Scores are capped so that nobody stays on top for long
+    #[clap(long = "demo-max-score", default_value = "10000")]
+    pub max_score: model::Score,
+}
+
+impl Options {
+    /// Overrides the parts of the config that would not be safe in public
+    pub fn apply(&self, config: &mut model::Config) {
+        config.allow_unknown_users = true;
+        config.time_to_run = Some(self.reset_mins * 60.0);
+        // Rounds and overtime would move the reset
+        config.rounds = None;
+        config.overtime_secs = None;
+        let limit = config
+            .max_requests_per_ip_per_minute
+            .map_or(self.max_requests_per_minute, |limit| {
+                limit.min(self.max_requests_per_minute)
+            });
+        config.max_requests_per_ip_per_minute = Some(limit);
+        config.max_user_score = Some(
+            config
+                .max_user_score
+                .map_or(self.max_score, |max| max.min(self.max_score)),
+        );
+    }
+}
+
+/// Plays games back to back, users and scores start from scratch in every game
+pub async fn run(
+    addrs: &[SocketAddr],
+    config: model::Config,
+    server_options: impl Fn() -> server::Options,
+) -> anyhow::Result<()> {
+    loop {
+        let app = model::App::init(config.clone(), Vec::new());
+        let time_to_run = app.config().time_to_run.map(Duration::from_secs_f64);
+        let app = server::run(addrs, app, time_to_run, server_options()).await?;
+        info!("Demo game finished: {:#?}", app.results().await);
+        if app.is_ended_early() {
+            info!("Demo stopped");
+            return Ok(());
+        }
+        info!("Starting a new demo game");
+    }
+}
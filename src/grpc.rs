@@ -0,0 +1,411 @@
+//! gRPC variant of the game api for clients generated from `proto/game.proto`,
+//! served next to the HTTP server and sharing its game state
+
+use crate::{
+    model::{self, PipeId, UserToken},
+    server::LogsQuery,
+    viewer_auth::{Scope, ViewerAuth},
+};
+use futures::{Stream, StreamExt};
+use std::{
+    borrow::Borrow,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{metadata::MetadataValue, Code, Request, Response, Status};
+use tracing::{info, warn};
+
+pub mod proto {
+    tonic::include_proto!("game");
+}
+
+use proto::game_server::{Game, GameServer};
+
+/// Serves the gRPC api until the listener fails
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<model::App>,
+    enable_logs: bool,
+    viewer_auth: Option<Arc<ViewerAuth>>,
+) -> anyhow::Result<()> {
+    let service = GameService {
+        state,
+        enable_logs,
+        viewer_auth,
+    };
+    tonic::transport::Server::builder()
+        .add_service(GameServer::new(service))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await?;
+    Ok(())
+}
+
+struct GameService {
+    state: Arc<model::App>,
+    enable_logs: bool,
+    viewer_auth: Option<Arc<ViewerAuth>>,
+}
+
+fn bearer_token<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get("authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+// Status is what the service methods return anyway
+#[allow(clippy::result_large_err)]
+fn pipe_id(pipe: u64) -> Result<PipeId, Status> {
+    usize::try_from(pipe)
+        .ok()
+        .and_then(PipeId::new)
+        .ok_or_else(|| Status::invalid_argument("Pipe ids start from 1"))
+}
+
+/// Game errors keep their code in the `game-error` metadata and their details as json
+/// in `game-error-details`, like the `code` and `details` fields over HTTP
+fn error_status(error: model::Error) -> Status {
+    let code = match error.code() {
+        model::ErrorCode::UserNotFound => Code::Unauthenticated,
+        model::ErrorCode::TooManyAuthFailures
+        | model::ErrorCode::ServerOverloaded
+        | model::ErrorCode::RateLimited
+        | model::ErrorCode::Cooldown
+        | model::ErrorCode::MisconductLockout => Code::ResourceExhausted,
+        model::ErrorCode::UserBusy
+        | model::ErrorCode::Bankrupt
+        | model::ErrorCode::SpectatorOnly
+        | model::ErrorCode::TokenBoundToOtherIp => Code::PermissionDenied,
+        model::ErrorCode::PipeNotFound | model::ErrorCode::OperationNotFound => Code::NotFound,
+        model::ErrorCode::ActionAborted => Code::Aborted,
+        model::ErrorCode::GamePaused => Code::Unavailable,
+        model::ErrorCode::PipeLocked
+        | model::ErrorCode::PipeNotAssigned
+        | model::ErrorCode::PipeShielded
+        | model::ErrorCode::NotEnoughScore
+        | model::ErrorCode::ModifierAlreadyApplied
+        | model::ErrorCode::ModifierDisabled
+        | model::ErrorCode::IdempotencyKeyReused
+        | model::ErrorCode::GameOver => Code::FailedPrecondition,
+    };
+    let mut status = Status::new(code, error.to_string());
+    let name = MetadataValue::try_from(format!("{:?}", error.code())).unwrap();
+    status.metadata_mut().insert("game-error", name);
+    let details = serde_json::to_string(&error.details()).unwrap_or_default();
+    if let Ok(details) = MetadataValue::try_from(details) {
+        status.metadata_mut().insert("game-error-details", details);
+    }
+    if let Some(retry_after) = error.retry_after() {
+        let seconds = MetadataValue::from(retry_after.as_secs_f64().ceil() as u64);
+        status.metadata_mut().insert("retry-after", seconds);
+    }
+    status
+}
+
+impl GameService {
+    /// Authorizes the user the same way as the HTTP api and waits for the game to start
+    async fn authorize<T>(&self, request: &Request<T>) -> Result<UserToken, Status> {
+        let token: UserToken = bearer_token(request)
+            .ok_or_else(|| Status::unauthenticated("Bearer token required"))?
+            .to_owned()
+            .into();
+        let ip = request.remote_addr().map(|addr| addr.ip());
+        self.state
+            .check_token_request_rate(&token)
+            .map_err(error_status)?;
+        self.state
+            .authenticate(&token, ip)
+            .await
+            .map_err(error_status)?;
+        self.state.wait_for_start().await;
+        Ok(token)
+    }
+
+    /// Records the call in the endpoint metrics and turns game errors into statuses
+    async fn respond<R>(
+        &self,
+        endpoint: &str,
+        user: &UserToken,
+        start: Instant,
+        result: model::Result<R>,
+    ) -> Result<Response<R>, Status> {
+        let error = result
+            .as_ref()
+            .err()
+            .map(|error| format!("{:?}", error.code()));
+        let token: &String = user.borrow();
+        self.state.metrics().record(
+            &format!("grpc {endpoint}"),
+            Some(token),
+            error.as_deref(),
+            start.elapsed(),
+        );
+        let error = match result {
+            Ok(response) => return Ok(Response::new(response)),
+            Err(error) => error,
+        };
+        Err(error_status(error))
+    }
+}
+
+#[tonic::async_trait]
+impl Game for GameService {
+    async fn collect(
+        &self,
+        request: Request<proto::PipeRequest>,
+    ) -> Result<Response<proto::CollectResponse>, Status> {
+        let start = Instant::now();
+        let pipe_id = pipe_id(request.get_ref().pipe)?;
+        let user = self.authorize(&request).await?;
+        let result =
+            self.state
+                .collect(&user, pipe_id)
+                .await
+                .map(|response| proto::CollectResponse {
+                    value: response.value,
+                    refund: response.refund,
+                });
+        self.respond("Collect", &user, start, result).await
+    }
+
+    async fn pipe_value(
+        &self,
+        request: Request<proto::PipeRequest>,
+    ) -> Result<Response<proto::PipeValueResponse>, Status> {
+        let start = Instant::now();
+        let pipe_id = pipe_id(request.get_ref().pipe)?;
+        let user = self.authorize(&request).await?;
+        let result =
+            self.state
+                .pipe_value(&user, pipe_id)
+                .await
+                .map(|response| proto::PipeValueResponse {
+                    value: response.value,
+                });
+        self.respond("PipeValue", &user, start, result).await
+    }
+
+    async fn apply_modifier(
+        &self,
+        request: Request<proto::ApplyModifierRequest>,
+    ) -> Result<Response<proto::ApplyModifierResponse>, Status> {
+        let start = Instant::now();
+        let pipe_id = pipe_id(request.get_ref().pipe)?;
+        let modifier = match request.get_ref().modifier() {
+            proto::Modifier::Unspecified => {
+                return Err(Status::invalid_argument("Modifier is required"))
+            }
+            proto::Modifier::Slow => model::Modifier::Slow,
+            proto::Modifier::Double => model::Modifier::Double,
+            proto::Modifier::Min => model::Modifier::Min,
+            proto::Modifier::Shuffle => model::Modifier::Shuffle,
+            proto::Modifier::Reverse => model::Modifier::Reverse,
+            proto::Modifier::Insurance => model::Modifier::Insurance,
+            proto::Modifier::Shield => model::Modifier::Shield,
+            proto::Modifier::Steal => model::Modifier::Steal,
+        };
+        let user = self.authorize(&request).await?;
+        let result = self
+            .state
+            .apply_modifier(&user, pipe_id, modifier)
+            .await
+            .map(|_| proto::ApplyModifierResponse {});
+        self.respond("ApplyModifier", &user, start, result).await
+    }
+
+    type LogsStream = LogsStream;
+
+    async fn logs(
+        &self,
+        request: Request<proto::LogsRequest>,
+    ) -> Result<Response<LogsStream>, Status> {
+        if !self.enable_logs {
+            return Err(Status::unimplemented("Logs api is disabled"));
+        }
+        if let Some(viewer_auth) = &self.viewer_auth {
+            if !bearer_token(&request).is_some_and(|token| viewer_auth.verify(token, Scope::Logs)) {
+                warn!("Rejected gRPC logs subscription without a valid viewer token");
+                return Err(Status::permission_denied("Viewer token required"));
+            }
+        }
+        let peer = request.remote_addr().map(|addr| addr.to_string());
+        let request = request.into_inner();
+        let query = LogsQuery {
+            history: request.history,
+            user: request.user,
+            types: (!request.types.is_empty()).then(|| request.types.join(",")),
+            overflow: None,
+        };
+        let subscriber = self.state.metrics().subscriber_connected(peer.clone());
+        info!(
+            "gRPC logs subscriber {subscriber} connected from {}",
+            peer.as_deref().unwrap_or("unknown address")
+        );
+        let (sender, receiver) = self.state.log_channel(query.overflow);
+        let state = self.state.clone();
+        let history = query.history;
+        let registered = sender.clone();
+        tokio::spawn(async move {
+            if history {
+                state.register_logs(registered).await;
+            } else {
+                state.register_live_logs(registered).await;
+            }
+        });
+        Ok(Response::new(LogsStream {
+            state: self.state.clone(),
+            sender,
+            receiver,
+            query,
+            subscriber,
+        }))
+    }
+}
+
+/// Log entries of a `Logs` call, unsubscribed when the client goes away
+pub struct LogsStream {
+    state: Arc<model::App>,
+    sender: model::LogSubscriber,
+    receiver: model::LogReceiver,
+    query: LogsQuery,
+    /// Id of the subscriber in the metrics
+    subscriber: u64,
+}
+
+impl Stream for LogsStream {
+    type Item = Result<proto::LogEntry, Status>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let Some(entry) = futures::ready!(self.receiver.poll_next_unpin(cx)) else {
+                return Poll::Ready(None);
+            };
+            if !self.query.matches(&entry) {
+                continue;
+            }
+            // Scores are plain numbers in the json, like in the saved game log
+            let msg = match serde_json::to_string(&entry.msg) {
+                Ok(msg) => msg,
+                Err(e) => return Poll::Ready(Some(Err(Status::internal(e.to_string())))),
+            };
+            self.state
+                .metrics()
+                .record_subscriber_message(self.subscriber, msg.len());
+            return Poll::Ready(Some(Ok(proto::LogEntry {
+                time: entry.time,
+                msg,
+            })));
+        }
+    }
+}
+
+impl Drop for LogsStream {
+    fn drop(&mut self) {
+        if let Some(stats) = self
+            .state
+            .metrics()
+            .subscriber_disconnected(self.subscriber)
+        {
+            info!(
+                "gRPC logs subscriber {} disconnected after {:.1}s, sent {} bytes in {} messages",
+                stats.id, stats.duration, stats.bytes_sent, stats.messages_sent,
+            );
+        }
+        let state = self.state.clone();
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            state.unregister_logs(sender).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{GameServer, Options};
+    use proto::game_client::GameClient;
+    use std::{net::SocketAddr, time::Duration};
+
+    fn authorized<T>(message: T, token: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        let value = format!("Bearer {token}").parse().unwrap();
+        request.metadata_mut().insert("authorization", value);
+        request
+    }
+
+    #[actix_web::test]
+    async fn test_grpc() {
+        crate::logger::init_for_tests();
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                pipe_value_delay_secs: 0.0,
+                min_value: 100,
+                max_value: 100,
+                ..Default::default()
+            })
+            .users([UserToken::from("player".to_owned())])
+            .options(Options {
+                enable_logs_api: true,
+                grpc_addr: Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.grpc_addr().unwrap();
+        let mut client = GameClient::connect(format!("http://{addr}")).await.unwrap();
+
+        let pipe = proto::PipeRequest { pipe: 1 };
+        let error = client.collect(pipe).await.unwrap_err();
+        assert_eq!(error.code(), Code::Unauthenticated);
+        let error = client
+            .collect(authorized(pipe, "stranger"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), Code::Unauthenticated);
+        assert_eq!(error.metadata().get("game-error").unwrap(), "UserNotFound");
+
+        let mut logs = client
+            .logs(proto::LogsRequest {
+                history: false,
+                user: Some("player".to_owned()),
+                types: vec!["CollectEnd".to_owned()],
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        // Give the subscription time to get registered
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+
+        let value = client.pipe_value(authorized(pipe, "player")).await.unwrap();
+        assert_eq!(value.get_ref().value, 100);
+        let collected = client.collect(authorized(pipe, "player")).await.unwrap();
+        assert_eq!(collected.get_ref().value, 100);
+        let error = client
+            .apply_modifier(authorized(
+                proto::ApplyModifierRequest {
+                    pipe: 1,
+                    modifier: proto::Modifier::Unspecified.into(),
+                },
+                "player",
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), Code::InvalidArgument);
+
+        let entry = logs.message().await.unwrap().unwrap();
+        let msg: serde_json::Value = serde_json::from_str(&entry.msg).unwrap();
+        assert_eq!(msg["type"], "CollectEnd");
+        assert_eq!(msg["user"], "player");
+        drop(logs);
+        server.stop().await.unwrap();
+    }
+}
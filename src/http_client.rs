@@ -0,0 +1,313 @@
+//! Minimal blocking http client for the command line tools talking to a running server.
+//!
+//! Only plain `http://` is supported, the server closes connections after every response.
+
+use anyhow::Context;
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+use tungstenite::{
+    client::IntoClientRequest, http::header::AUTHORIZATION, protocol::WebSocketConfig, Message,
+};
+
+/// Splits `http://host:port/path` into the part accepted by [`Client::new`] and the path
+pub fn split_url(url: &str) -> (&str, &str) {
+    let url = url.trim_start_matches("http://");
+    match url.find('/') {
+        Some(index) => url.split_at(index),
+        None => (url, "/"),
+    }
+}
+
+pub struct Client {
+    host: String,
+    token: Option<String>,
+    timeout: Option<Duration>,
+}
+
+pub struct Response {
+    pub status: u16,
+    /// Header names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Client {
+    /// Accepts `http://host:port` as well as just `host:port`
+    pub fn new(url: &str, token: Option<String>) -> Self {
+        let host = url.trim_start_matches("http://").trim_end_matches('/');
+        Self {
+            host: host.to_owned(),
+            token,
+            timeout: None,
+        }
+    }
+
+    /// Limits connecting and every read and write, by default they may block forever
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn connect(&self) -> anyhow::Result<TcpStream> {
+        let connect = || {
+            let Some(timeout) = self.timeout else {
+                return TcpStream::connect(&self.host);
+            };
+            let addr = self.host.to_socket_addrs()?.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "No address for the host")
+            })?;
+            let stream = TcpStream::connect_timeout(&addr, timeout)?;
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            Ok(stream)
+        };
+        connect().with_context(|| format!("Failed to connect to {}", self.host))
+    }
+
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> anyhow::Result<Response> {
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        self.request_with_headers(method, path, &[], &body)
+    }
+
+    pub fn request_with_headers(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> anyhow::Result<Response> {
+        let mut stream = self.connect()?;
+        let mut request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            self.host,
+            body.len(),
+        );
+        if !body.is_empty() {
+            request.push_str("Content-Type: application/json\r\n");
+        }
+        if let Some(token) = &self.token {
+            request.push_str(&format!("Authorization: Bearer {token}\r\n"));
+        }
+        for (name, value) in headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .context("Malformed http response")?;
+        let mut lines = head.lines();
+        let status = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse().ok())
+            .context("Malformed http status line")?;
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_owned()))
+            .collect();
+        Ok(Response {
+            status,
+            headers,
+            body: body.to_owned(),
+        })
+    }
+}
+
+/// Longest websocket message accepted, the largest log entries are far below it
+const MAX_MESSAGE_SIZE: usize = 16 << 20;
+
+/// Client side of a websocket connection exchanging text messages
+pub struct WebSocket {
+    socket: tungstenite::WebSocket<TcpStream>,
+}
+
+impl Client {
+    pub fn websocket(&self, path: &str) -> anyhow::Result<WebSocket> {
+        let stream = self.connect()?;
+        let mut request = format!("ws://{}{path}", self.host).into_client_request()?;
+        if let Some(token) = &self.token {
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, format!("Bearer {token}").parse()?);
+        }
+        let config = WebSocketConfig {
+            max_message_size: Some(MAX_MESSAGE_SIZE),
+            max_frame_size: Some(MAX_MESSAGE_SIZE),
+            ..Default::default()
+        };
+        let (socket, _) = tungstenite::client::client_with_config(request, stream, Some(config))
+            .map_err(|e| anyhow::anyhow!("Websocket handshake failed: {e}"))?;
+        Ok(WebSocket { socket })
+    }
+}
+
+impl WebSocket {
+    pub fn send(&mut self, message: &str) -> anyhow::Result<()> {
+        self.socket.send(Message::text(message))?;
+        Ok(())
+    }
+
+    /// Next text message, `None` when the connection is closed
+    pub fn receive(&mut self) -> anyhow::Result<Option<String>> {
+        loop {
+            match self.socket.read() {
+                Ok(Message::Text(message)) => return Ok(Some(message)),
+                Ok(Message::Close(_)) => {
+                    // Sends the queued reply to the close frame, the server may be gone already
+                    self.socket.flush().ok();
+                    return Ok(None);
+                }
+                // Pings are answered by tungstenite on the next read or write
+                Ok(_) => {}
+                Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                    return Ok(None)
+                }
+                Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(None)
+                }
+                Err(tungstenite::Error::Protocol(
+                    tungstenite::error::ProtocolError::ResetWithoutClosingHandshake,
+                )) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        thread,
+    };
+
+    /// Serves a single connection on a free port, returns the url to connect to
+    fn serve_once(handle: impl FnOnce(TcpStream) + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || handle(listener.accept().unwrap().0));
+        url
+    }
+
+    #[test]
+    fn test_request() {
+        let url = serve_once(|mut stream| {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push(line.trim_end().to_owned());
+            }
+            let mut body = vec![0; 12];
+            reader.read_exact(&mut body).unwrap();
+            assert_eq!(head[0], "POST /api/pipe/1/modifier HTTP/1.1");
+            assert!(head.contains(&"Authorization: Bearer secret".to_owned()));
+            assert!(head.contains(&"Content-Type: application/json".to_owned()));
+            assert_eq!(body, br#"{"type":"x"}"#);
+            stream
+                .write_all(b"HTTP/1.1 409 Conflict\r\nRetry-After: 2\r\n\r\n{\"error\":1}")
+                .unwrap();
+        });
+        let response = Client::new(&url, Some("secret".to_owned()))
+            .request(
+                "POST",
+                "/api/pipe/1/modifier",
+                Some(&serde_json::json!({"type": "x"})),
+            )
+            .unwrap();
+        assert_eq!(response.status, 409);
+        assert_eq!(response.header("retry-after"), Some("2"));
+        assert_eq!(response.body, r#"{"error":1}"#);
+    }
+
+    #[test]
+    fn test_websocket_messages() {
+        let url = serve_once(|stream| {
+            let mut socket = tungstenite::accept(stream).unwrap();
+            assert_eq!(socket.read().unwrap(), Message::text("hello"));
+            socket.send(Message::Ping(b"ping".to_vec())).unwrap();
+            socket.send(Message::text("first")).unwrap();
+            socket.send(Message::text("second")).unwrap();
+            socket.close(None).unwrap();
+            // The client answers the ping and the close frame
+            assert_eq!(socket.read().unwrap(), Message::Pong(b"ping".to_vec()));
+            while socket.read().is_ok() {}
+        });
+        let mut websocket = Client::new(&url, None).websocket("/logs").unwrap();
+        websocket.send("hello").unwrap();
+        assert_eq!(websocket.receive().unwrap().as_deref(), Some("first"));
+        assert_eq!(websocket.receive().unwrap().as_deref(), Some("second"));
+        assert_eq!(websocket.receive().unwrap(), None);
+    }
+
+    #[test]
+    fn test_websocket_accept_checked() {
+        let url = serve_once(|mut stream| {
+            let mut request = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while reader.read_line(&mut request).unwrap() > 2 {}
+            // The accept value for the key from the RFC, not for the one sent
+            stream
+                .write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                      Connection: Upgrade\r\n\
+                      Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+                )
+                .unwrap();
+        });
+        let error = Client::new(&url, None).websocket("/logs").err().unwrap();
+        assert!(
+            error.to_string().contains("Websocket handshake failed"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_websocket_frame_limit() {
+        let url = serve_once(|stream| {
+            let mut socket = tungstenite::accept(stream).unwrap();
+            // A text frame claiming to be 1 TiB long, nothing follows
+            let mut head = vec![0x81, 127];
+            head.extend_from_slice(&(1u64 << 40).to_be_bytes());
+            socket.get_mut().write_all(&head).unwrap();
+            thread::sleep(Duration::from_secs(1));
+        });
+        let mut websocket = Client::new(&url, None).websocket("/logs").unwrap();
+        let error = websocket.receive().unwrap_err();
+        assert!(error.to_string().contains("too long"), "{error}");
+    }
+}
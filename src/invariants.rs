@@ -0,0 +1,198 @@
+//! Randomized games played by concurrent bots on the virtual clock, checking that the
+//! score bookkeeping holds up under every interleaving the seeds happen to produce.
+//! A failing seed is printed and plays out the same way when run again.
+
+use crate::model::{
+    self, App, Config, LogEntry, LogMessage, Modifier, PipeId, ScoreReason, UserToken,
+};
+use futures::{channel::mpsc, future::join_all};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{collections::HashMap, time::Duration};
+use tokio::time::{sleep, timeout};
+
+const SEEDS: u64 = 40;
+const USERS: usize = 4;
+/// Bots playing as the same user at once, so that actions of a user overlap
+const BOTS_PER_USER: usize = 2;
+const ACTIONS_PER_BOT: usize = 30;
+
+fn config(seed: u64) -> Config {
+    Config {
+        seed: Some(seed),
+        initial_score: 200,
+        min_delay_secs: 0.1,
+        max_delay_secs: 1.0,
+        pipe_value_delay_secs: 0.1,
+        peek_delay_secs: 0.1,
+        start_delay_secs: 0.0,
+        time_to_run: None,
+        ..Default::default()
+    }
+}
+
+/// Outcomes of the actions of a user that succeeded, as seen by the bots
+#[derive(Default)]
+struct Outcomes {
+    collected: Vec<model::Score>,
+    modifiers: Vec<Modifier>,
+}
+
+async fn play_bot(app: &App, user: &UserToken, seed: u64) -> Outcomes {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut outcomes = Outcomes::default();
+    for _ in 0..ACTIONS_PER_BOT {
+        sleep(Duration::from_secs_f64(rng.gen_range(0.0..0.5))).await;
+        let pipe_id = PipeId::new(rng.gen_range(1..=app.config().pipe_count)).unwrap();
+        match rng.gen_range(0..10) {
+            0..=3 => {
+                if let Ok(response) = app.collect(user, pipe_id).await {
+                    outcomes.collected.push(response.value + response.refund);
+                }
+            }
+            4 => {
+                // The client goes away at some point, possibly before the collect is over
+                let gone = sleep(Duration::from_secs_f64(rng.gen_range(0.0..2.0)));
+                if let Ok(response) = app.collect_until(user, pipe_id, gone).await {
+                    outcomes.collected.push(response.value + response.refund);
+                }
+            }
+            5..=7 => {
+                let modifier = Modifier::ALL[rng.gen_range(0..Modifier::ALL.len())];
+                if app.apply_modifier(user, pipe_id, modifier).await.is_ok() {
+                    outcomes.modifiers.push(modifier);
+                }
+            }
+            8 => {
+                let _ = app.peek(user, pipe_id).await;
+            }
+            _ => {
+                let _ = app.pipe_value(user, pipe_id).await;
+            }
+        }
+    }
+    outcomes
+}
+
+/// Plays a whole game, panicking with the seed if an invariant is broken
+async fn check_seed(seed: u64) {
+    let users: Vec<UserToken> = (0..USERS)
+        .map(|i| UserToken::from(format!("user{i}")))
+        .collect();
+    let app = App::init(config(seed), users.clone());
+    let (sender, mut logs) = mpsc::unbounded();
+    app.register_logs(sender).await;
+
+    let bots = users.iter().enumerate().flat_map(|(i, user)| {
+        let app = &app;
+        (0..BOTS_PER_USER).map(move |bot| async move {
+            let bot_seed = seed * 1000 + (i * BOTS_PER_USER + bot) as u64;
+            (user, play_bot(app, user, bot_seed).await)
+        })
+    });
+    // Virtual time only runs out if every bot is stuck waiting for a lock
+    let Ok(results) = timeout(Duration::from_secs(24 * 3600), join_all(bots)).await else {
+        panic!("Seed {seed}: the bots deadlocked");
+    };
+    let mut outcomes: HashMap<&UserToken, Outcomes> = HashMap::new();
+    for (user, result) in results {
+        let outcome = outcomes.entry(user).or_default();
+        outcome.collected.extend(result.collected);
+        outcome.modifiers.extend(result.modifiers);
+    }
+
+    let mut entries: Vec<LogEntry> = Vec::new();
+    while let Ok(entry) = logs.try_recv() {
+        entries.push(entry);
+    }
+    for user in &users {
+        let outcome = outcomes.remove(user).unwrap_or_default();
+        check_user(&app, &entries, outcome, user, seed).await;
+    }
+    for entry in &entries {
+        if let LogMessage::UpdatePipe { id, state } = &entry.msg {
+            assert!(
+                state.modifiers.values().all(|&uses| uses > 0),
+                "Seed {seed}: pipe {id} kept a used up modifier: {:?}",
+                state.modifiers,
+            );
+        }
+    }
+}
+
+async fn check_user(
+    app: &App,
+    entries: &[LogEntry],
+    mut outcome: Outcomes,
+    user: &UserToken,
+    seed: u64,
+) {
+    let mut total = 0;
+    let mut collected = Vec::new();
+    let mut modifier_spending = 0;
+    let mut modifier_purchases = 0;
+    let mut stolen = 0;
+    for entry in entries {
+        match &entry.msg {
+            LogMessage::UpdateUser {
+                user: changed,
+                change: Some(change),
+                ..
+            } if changed == user => {
+                total += change.delta;
+                match change.reason {
+                    ScoreReason::Collect { .. } => collected.push(change.delta),
+                    ScoreReason::Modifier { .. } => {
+                        modifier_spending -= change.delta;
+                        modifier_purchases += 1;
+                    }
+                    ScoreReason::Steal { .. } => stolen -= change.delta,
+                    _ => {}
+                }
+            }
+            LogMessage::ScoreStolen {
+                user: thief,
+                amount,
+                ..
+            } if thief == user => stolen += amount,
+            _ => {}
+        }
+    }
+
+    let config = app.config();
+    let score = app.user_state(user).await.unwrap().score;
+    assert_eq!(
+        score,
+        config.initial_user(user).score + total,
+        "Seed {seed}: logged changes don't add up to the score of {user:?}",
+    );
+    outcome.collected.sort_unstable();
+    collected.sort_unstable();
+    assert_eq!(
+        collected, outcome.collected,
+        "Seed {seed}: logged collects of {user:?} differ from the responses",
+    );
+    assert_eq!(
+        modifier_purchases,
+        outcome.modifiers.len(),
+        "Seed {seed}: logged purchases of {user:?} differ from the responses",
+    );
+    let cost: model::Score = outcome
+        .modifiers
+        .iter()
+        .map(|&m| config.modifier_cost(m))
+        .sum();
+    assert_eq!(
+        modifier_spending, cost,
+        "Seed {seed}: {user:?} paid the wrong price for modifiers",
+    );
+    assert_eq!(stolen, 0, "Seed {seed}: stolen score of {user:?} got lost");
+}
+
+#[actix_web::test]
+async fn test_score_conservation() {
+    crate::logger::init_for_tests();
+    tokio::time::pause();
+    for seed in 0..SEEDS {
+        check_seed(seed).await;
+    }
+}
@@ -0,0 +1,51 @@
+//! Writing of json artifacts
+
+use serde::Serialize;
+use std::{io::Write, path::Path};
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    Pretty,
+    Compact,
+}
+
+pub fn write(path: impl AsRef<Path>, value: &impl Serialize, format: Format) -> anyhow::Result<()> {
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+    match format {
+        Format::Pretty => serde_json::to_writer_pretty(&mut writer, value)?,
+        Format::Compact => serde_json::to_writer(&mut writer, value)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Like [`write`], but through a temporary file renamed over the path at the end,
+/// so that a crash while writing leaves the previous file intact
+pub fn write_atomic(
+    path: impl AsRef<Path>,
+    value: &impl Serialize,
+    format: Format,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    write(&temporary, value, format)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("results.json");
+        std::fs::write(&path, "previous").unwrap();
+        write_atomic(&path, &serde_json::json!({"a": 1}), Format::Compact).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), r#"{"a":1}"#);
+        let files: Vec<_> = std::fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1, "The temporary file is left behind");
+    }
+}
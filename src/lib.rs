@@ -0,0 +1,28 @@
+//! Http server of the pipes game, usable as a library to embed the server into test harnesses
+
+pub mod accounts;
+pub mod audit;
+pub mod bench;
+pub mod chaos;
+pub mod config_schema;
+pub mod grpc;
+pub mod http_client;
+#[cfg(test)]
+mod invariants;
+pub mod lobby;
+pub mod log_format;
+pub mod logger;
+pub mod playback;
+#[cfg(feature = "python")]
+mod python;
+pub mod record;
+pub mod results_db;
+pub mod scheduler;
+pub mod selftest;
+pub mod server;
+pub mod simulate;
+pub mod testing;
+pub mod viewer_auth;
+pub mod webhooks;
+
+pub use pipes_engine::{history, metrics, model, replay, serde_duration, serde_score};
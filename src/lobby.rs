@@ -0,0 +1,189 @@
+//! Independent games hosted next to the main one, played at `/api/games/{id}/...`
+
+use crate::{model, results_db::ResultsDb};
+use actix_web::{rt::spawn, web};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::RwLock, time::Duration};
+use tracing::info;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LobbyError {
+    #[error("Game ids may only contain letters, digits, '-' and '_'")]
+    InvalidId,
+    #[error("Game {0:?} already exists")]
+    AlreadyExists(String),
+    #[error("Invalid config: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+    InvalidConfig(Vec<model::ConfigError>),
+}
+
+#[derive(Deserialize)]
+pub struct NewGame {
+    pub id: String,
+    /// Config of the main game if not set
+    pub config: Option<model::Config>,
+    /// Anyone may play if empty, unless disabled in the config
+    #[serde(default)]
+    pub users: Vec<model::UserToken>,
+}
+
+#[derive(Serialize)]
+pub struct GameSummary {
+    pub id: String,
+    #[serde(flatten)]
+    pub health: model::HealthResponse,
+}
+
+pub struct Lobby {
+    /// Config of games created without one
+    default_config: model::Config,
+    games: RwLock<BTreeMap<String, web::Data<model::App>>>,
+    results_db: Option<web::Data<ResultsDb>>,
+}
+
+impl Lobby {
+    pub fn new(default_config: model::Config, results_db: Option<web::Data<ResultsDb>>) -> Self {
+        Self {
+            default_config,
+            games: Default::default(),
+            results_db,
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<web::Data<model::App>> {
+        self.games.read().unwrap().get(id).cloned()
+    }
+
+    /// Starts the game right away, it then runs by itself like the main one.
+    /// Ended games stay available for their results until removed.
+    pub fn create(&self, game: NewGame) -> Result<web::Data<model::App>, LobbyError> {
+        let valid_id = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if game.id.is_empty() || !game.id.chars().all(valid_id) {
+            return Err(LobbyError::InvalidId);
+        }
+        let mut games = self.games.write().unwrap();
+        if games.contains_key(&game.id) {
+            return Err(LobbyError::AlreadyExists(game.id));
+        }
+        let config = game.config.unwrap_or_else(|| self.default_config.clone());
+        let errors = config.validate();
+        if !errors.is_empty() {
+            return Err(LobbyError::InvalidConfig(errors));
+        }
+        let mut app = model::App::init(config, game.users);
+        // Rounds and `time_scale` only show in the prepared config
+        let time_to_run = app.config().time_to_run.map(Duration::from_secs_f64);
+        app.set_time_to_run(time_to_run);
+        let app = web::Data::new(app);
+        info!("Created game {:?}", game.id);
+        games.insert(game.id.clone(), app.clone());
+        spawn(host(
+            game.id,
+            app.clone(),
+            time_to_run,
+            self.results_db.clone(),
+        ));
+        Ok(app)
+    }
+
+    /// Ends the game if it still runs and forgets it
+    pub async fn remove(&self, id: &str) -> Option<web::Data<model::App>> {
+        let app = self.games.write().unwrap().remove(id)?;
+        app.end_game().await;
+        info!("Removed game {id:?}");
+        Some(app)
+    }
+
+    pub async fn games(&self) -> Vec<GameSummary> {
+        let games: Vec<_> = self
+            .games
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, app)| (id.clone(), app.clone()))
+            .collect();
+        let mut summaries = Vec::with_capacity(games.len());
+        for (id, app) in games {
+            summaries.push(GameSummary {
+                id,
+                health: app.health().await,
+            });
+        }
+        summaries
+    }
+}
+
+/// Runs the timers of a lobby game until it is over
+async fn host(
+    id: String,
+    app: web::Data<model::App>,
+    time_to_run: Option<Duration>,
+    results_db: Option<web::Data<ResultsDb>>,
+) {
+    let scheduler = spawn({
+        let app = app.clone();
+        async move { app.run_scheduler().await }
+    });
+    let webhooks = spawn(crate::webhooks::run(app.clone().into_inner()));
+    let sweeper = spawn(crate::server::sweep_rate_limits(app.clone(), None));
+    crate::server::game_end(&app, time_to_run).await;
+    info!("Game {id:?} is over");
+    app.announce_end().await;
+    scheduler.abort();
+    webhooks.abort();
+    sweeper.abort();
+    if let Some(results_db) = results_db {
+        results_db.save(&app, Some(&id)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_game(id: &str, config: model::Config) -> NewGame {
+        NewGame {
+            id: id.to_owned(),
+            config: Some(config),
+            users: vec![],
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_prepared_time_to_run() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let lobby = Lobby::new(model::Config::default(), None);
+        let scaled = lobby
+            .create(new_game(
+                "scaled",
+                model::Config {
+                    time_to_run: Some(100.0),
+                    time_scale: 0.5,
+                    ..Default::default()
+                },
+            ))
+            .unwrap();
+        let rounds = lobby
+            .create(new_game(
+                "rounds",
+                model::Config {
+                    time_to_run: None,
+                    rounds: Some(model::Rounds {
+                        count: 2,
+                        duration_secs: 30.0,
+                        aggregation: Default::default(),
+                    }),
+                    ..Default::default()
+                },
+            ))
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        assert!(!scaled.is_over());
+        assert!(!rounds.is_over());
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert!(scaled.is_over());
+        assert!(!rounds.is_over());
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert!(rounds.is_over());
+    }
+}
@@ -0,0 +1,133 @@
+//! Hash chain over the entries of a saved game log, to prove that a published log is the one
+//! the server wrote. Every entry is hashed with the hash of the entries before it, as the exact
+//! bytes it was written as, so that edited, dropped or reordered entries change the digest.
+
+use crate::log_file;
+use anyhow::Context;
+use itonecup_mobile::log_format::LogFormat;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::{io::Read, path::PathBuf};
+
+pub const ALGORITHM: &str = "sha256-chain";
+
+/// Final digest of a log, as written to the results
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Digest {
+    pub algorithm: String,
+    pub entries: u64,
+    /// Hex of the last hash in the chain, zeros for an empty log
+    pub digest: String,
+}
+
+/// Chain in progress, fed the entries in the order they are written
+#[derive(Default)]
+pub struct LogDigest {
+    hash: [u8; 32],
+    entries: u64,
+}
+
+impl LogDigest {
+    pub fn update(&mut self, record: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash);
+        hasher.update(record);
+        self.hash = hasher.finalize().into();
+        self.entries += 1;
+    }
+
+    pub fn finish(&self) -> Digest {
+        Digest {
+            algorithm: ALGORITHM.to_owned(),
+            entries: self.entries,
+            digest: hex::encode(self.hash),
+        }
+    }
+}
+
+/// Hashes the segments of a log in order, the same way as while it was written
+pub fn compute(segments: &[PathBuf], format: LogFormat) -> anyhow::Result<Digest> {
+    let mut digest = LogDigest::default();
+    for path in segments {
+        let mut data = Vec::new();
+        log_file::open(path)
+            .and_then(|mut reader| reader.read_to_end(&mut data))
+            .with_context(|| format!("Failed to read the log {path:?}"))?;
+        let records = format
+            .split_records(&data)
+            .with_context(|| format!("Failed to split the log {path:?} into entries"))?;
+        for record in records {
+            digest.update(record);
+        }
+    }
+    Ok(digest.finish())
+}
+
+/// Fails unless the log hashes to the expected digest
+pub fn verify(segments: &[PathBuf], format: LogFormat, expected: &str) -> anyhow::Result<Digest> {
+    let digest = compute(segments, format)?;
+    anyhow::ensure!(
+        digest.digest.eq_ignore_ascii_case(expected.trim()),
+        "Log digest {} of {} entries does not match {expected}, the log was changed",
+        digest.digest,
+        digest.entries,
+    );
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log_file::{Compression, LogFile, Rotation};
+    use itonecup_mobile::model::{LogEntry, LogMessage};
+    use std::io::Write;
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("game_log.jsonl");
+        let rotation = Rotation {
+            max_bytes: Some(100),
+            max_duration: None,
+        };
+        let mut log = LogFile::create(&path, Some(Compression::Gzip), rotation).unwrap();
+        let mut digest = LogDigest::default();
+        for index in 0..10 {
+            let entry = LogEntry::<String> {
+                time: index as f64,
+                msg: LogMessage::GameEnding {
+                    seconds_left: 10.0 - index as f64,
+                },
+            };
+            let record = LogFormat::Json.to_vec(&entry).unwrap();
+            digest.update(&record);
+            log.rotate_if_due().unwrap();
+            log.write_all(&record).unwrap();
+        }
+        let segments = log.finish().unwrap();
+        assert!(segments.len() > 1);
+        let expected = digest.finish();
+        assert_eq!(expected.entries, 10);
+        let verified = verify(&segments, LogFormat::Json, &expected.digest).unwrap();
+        assert_eq!(verified, expected);
+
+        // Leaving out a segment breaks the chain as well as editing an entry would
+        let error = verify(&segments[1..], LogFormat::Json, &expected.digest).unwrap_err();
+        assert!(error.to_string().contains("does not match"), "{error}");
+        let mut edited = Vec::new();
+        for segment in &segments {
+            log_file::open(segment)
+                .unwrap()
+                .read_to_end(&mut edited)
+                .unwrap();
+        }
+        let plain = [dir.path().join("edited.jsonl")];
+        std::fs::write(&plain[0], &edited).unwrap();
+        verify(&plain, LogFormat::Json, &expected.digest).unwrap();
+        let edited = String::from_utf8(edited)
+            .unwrap()
+            .replacen("\"time\":3.0", "\"time\":3.5", 1);
+        std::fs::write(&plain[0], edited).unwrap();
+        assert!(verify(&plain, LogFormat::Json, &expected.digest).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+//! Game log file, optionally compressed and split into numbered segments
+//!
+//! Every segment is a complete file on its own, so finished segments can be
+//! collected while the game is still running.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+}
+
+/// When to start a new segment, the log is a single file if nothing is set
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rotation {
+    /// Uncompressed size of a segment
+    pub max_bytes: Option<u64>,
+    pub max_duration: Option<Duration>,
+}
+
+impl Rotation {
+    fn enabled(&self) -> bool {
+        self.max_bytes.is_some() || self.max_duration.is_some()
+    }
+}
+
+enum Encoder {
+    Plain(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Encoder {
+    fn create(path: &Path, compression: Option<Compression>) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(match compression {
+            None => Encoder::Plain(file),
+            Some(Compression::Gzip) => {
+                Encoder::Gzip(flate2::write::GzEncoder::new(file, Default::default()))
+            }
+            Some(Compression::Zstd) => Encoder::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    /// Writes the compression trailer, the file is incomplete without it
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            Encoder::Plain(file) => file,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(file) => file.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(file) => file.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// `game_log.jsonl` becomes `game_log.0001.jsonl.zst` for the first compressed segment
+fn segment_path(
+    path: &Path,
+    compression: Option<Compression>,
+    numbered: bool,
+    index: usize,
+) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_owned();
+    if numbered {
+        name.push(format!(".{index:04}"));
+    }
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    if let Some(compression) = compression {
+        name.push(".");
+        name.push(compression.extension());
+    }
+    path.with_file_name(name)
+}
+
+pub struct LogFile {
+    path: PathBuf,
+    compression: Option<Compression>,
+    rotation: Rotation,
+    encoder: Encoder,
+    /// Uncompressed bytes written to the current segment
+    written: u64,
+    started: Instant,
+    /// Every segment created so far, the last one is still being written
+    segments: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl LogFile {
+    pub fn create(
+        path: &Path,
+        compression: Option<Compression>,
+        rotation: Rotation,
+    ) -> io::Result<Self> {
+        let first = segment_path(path, compression, rotation.enabled(), 1);
+        Ok(Self {
+            path: path.to_owned(),
+            compression,
+            rotation,
+            encoder: Encoder::create(&first, compression)?,
+            written: 0,
+            started: Instant::now(),
+            segments: Arc::new(Mutex::new(vec![first])),
+        })
+    }
+
+    pub fn segments(&self) -> Arc<Mutex<Vec<PathBuf>>> {
+        self.segments.clone()
+    }
+
+    fn next_segment(&mut self) -> io::Result<Encoder> {
+        let mut segments = self.segments.lock().unwrap();
+        let path = segment_path(
+            &self.path,
+            self.compression,
+            self.rotation.enabled(),
+            segments.len() + 1,
+        );
+        let encoder = Encoder::create(&path, self.compression)?;
+        segments.push(path);
+        self.written = 0;
+        self.started = Instant::now();
+        Ok(encoder)
+    }
+
+    /// Starts a new segment if the current one is full, call only between entries
+    pub fn rotate_if_due(&mut self) -> io::Result<()> {
+        let too_big = self
+            .rotation
+            .max_bytes
+            .is_some_and(|max| self.written >= max);
+        let too_old = self
+            .rotation
+            .max_duration
+            .is_some_and(|max| self.started.elapsed() >= max);
+        if self.written > 0 && (too_big || too_old) {
+            let encoder = self.next_segment()?;
+            std::mem::replace(&mut self.encoder, encoder).finish()?;
+        }
+        Ok(())
+    }
+
+    /// Completes the last segment and returns the paths of all of them
+    pub fn finish(self) -> io::Result<Vec<PathBuf>> {
+        self.encoder.finish()?;
+        Ok(self.segments.lock().unwrap().clone())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.encoder.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// Opens a log file written with any compression, judging by its extension
+pub fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    Ok(match extension {
+        Some(extension) if extension == Compression::Gzip.extension() => {
+            Box::new(flate2::read::GzDecoder::new(file))
+        }
+        Some(extension) if extension == Compression::Zstd.extension() => {
+            Box::new(zstd::Decoder::new(file)?)
+        }
+        _ => Box::new(file),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let rotation = Rotation {
+            max_bytes: Some(10),
+            max_duration: None,
+        };
+        let path = dir.path().join("game_log.jsonl");
+        let mut log_file = LogFile::create(&path, Some(Compression::Zstd), rotation).unwrap();
+        for index in 0..5 {
+            log_file.rotate_if_due().unwrap();
+            writeln!(log_file, "{{\"index\": {index}}}").unwrap();
+        }
+        let segments = log_file.finish().unwrap();
+        assert_eq!(segments.len(), 5);
+        assert_eq!(segments[0].file_name().unwrap(), "game_log.0001.jsonl.zst");
+        for (index, segment) in segments.iter().enumerate() {
+            let decoder = open(segment).unwrap();
+            let lines: Vec<String> = BufReader::new(decoder)
+                .lines()
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(lines, [format!("{{\"index\": {index}}}")]);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Encodings of log entries, binary ones are much smaller for long games
+//!
+//! Binary entries delimit themselves, so a binary log is just entries back to back.
+
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Serialize,
+};
+use std::io::{BufRead, BufReader, Read, Write};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// One entry per line
+    #[default]
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+impl LogFormat {
+    pub fn is_binary(self) -> bool {
+        self != LogFormat::Json
+    }
+
+    pub fn write(self, mut writer: impl Write, value: &impl Serialize) -> anyhow::Result<()> {
+        match self {
+            LogFormat::Json => {
+                serde_json::to_writer(&mut writer, value)?;
+                writeln!(writer)?;
+            }
+            // Field names are kept, the entries use internally tagged enums
+            LogFormat::Msgpack => rmp_serde::encode::write_named(&mut writer, value)?,
+            LogFormat::Cbor => ciborium::into_writer(value, writer)?,
+        }
+        Ok(())
+    }
+
+    pub fn to_vec(self, value: &impl Serialize) -> anyhow::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        self.write(&mut buffer, value)?;
+        Ok(buffer)
+    }
+
+    /// Splits a whole log into the bytes of its entries, as they were written
+    pub fn split_records(self, mut data: &[u8]) -> anyhow::Result<Vec<&[u8]>> {
+        if !self.is_binary() {
+            return Ok(data.split_inclusive(|&byte| byte == b'\n').collect());
+        }
+        let mut records = Vec::new();
+        while !data.is_empty() {
+            let mut rest = data;
+            match self {
+                LogFormat::Json => unreachable!("Lines are split above"),
+                LogFormat::Msgpack => {
+                    rmp_serde::from_read::<_, IgnoredAny>(&mut rest)?;
+                }
+                LogFormat::Cbor => {
+                    ciborium::from_reader::<IgnoredAny, _>(&mut rest)?;
+                }
+            }
+            let (record, remaining) = data.split_at(data.len() - rest.len());
+            records.push(record);
+            data = remaining;
+        }
+        Ok(records)
+    }
+
+    /// Reads entries until the end of the input
+    pub fn read_all<T: DeserializeOwned>(
+        self,
+        reader: impl Read,
+    ) -> impl Iterator<Item = anyhow::Result<T>> {
+        let mut reader = BufReader::new(reader);
+        std::iter::from_fn(move || {
+            match reader.fill_buf() {
+                Ok([]) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+            Some(match self {
+                LogFormat::Json => {
+                    let mut line = String::new();
+                    reader
+                        .read_line(&mut line)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|_| Ok(serde_json::from_str(&line)?))
+                }
+                LogFormat::Msgpack => rmp_serde::from_read(&mut reader).map_err(Into::into),
+                LogFormat::Cbor => ciborium::from_reader(&mut reader).map_err(Into::into),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{LogEntry, LogMessage};
+
+    #[test]
+    fn test_round_trip() {
+        let entries: Vec<LogEntry> = (0..3)
+            .map(|index| LogEntry {
+                time: index as f64,
+                msg: LogMessage::GameEnding {
+                    seconds_left: 10.0 - index as f64,
+                },
+            })
+            .collect();
+        for format in [LogFormat::Json, LogFormat::Msgpack, LogFormat::Cbor] {
+            let mut buffer = Vec::new();
+            for entry in &entries {
+                format.write(&mut buffer, entry).unwrap();
+            }
+            let read: Vec<serde_json::Value> = format
+                .read_all(buffer.as_slice())
+                .collect::<anyhow::Result<_>>()
+                .unwrap();
+            let expected: Vec<serde_json::Value> = entries
+                .iter()
+                .map(|entry| serde_json::to_value(entry).unwrap())
+                .collect();
+            assert_eq!(read, expected, "{format:?}");
+            let records = format.split_records(&buffer).unwrap();
+            assert_eq!(records.len(), entries.len(), "{format:?}");
+            assert_eq!(
+                records[1],
+                format.to_vec(&entries[1]).unwrap(),
+                "{format:?}"
+            );
+        }
+    }
+}
@@ -1,16 +1,118 @@
-fn builder() -> env_logger::Builder {
-    let mut builder = env_logger::Builder::new();
-    builder.filter_level(log::LevelFilter::Info);
-    builder.format_timestamp_millis();
-    builder.parse_env("LOG");
-    builder
+//! Tracing setup, with per target levels that can be changed while running
+
+use std::sync::{Mutex, OnceLock};
+use tracing_subscriber::{
+    filter::{Directive, LevelFilter},
+    fmt::{writer::BoxMakeWriter, TestWriter},
+    layer::{Layered, SubscriberExt},
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+/// Span exporter, only set up if asked for
+type Exporter = Option<Box<dyn Layer<Registry> + Send + Sync>>;
+
+struct ReloadableFilter {
+    /// Levels set at runtime, applied on top of the `LOG` env variable
+    overrides: Mutex<Vec<(Option<String>, LevelFilter)>>,
+    handle: reload::Handle<EnvFilter, Layered<Exporter, Registry>>,
+}
+
+static FILTER: OnceLock<ReloadableFilter> = OnceLock::new();
+
+#[cfg(feature = "otlp")]
+static TRACER_PROVIDER: OnceLock<opentelemetry_sdk::trace::TracerProvider> = OnceLock::new();
+
+fn filter(overrides: &[(Option<String>, LevelFilter)]) -> EnvFilter {
+    let mut filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .with_env_var("LOG")
+        .from_env_lossy();
+    for (target, level) in overrides {
+        let directive = match target {
+            Some(target) => format!("{target}={level}").parse(),
+            None => Ok(Directive::from(*level)),
+        };
+        match directive {
+            Ok(directive) => filter = filter.add_directive(directive),
+            Err(e) => tracing::warn!("Ignoring log level of {target:?}: {e}"),
+        }
+    }
+    filter
+}
+
+fn try_init(is_test: bool, exporter: Exporter) -> anyhow::Result<()> {
+    let (filter, handle) = reload::Layer::new(filter(&[]));
+    let writer = if is_test {
+        BoxMakeWriter::new(TestWriter::new())
+    } else {
+        BoxMakeWriter::new(std::io::stderr)
+    };
+    // Events are printed with the fields of the spans they happen in,
+    // so that interleaved lines of concurrent requests can be told apart
+    let fmt = tracing_subscriber::fmt::layer().with_writer(writer);
+    tracing_subscriber::registry()
+        .with(exporter)
+        .with(filter)
+        .with(fmt)
+        .try_init()?;
+    let _ = FILTER.set(ReloadableFilter {
+        overrides: Mutex::new(Vec::new()),
+        handle,
+    });
+    Ok(())
 }
 
 pub fn init() {
-    builder().init();
+    try_init(false, None).expect("Failed to initialize logger");
+}
+
+/// Like [`init`], also sending spans to an OpenTelemetry collector at the given gRPC endpoint
+#[cfg(feature = "otlp")]
+pub fn init_with_otlp(endpoint: &str) -> anyhow::Result<()> {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(opentelemetry_sdk::Resource::new([
+            opentelemetry::KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+        ]))
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")));
+    let _ = TRACER_PROVIDER.set(provider);
+    try_init(false, Some(Box::new(layer)))
+}
+
+/// Sends the spans that are still buffered, if they are exported
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("Failed to export the remaining spans: {e}");
+        }
+    }
 }
 
 #[cfg(test)]
 pub fn init_for_tests() {
-    let _ = builder().is_test(true).try_init();
+    let _ = try_init(true, None);
+}
+
+/// Changes the level of the given target (or the default level if no target is given)
+pub fn set_level(target: Option<String>, level: LevelFilter) {
+    let Some(reloadable) = FILTER.get() else {
+        return;
+    };
+    let mut overrides = reloadable.overrides.lock().unwrap();
+    overrides.retain(|(existing, _)| *existing != target);
+    overrides.push((target, level));
+    if let Err(e) = reloadable.handle.reload(filter(&overrides)) {
+        tracing::error!("Failed to change the log level: {e}");
+    }
 }
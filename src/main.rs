@@ -1,131 +1,1161 @@
 use actix::spawn;
 use anyhow::Context;
 use futures::{channel::mpsc, FutureExt, StreamExt};
-use log::{debug, info};
-use std::{io::Write, net::SocketAddr, path::PathBuf, time::Duration};
+use itonecup_mobile::{
+    accounts::Accounts, audit, bench, config_schema, log_format::LogFormat, logger, model,
+    playback, record, replay, results_db, selftest, serde_score, server, simulate, viewer_auth,
+};
+use std::{
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{debug, error, info, warn};
 
 mod codehub;
-mod logger;
-mod model;
-mod serde_duration;
-mod server;
+mod config_file;
+mod demo;
+mod json;
+mod log_digest;
+mod log_file;
+mod play;
+mod report;
+mod soak;
+mod standby;
+mod video;
+mod watch;
 
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Host the game, the default when no subcommand is given
+    Serve(Box<ServeArgs>),
+    /// Generate an HTML statistics report from a saved game log
+    Report {
+        #[clap(long)]
+        log: PathBuf,
+        #[clap(long)]
+        out: PathBuf,
+    },
+    /// Interactively send requests to a running server
+    Play {
+        #[clap(long, default_value = "http://127.0.0.1:8080")]
+        url: String,
+        #[clap(long)]
+        token: String,
+    },
+    /// Load a running server with many clients and report latencies and errors per endpoint
+    Bench {
+        #[clap(long, default_value = "http://127.0.0.1:8080")]
+        url: String,
+        #[clap(long, default_value = "200")]
+        clients: usize,
+        #[clap(long, default_value = "30", value_parser = parse_secs)]
+        duration_secs: Duration,
+        /// Tokens given to the clients in turn, `bench-<n>` if not set
+        #[clap(long = "token")]
+        tokens: Vec<String>,
+    },
+    /// Show a live dashboard of a running game in the terminal
+    Watch {
+        #[clap(long, default_value = "http://127.0.0.1:8080")]
+        url: String,
+        /// Viewer token, if the server protects the logs
+        #[clap(long)]
+        token: Option<String>,
+    },
+    /// Re-issue requests saved with --record-requests on the virtual clock and check the results
+    ReplayRequests {
+        #[clap(long)]
+        requests: PathBuf,
+    },
+    /// Replay the same requests, or let the same seeded bots play, with `--config` and another
+    /// config and show how the outcomes differ
+    DiffRequests {
+        #[clap(long, required_unless_present = "bots", conflicts_with = "bots")]
+        requests: Option<PathBuf>,
+        /// Bots with the simulator's strategies playing a game seeded like `--config`
+        /// (or randomly) instead of recorded requests
+        #[clap(long)]
+        bots: Option<usize>,
+        #[clap(long)]
+        other_config: PathBuf,
+    },
+    /// Serve a saved game log to the viewer at its original pace
+    Replay {
+        #[clap(long)]
+        log: PathBuf,
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        addr: SocketAddr,
+        #[clap(long)]
+        serve_dir: Option<PathBuf>,
+        /// How many times faster than the original game to play the log
+        #[clap(long, default_value = "1", value_parser = parse_speed)]
+        speed: f64,
+    },
+    /// Run a scripted session against an in-process server and check the responses
+    Selftest,
+    /// Check a config file against the schema and for values the game can't run with
+    ValidateConfig,
+    /// Let bots with simple strategies play in-process games and compare how they score
+    Simulate {
+        #[clap(long, default_value = "6")]
+        bots: usize,
+        /// Strategies given to the bots in turn
+        #[clap(
+            long = "strategy",
+            value_enum,
+            default_values_t = simulate::StrategyKind::ALL,
+        )]
+        strategies: Vec<simulate::StrategyKind>,
+        #[clap(long, default_value = "1")]
+        games: usize,
+        /// Overrides `time_scale` of the config, e.g. 0.1 plays ten times faster
+        #[clap(long)]
+        time_scale: Option<f64>,
+    },
+    /// Transcode a binary game log to JSON lines for the viewer
+    #[clap(name = "logconvert")]
+    LogConvert {
+        /// Compressed logs are recognized by the extension
+        #[clap(long)]
+        log: PathBuf,
+        #[clap(long, value_enum)]
+        format: LogFormat,
+        #[clap(long)]
+        out: PathBuf,
+    },
+    /// Check a game log saved with --log-digest against its digest in the results
+    VerifyLog {
+        /// Segments of a rotated log in order, compressed ones are recognized by the extension
+        #[clap(long = "log", required = true)]
+        logs: Vec<PathBuf>,
+        #[clap(long, value_enum, default_value_t = LogFormat::Json)]
+        format: LogFormat,
+        /// Results saved with --save-results or the codehub results, to take the digest from
+        #[clap(long, required_unless_present = "digest", conflicts_with = "digest")]
+        results: Option<PathBuf>,
+        #[clap(long)]
+        digest: Option<String>,
+    },
+    /// Render a saved game log into a video, `ffmpeg` has to be installed
+    ExportVideo {
+        #[clap(long)]
+        log: PathBuf,
+        #[clap(long)]
+        out: PathBuf,
+        #[clap(flatten)]
+        options: video::Options,
+    },
+}
+
+impl Command {
+    async fn run(
+        self,
+        global: GlobalArgs,
+        codehub_config: Option<&codehub::Config>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::Serve(args) => serve(*args, global, codehub_config).await,
+            Self::Play { url, token } => play::run(&url, token),
+            Self::Watch { url, token } => watch::run(&url, token),
+            Self::Bench {
+                url,
+                clients,
+                duration_secs,
+                tokens,
+            } => {
+                let options = bench::Options {
+                    url,
+                    clients,
+                    duration: duration_secs,
+                    tokens,
+                };
+                let summary = bench::run(&options)?;
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+                Ok(())
+            }
+            Self::ReplayRequests { requests } => {
+                let config = global.load_config()?;
+                let recording = record::read_recording(requests)?;
+                let replay = record::replay(&recording, config).await?;
+                println!("{}", serde_json::to_string_pretty(&replay.results)?);
+                if recording.outcome.is_none() {
+                    warn!("The recorded game did not finish, there are no results to compare");
+                }
+                let differences = record::check(&recording, &replay);
+                for difference in &differences {
+                    println!("{difference}");
+                }
+                anyhow::ensure!(
+                    differences.is_empty(),
+                    "The replay differs from the recording"
+                );
+                Ok(())
+            }
+            Self::DiffRequests {
+                requests,
+                bots,
+                other_config,
+            } => {
+                let config = global
+                    .config
+                    .context("The config to compare with is given with --config")?;
+                let (config, other_config) = (load_config(&config)?, load_config(&other_config)?);
+                let (a, b) = if let Some(requests) = requests {
+                    let recording = record::read_recording(requests)?;
+                    let a = record::replay(&recording, config).await?;
+                    (a, record::replay(&recording, other_config).await?)
+                } else {
+                    let seed = config.seed.unwrap_or_else(rand::random);
+                    info!("Simulating games with seed {seed}");
+                    let options = simulate::Options {
+                        bots: bots.context("Either --requests or --bots is needed")?,
+                        strategies: simulate::StrategyKind::ALL.to_vec(),
+                        games: 1,
+                    };
+                    let seeded = |config| model::Config {
+                        seed: Some(seed),
+                        ..config
+                    };
+                    let a = simulate::trace(seeded(config), &options).await?;
+                    (a, simulate::trace(seeded(other_config), &options).await?)
+                };
+                let differences = record::diff(&a, &b);
+                for difference in &differences {
+                    println!("{difference}");
+                }
+                anyhow::ensure!(differences.is_empty(), "Outcomes differ");
+                println!("Outcomes are the same");
+                Ok(())
+            }
+            Self::Replay {
+                log,
+                addr,
+                serve_dir,
+                speed,
+            } => {
+                let options = playback::Options {
+                    static_files: serve_dir.map(server::StaticFiles::Dir),
+                    speed,
+                };
+                playback::serve(log, addr, options).await
+            }
+            Self::Selftest => selftest::run().await,
+            Self::ValidateConfig => {
+                let path = global.config.context("No config given, see --config")?;
+                load_config(&path)?;
+                println!("{} is valid", path.display());
+                Ok(())
+            }
+            Self::Simulate {
+                bots,
+                strategies,
+                games,
+                time_scale,
+            } => {
+                let mut config = global.load_config()?;
+                if let Some(time_scale) = time_scale {
+                    config.time_scale = time_scale;
+                }
+                let options = simulate::Options {
+                    bots,
+                    strategies,
+                    games,
+                };
+                let summary = simulate::run(config, &options).await?;
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+                Ok(())
+            }
+            Self::LogConvert { log, format, out } => {
+                let input = log_file::open(&log).context("Failed to open the log")?;
+                let mut writer = std::io::BufWriter::new(std::fs::File::create(out)?);
+                for entry in format.read_all::<serde_json::Value>(input) {
+                    LogFormat::Json.write(&mut writer, &entry?)?;
+                }
+                writer.flush()?;
+                Ok(())
+            }
+            Self::VerifyLog {
+                logs,
+                format,
+                results,
+                digest,
+            } => {
+                let expected = match (digest, results) {
+                    (Some(digest), _) => digest,
+                    (None, Some(path)) => {
+                        let results: serde_json::Value = serde_json::from_reader(
+                            std::fs::File::open(&path).context("Failed to open the results")?,
+                        )
+                        .context("Failed to parse the results")?;
+                        results["log_digest"]["digest"]
+                            .as_str()
+                            .with_context(|| format!("No log digest in {path:?}"))?
+                            .to_owned()
+                    }
+                    (None, None) => unreachable!("Required by clap"),
+                };
+                let digest = log_digest::verify(&logs, format, &expected)?;
+                println!("Log digest matches, {} entries", digest.entries);
+                Ok(())
+            }
+            Self::Report { log, out } => report::generate(log, out),
+            Self::ExportVideo { log, out, options } => video::export(log, out, &options),
+        }
+    }
+}
+
+fn parse_user(arg: &str) -> Result<(model::UserToken, Option<String>), String> {
+    let (token, name) = match arg.split_once(':') {
+        Some((token, name)) => (token, Some(name.to_owned())),
+        None => (arg, None),
+    };
+    Ok((token.to_owned().into(), name))
+}
+
+/// Seconds given as a float, rejecting what `Duration::from_secs_f64` would panic on
+fn parse_secs(arg: &str) -> Result<Duration, String> {
+    let secs: f64 = arg.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| format!("expected a non-negative number of seconds, got {arg}"))
+}
+
+/// Playback speed, time would stand still or run backwards at zero or less
+fn parse_speed(arg: &str) -> Result<f64, String> {
+    let speed: f64 = arg.parse().map_err(|e| format!("{e}"))?;
+    if speed.is_finite() && speed > 0.0 {
+        Ok(speed)
+    } else {
+        Err(format!("expected a positive speed, got {arg}"))
+    }
+}
+
+/// File referenced from the codehub summary under its stem
+fn parse_artifact(arg: &str) -> Result<codehub::Artifact, String> {
+    codehub::Artifact::private(arg).map_err(|e| format!("{e}"))
+}
+
+/// Seconds between repetitions of something, which can't be zero
+fn parse_interval_secs(arg: &str) -> Result<Duration, String> {
+    Some(parse_secs(arg)?)
+        .filter(|interval| !interval.is_zero())
+        .ok_or_else(|| format!("expected a positive number of seconds, got {arg}"))
+}
+
+/// Game server of IT_ONE Cup Mobile 23 and tools around it
 #[derive(clap::Parser)]
 struct CliArgs {
-    #[clap(long)]
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Server options without a subcommand serve the game, as codehub runs the bare binary
+    #[clap(flatten)]
+    serve: ServeArgs,
+    #[clap(flatten)]
+    global: GlobalArgs,
+}
+
+impl CliArgs {
+    fn parse() -> Self {
+        use clap::{Args, CommandFactory, FromArgMatches};
+
+        let mut command = Self::command();
+        let matches = command.get_matches_mut();
+        // Server options before a subcommand would be ignored, global ones are not
+        if let Some((subcommand, _)) = matches.subcommand() {
+            let mut serve_args = ServeArgs::augment_args(clap::Command::new("serve"));
+            serve_args.build();
+            let given = serve_args
+                .get_arguments()
+                .find(|arg| {
+                    matches.value_source(arg.get_id().as_str())
+                        == Some(clap::parser::ValueSource::CommandLine)
+                })
+                .map(ToString::to_string);
+            if let Some(arg) = given {
+                command
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        format!("{arg} only applies to serve, not to {subcommand}"),
+                    )
+                    .exit();
+            }
+        }
+        Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+    }
+}
+
+// Options shared by all subcommands
+#[derive(clap::Args)]
+struct GlobalArgs {
+    /// JSON, TOML or YAML by the extension, may extend another config with `extends`
+    #[clap(long, global = true)]
     config: Option<PathBuf>,
-    #[clap(long = "user")]
-    users: Vec<model::UserToken>,
+    /// Default level of log messages, overrides the `LOG` env variable
+    #[clap(long, global = true)]
+    log_level: Option<tracing_subscriber::filter::LevelFilter>,
+    /// Export tracing spans to an OpenTelemetry collector at this gRPC endpoint
+    #[cfg(feature = "otlp")]
+    #[clap(long, global = true)]
+    otlp_endpoint: Option<String>,
+}
+
+impl GlobalArgs {
+    /// The default config if none is given
+    fn load_config(&self) -> anyhow::Result<model::Config> {
+        match &self.config {
+            Some(path) => load_config(path),
+            None => Ok(model::Config::default()),
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// Print the JSON Schema of the config file and exit
+    #[clap(long)]
+    print_config_schema: bool,
+    /// `token` or `token:name`, the name is shown to spectators instead of the token
+    #[clap(long = "user", value_parser = parse_user)]
+    users: Vec<(model::UserToken, Option<String>)>,
     #[clap(long)]
     save_log: Option<PathBuf>,
+    /// Compress the game log, the extension of the compression is appended to its name
+    #[clap(long, value_enum)]
+    save_log_compress: Option<log_file::Compression>,
+    /// Start a new numbered game log file once this many bytes are written to one
+    #[clap(long)]
+    save_log_rotate_bytes: Option<u64>,
+    /// Start a new numbered game log file after this many seconds
+    #[clap(long, value_parser = parse_interval_secs)]
+    save_log_rotate_secs: Option<Duration>,
+    /// Serve a Swagger UI of the game api at /api/docs
+    #[clap(long)]
+    api_docs: bool,
+    /// Also serve the gRPC api of proto/game.proto on this address
+    #[clap(long)]
+    grpc_addr: Option<SocketAddr>,
+    /// Encoding of the saved game log and of the /logs websocket
+    #[clap(long, value_enum, default_value_t = LogFormat::Json)]
+    log_format: LogFormat,
+    /// Hash the saved game log entry by entry and write the digest to the results,
+    /// to check the log later with `verify-log`
+    #[clap(long, requires = "save_log")]
+    log_digest: bool,
     #[clap(long)]
     save_results: Option<PathBuf>,
-    #[clap(long, default_value = "127.0.0.1:8080")]
-    addr: SocketAddr,
+    /// Also write the results so far this often while the game runs, to --save-results
+    /// and the codehub results, so that a crash doesn't lose the standings
+    #[clap(long, value_parser = parse_interval_secs)]
+    results_interval_secs: Option<Duration>,
+    /// Where to save request metrics, next to the results by default
+    #[clap(long)]
+    save_metrics: Option<PathBuf>,
+    /// SQLite database to record the results of finished games in, served at /api/history
+    #[clap(long)]
+    db: Option<PathBuf>,
+    /// Let players sign up in the results database and sign in for their token at /api/account
+    #[clap(long, requires = "db")]
+    accounts: bool,
+    /// Where to save results when running on codehub
+    #[clap(long, default_value = "results.json")]
+    codehub_results: PathBuf,
+    #[clap(long, value_enum, default_value_t = json::Format::Pretty)]
+    json_format: json::Format,
+    /// How to combine scores of several tokens of one codehub user
+    #[clap(long, value_enum, default_value_t = codehub::SeatCombiner::Sum)]
+    seat_combiner: codehub::SeatCombiner,
+    /// Port 0 picks a free port, see --port-file. Can be repeated to listen on several addresses.
+    #[clap(long = "addr", default_value = "127.0.0.1:8080")]
+    addrs: Vec<SocketAddr>,
+    /// Also listen on this Unix domain socket, e.g. behind a reverse proxy
+    #[clap(long)]
+    unix_socket: Option<PathBuf>,
+    /// Serve the admin api only on this address, which is listened on in addition to --addr.
+    /// Can be repeated.
+    #[clap(long = "admin-addr")]
+    admin_addrs: Vec<SocketAddr>,
+    /// Where to write the port the server listens on, removed when the server stops
+    #[clap(long)]
+    port_file: Option<PathBuf>,
+    /// Also accept cleartext HTTP/2 connections (prior knowledge), so that requests can be
+    /// multiplexed over one connection
+    #[clap(long)]
+    http2: bool,
+    /// Keep idle connections open for this many seconds instead of closing them after every
+    /// response, saving bots a new connection per request
+    #[clap(long = "keep-alive", value_parser = parse_secs)]
+    keep_alive_secs: Option<Duration>,
+    /// Number of http worker threads, one per cpu core by default
+    #[clap(long)]
+    workers: Option<usize>,
+    /// Drop connections that do not send a request head within this many seconds
+    #[clap(long, value_parser = parse_secs)]
+    client_request_timeout_secs: Option<Duration>,
+    /// Drop connections that do not acknowledge closing within this many seconds
+    #[clap(long, value_parser = parse_secs)]
+    client_disconnect_timeout_secs: Option<Duration>,
+    /// Seed of the pipe layout and all other random choices, overrides the config
+    #[clap(long)]
+    seed: Option<u64>,
+    /// Origin allowed to call the api from a browser in addition to `cors_origins` of the config,
+    /// can be repeated
+    #[clap(long = "cors-origin")]
+    cors_origins: Vec<String>,
     #[clap(long)]
     serve_dir: Option<PathBuf>,
+    /// Serve the viewer built into the binary, instead of one from `--serve-dir`
+    #[cfg(feature = "embedded-viewer")]
+    #[clap(long, conflicts_with = "serve_dir")]
+    serve_embedded: bool,
+    /// Secret for signing viewer tokens, protects the viewer and logs when set
+    #[clap(long)]
+    viewer_secret: Option<String>,
+    /// Print a signed viewer token with the given scope and exit
+    #[clap(long, value_enum, requires = "viewer_secret")]
+    print_viewer_token: Option<viewer_auth::Scope>,
+    /// Lobby game the printed viewer token is for, it opens only that game.
+    /// The token is for the main game if not set
+    #[clap(long, requires = "print_viewer_token")]
+    viewer_token_game: Option<String>,
+    #[clap(long, default_value = "86400", value_parser = parse_interval_secs)]
+    viewer_token_ttl_secs: Duration,
+    /// Token for the admin api, which is disabled if not set
+    #[clap(long)]
+    admin_token: Option<model::UserToken>,
+    /// Save all game api calls to a file, to be replayed with the replay-requests subcommand
+    #[clap(long)]
+    record_requests: Option<PathBuf>,
+    /// Append every api call with its outcome, latency and source ip to this file,
+    /// CSV if it ends with `.csv` and JSON lines otherwise
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+    /// Periodically save the game state, to continue with --restore-state after a crash
+    #[clap(long)]
+    save_state: Option<PathBuf>,
+    #[clap(long, default_value = "10", value_parser = parse_interval_secs)]
+    save_state_interval_secs: Duration,
+    /// Continue the game from a state saved with --save-state
+    #[clap(long)]
+    restore_state: Option<PathBuf>,
+    #[clap(flatten)]
+    standby: standby::Options,
+    #[clap(flatten)]
+    soak: soak::Options,
+    #[clap(flatten)]
+    demo: demo::Options,
+    /// Additional private file to reference from the codehub summary
+    #[clap(long = "private-artifact", value_parser = parse_artifact)]
+    private_artifacts: Vec<codehub::Artifact>,
 }
 
-async fn run(codehub_config: Option<&codehub::Config>) -> anyhow::Result<()> {
-    let mut args: CliArgs = clap::Parser::parse();
-    let mut config: model::Config = match &args.config {
-        Some(path) => {
-            if path.to_str() == Some("-") {
-                serde_json::from_reader(std::io::stdin().lock())
-            } else {
-                serde_json::from_reader(
-                    std::fs::File::open(path).context("Failed to open config file")?,
-                )
+impl ServeArgs {
+    /// Where the viewer is served from, if it is served at all
+    fn static_files(&self) -> Option<server::StaticFiles> {
+        #[cfg(feature = "embedded-viewer")]
+        if self.serve_embedded {
+            return Some(server::StaticFiles::Embedded);
+        }
+        self.serve_dir.clone().map(server::StaticFiles::Dir)
+    }
+
+    /// Connection handling shared by the demo and the regular server
+    fn connection_options(&self) -> server::Options {
+        server::Options {
+            http2: self.http2,
+            keep_alive: self.keep_alive_secs,
+            workers: self.workers,
+            client_request_timeout: self.client_request_timeout_secs,
+            client_disconnect_timeout: self.client_disconnect_timeout_secs,
+            admin_addrs: self.admin_addrs.clone(),
+            unix_socket: self.unix_socket.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Reads a config file (or stdin if the path is `-`) and checks it against the schema
+fn load_config(path: &Path) -> anyhow::Result<model::Config> {
+    let config = config_file::read(path)?;
+    let errors = config_schema::validate(&config);
+    anyhow::ensure!(
+        errors.is_empty(),
+        "Config does not match the schema:\n{}",
+        errors.join("\n"),
+    );
+    let config: model::Config = serde_json::from_value(config).context("Failed to parse config")?;
+    let errors = config.validate();
+    anyhow::ensure!(
+        errors.is_empty(),
+        "Config can't be played:\n{}",
+        errors
+            .iter()
+            .map(|error| format!("$.{error}"))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    );
+    Ok(config)
+}
+
+async fn serve(
+    mut args: ServeArgs,
+    global: GlobalArgs,
+    codehub_config: Option<&codehub::Config>,
+) -> anyhow::Result<()> {
+    if args.print_config_schema {
+        print!("{}", config_schema::SCHEMA);
+        return Ok(());
+    }
+    if codehub_config.is_some() {
+        // Fail before the game rather than when the summary is written
+        let metrics = codehub::Artifact::private("metrics.json")?;
+        codehub::check_artifact_names([&metrics].into_iter().chain(&args.private_artifacts))?;
+    }
+    let viewer_auth = args
+        .viewer_secret
+        .as_deref()
+        .map(viewer_auth::ViewerAuth::new);
+    if let (Some(scope), Some(viewer_auth)) = (args.print_viewer_token, &viewer_auth) {
+        let game = args.viewer_token_game.as_deref();
+        println!(
+            "{}",
+            viewer_auth.sign_for(scope, game, args.viewer_token_ttl_secs)
+        );
+        return Ok(());
+    }
+    // Also used to reload the config file on SIGHUP, with the same command line overrides
+    let game_config = {
+        let path = global.config.clone();
+        let seed = args.seed;
+        let cors_origins = args.cors_origins.clone();
+        let soak_hours = args.soak.hours;
+        let names: Vec<_> = args
+            .users
+            .iter()
+            .filter_map(|(token, name)| Some((token.clone(), name.clone()?)))
+            .collect();
+        let codehub_time_to_run = codehub_config.and_then(|config| config.time_to_run);
+        move || -> anyhow::Result<model::Config> {
+            let mut config = match &path {
+                Some(path) => load_config(path)?,
+                None => model::Config::default(),
+            };
+            if let Some(seed) = seed {
+                config.seed = Some(seed);
+            }
+            config.cors_origins.extend(cors_origins.iter().cloned());
+            if let Some(hours) = soak_hours {
+                config.time_to_run = Some(hours * 3600.0);
+            }
+            for (token, name) in &names {
+                config.user_profiles.entry(token.clone()).or_default().name = Some(name.clone());
             }
+            if let Some(time) = codehub_time_to_run {
+                config.time_to_run = Some(time);
+            }
+            Ok(config)
         }
-        .context("Failed to parse config")?,
-        None => model::Config::default(),
     };
+    let mut config = game_config()?;
+    let mut users: Vec<_> = std::mem::take(&mut args.users)
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect();
     if let Some(codehub_config) = &codehub_config {
-        args.users = codehub_config.user_id_by_token.keys().cloned().collect();
-        if let Some(time) = codehub_config.time_to_run {
-            config.time_to_run = Some(time);
-        }
-        args.save_log = Some("game_log.jsonl".into());
+        users = codehub_config.user_id_by_token.keys().cloned().collect();
+        args.save_log.get_or_insert_with(|| "game_log.jsonl".into());
     }
 
-    let time_to_run = config.time_to_run.map(Duration::from_secs_f64);
     let enable_logs_api = codehub_config.is_none();
-    let serve_dir = args.serve_dir.as_ref().filter(|_| codehub_config.is_none());
+    let static_files = args.static_files().filter(|_| codehub_config.is_none());
 
-    let app = model::App::init(config, args.users);
+    if args.demo.enabled {
+        anyhow::ensure!(
+            users.is_empty()
+                && codehub_config.is_none()
+                && args.soak.hours.is_none()
+                && args.save_state.is_none()
+                && args.restore_state.is_none()
+                && args.standby.state_file.is_none(),
+            "The demo is open to everyone and restarts by itself, \
+             it can not be combined with users, codehub, soak tests or saved states",
+        );
+        args.demo.apply(&mut config);
+        return demo::run(&args.addrs, config, || server::Options {
+            static_files: static_files.clone(),
+            enable_logs_api,
+            admin_token: args.admin_token.clone(),
+            ..args.connection_options()
+        })
+        .await;
+    }
+
+    anyhow::ensure!(
+        config.history_limit.is_none()
+            || (args.save_state.is_none()
+                && args.restore_state.is_none()
+                && args.standby.state_file.is_none()),
+        "Saved states are rebuilt from the log history, it can not be limited with them",
+    );
+    let mut checkpoint = None;
+    if let Some(path) = &args.restore_state {
+        let file = std::fs::File::open(path).context("Failed to open the saved state")?;
+        checkpoint = Some(
+            serde_json::from_reader(std::io::BufReader::new(file))
+                .context("Failed to parse the saved state")?,
+        );
+    }
+    if let Some(path) = &args.standby.state_file {
+        let admin_token = args
+            .admin_token
+            .clone()
+            .context("A standby is promoted with the admin api, it needs --admin-token")?;
+        let promoted = standby::wait(
+            &args.standby,
+            path,
+            &args.addrs,
+            &args.admin_addrs,
+            admin_token,
+        )
+        .await?;
+        match promoted {
+            Some(promoted) => checkpoint = Some(promoted),
+            None => return Ok(()),
+        }
+    }
+    let mut app = model::App::init(config, users);
+    if let Some(checkpoint) = checkpoint {
+        app.restore(checkpoint);
+    }
+    let time_to_run = app.config().time_to_run.map(Duration::from_secs_f64);
     let log_writer = if let Some(path) = &args.save_log {
         let user_map = codehub_config.map(|config| config.user_id_by_token.clone());
+        let score_format = app.config().score_format;
+        let log_format = args.log_format;
+        if let Some(limit) = app.config().history_limit {
+            anyhow::ensure!(
+                app.history_len().await <= limit,
+                "The log history limit {limit} would drop initial entries from the saved log",
+            );
+        }
         let (sender, mut receiver) = mpsc::unbounded();
         app.register_logs(sender.clone()).await;
-        let file = std::fs::File::create(path).context("Failed to create log file")?;
+        let rotation = log_file::Rotation {
+            max_bytes: args.save_log_rotate_bytes,
+            max_duration: args.save_log_rotate_secs,
+        };
+        let mut writer = log_file::LogFile::create(path, args.save_log_compress, rotation)
+            .context("Failed to create log file")?;
+        let mut digest = args.log_digest.then(log_digest::LogDigest::default);
         Some((
             sender,
+            writer.segments(),
             // Need to spawn here otherwise work only done on .await
             spawn(async move {
-                let mut writer = std::io::BufWriter::new(file);
                 while let Some(entry) = receiver.next().await {
-                    if let Some(user_map) = &user_map {
-                        serde_json::to_writer(
-                            &mut writer,
-                            &entry.map_user(|token| user_map[&token]),
-                        )?;
-                    } else {
-                        serde_json::to_writer(&mut writer, &entry)?;
+                    writer.rotate_if_due()?;
+                    let record = serde_score::with_format(score_format, || {
+                        if let Some(user_map) = &user_map {
+                            log_format.to_vec(&entry.map_user(|token| user_map[&token]))
+                        } else {
+                            log_format.to_vec(&entry)
+                        }
+                    })?;
+                    if let Some(digest) = &mut digest {
+                        digest.update(&record);
                     }
-                    writeln!(&mut writer)?;
+                    writer.write_all(&record)?;
                 }
-                anyhow::Ok(())
+                anyhow::Ok((writer.finish()?, digest.map(|digest| digest.finish())))
             }),
         ))
     } else {
         None
     };
 
-    let app = server::run(args.addr, app, time_to_run, serve_dir, enable_logs_api).await?;
+    let soak_failures = Arc::new(std::sync::Mutex::new(Vec::new()));
+    // Stopped before the final results are written, so that they are not overwritten
+    let partial_results = Arc::new(std::sync::Mutex::new(None));
+    let results_db = args
+        .db
+        .as_deref()
+        .map(results_db::ResultsDb::open)
+        .transpose()?
+        .map(Arc::new);
+    let server_options = server::Options {
+        static_files: static_files.clone(),
+        enable_logs_api,
+        log_format: args.log_format,
+        api_docs: args.api_docs,
+        grpc_addr: args.grpc_addr,
+        viewer_auth,
+        admin_token: args.admin_token.clone(),
+        recorder: args
+            .record_requests
+            .as_ref()
+            .map(record::Recorder::create)
+            .transpose()?,
+        audit_log: args
+            .audit_log
+            .as_ref()
+            .map(audit::AuditLog::open)
+            .transpose()?,
+        results_db: results_db.clone(),
+        accounts: results_db
+            .as_deref()
+            .filter(|_| args.accounts)
+            .map(Accounts::new)
+            .transpose()?
+            .map(Arc::new),
+        // Stdin can only be read once
+        reload_config: global
+            .config
+            .as_ref()
+            .filter(|path| path.to_str() != Some("-"))
+            .map(|_| Box::new(game_config) as Box<dyn Fn() -> anyhow::Result<model::Config>>),
+        on_start: Some(Box::new({
+            let save_results = args.save_results.clone();
+            let json_format = args.json_format;
+            let codehub = codehub_config.cloned().map(|config| {
+                (
+                    config,
+                    // The log is always saved on codehub
+                    log_writer.as_ref().unwrap().1.clone(),
+                    args.codehub_results.clone(),
+                    args.seat_combiner,
+                )
+            });
+            let soak = args.soak.clone();
+            let soak_failures = soak_failures.clone();
+            let save_state = args.save_state.clone();
+            let save_state_interval = args.save_state_interval_secs;
+            let results_interval = args.results_interval_secs;
+            let partial_results = partial_results.clone();
+            let port_file = args.port_file.clone();
+            move |started: server::Started| {
+                for addr in &started.addrs {
+                    info!("Listening on http://{addr}");
+                }
+                let addr = started.addrs[0];
+                // Machine readable, for harnesses that start servers on port 0
+                println!("LISTENING {addr}");
+                if let Some(path) = &port_file {
+                    if let Err(e) = write_port_file(path, addr.port()) {
+                        error!("Failed to write the port file {path:?}: {e:#}");
+                        spawn(started.handle.clone().stop(false));
+                    }
+                }
+                if let Some(path) = save_state {
+                    let app = started.app.clone();
+                    spawn(save_checkpoints(app, path, save_state_interval));
+                }
+                if soak.hours.is_some() {
+                    let app = started.app.clone();
+                    spawn(async move { soak::monitor(app, &soak, soak_failures).await });
+                }
+                spawn({
+                    let app = started.app.clone();
+                    async move { simulate::join_ghosts(&app).await }
+                });
+                let app = started.app.clone();
+                let write_results = Arc::new(move |results: model::DetailedResults| {
+                    if let Some(path) = &save_results {
+                        json::write_atomic(path, &results, json_format)?;
+                    }
+                    let model::DetailedResults {
+                        scores,
+                        players,
+                        profiles,
+                        ..
+                    } = results;
+                    if let Some((config, log_segments, results_path, seat_combiner)) = &codehub {
+                        codehub::write_game_log(
+                            config,
+                            &log_segments.lock().unwrap(),
+                            results_path,
+                            &[],
+                            codehub::Results {
+                                players: Some(config.players(&app, &players, &profiles)),
+                                results: config.user_results(scores, *seat_combiner),
+                                seed: Some(app.seed()),
+                                incomplete: true,
+                                log_digest: None,
+                            },
+                            json_format,
+                        )?;
+                    }
+                    anyhow::Ok(())
+                });
+                if let Some(interval) = results_interval {
+                    let task = spawn(save_partial_results(
+                        started.app.clone(),
+                        interval,
+                        write_results.clone(),
+                    ));
+                    *partial_results.lock().unwrap() = Some(task);
+                }
+                install_partial_results_hook(started.app, move |results| write_results(results))
+            }
+        })),
+        ..args.connection_options()
+    };
+    let app = server::run(&args.addrs[..], app, time_to_run, server_options).await;
+    if let Some(path) = &args.port_file {
+        if let Err(e) = std::fs::remove_file(path) {
+            debug!("Failed to remove the port file {path:?}: {e}");
+        }
+    }
+    let app = app?;
+    // Final results are written below, restore the default panic hook
+    let _ = std::panic::take_hook();
+    if let Some(task) = partial_results.lock().unwrap().take() {
+        task.abort();
+    }
 
-    if let Some((sender, task)) = log_writer {
-        app.unregister_logs(&sender).await;
-        std::mem::drop(sender);
+    let mut log_segments = Vec::new();
+    let mut log_digest = None;
+    if let Some((sender, _, task)) = log_writer {
+        app.unregister_logs(sender).await;
         // Wait for the log writer to finish
         // It should be finishing since sender is dropped
-        task.await??;
+        (log_segments, log_digest) = task.await??;
     }
 
-    let results = app.results().await;
+    let detailed = app.detailed_results().await;
+    let metrics = app.metrics().snapshot();
 
-    info!("Results: {results:#?}");
+    for (index, scores) in app.round_scores().iter().enumerate() {
+        info!("Round {} scores: {scores:#?}", index + 1);
+    }
+    info!("Results: {:#?}", detailed.scores);
+    for token in detailed.scores.keys() {
+        let status = app.user_status(&token.clone().into());
+        if status != model::UserStatus::Active {
+            info!("{token:?} is {status:?}");
+        }
+    }
     if let Some(path) = &args.save_results {
         debug!("Saving results to {path:?}");
-        serde_json::to_writer_pretty(
-            std::io::BufWriter::new(
-                std::fs::File::create(path).expect("Failed to create results file"),
-            ),
-            &results,
-        )
-        .expect("Failed to write results");
+        #[derive(serde::Serialize)]
+        struct WithDigest<'a> {
+            #[serde(flatten)]
+            results: &'a model::DetailedResults,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            log_digest: Option<&'a log_digest::Digest>,
+        }
+        let results = WithDigest {
+            results: &detailed,
+            log_digest: log_digest.as_ref(),
+        };
+        json::write_atomic(path, &results, args.json_format).expect("Failed to write results");
     }
+    let metrics_path = args.save_metrics.clone().or_else(|| {
+        let results_path = match codehub_config {
+            Some(_) => Some(&args.codehub_results),
+            None => args.save_results.as_ref(),
+        };
+        results_path.map(|path| path.with_file_name("metrics.json"))
+    });
 
     if let Some(codehub_config) = &codehub_config {
+        let metrics = metrics.map_user(|token| match codehub_config.user_id_by_token.get(&token) {
+            Some(id) => id.to_string(),
+            None => "unknown".to_owned(),
+        });
+        let metrics_path = metrics_path.unwrap();
+        json::write(&metrics_path, &metrics, args.json_format).expect("Failed to write metrics");
+        let mut artifacts = vec![codehub::Artifact::private(metrics_path)?];
+        artifacts.extend(args.private_artifacts.iter().cloned());
         codehub::write_game_log(
             codehub_config,
-            args.save_log.as_ref().unwrap(),
+            &log_segments,
+            &args.codehub_results,
+            &artifacts,
             codehub::Results {
-                players: None,
-                results: results
-                    .into_iter()
-                    .map(|(token, score)| (codehub_config.user_id_by_token[&token], score as f64))
-                    .collect(),
-                seed: None,
+                players: Some(codehub_config.players(&app, &detailed.players, &detailed.profiles)),
+                results: codehub_config.user_results(detailed.scores, args.seat_combiner),
+                seed: Some(app.seed()),
+                incomplete: false,
+                log_digest,
             },
-        );
+            args.json_format,
+        )?;
+    } else if let Some(path) = &metrics_path {
+        debug!("Saving metrics to {path:?}");
+        json::write(path, &metrics, args.json_format).expect("Failed to write metrics");
+    }
+
+    let soak_failures = soak_failures.lock().unwrap();
+    anyhow::ensure!(
+        soak_failures.is_empty(),
+        "Soak test failed:\n{}",
+        soak_failures.join("\n"),
+    );
+    Ok(())
+}
+
+/// Saves the game state periodically. The file is replaced atomically,
+/// so a crash while writing leaves the previous checkpoint intact.
+async fn save_checkpoints(app: Arc<model::App>, path: PathBuf, interval: Duration) {
+    loop {
+        actix_web::rt::time::sleep(interval).await;
+        let checkpoint = app.checkpoint().await;
+        match json::write_atomic(&path, &checkpoint, json::Format::Compact) {
+            Ok(()) => debug!("Saved the game state at {:.1}s", checkpoint.time),
+            Err(e) => error!("Failed to save the game state: {e:#}"),
+        }
     }
+}
 
+/// Writes the results so far periodically, each write replaces the previous one atomically
+async fn save_partial_results(
+    app: Arc<model::App>,
+    interval: Duration,
+    write: Arc<impl Fn(model::DetailedResults) -> anyhow::Result<()>>,
+) {
+    loop {
+        actix_web::rt::time::sleep(interval).await;
+        let results = app.detailed_results().await;
+        match write(results) {
+            Ok(()) => debug!("Saved the results at {:.1}s", app.game_time()),
+            Err(e) => error!("Failed to save the results so far: {e:#}"),
+        }
+    }
+}
+
+/// Written at once, so that a harness polling for the file never reads a partial port
+fn write_port_file(path: &Path, port: u16) -> anyhow::Result<()> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, format!("{port}\n"))?;
+    std::fs::rename(&temporary, path)?;
     Ok(())
 }
 
+/// Saves whatever results are available if anything panics, so that an almost finished game
+/// is not lost completely
+fn install_partial_results_hook(
+    app: Arc<model::App>,
+    write: impl Fn(model::DetailedResults) -> anyhow::Result<()> + Send + Sync + 'static,
+) {
+    let written = AtomicBool::new(false);
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if written.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let Some(results) = app.try_detailed_results() else {
+            error!("Failed to save partial results: users are locked");
+            return;
+        };
+        error!("Saving partial results after a panic: {results:#?}");
+        if let Err(e) = write(results) {
+            error!("Failed to save partial results: {e}");
+        }
+    }));
+}
+
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
+    let CliArgs {
+        command,
+        serve,
+        global,
+    } = CliArgs::parse();
+    #[cfg(feature = "otlp")]
+    match &global.otlp_endpoint {
+        Some(endpoint) => logger::init_with_otlp(endpoint)?,
+        None => logger::init(),
+    }
+    #[cfg(not(feature = "otlp"))]
     logger::init();
-    codehub::wrapper(|config| run(config).boxed_local()).await
+    if let Some(level) = global.log_level {
+        logger::set_level(None, level);
+    }
+    let command = command.unwrap_or_else(|| Command::Serve(Box::new(serve)));
+    let result = codehub::wrapper(|config| command.run(global, config).boxed_local()).await;
+    logger::shutdown();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, Parser};
+
+    #[test]
+    fn test_cli() {
+        CliArgs::command().debug_assert();
+        let args =
+            CliArgs::try_parse_from(["itonecup-mobile", "--config", "game.toml", "simulate"])
+                .unwrap();
+        assert!(matches!(args.command, Some(Command::Simulate { .. })));
+        assert_eq!(args.global.config, Some("game.toml".into()));
+        let args =
+            CliArgs::try_parse_from(["itonecup-mobile", "validate-config", "--log-level", "debug"])
+                .unwrap();
+        assert_eq!(
+            args.global.log_level,
+            Some(tracing_subscriber::filter::LevelFilter::DEBUG)
+        );
+        let args = CliArgs::try_parse_from(["itonecup-mobile", "--user", "alice:Alice"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.serve.users.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!(parse_speed("0.5"), Ok(0.5));
+        for invalid in ["0", "-2", "NaN", "inf"] {
+            assert!(parse_speed(invalid).is_err(), "{invalid}");
+        }
+        let replay = [
+            "itonecup-mobile",
+            "replay",
+            "--log",
+            "game.jsonl",
+            "--speed",
+            "0",
+        ];
+        assert!(CliArgs::try_parse_from(replay).is_err());
+    }
+
+    #[test]
+    fn test_parse_secs() {
+        assert_eq!(parse_secs("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_secs("0"), Ok(Duration::ZERO));
+        for invalid in ["-1", "NaN", "inf", "1e30", "soon"] {
+            assert!(parse_secs(invalid).is_err(), "{invalid}");
+        }
+        assert!(parse_interval_secs("0").is_err());
+        assert!(parse_interval_secs("-1").is_err());
+        assert_eq!(parse_interval_secs("0.5"), Ok(Duration::from_millis(500)));
+        for flag in [
+            "--save-log-rotate-secs",
+            "--save-state-interval-secs",
+            "--results-interval-secs",
+            "--viewer-token-ttl-secs",
+        ] {
+            assert!(CliArgs::try_parse_from(["itonecup-mobile", flag, "0"]).is_err());
+        }
+        let bench = ["itonecup-mobile", "bench", "--duration-secs=-5"];
+        assert!(CliArgs::try_parse_from(bench).is_err());
+        for flag in [
+            "--keep-alive",
+            "--client-request-timeout-secs",
+            "--client-disconnect-timeout-secs",
+        ] {
+            let error = CliArgs::try_parse_from(["itonecup-mobile", &format!("{flag}=-1")]);
+            assert!(error.is_err_and(|e| e.to_string().contains("non-negative")));
+        }
+        let args = CliArgs::try_parse_from(["itonecup-mobile", "--keep-alive", "2.5"]).unwrap();
+        assert_eq!(
+            args.serve.connection_options().keep_alive,
+            Some(Duration::from_millis(2500))
+        );
+    }
 }
@@ -0,0 +1,117 @@
+//! Interactive client for exploring the api by hand
+
+use itonecup_mobile::http_client::Client;
+use std::io::{BufRead, Write};
+
+const HELP: &str = "\
+Commands:
+  value <pipe>              find out the value of a pipe
+  peek <pipe>               find out roughly the value of a pipe
+  collect <pipe>            collect a pipe
+  modifier <pipe> <type>    apply a modifier (slow, double, min, shuffle, reverse, insurance, shield, steal)
+  modifiers <pipe>          list modifiers on a pipe
+  pipes                     list all pipes with their modifiers
+  score                     show own score
+  actions                   list actions in flight
+  cancel                    abort all actions in flight
+  assigned                  show the pipe assigned in round robin mode
+  history                   show own log history
+  shop                      show modifier costs
+  time                      show game time and the current phase
+  help                      show this message
+  quit                      exit";
+
+/// Method, path and body of the request for a command, `None` for unknown commands
+fn parse(line: &str) -> Option<(&'static str, String, Option<serde_json::Value>)> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let request = match words.as_slice() {
+        ["value", pipe] => ("GET", format!("/api/pipe/{pipe}/value"), None),
+        ["peek", pipe] => ("GET", format!("/api/pipe/{pipe}/peek"), None),
+        ["collect", pipe] => ("PUT", format!("/api/pipe/{pipe}"), None),
+        ["modifier", pipe, modifier] => (
+            "POST",
+            format!("/api/pipe/{pipe}/modifier"),
+            Some(serde_json::json!({ "type": modifier })),
+        ),
+        ["modifiers", pipe] => ("GET", format!("/api/pipe/{pipe}/modifier"), None),
+        ["pipes"] => ("GET", "/api/pipes".to_owned(), None),
+        ["score"] => ("GET", "/api/user".to_owned(), None),
+        ["actions"] => ("GET", "/api/user/action".to_owned(), None),
+        ["cancel"] => ("DELETE", "/api/user/action".to_owned(), None),
+        ["assigned"] => ("GET", "/api/user/pipe".to_owned(), None),
+        ["history"] => ("GET", "/api/user/history".to_owned(), None),
+        ["shop"] => ("GET", "/api/shop".to_owned(), None),
+        ["time"] => ("GET", "/api/time".to_owned(), None),
+        _ => return None,
+    };
+    Some(request)
+}
+
+fn command(client: &Client, line: &str) -> anyhow::Result<()> {
+    let Some((method, path, body)) = parse(line) else {
+        println!("{HELP}");
+        return Ok(());
+    };
+    let response = client.request(method, &path, body.as_ref())?;
+    println!("{}", response.status);
+    match serde_json::from_str::<serde_json::Value>(&response.body) {
+        Ok(body) => println!("{}", serde_json::to_string_pretty(&body)?),
+        Err(_) => println!("{}", response.body),
+    }
+    Ok(())
+}
+
+pub fn run(url: &str, token: String) -> anyhow::Result<()> {
+    let client = Client::new(url, Some(token));
+    println!(
+        "Playing on {}, type `help` for the list of commands",
+        client.host()
+    );
+    let stdin = std::io::stdin();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        match line.trim() {
+            "" => continue,
+            "quit" | "exit" => return Ok(()),
+            line => {
+                if let Err(e) = command(&client, line) {
+                    println!("Error: {e:#}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("collect 3"),
+            Some(("PUT", "/api/pipe/3".to_owned(), None))
+        );
+        assert_eq!(
+            parse("  modifier 2   shield "),
+            Some((
+                "POST",
+                "/api/pipe/2/modifier".to_owned(),
+                Some(serde_json::json!({"type": "shield"}))
+            ))
+        );
+        assert_eq!(
+            parse("cancel"),
+            Some(("DELETE", "/api/user/action".to_owned(), None))
+        );
+        // Wrong number of arguments shows the help instead of sending anything
+        for line in ["collect", "value 1 2", "pipes 1", "help", "jump 1"] {
+            assert_eq!(parse(line), None, "{line}");
+        }
+    }
+}
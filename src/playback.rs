@@ -0,0 +1,138 @@
+//! Serving a finished game from a saved log, so that the viewer can replay it offline
+
+use crate::{
+    model::LogEntry,
+    replay,
+    server::{log_frame, StaticFiles},
+};
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::{
+    get,
+    rt::{spawn, time::sleep},
+    web, App, HttpRequest, HttpResponse, HttpServer,
+};
+use actix_web_actors::ws;
+use anyhow::Context;
+use std::{
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+pub struct Options {
+    pub static_files: Option<StaticFiles>,
+    /// How many times faster than the original game the log is played
+    pub speed: f64,
+}
+
+struct Playback {
+    entries: Vec<LogEntry<replay::User>>,
+    speed: f64,
+}
+
+struct EntryMessage(usize);
+
+impl actix::Message for EntryMessage {
+    type Result = ();
+}
+
+struct EndMessage;
+
+impl actix::Message for EndMessage {
+    type Result = ();
+}
+
+/// Every connection gets the whole game from the start
+#[get("/logs")]
+async fn logs(
+    playback: web::Data<Playback>,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    struct PlaybackWs {
+        playback: web::Data<Playback>,
+    }
+    impl Actor for PlaybackWs {
+        type Context = ws::WebsocketContext<Self>;
+        fn started(&mut self, ctx: &mut Self::Context) {
+            let addr = ctx.address();
+            let playback = self.playback.clone();
+            spawn(async move {
+                let start = Instant::now();
+                for (index, entry) in playback.entries.iter().enumerate() {
+                    let due = Duration::from_secs_f64((entry.time / playback.speed).max(0.0));
+                    sleep(due.saturating_sub(start.elapsed())).await;
+                    if !addr.connected() {
+                        return;
+                    }
+                    addr.do_send(EntryMessage(index));
+                }
+                addr.do_send(EndMessage);
+            });
+        }
+    }
+    impl actix::Handler<EntryMessage> for PlaybackWs {
+        type Result = ();
+        fn handle(&mut self, msg: EntryMessage, ctx: &mut Self::Context) {
+            ctx.text(log_frame(&self.playback.entries[msg.0]));
+        }
+    }
+    impl actix::Handler<EndMessage> for PlaybackWs {
+        type Result = ();
+        fn handle(&mut self, _msg: EndMessage, ctx: &mut Self::Context) {
+            debug!("Playback finished");
+            ctx.close(Some(ws::CloseCode::Normal.into()));
+            ctx.stop();
+        }
+    }
+    impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for PlaybackWs {
+        fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+            match msg {
+                Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+                Ok(ws::Message::Close(reason)) => {
+                    ctx.close(reason);
+                    ctx.stop();
+                }
+                Ok(_) => {}
+                Err(_) => ctx.stop(),
+            }
+        }
+    }
+    ws::start(PlaybackWs { playback }, &req, stream)
+}
+
+/// Serves the log at `/logs` and the viewer until stopped with Ctrl-C
+pub async fn serve(
+    log: impl AsRef<Path>,
+    addr: SocketAddr,
+    options: Options,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(options.speed > 0.0, "Playback speed has to be positive");
+    if let Some(static_files) = &options.static_files {
+        static_files.check()?;
+    }
+    let entries = replay::read_log(log)?;
+    info!(
+        "Playing {} log entries at x{} speed on http://{addr}",
+        entries.len(),
+        options.speed,
+    );
+    let playback = web::Data::new(Playback {
+        entries,
+        speed: options.speed,
+    });
+    let static_files = options.static_files;
+    HttpServer::new(move || {
+        let mut app = App::new().app_data(playback.clone()).service(logs);
+        if let Some(static_files) = &static_files {
+            app = app.configure(|config| static_files.configure(config));
+        }
+        app
+    })
+    .bind(addr)
+    .context("Failed to bind server")?
+    .run()
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//! Python module `pipes` for post-game analysis in notebooks, build and install it with
+//! `maturin develop --release`, see `pyproject.toml`.
+//!
+//! Log entries, states and summaries are handed over as the dicts and lists that `json.loads`
+//! makes of the logs, so analysis code reads the same fields as the log files.
+
+use crate::{
+    record,
+    simulate::{self, StrategyKind},
+};
+use clap::ValueEnum;
+use pipes_engine::{model, replay};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use serde::Serialize;
+use std::path::PathBuf;
+
+fn to_python<'py>(py: Python<'py>, value: &impl Serialize) -> PyResult<Bound<'py, PyAny>> {
+    let json = serde_json::to_string(value).map_err(anyhow::Error::from)?;
+    py.import("json")?.call_method1("loads", (json,))
+}
+
+/// Parses a line of a game log, users are strings whether the log has tokens or numeric ids
+#[pyfunction]
+fn parse_entry<'py>(py: Python<'py>, line: &str) -> PyResult<Bound<'py, PyAny>> {
+    to_python(py, &replay::parse_entry(line)?)
+}
+
+/// All entries of an uncompressed game log
+#[pyfunction]
+fn read_log(py: Python<'_>, path: PathBuf) -> PyResult<Bound<'_, PyAny>> {
+    to_python(py, &replay::read_log(path)?)
+}
+
+/// Game state rebuilt from the log lines applied so far
+#[pyclass]
+#[derive(Default)]
+struct ReplayState(replay::State);
+
+#[pymethods]
+impl ReplayState {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn apply(&mut self, line: &str) -> PyResult<()> {
+        self.0.apply(&replay::parse_entry(line)?);
+        Ok(())
+    }
+
+    /// Game time of the last applied entry
+    #[getter]
+    fn time(&self) -> f64 {
+        self.0.time
+    }
+
+    /// Pipes, scores and the rest of the state
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        to_python(py, &self.0)
+    }
+}
+
+/// The default config with the fields of `overrides` replaced
+fn merged_config(overrides: Option<&Bound<'_, PyAny>>) -> PyResult<model::Config> {
+    let mut config = serde_json::to_value(model::Config::default()).map_err(anyhow::Error::from)?;
+    if let Some(overrides) = overrides {
+        let json: String = overrides
+            .py()
+            .import("json")?
+            .call_method1("dumps", (overrides,))?
+            .extract()?;
+        let serde_json::Value::Object(overrides) =
+            serde_json::from_str(&json).map_err(anyhow::Error::from)?
+        else {
+            return Err(PyValueError::new_err("The config should be a dict"));
+        };
+        config.as_object_mut().unwrap().extend(overrides);
+    }
+    let config: model::Config = serde_json::from_value(config)
+        .map_err(|e| PyValueError::new_err(format!("Failed to parse config: {e}")))?;
+    let errors = config.validate();
+    if !errors.is_empty() {
+        let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+        return Err(PyValueError::new_err(format!(
+            "Config can't be played: {}",
+            errors.join("; ")
+        )));
+    }
+    Ok(config)
+}
+
+/// Plays games of bots against each other on a virtual clock, so a game takes as long as its
+/// computation, and returns the scores of every game and a summary per strategy
+#[pyfunction]
+#[pyo3(name = "simulate", signature = (config=None, bots=6, strategies=None, games=1))]
+fn run_simulation<'py>(
+    py: Python<'py>,
+    config: Option<&Bound<'py, PyAny>>,
+    bots: usize,
+    strategies: Option<Vec<String>>,
+    games: usize,
+) -> PyResult<Bound<'py, PyAny>> {
+    let config = merged_config(config)?;
+    let strategies = match strategies {
+        Some(names) => names
+            .iter()
+            .map(|name| StrategyKind::from_str(name, true).map_err(PyValueError::new_err))
+            .collect::<PyResult<_>>()?,
+        None => StrategyKind::ALL.to_vec(),
+    };
+    let options = simulate::Options {
+        bots,
+        strategies,
+        games,
+    };
+    let summary = py.detach(|| {
+        futures::executor::block_on(record::on_virtual_clock(move || async move {
+            simulate::run(config, &options).await
+        }))
+    })?;
+    to_python(py, &summary)
+}
+
+#[pymodule]
+fn pipes(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(parse_entry, module)?)?;
+    module.add_function(wrap_pyfunction!(read_log, module)?)?;
+    module.add_function(wrap_pyfunction!(run_simulation, module)?)?;
+    module.add_class::<ReplayState>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyDict;
+
+    #[test]
+    fn test_module() {
+        Python::initialize();
+        Python::attach(|py| {
+            let module = PyModule::new(py, "pipes").unwrap();
+            pipes(&module).unwrap();
+            let locals = PyDict::new(py);
+            locals.set_item("pipes", module).unwrap();
+            py.run(
+                cr#"
+summary = pipes.simulate({"time_to_run": 30, "seed": 1}, bots=2, strategies=["greedy"])
+assert [game["seed"] for game in summary["games"]] == [1]
+assert summary["strategies"]["greedy"]["bots"] == 2
+
+state = pipes.ReplayState()
+line = '{"time": 1.5, "msg": {"type": "UpdateUser", "user": 7, "score": 12}}'
+state.apply(line)
+assert state.time == 1.5
+assert state.to_dict()["scores"] == {"7": 12}
+assert pipes.parse_entry(line)["msg"]["user"] == "7"
+
+try:
+    pipes.simulate({"time_to_run": 30}, strategies=["lucky"])
+    raise AssertionError("unknown strategy accepted")
+except ValueError:
+    pass
+"#,
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+        });
+    }
+}
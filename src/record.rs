@@ -0,0 +1,576 @@
+//! Recording of api requests and replaying them against a fresh in-process server
+
+use crate::{
+    model::{self, UserToken},
+    server::{self, AdminToken},
+};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header::AUTHORIZATION, Method, StatusCode},
+    middleware::Next,
+    rt::{spawn, time::sleep, System},
+    test, web, App,
+};
+use anyhow::Context;
+use futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    future::Future,
+    io::{BufRead, Write},
+    path::Path,
+    rc::Rc,
+    sync::Mutex,
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+/// Seed and users of the recorded game, the first line of a recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub seed: u64,
+    /// Users the game started with, replayed as `user-0`, `user-1` and so on
+    pub users: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_to_run: Option<f64>,
+}
+
+/// Single api call as saved to the recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    /// Game time when the request was received
+    pub time: f64,
+    pub method: String,
+    pub path: String,
+    /// Stable id of the user instead of the token, see [`user_id`]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<serde_json::Value>,
+    pub status: u16,
+}
+
+/// Final results of the recorded game, the last line of a recording of a finished game
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Outcome {
+    pub time: f64,
+    pub results: model::Results,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Line {
+    Header(Header),
+    Request(Record),
+    Outcome(Outcome),
+}
+
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub header: Header,
+    pub records: Vec<Record>,
+    /// Missing if the server was stopped before the game ended
+    pub outcome: Option<Outcome>,
+}
+
+/// Stands in for the admin token, which is replayed as this
+const ADMIN_ID: &str = "admin";
+
+/// Id of a user that stays the same in a replay. Tokens are secrets and are never saved,
+/// users are told apart by the order they joined in
+fn user_id(state: &model::App, token: &UserToken) -> String {
+    match state.user_index(token) {
+        Some(index) => format!("user-{index}"),
+        None => "unknown".to_owned(),
+    }
+}
+
+pub struct Recorder {
+    writer: Mutex<std::io::BufWriter<std::fs::File>>,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let file = std::fs::File::create(path).context("Failed to create requests file")?;
+        Ok(Self {
+            writer: Mutex::new(std::io::BufWriter::new(file)),
+        })
+    }
+
+    fn write(&self, line: &Line) -> anyhow::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        serde_json::to_writer(&mut *writer, line)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Saves what a replay needs to set up the same game, before any request is recorded
+    pub(crate) fn start(
+        &self,
+        state: &model::App,
+        time_to_run: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        self.write(&Line::Header(Header {
+            seed: state.seed(),
+            users: state.user_count(),
+            time_to_run: time_to_run.map(|time| time.as_secs_f64()),
+        }))
+    }
+
+    /// Saves the final results for replays to be checked against
+    pub(crate) async fn finish(&self, state: &model::App) {
+        let results = state
+            .results()
+            .await
+            .into_iter()
+            .map(|(token, score)| (user_id(state, &UserToken::from(token)), score))
+            .collect();
+        let outcome = Outcome {
+            time: state.game_time(),
+            results,
+        };
+        if let Err(e) = self.write(&Line::Outcome(outcome)) {
+            error!("Failed to record the results: {e}");
+        }
+    }
+}
+
+/// Middleware saving every game api call if a [`Recorder`] is registered
+pub async fn record_requests(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let recorder = req.app_data::<web::Data<Recorder>>().cloned();
+    let state = req.app_data::<web::Data<model::App>>().cloned();
+    let (Some(recorder), Some(state)) =
+        (recorder.filter(|_| req.path().starts_with("/api/")), state)
+    else {
+        return next.call(req).await;
+    };
+    let time = state.game_time();
+    let body = req.extract::<web::Bytes>().await?;
+    req.set_payload(actix_web::dev::Payload::from(body.clone()));
+    let method = req.method().to_string();
+    // Admin endpoints take tokens in the path
+    let path = req
+        .uri()
+        .to_string()
+        .split('/')
+        .map(|segment| {
+            let token = UserToken::from(segment.to_owned());
+            match state.user_index(&token) {
+                Some(_) => user_id(&state, &token),
+                None => segment.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(|token| UserToken::from(token.to_owned()));
+    let is_admin = token.as_ref().is_some_and(|token| {
+        req.app_data::<web::Data<AdminToken>>()
+            .is_some_and(|admin| admin.0 == *token)
+    });
+    let response = next.call(req).await?;
+    // After the response, so that users joining with the request are known
+    let user = match &token {
+        _ if is_admin => Some(ADMIN_ID.to_owned()),
+        Some(token) => Some(user_id(&state, token)),
+        None => None,
+    };
+    let record = Record {
+        time,
+        method,
+        path,
+        user,
+        // Admin requests like user imports carry tokens
+        body: serde_json::from_slice(&body).ok().filter(|_| !is_admin),
+        status: response.status().as_u16(),
+    };
+    if let Err(e) = recorder.write(&Line::Request(record)) {
+        error!("Failed to record request: {e}");
+    }
+    Ok(response)
+}
+
+pub fn read_recording(path: impl AsRef<Path>) -> anyhow::Result<Recording> {
+    let file = std::fs::File::open(path).context("Failed to open requests file")?;
+    let mut header = None;
+    let mut records = Vec::new();
+    let mut outcome = None;
+    for (index, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line = serde_json::from_str(&line)
+            .with_context(|| format!("Failed to parse request line {}", index + 1))?;
+        match line {
+            Line::Header(line) => header = Some(line),
+            Line::Request(record) => records.push(record),
+            Line::Outcome(line) => outcome = Some(line),
+        }
+    }
+    Ok(Recording {
+        header: header.context("The recording has no header")?,
+        records,
+        outcome,
+    })
+}
+
+/// Outcome of a replay
+pub struct Replay {
+    pub results: model::Results,
+    pub log: Vec<model::LogEntry>,
+    /// Requests that got a different status than when recorded
+    pub mismatches: usize,
+}
+
+/// Runs the future in a runtime of its own whose clock starts paused, it then only moves on
+/// while every task waits. Games started in it play out the same way every time, unaffected
+/// by the load of the machine or the rounding of timers to the ticks of a running clock.
+pub async fn on_virtual_clock<T, F>(make: impl FnOnce() -> F + Send + 'static) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let (sender, receiver) = oneshot::channel();
+    std::thread::spawn(move || {
+        let system = System::with_tokio_rt(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .start_paused(true)
+                .build()
+                .expect("Failed to build the runtime of the virtual clock")
+        });
+        let _ = sender.send(system.block_on(make()));
+    });
+    receiver
+        .await
+        .context("The game on the virtual clock panicked")?
+}
+
+/// Re-issues recorded requests at their original game times against a new server seeded
+/// like the recorded game, on the virtual clock
+pub async fn replay(recording: &Recording, config: model::Config) -> anyhow::Result<Replay> {
+    let recording = recording.clone();
+    on_virtual_clock(move || async move { replay_paused(&recording, config).await }).await
+}
+
+async fn replay_paused(recording: &Recording, config: model::Config) -> anyhow::Result<Replay> {
+    let Recording {
+        header,
+        records,
+        outcome,
+    } = recording;
+    let config = model::Config {
+        seed: Some(header.seed),
+        ..config
+    };
+    let users = (0..header.users).map(|index| UserToken::from(format!("user-{index}")));
+    let mut state = model::App::init(config, users);
+    state.set_time_to_run(header.time_to_run.map(Duration::from_secs_f64));
+    let state = web::Data::new(state);
+    let (sender, receiver) = mpsc::unbounded();
+    state.register_logs(sender.clone()).await;
+    let scheduler = spawn({
+        let state = state.clone();
+        async move { state.run_scheduler().await }
+    });
+    let service = Rc::new(
+        test::init_service(
+            App::new()
+                .configure({
+                    let state = state.clone();
+                    move |config| server::configure(config, state)
+                })
+                .app_data(web::Data::new(AdminToken(UserToken::from(
+                    ADMIN_ID.to_owned(),
+                ))))
+                .configure(server::configure_admin),
+        )
+        .await,
+    );
+    let tasks: Vec<_> = records
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(|(index, record)| {
+            let service = service.clone();
+            let state = state.clone();
+            spawn(async move {
+                let wait = Duration::from_secs_f64((record.time - state.game_time()).max(0.0));
+                sleep(wait).await;
+                let method = Method::from_bytes(record.method.as_bytes())?;
+                let mut req = test::TestRequest::default()
+                    .method(method)
+                    .uri(&record.path);
+                if let Some(user) = &record.user {
+                    req = req.insert_header((AUTHORIZATION, format!("Bearer {user}")));
+                }
+                if let Some(body) = &record.body {
+                    req = req.set_json(body);
+                }
+                let status = test::call_service(&*service, req.to_request())
+                    .await
+                    .status();
+                if status != StatusCode::from_u16(record.status)? {
+                    warn!(
+                        "Request #{} {} {} got {status} instead of {}",
+                        index + 1,
+                        record.method,
+                        record.path,
+                        record.status,
+                    );
+                    return anyhow::Ok(false);
+                }
+                Ok(true)
+            })
+        })
+        .collect();
+    let mut mismatches = 0;
+    for task in tasks {
+        if !task.await?? {
+            mismatches += 1;
+        }
+    }
+    info!("Replay finished, {mismatches} requests got a different status");
+    if let Some(outcome) = outcome {
+        sleep(Duration::from_secs_f64(
+            (outcome.time - state.game_time()).max(0.0),
+        ))
+        .await;
+        state.announce_end().await;
+    }
+    scheduler.abort();
+    state.unregister_logs(sender).await;
+    Ok(Replay {
+        results: state.results().await,
+        log: receiver.collect().await,
+        mismatches,
+    })
+}
+
+/// Differences between the results of the recorded game and a replay of it
+pub fn check(recording: &Recording, replay: &Replay) -> Vec<String> {
+    let mut differences = Vec::new();
+    if replay.mismatches > 0 {
+        differences.push(format!(
+            "{} requests got a different status",
+            replay.mismatches
+        ));
+    }
+    if let Some(outcome) = &recording.outcome {
+        differences.extend(score_differences(&outcome.results, &replay.results));
+    }
+    differences
+}
+
+fn score_differences(a: &model::Results, b: &model::Results) -> Vec<String> {
+    let users: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+    users
+        .into_iter()
+        .filter(|user| a.get(*user) != b.get(*user))
+        .map(|user| format!("{user}: score {:?} vs {:?}", a.get(user), b.get(user)))
+        .collect()
+}
+
+/// Human readable differences between two replays of the same requests
+pub fn diff(a: &Replay, b: &Replay) -> Vec<String> {
+    let mut differences = score_differences(&a.results, &b.results);
+    // Both ran on the virtual clock, so the times of the entries have to match as well
+    let entries = |replay: &Replay| -> Vec<serde_json::Value> {
+        replay
+            .log
+            .iter()
+            .map(|entry| serde_json::to_value(entry).unwrap())
+            .collect()
+    };
+    let (log_a, log_b) = (entries(a), entries(b));
+    if let Some(index) = (0..log_a.len().max(log_b.len())).find(|&i| log_a.get(i) != log_b.get(i)) {
+        let describe = |replay: &Replay| {
+            replay.log.get(index).map_or("nothing".to_owned(), |entry| {
+                format!(
+                    "{} at {:.3}s",
+                    serde_json::to_string(&entry.msg).unwrap(),
+                    entry.time
+                )
+            })
+        };
+        differences.push(format!(
+            "logs diverge at entry #{index}: {} vs {}",
+            describe(a),
+            describe(b),
+        ));
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        http_client::Client,
+        server::{GameServer, Options},
+    };
+    use actix_web::rt::task::spawn_blocking;
+
+    #[actix_web::test]
+    async fn test_round_trip() {
+        crate::logger::init_for_tests();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        // Not seeded, the recording has to bring the seed along
+        let config = model::Config {
+            time_to_run: Some(1.0),
+            start_delay_secs: 0.0,
+            min_delay_secs: 0.01,
+            max_delay_secs: 0.02,
+            pipe_value_delay_secs: 0.0,
+            game_over_grace_secs: 0.0,
+            ..Default::default()
+        };
+        let server = GameServer::builder()
+            .config(config.clone())
+            .users(["alice-secret", "bob-secret"].map(|token| UserToken::from(token.to_owned())))
+            .options(Options {
+                recorder: Some(Recorder::create(file.path()).unwrap()),
+                admin_token: Some(UserToken::from("admin-secret".to_owned())),
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.addr().to_string();
+        spawn_blocking(move || {
+            let client = |token: &str| Client::new(&addr, Some(token.to_owned()));
+            for pipe in 1..=3 {
+                for token in ["alice-secret", "bob-secret", "mallory"] {
+                    let path = format!("/api/pipe/{pipe}");
+                    client(token).request("PUT", &path, None).unwrap();
+                }
+            }
+            let state = client("admin-secret")
+                .request("GET", "/api/admin/state", None)
+                .unwrap();
+            assert_eq!(state.status, 200);
+        })
+        .await
+        .unwrap();
+        let app = server.wait().await.unwrap();
+
+        let saved = std::fs::read_to_string(file.path()).unwrap();
+        assert!(!saved.contains("secret"), "{saved}");
+        let recording = read_recording(file.path()).unwrap();
+        assert_eq!(recording.header.seed, app.seed());
+        assert_eq!(recording.header.users, 2);
+        assert_eq!(recording.records.len(), 10);
+        assert_eq!(recording.records[2].user.as_deref(), Some("unknown"));
+        assert_eq!(recording.records[9].user.as_deref(), Some(ADMIN_ID));
+        let outcome = recording.outcome.as_ref().unwrap();
+        let results = app.results().await;
+        assert_eq!(outcome.results["user-0"], results["alice-secret"]);
+        assert_eq!(outcome.results["user-1"], results["bob-secret"]);
+
+        let replayed = replay(&recording, config.clone()).await.unwrap();
+        assert_eq!(replayed.mismatches, 0);
+        assert_eq!(replayed.results, outcome.results);
+        assert_eq!(check(&recording, &replayed), Vec::<String>::new());
+
+        // Replays with the same config don't differ, other rules show up in the diff
+        let again = replay(&recording, config.clone()).await.unwrap();
+        assert_eq!(diff(&replayed, &again), Vec::<String>::new());
+        let other = model::Config {
+            initial_score: config.initial_score + 10,
+            ..config
+        };
+        let other = replay(&recording, other).await.unwrap();
+        let differences = diff(&replayed, &other);
+        assert!(
+            differences[0].starts_with("user-0: score"),
+            "{differences:?}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_read_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let read = |lines: &[&str]| {
+            let path = dir.path().join("requests.jsonl");
+            std::fs::write(&path, lines.join("\n")).unwrap();
+            read_recording(path)
+        };
+        let header = r#"{"header":{"seed":7,"users":2}}"#;
+        let request = r#"{"request":{"time":0.5,"method":"PUT","path":"/api/pipe/1","user":"user-1","status":200}}"#;
+
+        // Unfinished games have no outcome, blank lines are skipped
+        let recording = read(&[header, "", request]).unwrap();
+        assert_eq!((recording.header.seed, recording.header.users), (7, 2));
+        assert_eq!(recording.header.time_to_run, None);
+        assert_eq!(recording.records.len(), 1);
+        assert_eq!(recording.records[0].user.as_deref(), Some("user-1"));
+        assert_eq!(recording.records[0].body, None);
+        assert!(recording.outcome.is_none());
+
+        let outcome = r#"{"outcome":{"time":3.0,"results":{"user-0":5}}}"#;
+        let recording = read(&[header, request, outcome]).unwrap();
+        assert_eq!(recording.outcome.unwrap().results["user-0"], 5);
+
+        let error = read(&[request]).unwrap_err();
+        assert_eq!(error.to_string(), "The recording has no header");
+        let error = read(&[header, request, "{\"request\":{}}"]).unwrap_err();
+        assert_eq!(error.to_string(), "Failed to parse request line 3");
+    }
+
+    #[actix_web::test]
+    async fn test_diff() {
+        let entry = |time: f64, msg: model::LogMessage| model::LogEntry { time, msg };
+        let replay = |scores: &[(&str, model::Score)], log: Vec<model::LogEntry>| Replay {
+            results: scores
+                .iter()
+                .map(|&(user, score)| (user.to_owned(), score))
+                .collect(),
+            log,
+            mismatches: 0,
+        };
+        let log = || {
+            vec![
+                entry(0.0, model::LogMessage::GameStart { players: 2 }),
+                entry(1.0, model::LogMessage::GamePaused),
+            ]
+        };
+        let a = replay(&[("user-0", 10), ("user-1", 5)], log());
+        assert_eq!(
+            diff(&a, &replay(&[("user-0", 10), ("user-1", 5)], log())),
+            Vec::<String>::new()
+        );
+
+        // Same scores, but the log moved in time
+        let mut later = log();
+        later[1].time = 1.5;
+        let b = replay(&[("user-0", 10), ("user-1", 5)], later);
+        assert_eq!(
+            diff(&a, &b),
+            [
+                r#"logs diverge at entry #1: {"type":"GamePaused"} at 1.000s vs {"type":"GamePaused"} at 1.500s"#
+            ]
+        );
+
+        // A user missing on one side, and a log cut short
+        let c = replay(&[("user-0", 10)], log()[..1].to_vec());
+        assert_eq!(
+            diff(&a, &c),
+            [
+                "user-1: score Some(5) vs None",
+                r#"logs diverge at entry #1: {"type":"GamePaused"} at 1.000s vs nothing"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Self-contained HTML statistics report generated from a saved game log
+
+use crate::{
+    model::{LogEntry, LogMessage, Modifier, Observation, PipeId, Score, ScoreReason},
+    replay::{self, State},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    path::Path,
+};
+
+const HEATMAP_BUCKETS: usize = 20;
+const CHART_WIDTH: f64 = 800.0;
+const CHART_HEIGHT: f64 = 300.0;
+
+#[derive(Default)]
+struct UserStats {
+    scores: Vec<(f64, Score)>,
+    collects: usize,
+    blocked_secs: f64,
+    values_seen: usize,
+    peeks: usize,
+    /// Collects of pipes observed since the user last collected them
+    informed_collects: usize,
+    /// Pipes observed since the user last collected them
+    observed: BTreeSet<PipeId>,
+    /// Score gained from collects
+    earned: Score,
+    /// Score paid for modifiers and peeks
+    spent: Score,
+}
+
+#[derive(Default)]
+struct Stats {
+    duration: f64,
+    users: BTreeMap<replay::User, UserStats>,
+    modifiers: BTreeMap<Modifier, usize>,
+    /// Collects per pipe per time bucket
+    heatmap: BTreeMap<PipeId, [usize; HEATMAP_BUCKETS]>,
+}
+
+impl Stats {
+    fn collect(entries: &[LogEntry<replay::User>]) -> Self {
+        let mut stats = Stats {
+            duration: entries.last().map_or(0.0, |entry| entry.time),
+            ..Default::default()
+        };
+        let bucket = |time: f64| {
+            let bucket =
+                (time / stats.duration.max(f64::EPSILON) * HEATMAP_BUCKETS as f64) as usize;
+            bucket.min(HEATMAP_BUCKETS - 1)
+        };
+        let mut state = State::default();
+        for entry in entries {
+            match &entry.msg {
+                LogMessage::CollectStart {
+                    user,
+                    pipe_id,
+                    delay,
+                } => {
+                    let user = stats.users.entry(user.clone()).or_default();
+                    user.collects += 1;
+                    user.blocked_secs += delay.as_secs_f64();
+                    if user.observed.remove(pipe_id) {
+                        user.informed_collects += 1;
+                    }
+                    stats
+                        .heatmap
+                        .entry(*pipe_id)
+                        .or_insert([0; HEATMAP_BUCKETS])[bucket(entry.time)] += 1;
+                }
+                LogMessage::UpdatePipe { id, state: pipe } => {
+                    if let Some(old) = state.pipes.get(id) {
+                        for modifier in pipe.modifiers.keys() {
+                            if !old.modifiers.contains_key(modifier) {
+                                *stats.modifiers.entry(*modifier).or_default() += 1;
+                            }
+                        }
+                        if old.direction != pipe.direction {
+                            *stats.modifiers.entry(Modifier::Reverse).or_default() += 1;
+                        }
+                        if old.base_delay != pipe.base_delay {
+                            *stats.modifiers.entry(Modifier::Shuffle).or_default() += 1;
+                        }
+                    }
+                }
+                LogMessage::UpdateUser {
+                    user,
+                    state,
+                    change,
+                } => {
+                    let user = stats.users.entry(user.clone()).or_default();
+                    user.scores.push((entry.time, state.score));
+                    match change {
+                        Some(change)
+                            if matches!(
+                                change.reason,
+                                ScoreReason::Collect { .. } | ScoreReason::Steal { .. }
+                            ) =>
+                        {
+                            user.earned += change.delta;
+                        }
+                        Some(change) => user.spent -= change.delta,
+                        None => {}
+                    }
+                }
+                LogMessage::ValueObserved {
+                    user,
+                    pipe_id,
+                    observation,
+                } => {
+                    let user = stats.users.entry(user.clone()).or_default();
+                    match observation {
+                        Observation::PipeValue { .. } => user.values_seen += 1,
+                        Observation::Peek { .. } => user.peeks += 1,
+                    }
+                    user.observed.insert(*pipe_id);
+                }
+                _ => {}
+            }
+            state.apply(entry);
+        }
+        stats
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn user_color(index: usize, count: usize) -> String {
+    format!("hsl({}turn 100% 40%)", index as f64 / count.max(1) as f64)
+}
+
+fn score_chart(html: &mut String, stats: &Stats) -> std::fmt::Result {
+    let scores = stats.users.values().flat_map(|user| &user.scores);
+    let min = scores
+        .clone()
+        .map(|&(_, score)| score)
+        .min()
+        .unwrap_or(0)
+        .min(0);
+    let max = scores
+        .map(|&(_, score)| score)
+        .max()
+        .unwrap_or(0)
+        .max(min + 1);
+    let x = |time: f64| time / stats.duration.max(f64::EPSILON) * CHART_WIDTH;
+    let y = |score: Score| CHART_HEIGHT - (score - min) as f64 / (max - min) as f64 * CHART_HEIGHT;
+    writeln!(
+        html,
+        r#"<svg viewBox="0 0 {CHART_WIDTH} {CHART_HEIGHT}" width="{CHART_WIDTH}" height="{CHART_HEIGHT}">"#
+    )?;
+    for (index, user) in stats.users.values().enumerate() {
+        let mut points = String::new();
+        // Users do not necessarily start with zero score
+        let mut last = user.scores.first().map_or(0, |&(_, score)| score);
+        for &(time, score) in &user.scores {
+            // Scores are step functions
+            write!(points, "{},{} {},{} ", x(time), y(last), x(time), y(score))?;
+            last = score;
+        }
+        write!(points, "{},{}", x(stats.duration), y(last))?;
+        writeln!(
+            html,
+            r#"<polyline fill="none" stroke="{}" points="{points}"/>"#,
+            user_color(index, stats.users.len()),
+        )?;
+    }
+    writeln!(html, "</svg>")
+}
+
+pub fn generate(log: impl AsRef<Path>, out: impl AsRef<Path>) -> anyhow::Result<()> {
+    let entries = replay::read_log(log)?;
+    let stats = Stats::collect(&entries);
+
+    let mut html = String::new();
+    writeln!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Game report</title>"
+    )?;
+    writeln!(
+        html,
+        "<style>body {{ font-family: sans-serif; }} td, th {{ padding: 2px 8px; }}</style>"
+    )?;
+    writeln!(html, "</head><body>\n<h1>Game report</h1>")?;
+    writeln!(html, "<p>Duration: {:.1}s</p>", stats.duration)?;
+
+    writeln!(html, "<h2>Scores</h2>")?;
+    score_chart(&mut html, &stats)?;
+    writeln!(
+        html,
+        "<table><tr><th>User</th><th>Final score</th><th>Collects</th><th>Blocked time</th>\
+         <th>Values seen</th><th>Peeks</th><th>Informed collects</th>\
+         <th>Earned</th><th>Spent</th></tr>"
+    )?;
+    for (index, (name, user)) in stats.users.iter().enumerate() {
+        writeln!(
+            html,
+            r#"<tr><td style="color: {}">{}</td><td>{}</td><td>{}</td><td>{:.1}s</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>"#,
+            user_color(index, stats.users.len()),
+            escape(name),
+            user.scores.last().map_or(0, |&(_, score)| score),
+            user.collects,
+            user.blocked_secs,
+            user.values_seen,
+            user.peeks,
+            user.informed_collects,
+            user.earned,
+            user.spent,
+        )?;
+    }
+    writeln!(html, "</table>")?;
+
+    writeln!(html, "<h2>Modifier usage</h2>\n<table>")?;
+    for (modifier, count) in &stats.modifiers {
+        writeln!(html, "<tr><td>{modifier:?}</td><td>{count}</td></tr>")?;
+    }
+    writeln!(html, "</table>")?;
+
+    writeln!(html, "<h2>Collects per pipe over time</h2>\n<table>")?;
+    let max_collects = stats
+        .heatmap
+        .values()
+        .flatten()
+        .copied()
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    for (pipe_id, buckets) in &stats.heatmap {
+        write!(html, "<tr><th>#{pipe_id}</th>")?;
+        for &collects in buckets {
+            let alpha = collects as f64 / max_collects as f64;
+            write!(
+                html,
+                r#"<td title="{collects}" style="background: rgba(255, 64, 0, {alpha:.2})"></td>"#
+            )?;
+        }
+        writeln!(html, "</tr>")?;
+    }
+    writeln!(html, "</table>\n</body></html>")?;
+
+    std::fs::write(out, html)?;
+    Ok(())
+}
@@ -0,0 +1,305 @@
+//! SQLite database of finished games, so that results of many games can be compared
+
+use crate::model;
+use actix_web::rt::task::spawn_blocking;
+use anyhow::Context;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS games (
+    id INTEGER PRIMARY KEY,
+    name TEXT,
+    finished_at INTEGER NOT NULL,
+    duration_secs REAL NOT NULL,
+    seed INTEGER NOT NULL,
+    config TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS players (
+    game_id INTEGER NOT NULL REFERENCES games(id) ON DELETE CASCADE,
+    user TEXT NOT NULL,
+    name TEXT,
+    rank INTEGER NOT NULL,
+    score INTEGER NOT NULL,
+    collects INTEGER NOT NULL,
+    modifiers_bought INTEGER NOT NULL,
+    earned INTEGER NOT NULL,
+    spent INTEGER NOT NULL,
+    errors INTEGER NOT NULL,
+    time_blocked REAL NOT NULL,
+    PRIMARY KEY (game_id, user)
+);
+CREATE INDEX IF NOT EXISTS players_by_user ON players(user);
+";
+
+#[derive(Debug, Serialize)]
+pub struct PastPlayer {
+    pub user: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Players with equal scores share a rank
+    pub rank: usize,
+    #[serde(with = "crate::serde_score")]
+    pub score: model::Score,
+    pub collects: u64,
+    pub modifiers_bought: u64,
+    #[serde(with = "crate::serde_score")]
+    pub earned: model::Score,
+    #[serde(with = "crate::serde_score")]
+    pub spent: model::Score,
+    pub errors: u64,
+    pub time_blocked: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PastGame {
+    pub id: i64,
+    /// Id of a lobby game, missing for the main one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Unix time in seconds
+    pub finished_at: i64,
+    pub duration_secs: f64,
+    pub seed: u64,
+    pub config: serde_json::Value,
+    /// From the winner down
+    pub players: Vec<PastPlayer>,
+}
+
+#[derive(Debug, Clone, Deserialize, utoipa::IntoParams)]
+pub struct PastGamesQuery {
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    #[serde(default)]
+    pub offset: usize,
+    /// Only games this user played in
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+fn default_limit() -> usize {
+    20
+}
+
+/// Queries run on the blocking thread pool, a slow disk must not stall the api workers
+pub struct ResultsDb {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl ResultsDb {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open the results database {path:?}"))?;
+        connection
+            .execute_batch(SCHEMA)
+            .context("Failed to create the results database tables")?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Records the game, failures are only logged so that they do not stop the server
+    pub async fn save(&self, app: &model::App, name: Option<&str>) {
+        match self.record(app, name).await {
+            Ok(id) => info!("Results of the game are recorded as #{id}"),
+            Err(e) => error!("Failed to record the results of the game: {e:#}"),
+        }
+    }
+
+    /// Saves the results of a finished game, returns the id of its record
+    pub async fn record(&self, app: &model::App, name: Option<&str>) -> anyhow::Result<i64> {
+        let results = app.detailed_results().await;
+        let config = serde_json::to_string(&*app.config())?;
+        let finished_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let duration_secs = app.game_time();
+        let seed = app.seed() as i64;
+        let name = name.map(ToOwned::to_owned);
+        let connection = self.connection.clone();
+        spawn_blocking(move || {
+            let mut connection = connection.lock().unwrap();
+            insert_game(
+                &mut connection,
+                name.as_deref(),
+                finished_at,
+                duration_secs,
+                seed,
+                &config,
+                &results,
+            )
+        })
+        .await?
+    }
+
+    /// For keeping more tables in the same database
+    pub(crate) fn connection(&self) -> Arc<Mutex<Connection>> {
+        self.connection.clone()
+    }
+
+    /// Recorded games, the latest first
+    pub async fn history(&self, query: PastGamesQuery) -> anyhow::Result<Vec<PastGame>> {
+        let connection = self.connection.clone();
+        spawn_blocking(move || select_games(&connection.lock().unwrap(), &query)).await?
+    }
+}
+
+fn insert_game(
+    connection: &mut Connection,
+    name: Option<&str>,
+    finished_at: i64,
+    duration_secs: f64,
+    seed: i64,
+    config: &str,
+    results: &model::DetailedResults,
+) -> anyhow::Result<i64> {
+    let mut scores: Vec<(&String, &model::Score)> = results.scores.iter().collect();
+    scores.sort_by_key(|(_, &score)| std::cmp::Reverse(score));
+    let transaction = connection.transaction()?;
+    transaction.execute(
+        "INSERT INTO games (name, finished_at, duration_secs, seed, config)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![name, finished_at, duration_secs, seed, config],
+    )?;
+    let game_id = transaction.last_insert_rowid();
+    let mut rank = 0;
+    let mut last_score = None;
+    for (index, (user, &score)) in scores.into_iter().enumerate() {
+        if last_score != Some(score) {
+            rank = index + 1;
+            last_score = Some(score);
+        }
+        let stats = results.players.get(user).cloned().unwrap_or_default();
+        let name = results
+            .profiles
+            .get(user)
+            .and_then(|profile| profile.name.as_deref());
+        transaction.execute(
+            "INSERT INTO players (game_id, user, name, rank, score, collects,
+             modifiers_bought, earned, spent, errors, time_blocked)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                game_id,
+                user,
+                name,
+                rank,
+                score,
+                stats.collects,
+                stats.modifiers_bought,
+                stats.earned,
+                stats.spent,
+                stats.errors,
+                stats.time_blocked,
+            ],
+        )?;
+    }
+    transaction.commit()?;
+    Ok(game_id)
+}
+
+fn select_games(connection: &Connection, query: &PastGamesQuery) -> anyhow::Result<Vec<PastGame>> {
+    let mut games_statement = connection.prepare(
+        "SELECT id, name, finished_at, duration_secs, seed, config FROM games
+         WHERE ?1 IS NULL OR id IN (SELECT game_id FROM players WHERE user = ?1)
+         ORDER BY id DESC LIMIT ?2 OFFSET ?3",
+    )?;
+    let mut players_statement = connection.prepare(
+        "SELECT user, name, rank, score, collects, modifiers_bought, earned, spent,
+         errors, time_blocked FROM players WHERE game_id = ?1 ORDER BY rank, user",
+    )?;
+    let games = games_statement
+        .query_map(
+            params![query.user, query.limit as i64, query.offset as i64],
+            |row| {
+                Ok(PastGame {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    finished_at: row.get(2)?,
+                    duration_secs: row.get(3)?,
+                    seed: row.get::<_, i64>(4)? as u64,
+                    config: serde_json::from_str(&row.get::<_, String>(5)?).unwrap_or_default(),
+                    players: Vec::new(),
+                })
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    games
+        .into_iter()
+        .map(|mut game| {
+            game.players = players_statement
+                .query_map([game.id], |row| {
+                    Ok(PastPlayer {
+                        user: row.get(0)?,
+                        name: row.get(1)?,
+                        rank: row.get(2)?,
+                        score: row.get(3)?,
+                        collects: row.get(4)?,
+                        modifiers_bought: row.get(5)?,
+                        earned: row.get(6)?,
+                        spent: row.get(7)?,
+                        errors: row.get(8)?,
+                        time_blocked: row.get(9)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(game)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let results_db = ResultsDb::open(&dir.path().join("results.sqlite")).unwrap();
+        let [alice, bob, carol] =
+            ["alice", "bob", "carol"].map(|token| model::UserToken::from(token.to_owned()));
+        let config = model::Config {
+            initial_score: 100,
+            initial_scores: [(carol.clone(), 50)].into(),
+            ..Default::default()
+        };
+        let first = model::App::init(config.clone(), [alice.clone(), bob.clone(), carol]);
+        assert_eq!(results_db.record(&first, None).await.unwrap(), 1);
+        let second = model::App::init(config, [alice, bob]);
+        assert_eq!(
+            results_db.record(&second, Some("rematch")).await.unwrap(),
+            2
+        );
+
+        let all = PastGamesQuery {
+            limit: 10,
+            offset: 0,
+            user: None,
+        };
+        let games = results_db.history(all.clone()).await.unwrap();
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[0].name.as_deref(), Some("rematch"));
+        assert_eq!(games[1].seed, first.seed());
+        assert_eq!(games[1].config["initial_score"], 100);
+        let ranks: Vec<_> = games[1]
+            .players
+            .iter()
+            .map(|player| (player.user.as_str(), player.rank, player.score))
+            .collect();
+        assert_eq!(
+            ranks,
+            [("alice", 1, 100), ("bob", 1, 100), ("carol", 3, 50)]
+        );
+
+        let carols = PastGamesQuery {
+            user: Some("carol".to_owned()),
+            ..all
+        };
+        let games = results_db.history(carols).await.unwrap();
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].id, 1);
+    }
+}
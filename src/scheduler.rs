@@ -0,0 +1,111 @@
+//! Client side helper that runs the actions of one user without fighting `UserBusy`.
+//!
+//! Actions are sent one at a time. When the server reports the user busy, the scheduler
+//! asks for the actions in flight and waits until they are expected to complete,
+//! overloaded servers are retried after their `Retry-After`.
+
+use crate::{
+    http_client::{Client, Response},
+    model::{TimeResponse, UserActionsResponse},
+};
+use std::{collections::VecDeque, thread::sleep, time::Duration};
+use tracing::debug;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+pub struct Action {
+    pub method: String,
+    pub path: String,
+    pub body: Option<serde_json::Value>,
+}
+
+impl Action {
+    pub fn new(method: &str, path: &str, body: Option<serde_json::Value>) -> Self {
+        Self {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            body,
+        }
+    }
+}
+
+pub struct Scheduler {
+    client: Client,
+    queue: VecDeque<Action>,
+    /// Attempts of a single action before its last response is returned as is
+    pub max_attempts: usize,
+}
+
+fn is_busy(response: &Response) -> bool {
+    serde_json::from_str::<serde_json::Value>(&response.body)
+        .is_ok_and(|body| body["error"] == "UserBusy")
+}
+
+impl Scheduler {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            queue: VecDeque::new(),
+            max_attempts: 20,
+        }
+    }
+
+    /// How long until the actions of the user in flight are expected to complete
+    fn busy_for(&self) -> anyhow::Result<Option<Duration>> {
+        let actions: UserActionsResponse =
+            serde_json::from_str(&self.client.request("GET", "/api/user/action", None)?.body)?;
+        let time: TimeResponse =
+            serde_json::from_str(&self.client.request("GET", "/api/time", None)?.body)?;
+        Ok(actions
+            .actions
+            .iter()
+            .map(|action| action.expected_completion - time.time)
+            .max_by(f64::total_cmp)
+            .map(|secs| Duration::from_secs_f64(secs.max(0.0))))
+    }
+
+    /// Sends the action as soon as the user is free, retrying while the user is busy
+    /// or the server is overloaded
+    pub fn act_when_free(&mut self, action: &Action) -> anyhow::Result<Response> {
+        let mut backoff = MIN_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let response =
+                self.client
+                    .request(&action.method, &action.path, action.body.as_ref())?;
+            let wait = match response.status {
+                429 | 503 => response
+                    .header("retry-after")
+                    .and_then(|secs| secs.parse().ok())
+                    .map(Duration::from_secs_f64),
+                _ if is_busy(&response) => self.busy_for()?,
+                _ => return Ok(response),
+            };
+            if attempt >= self.max_attempts {
+                return Ok(response);
+            }
+            let wait = wait.unwrap_or(backoff).max(MIN_BACKOFF);
+            debug!(
+                "{} {} got {}, retrying in {wait:?}",
+                action.method, action.path, response.status
+            );
+            sleep(wait);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+
+    pub fn enqueue(&mut self, action: Action) {
+        self.queue.push_back(action);
+    }
+
+    /// Runs the queued actions in order, stops at the first request that could not be sent
+    pub fn run_queue(&mut self) -> anyhow::Result<Vec<Response>> {
+        let mut responses = Vec::with_capacity(self.queue.len());
+        while let Some(action) = self.queue.pop_front() {
+            responses.push(self.act_when_free(&action)?);
+        }
+        Ok(responses)
+    }
+}
@@ -0,0 +1,194 @@
+//! Smoke test of the game api, runs a scripted session against an in-process server
+
+use crate::{
+    config_schema,
+    model::{self, LogMessage, UserToken},
+    server,
+};
+use actix_web::{
+    http::{header::AUTHORIZATION, StatusCode},
+    test, web, App,
+};
+use anyhow::ensure;
+use futures::{channel::mpsc, StreamExt};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tracing::info;
+
+/// Finds the documented operation for a concrete request path like `/api/pipe/1/value`
+fn find_operation<'a>(spec: &'a Value, method: &str, path: &str) -> Option<&'a Value> {
+    let segments: Vec<_> = path.split('/').collect();
+    spec["paths"]
+        .as_object()?
+        .iter()
+        .find(|(template, _)| {
+            let template: Vec<_> = template.split('/').collect();
+            template.len() == segments.len()
+                && template.iter().zip(&segments).all(|(expected, actual)| {
+                    expected == actual || (expected.starts_with('{') && expected.ends_with('}'))
+                })
+        })
+        .and_then(|(_, item)| item.get(method))
+}
+
+/// Checks a response body against the schema the api description gives for its status
+fn conforms(
+    spec: &Value,
+    method: &str,
+    path: &str,
+    status: StatusCode,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let operation = find_operation(spec, method, path)
+        .ok_or_else(|| anyhow::anyhow!("{method} {path} is not documented"))?;
+    conforms_to(spec, operation, status, body)
+}
+
+/// Checks a response body against the schema the operation gives for its status
+pub(crate) fn conforms_to(
+    spec: &Value,
+    operation: &Value,
+    status: StatusCode,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let responses = &operation["responses"];
+    let range = format!("{}XX", status.as_u16() / 100);
+    let response = [status.as_str(), &range, "default"]
+        .into_iter()
+        .map(|code| &responses[code])
+        .find(|response| response.is_object())
+        .ok_or_else(|| anyhow::anyhow!("status {status} is not documented"))?;
+    let schema = &response["content"]["application/json"]["schema"];
+    if schema.is_null() {
+        return Ok(());
+    }
+    let value: Value = serde_json::from_slice(body)?;
+    let errors = config_schema::validate_against(spec, schema, &value);
+    ensure!(
+        errors.is_empty(),
+        "response does not match the schema: {errors:?}"
+    );
+    Ok(())
+}
+
+async fn check<T: DeserializeOwned>(
+    service: &impl actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+    >,
+    spec: &Value,
+    name: &str,
+    request: test::TestRequest,
+    expected_status: StatusCode,
+) -> anyhow::Result<T> {
+    let response = test::call_service(service, request.to_request()).await;
+    let method = response.request().method().as_str().to_lowercase();
+    let path = response.request().path().to_owned();
+    let status = response.status();
+    let body = test::read_body(response).await;
+    ensure!(
+        status == expected_status,
+        "{name}: expected {expected_status}, got {status}: {}",
+        String::from_utf8_lossy(&body),
+    );
+    conforms(spec, &method, &path, status, &body).map_err(|e| anyhow::anyhow!("{name}: {e}"))?;
+    let result = serde_json::from_slice(&body)
+        .map_err(|e| anyhow::anyhow!("{name}: unexpected response {body:?}: {e}"))?;
+    info!("{name}: ok");
+    Ok(result)
+}
+
+const TOKEN: &str = "selftest";
+
+pub async fn run() -> anyhow::Result<()> {
+    let token = UserToken::from(TOKEN.to_owned());
+    let config = model::Config {
+        min_delay_secs: 0.0,
+        max_delay_secs: 0.0,
+        pipe_value_delay_secs: 0.0,
+        min_value: 100,
+        max_value: 200,
+        ..Default::default()
+    };
+    let state = web::Data::new(model::App::init(config, vec![token.clone()]));
+    let (sender, receiver) = mpsc::unbounded();
+    state.register_logs(sender.clone()).await;
+    let service = test::init_service(App::new().configure({
+        let state = state.clone();
+        move |config| server::configure(config, state)
+    }))
+    .await;
+    let auth = (AUTHORIZATION, format!("Bearer {TOKEN}"));
+    let request = test::TestRequest::get()
+        .uri("/api/openapi.json")
+        .to_request();
+    let spec: Value = test::call_and_read_body_json(&service, request).await;
+
+    check::<serde_json::Value>(
+        &service,
+        &spec,
+        "unknown user",
+        test::TestRequest::get()
+            .uri("/api/pipe/1/value")
+            .insert_header((AUTHORIZATION, "Bearer intruder")),
+        StatusCode::UNAUTHORIZED,
+    )
+    .await?;
+    check::<model::PipeValueResponse>(
+        &service,
+        &spec,
+        "pipe value",
+        test::TestRequest::get()
+            .uri("/api/pipe/1/value")
+            .insert_header(auth.clone()),
+        StatusCode::OK,
+    )
+    .await?;
+    let collected: model::CollectResponse = check(
+        &service,
+        &spec,
+        "collect",
+        test::TestRequest::put()
+            .uri("/api/pipe/1")
+            .insert_header(auth.clone()),
+        StatusCode::OK,
+    )
+    .await?;
+    check::<model::ApplyModifierResponse>(
+        &service,
+        &spec,
+        "apply modifier",
+        test::TestRequest::post()
+            .uri("/api/pipe/2/modifier")
+            .insert_header(auth.clone())
+            .set_json(serde_json::json!({ "type": model::Modifier::Reverse })),
+        StatusCode::OK,
+    )
+    .await?;
+    check::<model::ShopResponse>(
+        &service,
+        &spec,
+        "shop",
+        test::TestRequest::get().uri("/api/shop"),
+        StatusCode::OK,
+    )
+    .await?;
+
+    state.unregister_logs(sender).await;
+    let entries: Vec<model::LogEntry> = receiver.collect().await;
+    let collect_logged = entries.iter().any(
+        |entry| matches!(&entry.msg, LogMessage::CollectEnd { user, pipe_id } if *user == token && pipe_id.get() == 1),
+    );
+    ensure!(collect_logged, "logs: collect was not logged");
+    let score = entries.iter().rev().find_map(|entry| match &entry.msg {
+        LogMessage::UpdateUser { user, state, .. } if *user == token => Some(state.score),
+        _ => None,
+    });
+    ensure!(
+        score == Some(collected.value - state.modifier_cost(model::Modifier::Reverse)),
+        "logs: unexpected final score {score:?}",
+    );
+    info!("logs: ok");
+    Ok(())
+}
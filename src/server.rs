@@ -1,10 +1,34 @@
-use crate::model::{self, UserToken};
+use crate::{
+    accounts::{AccountError, AccountResponse, Accounts, ApiKeyResponse, Credentials},
+    audit::{audit_requests, AuditLog},
+    chaos::{inject_chaos, ChaosInjector},
+    lobby::{Lobby, LobbyError, NewGame},
+    log_format::LogFormat,
+    model::{self, PipeId, UserToken},
+    record::{record_requests, Recorder},
+    replay::LOG_SCHEMA_VERSION,
+    results_db::{PastGamesQuery, ResultsDb},
+    serde_score,
+    viewer_auth::{Scope, ViewerAuth},
+};
 use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
 use actix_web::{
+    body::{EitherBody, MessageBody},
+    cookie::Cookie,
+    delete,
+    dev::{ServerHandle, Service, ServiceRequest, ServiceResponse},
+    error::PathError,
     get,
-    http::{KeepAlive, StatusCode},
+    http::{
+        header::{
+            HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, LOCATION,
+            RETRY_AFTER, WWW_AUTHENTICATE,
+        },
+        KeepAlive, StatusCode,
+    },
+    middleware::{from_fn, Condition, Next},
     post, put,
-    rt::{spawn, time::sleep},
+    rt::{signal, spawn, task::JoinHandle, time::sleep},
     web::{self, ServiceConfig},
     App, FromRequest, HttpRequest, HttpResponse, HttpServer, Responder,
 };
@@ -12,347 +36,6961 @@ use actix_web_actors::ws;
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use anyhow::Context;
 use futures::{
-    channel::mpsc,
+    channel::oneshot,
     future::{
-        select,
+        self, select,
         Either::{Left, Right},
     },
-    Future, FutureExt, StreamExt,
+    stream, Future, FutureExt, StreamExt,
 };
-use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::{net::ToSocketAddrs, path::Path, pin::Pin, sync::Arc, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
+
+/// Token of a user that passed authentication
+struct AuthorizedUser(UserToken);
+
+impl std::ops::Deref for AuthorizedUser {
+    type Target = UserToken;
+    fn deref(&self) -> &UserToken {
+        &self.0
+    }
+}
 
 // Authorization is done using bearer tokens
-impl FromRequest for UserToken {
-    type Error = <BearerAuth as FromRequest>::Error;
+impl FromRequest for AuthorizedUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+    fn from_request(req: &HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let auth = BearerAuth::from_request(req, payload);
+        let state = req.app_data::<web::Data<model::App>>().cloned();
+        let ip = req.peer_addr().map(|addr| addr.ip());
+        async move {
+            let Ok(auth) = auth.await else {
+                // Same body as for unknown tokens, so that clients handle both alike
+                let config = state
+                    .as_ref()
+                    .map(|state| state.config())
+                    .unwrap_or_default();
+                return Err(ApiError::new(&config, model::Error::UserNotFound).into());
+            };
+            let token: UserToken = auth.token().to_owned().into();
+            if let Some(state) = state {
+                state
+                    .authenticate(&token, ip)
+                    .await
+                    .map_err(|error| ApiError::new(&state.config(), error))?;
+                state.wait_for_start().await;
+            }
+            Ok(AuthorizedUser(token))
+        }
+        .boxed_local()
+    }
+}
+
+pub(crate) struct AdminToken(pub(crate) UserToken);
+
+impl AdminToken {
+    /// Compares in constant time, so that response times tell nothing about the token
+    fn matches(&self, token: &UserToken) -> bool {
+        use std::borrow::Borrow;
+        let expected: &String = self.0.borrow();
+        let token: &String = token.borrow();
+        subtle::ConstantTimeEq::ct_eq(expected.as_bytes(), token.as_bytes()).into()
+    }
+}
+
+/// Addresses of the listeners dedicated to the admin api, set once they are bound
+#[derive(Default)]
+struct AdminListeners(std::sync::OnceLock<Vec<SocketAddr>>);
+
+/// Operator authorized with the admin token
+struct Admin;
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
     fn from_request(req: &HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
         let auth = BearerAuth::from_request(req, payload);
+        let admin_token = req.app_data::<web::Data<AdminToken>>().cloned();
+        // Not even admitting that the admin api exists on the public listeners
+        let other_listener = req
+            .app_data::<web::Data<AdminListeners>>()
+            .and_then(|listeners| listeners.0.get())
+            .is_some_and(|addrs| !addrs.contains(&req.app_config().local_addr()));
         async move {
-            let auth = auth.await?;
-            Ok(auth.token().to_owned().into())
+            if other_listener {
+                return Err(actix_web::error::ErrorNotFound("Not found"));
+            }
+            let token: UserToken = auth.await?.token().to_owned().into();
+            match admin_token {
+                Some(admin_token) if admin_token.matches(&token) => Ok(Admin),
+                _ => {
+                    warn!("Someone tried to use the admin api with incorrect token: {token:?}");
+                    Err(actix_web::error::ErrorForbidden("Admin token required"))
+                }
+            }
         }
         .boxed_local()
     }
 }
 
-fn respond<T: Serialize>(result: Result<T, model::Error>) -> HttpResponse {
-    #[derive(Serialize)]
-    struct ErrorPayload {
-        error: model::Error,
+/// Attached to error responses so that metrics can count them
+/// and problem details can be built from them
+pub(crate) struct GameError(pub(crate) model::Error);
+
+/// Reader of the log over the api. When the viewer is protected this takes a viewer token
+/// with access to the logs, as the `/logs` streams do, in the query, a cookie or the header
+struct LogsReader;
+
+impl FromRequest for LogsReader {
+    type Error = actix_web::Error;
+    type Future = future::Ready<Result<Self, Self::Error>>;
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let Some(auth) = req.app_data::<web::Data<ViewerAuth>>() else {
+            return future::ready(Ok(LogsReader));
+        };
+        let query_token = web::Query::<ViewerTokenQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().token);
+        let cookie = req.cookie(VIEWER_TOKEN_COOKIE);
+        let header_token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "));
+        let allowed = query_token
+            .as_deref()
+            .into_iter()
+            .chain(cookie.as_ref().map(|cookie| cookie.value()))
+            .chain(header_token)
+            .any(|token| auth.verify_for(token, Scope::Logs, lobby_game(req)));
+        if allowed {
+            return future::ready(Ok(LogsReader));
+        }
+        warn!(
+            "Rejected request to {:?} without a valid viewer token",
+            req.path()
+        );
+        future::ready(Err(actix_web::error::ErrorForbidden(
+            "Viewer token with access to the logs required",
+        )))
+    }
+}
+
+/// Status of a game error unless overridden in `Config::error_statuses`
+pub(crate) fn default_status(code: model::ErrorCode) -> StatusCode {
+    match code {
+        model::ErrorCode::UserNotFound => StatusCode::UNAUTHORIZED,
+        model::ErrorCode::TooManyAuthFailures => StatusCode::TOO_MANY_REQUESTS,
+        model::ErrorCode::UserBusy => StatusCode::FORBIDDEN,
+        model::ErrorCode::PipeNotFound => StatusCode::NOT_FOUND,
+        model::ErrorCode::PipeLocked => StatusCode::CONFLICT,
+        model::ErrorCode::NotEnoughScore => StatusCode::UNPROCESSABLE_ENTITY,
+        model::ErrorCode::ModifierAlreadyApplied => StatusCode::UNPROCESSABLE_ENTITY,
+        model::ErrorCode::Bankrupt => StatusCode::FORBIDDEN,
+        model::ErrorCode::PipeNotAssigned => StatusCode::CONFLICT,
+        model::ErrorCode::ServerOverloaded => StatusCode::SERVICE_UNAVAILABLE,
+        model::ErrorCode::ActionAborted => StatusCode::CONFLICT,
+        model::ErrorCode::GamePaused => StatusCode::SERVICE_UNAVAILABLE,
+        model::ErrorCode::PipeShielded => StatusCode::CONFLICT,
+        model::ErrorCode::ModifierDisabled => StatusCode::UNPROCESSABLE_ENTITY,
+        model::ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        model::ErrorCode::Cooldown => StatusCode::TOO_MANY_REQUESTS,
+        model::ErrorCode::GameOver => StatusCode::GONE,
+        model::ErrorCode::SpectatorOnly => StatusCode::FORBIDDEN,
+        model::ErrorCode::OperationNotFound => StatusCode::NOT_FOUND,
+        model::ErrorCode::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+        model::ErrorCode::MisconductLockout => StatusCode::TOO_MANY_REQUESTS,
+        model::ErrorCode::TokenBoundToOtherIp => StatusCode::FORBIDDEN,
+    }
+}
+
+/// Game errors as http responses
+#[derive(Debug, thiserror::Error)]
+#[error("{error}")]
+struct ApiError {
+    error: model::Error,
+    status: StatusCode,
+}
+
+impl ApiError {
+    fn new(config: &model::Config, error: model::Error) -> Self {
+        let status = config
+            .error_statuses
+            .get(&error.code())
+            .and_then(|&status| StatusCode::from_u16(status).ok())
+            .unwrap_or_else(|| default_status(error.code()));
+        Self { error, status }
+    }
+}
+
+/// Body of game errors, the status of every error can be changed with `error_statuses`
+#[derive(Serialize, utoipa::ToSchema)]
+struct ErrorPayload {
+    /// Stable, for telling errors apart
+    code: model::ErrorCode,
+    /// For humans, may change
+    message: String,
+    details: model::ErrorDetails,
+    /// Same as `code`, kept for older clients
+    error: model::ErrorCode,
+    /// Same as `details.retry_after_secs`, kept for older clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<f64>,
+}
+
+impl From<model::Error> for ErrorPayload {
+    fn from(error: model::Error) -> Self {
+        let details = error.details();
+        Self {
+            code: error.code(),
+            message: error.to_string(),
+            retry_after_secs: details.retry_after_secs,
+            details,
+            error: error.code(),
+        }
+    }
+}
+
+impl actix_web::ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+    fn error_response(&self) -> HttpResponse {
+        let mut response =
+            HttpResponse::build(self.status_code()).json(ErrorPayload::from(self.error));
+        if let Some(wait) = self.error.retry_after() {
+            // The header only takes whole seconds
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+        if let model::Error::UserNotFound = self.error {
+            // As in RFC 6750, so that generic clients know to get a new token
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static(
+                    "Bearer error=\"invalid_token\", error_description=\"User not found\"",
+                ),
+            );
+        }
+        response.extensions_mut().insert(GameError(self.error));
+        response
     }
-    match result {
+}
+
+fn respond<T: Serialize>(state: &model::App, result: Result<T, model::Error>) -> HttpResponse {
+    let mut response = match result {
         Ok(result) => HttpResponse::Ok().json(result),
-        Err(error) => HttpResponse::build(match error {
-            model::Error::UserNotFound => StatusCode::UNAUTHORIZED,
-            model::Error::UserBusy => StatusCode::FORBIDDEN,
-            model::Error::PipeNotFound => StatusCode::NOT_FOUND,
-            model::Error::NotEnoughScore => StatusCode::UNPROCESSABLE_ENTITY,
-            model::Error::ModifierAlreadyApplied => StatusCode::UNPROCESSABLE_ENTITY,
-        })
-        .json(ErrorPayload { error }),
+        Err(error) => {
+            actix_web::ResponseError::error_response(&ApiError::new(&state.config(), error))
+        }
+    };
+    if let Some(time_left) = state.time_left() {
+        response.headers_mut().insert(
+            HeaderName::from_static("x-game-ends-in"),
+            HeaderValue::from_str(&format!("{:.3}", time_left.as_secs_f64())).unwrap(),
+        );
     }
+    response
+}
+
+#[derive(Deserialize, utoipa::IntoParams)]
+struct CollectQuery {
+    /// Respond right away with the pending operation instead of waiting for the outcome
+    #[serde(default = "default_wait")]
+    wait: bool,
+}
+
+fn default_wait() -> bool {
+    true
 }
 
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const OPERATION_ID: &str = "operation-id";
+
+/// Every collect is an operation, its id is sent in the `operation-id` header.
+/// Retries with the same `idempotency-key` header get the outcome of the first request,
+/// such collects as well as the ones not waited for are never rolled back.
+#[utoipa::path(
+    params(
+        ("n" = usize, Path, description = "Pipe id, from 1"),
+        CollectQuery,
+        ("idempotency-key" = Option<String>, Header, description = "Identifies retries of the request"),
+    ),
+    responses(
+        (status = OK, body = model::CollectResponse),
+        (status = ACCEPTED, body = model::Operation, description = "Started without waiting"),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
 #[put("/api/pipe/{n}")]
 async fn collect(
     state: web::Data<model::App>,
-    user: UserToken,
-    path: web::Path<usize>,
+    user: AuthorizedUser,
+    path: web::Path<PipeId>,
+    query: web::Query<CollectQuery>,
+    req: HttpRequest,
+) -> impl Responder {
+    let pipe_id = path.into_inner();
+    let key = req
+        .headers()
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|key| key.to_str().ok())
+        .map(str::to_owned);
+    let recoverable = key.is_some() || !query.wait;
+    let id = match state.begin_operation(&user, pipe_id, key).await {
+        Ok(model::OperationStart::New(id)) => {
+            // The collect runs in its own task so that it is rolled back (or committed) in full
+            // when the client goes away, which drops this handler and with it `_connected`
+            let (_connected, gone) = oneshot::channel::<()>();
+            let task = spawn({
+                let (state, user) = (state.clone(), user.0.clone());
+                async move {
+                    if recoverable {
+                        // The client can find out the outcome later
+                        state
+                            .run_operation(&user, id, pipe_id, future::pending())
+                            .await
+                    } else {
+                        let gone = gone.map(|_| ());
+                        state.run_operation(&user, id, pipe_id, gone).await
+                    }
+                }
+                .in_current_span()
+            });
+            if !recoverable {
+                let result = task.await.expect("Collect task panicked");
+                let mut response = respond(&state, result);
+                insert_operation_id(&mut response, id);
+                return response;
+            }
+            id
+        }
+        Ok(model::OperationStart::Existing(id)) => id,
+        Err(error) => {
+            let result: Result<model::CollectResponse, _> = Err(error);
+            return respond(&state, result);
+        }
+    };
+    let mut response = if query.wait {
+        let result = state
+            .wait_for_operation(&user, id)
+            .await
+            .and_then(|operation| operation.status.result().unwrap());
+        respond(&state, result)
+    } else {
+        match state.operation(&user, id).await {
+            Ok(operation) => HttpResponse::Accepted().json(operation),
+            Err(error) => respond(&state, Err::<(), _>(error)),
+        }
+    };
+    insert_operation_id(&mut response, id);
+    response
+}
+
+fn insert_operation_id(response: &mut HttpResponse, id: u64) {
+    response
+        .headers_mut()
+        .insert(HeaderName::from_static(OPERATION_ID), HeaderValue::from(id));
+}
+
+#[utoipa::path(
+    params(("id" = u64, Path, description = "Operation id from the `operation-id` header")),
+    responses(
+        (status = OK, body = model::Operation),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/operations/{id}")]
+async fn operation_status(
+    state: web::Data<model::App>,
+    user: AuthorizedUser,
+    path: web::Path<u64>,
+) -> impl Responder {
+    respond(&state, state.operation(&user, path.into_inner()).await)
+}
+
+#[utoipa::path(
+    params(("n" = usize, Path, description = "Pipe id, from 1")),
+    responses(
+        (status = OK, body = model::PeekResponse),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/pipe/{n}/peek")]
+async fn peek(
+    state: web::Data<model::App>,
+    user: AuthorizedUser,
+    path: web::Path<PipeId>,
 ) -> impl Responder {
     let pipe_id = path.into_inner();
-    respond(state.collect(&user, pipe_id).await)
+    let result = state.peek(&user, pipe_id).await;
+    respond(&state, result)
 }
 
+#[utoipa::path(
+    params(("n" = usize, Path, description = "Pipe id, from 1")),
+    responses(
+        (status = OK, body = model::PipeValueResponse),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
 #[get("/api/pipe/{n}/value")]
 async fn pipe_value(
     state: web::Data<model::App>,
-    user: UserToken,
-    path: web::Path<usize>,
+    user: AuthorizedUser,
+    path: web::Path<PipeId>,
 ) -> impl Responder {
     let pipe_id = path.into_inner();
-    respond(state.pipe_value(&user, pipe_id).await)
+    let result = state.pipe_value(&user, pipe_id).await;
+    respond(&state, result)
 }
 
-#[derive(Serialize, Deserialize)]
-struct ApplyModifierInput {
-    #[serde(rename = "type")]
-    modifier: model::Modifier,
+#[derive(Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PipeValuesQuery {
+    /// Comma separated pipe ids
+    ids: String,
 }
 
-#[post("/api/pipe/{n}/modifier")]
-async fn apply_modifier(
+#[utoipa::path(
+    params(PipeValuesQuery),
+    responses(
+        (status = OK, body = model::PipeValuesResponse),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/pipes/values")]
+async fn pipe_values(
     state: web::Data<model::App>,
-    user: UserToken,
-    path: web::Path<usize>,
-    input: web::Json<ApplyModifierInput>,
+    user: AuthorizedUser,
+    query: web::Query<PipeValuesQuery>,
+) -> impl Responder {
+    let pipe_ids = query
+        .ids
+        .split(',')
+        .map(|id| id.trim().parse::<PipeId>())
+        .collect::<Result<Vec<_>, _>>();
+    let result = match pipe_ids {
+        Ok(pipe_ids) => state.pipe_values(&user, &pipe_ids).await,
+        Err(_) => Err(model::Error::PipeNotFound { pipe_id: None }),
+    };
+    respond(&state, result)
+}
+
+#[utoipa::path(
+    responses(
+        (status = OK, body = model::User),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/user")]
+async fn user_state(state: web::Data<model::App>, user: AuthorizedUser) -> impl Responder {
+    respond(&state, state.user_state(&user).await)
+}
+
+#[utoipa::path(
+    responses(
+        (status = OK, body = model::UserActionsResponse),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/user/action")]
+async fn user_actions(state: web::Data<model::App>, user: AuthorizedUser) -> impl Responder {
+    respond(&state, state.user_actions(&user).await)
+}
+
+#[utoipa::path(
+    responses(
+        (status = OK, body = model::AssignedPipeResponse),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/user/pipe")]
+async fn assigned_pipe(state: web::Data<model::App>, user: AuthorizedUser) -> impl Responder {
+    respond(&state, state.assigned_pipe(&user).await)
+}
+
+#[utoipa::path(
+    responses(
+        (status = OK, body = model::AbortActionsResponse),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[delete("/api/user/action")]
+async fn cancel_actions(state: web::Data<model::App>, user: AuthorizedUser) -> impl Responder {
+    respond(
+        &state,
+        state
+            .abort_actions(&user, model::AbortReason::Cancelled)
+            .await,
+    )
+}
+
+const MAX_HISTORY_PAGE: usize = 1000;
+
+fn default_history_limit() -> usize {
+    100
+}
+
+#[derive(Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HistoryQuery {
+    #[serde(default)]
+    from: usize,
+    #[serde(default = "default_history_limit")]
+    limit: usize,
+}
+
+#[get("/api/pipe/{n}/history")]
+async fn pipe_history(
+    state: web::Data<model::App>,
+    _reader: LogsReader,
+    path: web::Path<PipeId>,
+    query: web::Query<HistoryQuery>,
 ) -> impl Responder {
     let pipe_id = path.into_inner();
-    let input = input.into_inner();
-    respond(state.apply_modifier(&user, pipe_id, input.modifier).await)
+    let limit = query.limit.min(MAX_HISTORY_PAGE);
+    respond(&state, state.pipe_history(pipe_id, query.from, limit).await)
 }
 
-#[get("/logs")]
-async fn logs(
+#[derive(Deserialize)]
+struct LogsPageQuery {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_history_limit")]
+    limit: usize,
+}
+
+#[get("/api/logs")]
+async fn logs_page(
     state: web::Data<model::App>,
-    req: HttpRequest,
-    stream: web::Payload,
-) -> actix_web::Result<HttpResponse> {
-    struct LogsWs {
-        state: web::Data<model::App>,
-        sender: Option<mpsc::UnboundedSender<model::LogEntry>>,
-    }
-    impl Actor for LogsWs {
-        type Context = ws::WebsocketContext<Self>;
-        fn started(&mut self, ctx: &mut Self::Context) {
-            let addr = ctx.address();
-            let state = self.state.clone();
-            let (sender, receiver) = mpsc::unbounded::<model::LogEntry>();
-            self.sender = Some(sender.clone());
-            spawn(async move {
-                state.register_logs(sender.clone()).await;
-                let mut receiver = receiver.boxed_local();
-                while let Some(entry) = receiver.next().await {
-                    addr.do_send(entry);
-                }
-            });
-        }
-        fn stopped(&mut self, _ctx: &mut Self::Context) {
-            if let Some(sender) = self.sender.clone() {
-                let state = self.state.clone();
-                spawn(async move {
-                    state.unregister_logs(&sender).await;
-                });
-            }
-        }
-    }
-    impl actix::Message for model::LogEntry {
-        type Result = ();
-    }
-    impl actix::Handler<model::LogEntry> for LogsWs {
-        type Result = ();
-        fn handle(&mut self, msg: model::LogEntry, ctx: &mut Self::Context) {
-            ctx.text(serde_json::to_string_pretty(&msg).expect("Failed to serialize log message"));
-        }
-    }
-    impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LogsWs {
-        fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-            let msg = match msg {
-                Err(_) => {
-                    ctx.stop();
-                    return;
-                }
-                Ok(msg) => msg,
-            };
-            debug!("WEBSOCKET MESSAGE: {msg:?}");
-            match msg {
-                ws::Message::Ping(msg) => {
-                    ctx.pong(&msg);
-                }
-                ws::Message::Pong(_) => {}
-                ws::Message::Text(_) => error!("Unexpected text message"),
-                ws::Message::Binary(_) => error!("Unexpected binary"),
-                ws::Message::Close(reason) => {
-                    ctx.close(reason);
-                    ctx.stop();
-                }
-                ws::Message::Continuation(_) => {
-                    ctx.stop();
-                }
-                ws::Message::Nop => (),
-            }
+    _reader: LogsReader,
+    query: web::Query<LogsPageQuery>,
+) -> impl Responder {
+    let limit = query.limit.min(MAX_HISTORY_PAGE);
+    HttpResponse::Ok().json(state.logs_page(query.offset, limit).await)
+}
+
+#[utoipa::path(
+    params(HistoryQuery),
+    responses(
+        (status = OK, body = crate::history::HistoryResponse),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/user/history")]
+async fn user_history(
+    state: web::Data<model::App>,
+    user: AuthorizedUser,
+    query: web::Query<HistoryQuery>,
+) -> impl Responder {
+    let limit = query.limit.min(MAX_HISTORY_PAGE);
+    respond(&state, state.user_history(&user, query.from, limit).await)
+}
+
+/// Games recorded in the results database, the latest first
+#[utoipa::path(
+    params(PastGamesQuery),
+    responses(
+        (status = OK, body = Value, description = "Past games"),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+        (status = NOT_FOUND, description = "No results database"),
+        (status = INTERNAL_SERVER_ERROR, description = "The results database failed"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/history")]
+async fn game_history(
+    results_db: Option<web::Data<ResultsDb>>,
+    _admin: Admin,
+    query: web::Query<PastGamesQuery>,
+) -> impl Responder {
+    let Some(results_db) = results_db else {
+        return HttpResponse::NotFound().body("No results database, start with --db");
+    };
+    let mut query = query.into_inner();
+    query.limit = query.limit.min(MAX_HISTORY_PAGE);
+    match results_db.history(query).await {
+        Ok(games) => HttpResponse::Ok().json(games),
+        Err(e) => {
+            error!("Failed to read the results database: {e:#}");
+            HttpResponse::InternalServerError().finish()
         }
     }
-    ws::start(
-        LogsWs {
-            state,
-            sender: None,
-        },
-        &req,
-        stream,
+}
+
+#[utoipa::path(
+    params(("token" = String, Path, description = "Token of the user"), HistoryQuery),
+    responses(
+        (status = OK, body = crate::history::HistoryResponse),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/admin/user/{token}/history")]
+async fn admin_user_history(
+    state: web::Data<model::App>,
+    _admin: Admin,
+    path: web::Path<UserToken>,
+    query: web::Query<HistoryQuery>,
+) -> impl Responder {
+    let user = path.into_inner();
+    let limit = query.limit.min(MAX_HISTORY_PAGE);
+    respond(&state, state.user_history(&user, query.from, limit).await)
+}
+
+#[utoipa::path(
+    params(("token" = String, Path, description = "Token of the user")),
+    responses(
+        (status = OK, body = model::AbortActionsResponse),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[delete("/api/admin/user/{token}/action")]
+async fn admin_abort_actions(
+    state: web::Data<model::App>,
+    _admin: Admin,
+    path: web::Path<UserToken>,
+) -> impl Responder {
+    let user = path.into_inner();
+    respond(
+        &state,
+        state.abort_actions(&user, model::AbortReason::Admin).await,
     )
 }
 
-fn configure(config: &mut ServiceConfig, state: web::Data<model::App>) {
-    config
-        .app_data(state)
-        .service(pipe_value)
-        .service(collect)
-        .service(apply_modifier);
+#[utoipa::path(
+    request_body(content = Value, description = "Users with their profiles"),
+    responses(
+        (status = OK, body = Value, description = "Users added and updated"),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+        (status = BAD_REQUEST, description = "Malformed users"),
+    ),
+    security(("token" = [])),
+)]
+#[post("/api/admin/users/import")]
+async fn admin_import_users(
+    state: web::Data<model::App>,
+    _admin: Admin,
+    input: web::Json<Vec<model::UserRecord>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(state.import_users(input.into_inner()).await)
 }
 
-pub async fn run(
-    addr: impl ToSocketAddrs,
-    state: model::App,
-    time_to_run: Option<Duration>,
-    serve_dir: Option<impl AsRef<Path>>,
-    enable_logs_api: bool,
-) -> anyhow::Result<Arc<model::App>> {
-    let serve_dir = serve_dir.map(|s| s.as_ref().to_owned());
-    let state = web::Data::new(state);
-    let server = HttpServer::new({
-        let state = state.clone();
-        move || {
-            let mut app = App::new().configure(|config| configure(config, state.clone()));
-            if enable_logs_api {
-                app = app.service(logs);
-            }
-            if let Some(dir) = &serve_dir {
-                app = app.service(actix_files::Files::new("/", dir).index_file("index.html"));
-            }
-            app
-        }
-    })
-    .keep_alive(KeepAlive::Disabled)
-    .bind(addr)
-    .context("Failed to bind server")?
-    .run();
-    let server_handle = server.handle();
-    let server_future = spawn(server);
-    match time_to_run {
-        Some(time) => match select(server_future, sleep(time).boxed()).await {
-            Left((server, _sleep)) => {
-                warn!("Server was shutdown before timeout was reached");
-                server??;
-            }
-            Right((_sleep, server_future)) => {
-                info!("Time is up, shutting down the server");
-                server_handle.stop(true).await;
-                server_future.await??;
-            }
-        },
-        None => {
-            info!("You can press Ctrl-C to stop the server");
-            server_future.await??;
-        }
-    };
-    info!("Server stopped");
+#[utoipa::path(
+    responses(
+        (status = OK, body = Value, description = "Users with their profiles and scores"),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/admin/users/export")]
+async fn admin_export_users(state: web::Data<model::App>, _admin: Admin) -> impl Responder {
+    HttpResponse::Ok().json(state.export_users().await)
+}
 
-    Ok(state.into_inner())
+#[utoipa::path(
+    responses(
+        (status = OK, body = Value, description = "Game state rebuilt from the log"),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/admin/state")]
+async fn admin_game_state(state: web::Data<model::App>, _admin: Admin) -> impl Responder {
+    HttpResponse::Ok().json(state.game_state())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use actix_web::{http::header::AUTHORIZATION, rt::task::spawn_blocking, test};
-    use actix_web_httpauth::headers::authorization::Bearer;
+/// Viewers connected to `/logs`, to spot one saturating the uplink
+#[utoipa::path(
+    responses(
+        (status = OK, body = Value, description = "Connected viewers"),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/admin/subscribers")]
+async fn admin_subscribers(state: web::Data<model::App>, _admin: Admin) -> impl Responder {
+    HttpResponse::Ok().json(state.metrics().subscribers())
+}
 
-    #[actix_web::test]
-    #[ignore]
-    async fn test_java() {
-        crate::logger::init_for_tests();
-        spawn_blocking(|| {
-            let compilation_status = std::process::Command::new("javac")
-                .arg("java_test/Main.java")
-                .status()
-                .unwrap();
-            assert!(compilation_status.success());
-        })
-        .await
-        .unwrap();
-        let config = model::Config::default();
-        let app = run(
-            "127.0.0.1:8080",
-            model::App::init(config, vec![]),
-            Some(Duration::from_secs(2)),
-            None::<&str>,
-            false,
-        );
-        let client = async {
-            sleep(Duration::from_secs(1)).await; // Wait for server to start
-            let status = spawn_blocking(|| {
-                std::process::Command::new("java")
-                    .arg("java_test/Main")
-                    .status()
-                    .unwrap()
+#[utoipa::path(
+    responses(
+        (status = OK, body = Value, description = "Rate limited requests by endpoint"),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/admin/rate_limits")]
+async fn admin_rate_limits(state: web::Data<model::App>, _admin: Admin) -> impl Responder {
+    HttpResponse::Ok().json(state.metrics().rate_limits())
+}
+
+/// Lets a token bound with `bind_tokens_to_ip` be used from another ip
+#[utoipa::path(
+    params(("token" = String, Path, description = "Token of the user")),
+    responses(
+        (status = OK, body = Value, description = "Ip the token was bound to"),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+    ),
+    security(("token" = [])),
+)]
+#[delete("/api/admin/user/{token}/ip")]
+async fn admin_unbind_ip(
+    state: web::Data<model::App>,
+    _admin: Admin,
+    path: web::Path<UserToken>,
+) -> impl Responder {
+    let ip = state.unbind_token_ip(&path.into_inner());
+    HttpResponse::Ok().json(serde_json::json!({ "ip": ip }))
+}
+
+#[utoipa::path(
+    params(("token" = String, Path, description = "Token of the user")),
+    responses(
+        (status = OK),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[delete("/api/admin/user/{token}")]
+async fn admin_remove_user(
+    state: web::Data<model::App>,
+    _admin: Admin,
+    path: web::Path<UserToken>,
+) -> impl Responder {
+    respond(&state, state.remove_user(&path.into_inner()).await)
+}
+
+#[utoipa::path(
+    params(("n" = usize, Path, description = "Pipe id, from 1")),
+    request_body(content = Value, description = "Pipe fields to change"),
+    responses(
+        (status = OK, body = Value, description = "The pipe after the update"),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+        (status = BAD_REQUEST, description = "Malformed update"),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[put("/api/admin/pipe/{n}")]
+async fn admin_update_pipe(
+    state: web::Data<model::App>,
+    _admin: Admin,
+    path: web::Path<PipeId>,
+    input: web::Json<model::PipeUpdate>,
+) -> impl Responder {
+    respond(
+        &state,
+        state
+            .update_pipe(path.into_inner(), input.into_inner())
+            .await,
+    )
+}
+
+#[utoipa::path(
+    responses(
+        (status = OK),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+    ),
+    security(("token" = [])),
+)]
+#[post("/api/admin/end")]
+async fn admin_end_game(state: web::Data<model::App>, _admin: Admin) -> impl Responder {
+    state.end_game().await;
+    HttpResponse::Ok().finish()
+}
+
+/// Starts a game waiting for players without waiting for the rest
+#[utoipa::path(
+    responses(
+        (status = OK),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+        (status = CONFLICT, description = "The game is not waiting for players"),
+    ),
+    security(("token" = [])),
+)]
+#[post("/api/admin/start")]
+async fn admin_start_game(state: web::Data<model::App>, _admin: Admin) -> impl Responder {
+    if state.start_game() {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::Conflict().body("The game is not waiting for players")
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (status = OK),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+    ),
+    security(("token" = [])),
+)]
+#[post("/api/admin/pause")]
+async fn admin_pause(state: web::Data<model::App>, _admin: Admin) -> impl Responder {
+    state.pause().await;
+    HttpResponse::Ok().finish()
+}
+
+#[utoipa::path(
+    responses(
+        (status = OK),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+    ),
+    security(("token" = [])),
+)]
+#[post("/api/admin/resume")]
+async fn admin_resume(state: web::Data<model::App>, _admin: Admin) -> impl Responder {
+    state.resume().await;
+    HttpResponse::Ok().finish()
+}
+
+/// Replaces the config of the running game, fields that only matter at the start can't change
+#[utoipa::path(
+    request_body(content = Value, description = "Game config, see `--print-config-schema`"),
+    responses(
+        (status = OK, body = Value, description = "The config in effect"),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+        (status = BAD_REQUEST, description = "Malformed config"),
+        (status = UNPROCESSABLE_ENTITY, description = "Fields that can't change were changed"),
+    ),
+    security(("token" = [])),
+)]
+#[post("/api/admin/config")]
+async fn admin_update_config(
+    state: web::Data<model::App>,
+    _admin: Admin,
+    input: web::Json<model::Config>,
+) -> impl Responder {
+    match state.update_config(input.into_inner()) {
+        Ok(config) => HttpResponse::Ok().json(&*config),
+        Err(e) => HttpResponse::UnprocessableEntity().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+struct LogLevelInput {
+    /// Module path prefix, the default level is changed if not specified
+    target: Option<String>,
+    level: String,
+}
+
+#[utoipa::path(
+    request_body = LogLevelInput,
+    responses(
+        (status = OK),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+        (status = BAD_REQUEST, description = "Unknown log level"),
+    ),
+    security(("token" = [])),
+)]
+#[put("/api/admin/log_level")]
+async fn set_log_level(_admin: Admin, input: web::Json<LogLevelInput>) -> impl Responder {
+    let LogLevelInput { target, level } = input.into_inner();
+    let Ok(level) = level.parse::<tracing_subscriber::filter::LevelFilter>() else {
+        return HttpResponse::BadRequest().body(format!("Unknown log level: {level}"));
+    };
+    info!("Setting log level of {target:?} to {level}");
+    crate::logger::set_level(target, level);
+    HttpResponse::Ok().finish()
+}
+
+#[get("/api/overlay")]
+async fn overlay(state: web::Data<model::App>, _reader: LogsReader) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "public, max-age=1"))
+        .json(&*state.overlay())
+}
+
+/// Full standings with display names, for spectators
+#[get("/api/scoreboard")]
+async fn scoreboard(state: web::Data<model::App>, _reader: LogsReader) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "public, max-age=1"))
+        .json(state.scoreboard())
+}
+
+#[utoipa::path(responses((status = OK, body = model::TimeResponse)))]
+#[get("/api/time")]
+async fn game_time(state: web::Data<model::App>) -> impl Responder {
+    HttpResponse::Ok().json(state.time())
+}
+
+#[utoipa::path(responses((status = OK, body = model::GameResponse)))]
+#[get("/api/game")]
+async fn game_clock(state: web::Data<model::App>) -> impl Responder {
+    HttpResponse::Ok().json(state.game())
+}
+
+/// Hands out a fresh token when anyone may play, the body with a display name is optional
+#[utoipa::path(
+    request_body(content = Option<model::RegisterRequest>),
+    responses(
+        (status = OK, body = model::RegisterResponse),
+        (status = NOT_FOUND, description = "Only listed users may play"),
+    ),
+)]
+#[post("/api/register")]
+async fn register(
+    state: web::Data<model::App>,
+    body: Option<web::Json<model::RegisterRequest>>,
+) -> impl Responder {
+    let request = body.map_or_else(Default::default, web::Json::into_inner);
+    match state.register(request).await {
+        Some(registered) => HttpResponse::Ok().json(registered),
+        None => HttpResponse::NotFound().body("Only listed users may play this game"),
+    }
+}
+
+fn account_error(error: AccountError) -> HttpResponse {
+    match error {
+        e @ (AccountError::InvalidUsername | AccountError::WeakPassword) => {
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+        e @ AccountError::UsernameTaken => HttpResponse::Conflict().body(e.to_string()),
+        e @ (AccountError::WrongCredentials
+        | AccountError::UnknownToken
+        | AccountError::UnknownApiKey) => HttpResponse::Unauthorized().body(e.to_string()),
+        AccountError::Database(e) => {
+            error!("Failed to use the accounts database: {e:#}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Creates a ladder account, its games are recorded under its name
+#[utoipa::path(
+    request_body = Credentials,
+    responses(
+        (status = CREATED, body = AccountResponse),
+        (status = BAD_REQUEST, description = "Invalid username or weak password"),
+        (status = CONFLICT, description = "The username is taken"),
+    ),
+)]
+#[post("/api/account")]
+async fn create_account(
+    accounts: web::Data<Accounts>,
+    credentials: web::Json<Credentials>,
+) -> impl Responder {
+    match accounts.create(credentials.into_inner()).await {
+        Ok(account) => HttpResponse::Created().json(account),
+        Err(e) => account_error(e),
+    }
+}
+
+/// Token of the account for this game, the same one until it leaves the game
+#[utoipa::path(
+    request_body = Credentials,
+    responses(
+        (status = OK, body = model::RegisterResponse),
+        (status = BAD_REQUEST, description = "Malformed credentials"),
+        (status = UNAUTHORIZED, description = "Wrong username or password"),
+    ),
+)]
+#[post("/api/account/token")]
+async fn account_token(
+    state: web::Data<model::App>,
+    accounts: web::Data<Accounts>,
+    credentials: web::Json<Credentials>,
+) -> impl Responder {
+    match accounts.issue_token(&state, credentials.into_inner()).await {
+        Ok(registered) => HttpResponse::Ok().json(registered),
+        Err(e) => account_error(e),
+    }
+}
+
+/// New long-lived API key of the account, for bots to get their tokens at `/api/token`
+#[utoipa::path(
+    request_body = Credentials,
+    responses(
+        (status = CREATED, body = ApiKeyResponse),
+        (status = BAD_REQUEST, description = "Malformed credentials"),
+        (status = UNAUTHORIZED, description = "Wrong username or password"),
+    ),
+)]
+#[post("/api/account/key")]
+async fn create_api_key(
+    accounts: web::Data<Accounts>,
+    credentials: web::Json<Credentials>,
+) -> impl Responder {
+    match accounts.create_api_key(credentials.into_inner()).await {
+        Ok(key) => HttpResponse::Created().json(key),
+        Err(e) => account_error(e),
+    }
+}
+
+/// Exchanges an API key of an account, given as the bearer token, for its token in this game.
+/// The token only works in the game it was issued for, lobby games hand out theirs at
+/// `/api/games/{id}/token`
+#[utoipa::path(
+    responses(
+        (status = OK, body = model::RegisterResponse),
+        (status = UNAUTHORIZED, description = "No bearer token or unknown API key"),
+    ),
+    security(("token" = [])),
+)]
+#[post("/api/token")]
+async fn exchange_api_key(
+    state: web::Data<model::App>,
+    accounts: web::Data<Accounts>,
+    auth: BearerAuth,
+) -> impl Responder {
+    match accounts.exchange_api_key(&state, auth.token()).await {
+        Ok(registered) => HttpResponse::Ok().json(registered),
+        Err(e) => account_error(e),
+    }
+}
+
+/// The account a token was issued to with its recorded games, tokens of past games work too
+#[utoipa::path(
+    responses(
+        (status = OK, body = AccountResponse),
+        (status = UNAUTHORIZED, description = "No bearer token or not issued to an account"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/account")]
+async fn account_state(accounts: web::Data<Accounts>, auth: BearerAuth) -> impl Responder {
+    match accounts.account(&auth.token().to_owned().into()).await {
+        Ok(account) => HttpResponse::Ok().json(account),
+        Err(e) => account_error(e),
+    }
+}
+
+#[utoipa::path(responses((status = OK, body = model::ShopResponse)))]
+#[get("/api/shop")]
+async fn shop(state: web::Data<model::App>) -> impl Responder {
+    HttpResponse::Ok().json(state.shop())
+}
+
+/// Current modifier prices, they rise with demand if `dynamic_pricing` is configured
+#[utoipa::path(responses((status = OK, body = model::PricesResponse)))]
+#[get("/api/prices")]
+async fn prices(state: web::Data<model::App>) -> impl Responder {
+    HttpResponse::Ok().json(model::PricesResponse {
+        dynamic: state.config().dynamic_pricing.is_some(),
+        prices: state.prices(),
+    })
+}
+
+/// Global events in effect, so that bots can adapt to them
+#[utoipa::path(responses((status = OK, body = model::GlobalEventsResponse)))]
+#[get("/api/events")]
+async fn global_events(state: web::Data<model::App>) -> impl Responder {
+    HttpResponse::Ok().json(model::GlobalEventsResponse {
+        events: state.active_global_events(),
+    })
+}
+
+#[derive(Serialize, Deserialize, utoipa::ToSchema)]
+struct ApplyModifierInput {
+    #[serde(rename = "type")]
+    modifier: model::Modifier,
+}
+
+#[utoipa::path(
+    responses(
+        (status = OK, body = model::PipesResponse),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/pipes")]
+async fn list_pipes(state: web::Data<model::App>, user: AuthorizedUser) -> impl Responder {
+    respond(&state, state.list_pipes(&user).await)
+}
+
+#[utoipa::path(
+    params(("n" = usize, Path, description = "Pipe id, from 1")),
+    responses(
+        (status = OK, body = model::PipeModifiersResponse),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[get("/api/pipe/{n}/modifier")]
+async fn pipe_modifiers(
+    state: web::Data<model::App>,
+    user: AuthorizedUser,
+    path: web::Path<PipeId>,
+) -> impl Responder {
+    let pipe_id = path.into_inner();
+    respond(&state, state.pipe_modifiers(&user, pipe_id).await)
+}
+
+#[utoipa::path(
+    params(("n" = usize, Path, description = "Pipe id, from 1")),
+    responses(
+        (status = OK, body = model::ApplyModifierResponse),
+        (status = BAD_REQUEST, description = "Malformed body or unknown modifier"),
+        (status = "4XX", body = ErrorPayload, description = "Game error"),
+    ),
+    security(("token" = [])),
+)]
+#[post("/api/pipe/{n}/modifier")]
+async fn apply_modifier(
+    state: web::Data<model::App>,
+    user: AuthorizedUser,
+    path: web::Path<PipeId>,
+    input: web::Json<ApplyModifierInput>,
+) -> impl Responder {
+    let pipe_id = path.into_inner();
+    let input = input.into_inner();
+    let result = state.apply_modifier(&user, pipe_id, input.modifier).await;
+    respond(&state, result)
+}
+
+/// Serializes a log entry into a websocket frame.
+/// Failures are reported to the subscriber instead of killing the connection.
+pub(crate) fn log_frame(entry: &impl Serialize) -> String {
+    serde_json::to_string_pretty(entry).unwrap_or_else(|e| {
+        error!("Failed to serialize log message: {e}");
+        serde_json::json!({
+            "error": "SerializationFailed",
+            "message": e.to_string(),
+        })
+        .to_string()
+    })
+}
+
+/// Command sent over the game websocket, answered with a message carrying the same id
+#[derive(Deserialize)]
+struct WsCommand {
+    id: u64,
+    #[serde(flatten)]
+    action: WsAction,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WsAction {
+    Value {
+        pipe: PipeId,
+    },
+    Collect {
+        pipe: PipeId,
+    },
+    Modifier {
+        pipe: PipeId,
+        modifier: model::Modifier,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WsMessage {
+    Response {
+        id: u64,
+        body: serde_json::Value,
+    },
+    Error {
+        id: u64,
+        #[serde(flatten)]
+        error: ErrorPayload,
+    },
+    /// Sent for commands that could not be parsed
+    Invalid {
+        message: String,
+    },
+    /// Own score, sent once connected and whenever it changes
+    Score {
+        #[serde(with = "serde_score")]
+        score: model::Score,
+    },
+}
+
+impl actix::Message for WsMessage {
+    type Result = ();
+}
+
+type WsResponse = model::Result<serde_json::Value>;
+
+async fn ws_command(state: &model::App, user: &UserToken, action: WsAction) -> WsResponse {
+    fn body<T: Serialize>(result: model::Result<T>) -> WsResponse {
+        result.map(|body| serde_json::to_value(body).expect("Responses are valid json"))
+    }
+    match action {
+        WsAction::Value { pipe } => body(state.pipe_value(user, pipe).await),
+        WsAction::Collect { pipe } => body(state.collect(user, pipe).await),
+        WsAction::Modifier { pipe, modifier } => {
+            body(state.apply_modifier(user, pipe, modifier).await)
+        }
+    }
+}
+
+/// Game api over a websocket, saving the latency of a request per action.
+/// Commands run concurrently, same as separate http requests would
+#[get("/api/ws")]
+async fn game_ws(
+    state: web::Data<model::App>,
+    user: AuthorizedUser,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    struct GameWs {
+        state: web::Data<model::App>,
+        user: UserToken,
+        score_format: serde_score::Format,
+        sender: Option<model::LogSubscriber>,
+    }
+    impl Actor for GameWs {
+        type Context = ws::WebsocketContext<Self>;
+        fn started(&mut self, ctx: &mut Self::Context) {
+            let state = self.state.clone();
+            let user = self.user.clone();
+            let (sender, receiver) = state.log_channel(None);
+            self.sender = Some(sender.clone());
+            // The current score is read once subscribed, so that no change is missed in between
+            let current = {
+                let user = user.clone();
+                async move {
+                    state.register_live_logs(sender).await;
+                    state.user_state(&user).await.ok().map(|user| user.score)
+                }
+            };
+            let updates = receiver.filter_map(move |entry| {
+                let score = match entry.msg {
+                    model::LogMessage::UpdateUser {
+                        user: updated,
+                        state,
+                        ..
+                    } if updated == user => Some(state.score),
+                    _ => None,
+                };
+                future::ready(score)
+            });
+            // Polled only as fast as the client reads, so that a slow one fills its buffer
+            ctx.add_stream(
+                stream::once(current)
+                    .filter_map(future::ready)
+                    .chain(updates),
+            );
+        }
+        fn stopped(&mut self, _ctx: &mut Self::Context) {
+            if let Some(sender) = self.sender.clone() {
+                let state = self.state.clone();
+                spawn(async move {
+                    state.unregister_logs(sender).await;
+                });
+            }
+        }
+    }
+    impl StreamHandler<model::Score> for GameWs {
+        fn handle(&mut self, score: model::Score, ctx: &mut Self::Context) {
+            ctx.text(serde_score::with_format(self.score_format, || {
+                log_frame(&WsMessage::Score { score })
+            }));
+        }
+        fn finished(&mut self, ctx: &mut Self::Context) {
+            // The client fell behind on score updates and got disconnected
+            ctx.close(Some(ws::CloseCode::Again.into()));
+            ctx.stop();
+        }
+    }
+    impl actix::Handler<WsMessage> for GameWs {
+        type Result = ();
+        fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) {
+            ctx.text(serde_score::with_format(self.score_format, || {
+                log_frame(&msg)
+            }));
+        }
+    }
+    impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for GameWs {
+        fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+            match msg {
+                Ok(ws::Message::Text(text)) => {
+                    let command = match serde_json::from_str::<WsCommand>(&text) {
+                        Ok(command) => command,
+                        Err(e) => {
+                            ctx.address().do_send(WsMessage::Invalid {
+                                message: e.to_string(),
+                            });
+                            return;
+                        }
+                    };
+                    let (addr, state, user) =
+                        (ctx.address(), self.state.clone(), self.user.clone());
+                    let score_format = self.score_format;
+                    spawn(serde_score::scope(score_format, async move {
+                        let id = command.id;
+                        addr.do_send(match ws_command(&state, &user, command.action).await {
+                            Ok(body) => WsMessage::Response { id, body },
+                            Err(error) => WsMessage::Error {
+                                id,
+                                error: error.into(),
+                            },
+                        });
+                    }));
+                }
+                Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+                Ok(ws::Message::Close(reason)) => {
+                    ctx.close(reason);
+                    ctx.stop();
+                }
+                Ok(_) => {}
+                Err(_) => ctx.stop(),
+            }
+        }
+    }
+    ws::start(
+        GameWs {
+            score_format: score_format(&req, &state.config()),
+            state,
+            user: user.0,
+            sender: None,
+        },
+        &req,
+        stream,
+    )
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LogsQuery {
+    /// Replay the log entries from before the subscription
+    #[serde(default = "default_logs_history")]
+    pub(crate) history: bool,
+    /// Only entries about this user
+    pub(crate) user: Option<String>,
+    /// Comma separated log message types to send
+    pub(crate) types: Option<String>,
+    /// What to do when the subscriber falls behind, `log_subscriber_overflow` if not set
+    pub(crate) overflow: Option<model::LogOverflow>,
+}
+
+fn default_logs_history() -> bool {
+    true
+}
+
+impl LogsQuery {
+    pub(crate) fn matches(&self, entry: &model::LogEntry) -> bool {
+        if let Some(user) = &self.user {
+            if entry
+                .msg
+                .user()
+                .is_none_or(|token| *token != UserToken::from(user.clone()))
+            {
+                return false;
+            }
+        }
+        let Some(types) = &self.types else {
+            return true;
+        };
+        let msg = serde_json::to_value(&entry.msg).unwrap_or_default();
+        msg["type"]
+            .as_str()
+            .is_some_and(|msg_type| types.split(',').any(|t| t.trim() == msg_type))
+    }
+}
+
+#[get("/logs")]
+async fn logs(
+    state: web::Data<model::App>,
+    req: HttpRequest,
+    query: web::Query<LogsQuery>,
+    stream: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    struct LogsWs {
+        state: web::Data<model::App>,
+        score_format: serde_score::Format,
+        log_format: LogFormat,
+        query: Arc<LogsQuery>,
+        sender: Option<model::LogSubscriber>,
+        /// Id of the subscriber in the metrics
+        subscriber: u64,
+        chaos: Option<web::Data<ChaosInjector>>,
+    }
+    impl Actor for LogsWs {
+        type Context = ws::WebsocketContext<Self>;
+        fn started(&mut self, ctx: &mut Self::Context) {
+            let state = self.state.clone();
+            let history = self.query.history;
+            let (sender, receiver) = state.log_channel(self.query.overflow);
+            self.sender = Some(sender.clone());
+            // Polled only as fast as the client reads, so that a slow one fills its buffer
+            ctx.add_stream(receiver);
+            spawn(async move {
+                if history {
+                    state.register_logs(sender).await;
+                } else {
+                    state.register_live_logs(sender).await;
+                }
+            });
+        }
+        fn stopped(&mut self, _ctx: &mut Self::Context) {
+            if let Some(stats) = self
+                .state
+                .metrics()
+                .subscriber_disconnected(self.subscriber)
+            {
+                info!(
+                    "Logs subscriber {} from {} disconnected after {:.1}s, sent {} bytes in {} messages",
+                    stats.id,
+                    stats.peer.as_deref().unwrap_or("unknown address"),
+                    stats.duration,
+                    stats.bytes_sent,
+                    stats.messages_sent,
+                );
+            }
+            if let Some(sender) = self.sender.clone() {
+                let state = self.state.clone();
+                spawn(async move {
+                    state.unregister_logs(sender).await;
+                });
+            }
+        }
+    }
+    impl StreamHandler<model::LogEntry> for LogsWs {
+        fn handle(&mut self, entry: model::LogEntry, ctx: &mut Self::Context) {
+            // Sent to everyone whatever they subscribed to, the connection is closed after it
+            let game_over = matches!(entry.msg, model::LogMessage::GameOver { .. });
+            if !game_over && !self.query.matches(&entry) {
+                return;
+            }
+            if !game_over
+                && self
+                    .chaos
+                    .as_ref()
+                    .is_some_and(|chaos| chaos.drop_log_message())
+            {
+                debug!(target: "chaos", "Dropping log message to subscriber {}", self.subscriber);
+                return;
+            }
+            if self.log_format.is_binary() {
+                let frame =
+                    serde_score::with_format(self.score_format, || self.log_format.to_vec(&entry));
+                match frame {
+                    Ok(frame) => {
+                        self.state
+                            .metrics()
+                            .record_subscriber_message(self.subscriber, frame.len());
+                        ctx.binary(frame);
+                    }
+                    Err(e) => error!("Failed to encode log message: {e}"),
+                }
+            } else {
+                let frame = serde_score::with_format(self.score_format, || log_frame(&entry));
+                self.state
+                    .metrics()
+                    .record_subscriber_message(self.subscriber, frame.len());
+                ctx.text(frame);
+            }
+            if game_over {
+                ctx.close(Some(ws::CloseCode::Normal.into()));
+                ctx.stop();
+            }
+        }
+        fn finished(&mut self, ctx: &mut Self::Context) {
+            // The subscriber fell behind and got disconnected
+            ctx.close(Some(ws::CloseCode::Again.into()));
+            ctx.stop();
+        }
+    }
+    impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LogsWs {
+        fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+            let msg = match msg {
+                Err(_) => {
+                    ctx.stop();
+                    return;
+                }
+                Ok(msg) => msg,
+            };
+            debug!("WEBSOCKET MESSAGE: {msg:?}");
+            match msg {
+                ws::Message::Ping(msg) => {
+                    ctx.pong(&msg);
+                }
+                ws::Message::Pong(_) => {}
+                ws::Message::Text(_) => error!("Unexpected text message"),
+                ws::Message::Binary(_) => error!("Unexpected binary"),
+                ws::Message::Close(reason) => {
+                    ctx.close(reason);
+                    ctx.stop();
+                }
+                ws::Message::Continuation(_) => {
+                    ctx.stop();
+                }
+                ws::Message::Nop => (),
+            }
+        }
+    }
+    let peer = req.peer_addr().map(|addr| addr.to_string());
+    let subscriber = state.metrics().subscriber_connected(peer.clone());
+    info!(
+        "Logs subscriber {subscriber} connected from {}",
+        peer.as_deref().unwrap_or("unknown address")
+    );
+    ws::start(
+        LogsWs {
+            score_format: score_format(&req, &state.config()),
+            log_format: req
+                .app_data::<web::Data<LogFormat>>()
+                .map_or_else(LogFormat::default, |format| *format.get_ref()),
+            query: Arc::new(query.into_inner()),
+            subscriber,
+            state: state.clone(),
+            sender: None,
+            chaos: req.app_data::<web::Data<ChaosInjector>>().cloned(),
+        },
+        &req,
+        stream,
+    )
+    .inspect_err(|_| {
+        // The actor never started, so it can not report the disconnect itself
+        state.metrics().subscriber_disconnected(subscriber);
+    })
+}
+
+#[derive(Deserialize)]
+struct LogsSseQuery {
+    /// Skip log entries older than this game time
+    from: Option<f64>,
+    /// What to do when the subscriber falls behind, `log_subscriber_overflow` if not set
+    overflow: Option<model::LogOverflow>,
+}
+
+/// Log entries as server-sent events, unsubscribed when the client goes away
+struct LogsSse {
+    state: web::Data<model::App>,
+    score_format: serde_score::Format,
+    sender: model::LogSubscriber,
+    receiver: model::LogReceiver,
+    from: Option<f64>,
+    /// Id of the subscriber in the metrics
+    subscriber: u64,
+    /// The stream ends after the final standings
+    game_over: bool,
+}
+
+impl futures::Stream for LogsSse {
+    type Item = Result<web::Bytes, actix_web::Error>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        loop {
+            if self.game_over {
+                return std::task::Poll::Ready(None);
+            }
+            let Some(entry) = futures::ready!(self.receiver.poll_next_unpin(cx)) else {
+                return std::task::Poll::Ready(None);
+            };
+            if self.from.is_some_and(|from| entry.time < from) {
+                continue;
+            }
+            self.game_over = matches!(entry.msg, model::LogMessage::GameOver { .. });
+            let frame = serde_score::with_format(self.score_format, || log_frame(&entry));
+            // Every line of a multiline event needs its own field name
+            let mut event: String = frame
+                .lines()
+                .map(|line| format!("data: {line}\n"))
+                .collect();
+            event.push('\n');
+            self.state
+                .metrics()
+                .record_subscriber_message(self.subscriber, event.len());
+            return std::task::Poll::Ready(Some(Ok(event.into())));
+        }
+    }
+}
+
+impl Drop for LogsSse {
+    fn drop(&mut self) {
+        if let Some(stats) = self
+            .state
+            .metrics()
+            .subscriber_disconnected(self.subscriber)
+        {
+            info!(
+                "Logs subscriber {} from {} disconnected after {:.1}s, sent {} bytes in {} messages",
+                stats.id,
+                stats.peer.as_deref().unwrap_or("unknown address"),
+                stats.duration,
+                stats.bytes_sent,
+                stats.messages_sent,
+            );
+        }
+        let state = self.state.clone();
+        let sender = self.sender.clone();
+        spawn(async move {
+            state.unregister_logs(sender).await;
+        });
+    }
+}
+
+/// Same stream of log entries as `/logs`, for clients without websockets
+#[get("/logs/sse")]
+async fn logs_sse(
+    state: web::Data<model::App>,
+    req: HttpRequest,
+    query: web::Query<LogsSseQuery>,
+) -> HttpResponse {
+    let peer = req.peer_addr().map(|addr| addr.to_string());
+    let subscriber = state.metrics().subscriber_connected(peer.clone());
+    info!(
+        "Logs subscriber {subscriber} connected from {}",
+        peer.as_deref().unwrap_or("unknown address")
+    );
+    let (sender, receiver) = state.log_channel(query.overflow);
+    let stream = LogsSse {
+        state: state.clone(),
+        score_format: score_format(&req, &state.config()),
+        sender: sender.clone(),
+        receiver,
+        from: query.from,
+        subscriber,
+        game_over: false,
+    };
+    // History is sent into the channel before it is polled, so register in the background
+    spawn(async move {
+        state.register_logs(sender).await;
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .streaming(stream)
+}
+
+/// Lets pages from the given origins call the api and open the log streams.
+/// Added last so that preflight requests are answered before any authorization.
+fn cors(origins: &[String]) -> actix_cors::Cors {
+    let mut cors = actix_cors::Cors::default()
+        .allow_any_method()
+        .allow_any_header()
+        .expose_headers([
+            HeaderName::from_static("x-game-ends-in"),
+            HeaderName::from_static(SERVER_LOAD_HEADER),
+            RETRY_AFTER,
+        ])
+        .max_age(3600);
+    for origin in origins {
+        cors = if origin == "*" {
+            cors.allow_any_origin()
+        } else {
+            cors.allowed_origin(origin)
+        };
+    }
+    cors
+}
+
+/// Id of the lobby game a request was routed to, viewer tokens are checked against it
+struct LobbyGame(String);
+
+fn lobby_game(req: &HttpRequest) -> Option<&str> {
+    req.app_data::<LobbyGame>().map(|game| game.0.as_str())
+}
+
+/// Plays lobby games with the usual handlers: `/api/games/{id}/pipe/1` is handled as
+/// `/api/pipe/1` and `/api/games/{id}/logs` as `/logs`, both with the state of that game.
+/// The viewer is served at `/api/games/{id}/viewer/`, it follows the logs of the game
+async fn route_games(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let lobby = req.app_data::<web::Data<Lobby>>().cloned();
+    let route = req
+        .path()
+        .strip_prefix("/api/games/")
+        .and_then(|rest| rest.split_once('/'))
+        .map(|(id, rest)| (id.to_owned(), rest.to_owned()));
+    let (Some(lobby), Some((id, rest))) = (lobby, route) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let Some(game) = lobby.get(&id) else {
+        let response = HttpResponse::NotFound().body(format!("Game {id:?} not found"));
+        return Ok(req.into_response(response).map_into_right_body());
+    };
+    if rest == "viewer" {
+        // The assets of the viewer are relative to its directory
+        let location = match req.query_string() {
+            "" => format!("/api/games/{id}/viewer/"),
+            query => format!("/api/games/{id}/viewer/?{query}"),
+        };
+        let response = HttpResponse::PermanentRedirect()
+            .insert_header((LOCATION, location))
+            .finish();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    let path = if let Some(file) = rest.strip_prefix("viewer/") {
+        format!("/{file}")
+    } else if rest == "logs" || rest.starts_with("logs/") {
+        format!("/{rest}")
+    } else {
+        format!("/api/{rest}")
+    };
+    let path_and_query = match req.query_string() {
+        "" => path,
+        query => format!("{path}?{query}"),
+    };
+    let mut uri = req.uri().clone().into_parts();
+    uri.path_and_query = Some(
+        path_and_query
+            .parse()
+            .map_err(actix_web::error::ErrorBadRequest)?,
+    );
+    let uri = actix_web::http::Uri::from_parts(uri).map_err(actix_web::error::ErrorBadRequest)?;
+    req.match_info_mut().get_mut().update(&uri);
+    req.head_mut().uri = uri;
+    let mut data = actix_web::dev::Extensions::new();
+    data.insert(game);
+    data.insert(LobbyGame(id));
+    req.add_data_container(std::rc::Rc::new(data));
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+#[get("/api/games")]
+async fn list_games(lobby: web::Data<Lobby>) -> impl Responder {
+    HttpResponse::Ok().json(lobby.games().await)
+}
+
+#[utoipa::path(
+    request_body(content = Value, description = "Id of the game and its config"),
+    responses(
+        (status = OK, body = Value, description = "Health of the new game"),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+        (status = BAD_REQUEST, description = "Malformed game or invalid id"),
+        (status = CONFLICT, description = "The id is taken"),
+        (status = UNPROCESSABLE_ENTITY, description = "Invalid config"),
+    ),
+    security(("token" = [])),
+)]
+#[post("/api/admin/games")]
+async fn admin_create_game(
+    lobby: web::Data<Lobby>,
+    _admin: Admin,
+    input: web::Json<NewGame>,
+) -> impl Responder {
+    match lobby.create(input.into_inner()) {
+        Ok(game) => HttpResponse::Ok().json(game.health().await),
+        Err(e @ LobbyError::InvalidId) => HttpResponse::BadRequest().body(e.to_string()),
+        Err(e @ LobbyError::AlreadyExists(_)) => HttpResponse::Conflict().body(e.to_string()),
+        Err(e @ LobbyError::InvalidConfig(_)) => {
+            HttpResponse::UnprocessableEntity().body(e.to_string())
+        }
+    }
+}
+
+#[utoipa::path(
+    params(("id" = String, Path, description = "Id of the game")),
+    responses(
+        (status = OK, body = Value, description = "Results of the game"),
+        (status = UNAUTHORIZED, description = "No bearer token"),
+        (status = FORBIDDEN, description = "Admin token required"),
+        (status = NOT_FOUND, description = "No such game"),
+    ),
+    security(("token" = [])),
+)]
+#[delete("/api/admin/games/{id}")]
+async fn admin_remove_game(
+    lobby: web::Data<Lobby>,
+    _admin: Admin,
+    path: web::Path<String>,
+) -> impl Responder {
+    match lobby.remove(&path).await {
+        Some(game) => HttpResponse::Ok().json(game.results().await),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+const VIEWER_TOKEN_COOKIE: &str = "viewer_token";
+
+#[derive(Deserialize)]
+struct ViewerTokenQuery {
+    token: Option<String>,
+}
+
+const SERVER_LOAD_HEADER: &str = "x-server-load";
+
+/// Rejects game api requests beyond `max_in_flight_requests`
+/// and tells clients how loaded the server is, so that they can slow down
+async fn shed_load(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let state = req.app_data::<web::Data<model::App>>().cloned();
+    let Some(state) = state.filter(|_| req.path().starts_with("/api/")) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    // Status has to stay available exactly when the server is overloaded
+    let in_flight = (req.path() != "/api/status").then(|| state.metrics().start_request());
+    let load = HeaderValue::from_str(&format!("{:.2}", state.load())).unwrap();
+    let count = in_flight.as_ref().map_or(0, |in_flight| in_flight.count);
+    if count > state.config().max_in_flight_requests {
+        debug!("Shedding request to {:?}, {count} in flight", req.path());
+        state.metrics().record_shed();
+        let mut response = actix_web::ResponseError::error_response(&ApiError::new(
+            &state.config(),
+            model::Error::ServerOverloaded,
+        ));
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(SERVER_LOAD_HEADER), load);
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    let mut response = next.call(req).await?;
+    response
+        .headers_mut()
+        .insert(HeaderName::from_static(SERVER_LOAD_HEADER), load);
+    Ok(response.map_into_left_body())
+}
+
+/// Rejects game api requests beyond `max_requests_per_ip_per_minute`
+async fn limit_ip_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let state = req.app_data::<web::Data<model::App>>().cloned();
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    let (Some(state), Some(ip)) = (state.filter(|_| req.path().starts_with("/api/")), ip) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    if let Err(error) = state.check_request_rate(ip) {
+        // Retry-After is set from how long until the oldest request counted leaves the window
+        let response =
+            actix_web::ResponseError::error_response(&ApiError::new(&state.config(), error));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+/// Connection counted against `max_connections_per_ip`, for as long as it is open
+struct ConnectionSlot {
+    state: web::Data<model::App>,
+    ip: IpAddr,
+    allowed: bool,
+}
+
+impl ConnectionSlot {
+    fn open(state: web::Data<model::App>, ip: IpAddr) -> Self {
+        let allowed = state.open_connection(ip);
+        Self { state, ip, allowed }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.state.close_connection(self.ip);
+    }
+}
+
+/// Socket of a connection, to read the round trip time the kernel measures for it
+#[cfg(target_os = "linux")]
+struct ConnectionSocket(std::os::fd::RawFd);
+
+/// Smoothed round trip time the kernel estimates from the acknowledgements of the connection
+/// the request came over, only available on Linux
+fn socket_round_trip(req: &ServiceRequest) -> Option<Duration> {
+    #[cfg(target_os = "linux")]
+    {
+        let socket = req.conn_data::<ConnectionSocket>()?;
+        let mut info = std::mem::MaybeUninit::<libc::tcp_info>::zeroed();
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        // SAFETY: the connection, and with it the socket, stays open while its requests are
+        // dispatched, and the kernel writes at most `len` bytes
+        let result = unsafe {
+            libc::getsockopt(
+                socket.0,
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                info.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        // SAFETY: zeroed is a valid `tcp_info`, whatever part the kernel filled in
+        let info = unsafe { info.assume_init() };
+        (result == 0).then(|| Duration::from_micros(info.tcpi_rtt.into()))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = req;
+        None
+    }
+}
+
+/// Answers every request of a connection beyond `max_connections_per_ip` with an error
+/// and closes it, the admin api is left alone
+async fn limit_ip_connections(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let turned_away = req
+        .conn_data::<ConnectionSlot>()
+        .is_some_and(|slot| !slot.allowed)
+        && !req.path().starts_with("/api/admin/");
+    let state = req.app_data::<web::Data<model::App>>().cloned();
+    let Some(state) = state.filter(|_| turned_away) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let error = model::Error::RateLimited { retry_after: None };
+    let mut response =
+        actix_web::ResponseError::error_response(&ApiError::new(&state.config(), error));
+    response
+        .head_mut()
+        .set_connection_type(actix_http::ConnectionType::Close);
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+/// Value of a path parameter such as `{n}`, found by where it is in the route pattern
+fn path_param<'a>(pattern: &str, path: &'a str, param: &str) -> Option<&'a str> {
+    let index = pattern.split('/').position(|segment| segment == param)?;
+    path.split('/').nth(index)
+}
+
+/// Runs every request in a span, so that what is logged while handling it
+/// can be told apart from the other requests handled at the same time
+async fn trace_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let pattern = req.match_pattern();
+    let action = format!(
+        "{} {}",
+        req.method(),
+        pattern.as_deref().unwrap_or(req.path())
+    );
+    let span = info_span!(
+        "request",
+        id,
+        action,
+        user = field::Empty,
+        pipe_id = field::Empty
+    );
+    let user = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .and_then(|token| token.parse::<UserToken>().ok());
+    if let Some(user) = user {
+        span.record("user", field::display(user.digest()));
+    }
+    if let Some(pipe_id) = pattern
+        .as_deref()
+        .and_then(|pattern| path_param(pattern, req.path(), "{n}"))
+    {
+        span.record("pipe_id", pipe_id);
+    }
+    let start = Instant::now();
+    let response = span
+        .in_scope(|| next.call(req))
+        .instrument(span.clone())
+        .await?;
+    debug!(
+        parent: &span,
+        status = response.status().as_u16(),
+        elapsed = ?start.elapsed(),
+        "Request handled"
+    );
+    Ok(response)
+}
+
+/// Rejects game api requests beyond `max_requests_per_token_per_second`
+async fn limit_token_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let state = req.app_data::<web::Data<model::App>>().cloned();
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .and_then(|token| token.parse::<UserToken>().ok());
+    let (Some(state), Some(token)) = (state.filter(|_| req.path().starts_with("/api/")), token)
+    else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    if let Err(error) = state.check_token_request_rate(&token) {
+        // Retry-After is set from how long until the oldest request counted leaves the window
+        let response =
+            actix_web::ResponseError::error_response(&ApiError::new(&state.config(), error));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+/// Score format asked for with the `scores` parameter of the `Accept` header,
+/// e.g. `Accept: application/json; scores=string`, otherwise the configured one
+fn score_format(req: &HttpRequest, config: &model::Config) -> serde_score::Format {
+    req.headers()
+        .get_all(ACCEPT)
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split([',', ';']))
+        .find_map(|param| match param.trim().strip_prefix("scores=")? {
+            "number" => Some(serde_score::Format::Number),
+            "string" => Some(serde_score::Format::String),
+            _ => None,
+        })
+        .unwrap_or(config.score_format)
+}
+
+/// Serializes scores in responses in the format asked for by the client
+async fn format_scores(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let format = match req.app_data::<web::Data<model::App>>() {
+        Some(state) => score_format(req.request(), &state.config()),
+        None => serde_score::Format::default(),
+    };
+    serde_score::scope(format, next.call(req)).await
+}
+
+/// Error body as described in RFC 7807
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    title: String,
+    status: u16,
+    instance: String,
+    /// Same as `code` of the plain json errors
+    code: model::ErrorCode,
+    details: model::ErrorDetails,
+    /// Same as `code`, kept for older clients
+    error: model::ErrorCode,
+}
+
+fn accepts_problem(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(ACCEPT)
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .filter_map(|media_type| media_type.split(';').next())
+        .any(|media_type| media_type.trim() == "application/problem+json")
+}
+
+/// Turns game errors into `application/problem+json` bodies for clients accepting them
+async fn problem_details(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> actix_web::Result<ServiceResponse<EitherBody<impl MessageBody, String>>> {
+    let wanted = accepts_problem(req.request());
+    let response = next.call(req).await?;
+    let error = response
+        .response()
+        .extensions()
+        .get::<GameError>()
+        .map(|error| error.0);
+    let Some(error) = error.filter(|_| wanted) else {
+        return Ok(response.map_into_left_body());
+    };
+    let problem = Problem {
+        kind: format!("urn:itonecup:error:{:?}", error.code()),
+        title: error.to_string(),
+        status: response.status().as_u16(),
+        instance: response.request().path().to_owned(),
+        code: error.code(),
+        details: error.details(),
+        error: error.code(),
+    };
+    let body = serde_json::to_string(&problem)?;
+    Ok(response
+        .map_body(|head, _| {
+            head.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/problem+json"),
+            );
+            body
+        })
+        .map_into_right_body())
+}
+
+/// Liveness probe, answers as long as the server runs
+#[get("/healthz")]
+async fn healthz(state: web::Data<model::App>) -> impl Responder {
+    HttpResponse::Ok().json(state.health().await)
+}
+
+/// Readiness probe, unavailable once the game ended or while requests are shed
+#[get("/readyz")]
+async fn readyz(state: web::Data<model::App>) -> impl Responder {
+    let health = state.health().await;
+    if health.ready {
+        HttpResponse::Ok().json(health)
+    } else {
+        HttpResponse::ServiceUnavailable().json(health)
+    }
+}
+
+#[utoipa::path(responses((status = OK, body = model::StatusResponse)))]
+#[get("/api/status")]
+async fn server_status(state: web::Data<model::App>) -> impl Responder {
+    HttpResponse::Ok().json(state.status())
+}
+
+/// Protects everything except the game api with signed viewer tokens,
+/// passed once in the query and remembered in a cookie. Lobby games take tokens of their own
+async fn check_viewer_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let auth = req.app_data::<web::Data<ViewerAuth>>().cloned();
+    let probe = ["/healthz", "/readyz"].contains(&req.path());
+    let Some(auth) = auth.filter(|_| !req.path().starts_with("/api/") && !probe) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let scope = if req.path() == "/logs" || req.path().starts_with("/logs/") {
+        Scope::Logs
+    } else {
+        Scope::Viewer
+    };
+    let game = lobby_game(req.request()).map(str::to_owned);
+    let query_token = web::Query::<ViewerTokenQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().token);
+    if let Some(token) = query_token.filter(|token| auth.verify_for(token, scope, game.as_deref()))
+    {
+        // Kept to the paths of the game, so that the tokens of other games are not replaced
+        let path = game.map_or("/".to_owned(), |game| format!("/api/games/{game}/"));
+        let mut response = next.call(req).await?;
+        response.response_mut().add_cookie(
+            &Cookie::build(VIEWER_TOKEN_COOKIE, token)
+                .path(path)
+                .http_only(true)
+                .finish(),
+        )?;
+        return Ok(response.map_into_left_body());
+    }
+    if req
+        .cookie(VIEWER_TOKEN_COOKIE)
+        .is_some_and(|cookie| auth.verify_for(cookie.value(), scope, game.as_deref()))
+    {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+    warn!(
+        "Rejected request to {:?} without a valid viewer token",
+        req.path()
+    );
+    Ok(req
+        .into_response(HttpResponse::Forbidden().finish())
+        .map_into_right_body())
+}
+
+/// Ids that fail to parse, zero included, can not name a pipe in the game either
+fn pipe_path_error(_: PathError, req: &HttpRequest) -> actix_web::Error {
+    let state = req
+        .app_data::<web::Data<model::App>>()
+        .expect("App data is configured");
+    ApiError::new(
+        &state.config(),
+        model::Error::PipeNotFound { pipe_id: None },
+    )
+    .into()
+}
+
+/// Admin api, only served with an [`AdminToken`] registered
+pub(crate) fn configure_admin(config: &mut ServiceConfig) {
+    config
+        .service(admin_openapi_json)
+        .service(admin_user_history)
+        .service(game_history)
+        .service(admin_abort_actions)
+        .service(admin_import_users)
+        .service(admin_export_users)
+        .service(admin_game_state)
+        .service(admin_subscribers)
+        .service(admin_rate_limits)
+        .service(admin_unbind_ip)
+        .service(admin_remove_user)
+        .service(admin_update_pipe)
+        .service(admin_end_game)
+        .service(admin_start_game)
+        .service(admin_pause)
+        .service(admin_resume)
+        .service(admin_update_config)
+        .service(admin_create_game)
+        .service(admin_remove_game)
+        .service(set_log_level);
+}
+
+/// Ladder accounts, only served with [`Accounts`] registered
+pub(crate) fn configure_accounts(config: &mut ServiceConfig) {
+    config
+        .service(create_account)
+        .service(account_token)
+        .service(create_api_key)
+        .service(exchange_api_key)
+        .service(account_state);
+}
+
+pub fn configure(config: &mut ServiceConfig, state: web::Data<model::App>) {
+    config
+        .app_data(state)
+        .app_data(web::PathConfig::default().error_handler(pipe_path_error))
+        .service(pipe_value)
+        .service(pipe_values)
+        .service(peek)
+        .service(collect)
+        .service(operation_status)
+        .service(apply_modifier)
+        .service(pipe_modifiers)
+        .service(list_pipes)
+        .service(game_ws)
+        .service(user_state)
+        .service(user_actions)
+        .service(cancel_actions)
+        .service(assigned_pipe)
+        .service(user_history)
+        .service(shop)
+        .service(prices)
+        .service(global_events)
+        .service(game_time)
+        .service(game_clock)
+        .service(register)
+        .service(server_status)
+        .service(healthz)
+        .service(readyz)
+        .service(openapi_json);
+}
+
+/// Game api as seen by participants, the websocket at `/api/ws` is not covered
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    info(
+        title = "Pipes game API",
+        description = "Scores are numbers, or strings if requested with `Accept: application/json; scores=string`"
+    ),
+    paths(
+        collect,
+        peek,
+        pipe_value,
+        pipe_values,
+        list_pipes,
+        pipe_modifiers,
+        apply_modifier,
+        user_state,
+        user_actions,
+        operation_status,
+        cancel_actions,
+        assigned_pipe,
+        user_history,
+        shop,
+        prices,
+        global_events,
+        game_time,
+        game_clock,
+        register,
+        create_account,
+        account_token,
+        create_api_key,
+        exchange_api_key,
+        account_state,
+        server_status,
+    ),
+    modifiers(&BearerToken)
+)]
+struct ApiDoc;
+
+struct BearerToken;
+
+impl utoipa::Modify for BearerToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "token",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+    }
+}
+
+#[get("/api/openapi.json")]
+async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(<ApiDoc as utoipa::OpenApi>::openapi())
+}
+
+/// Admin api, only described to admins
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    info(title = "Pipes game admin API"),
+    paths(
+        admin_user_history,
+        game_history,
+        admin_abort_actions,
+        admin_import_users,
+        admin_export_users,
+        admin_game_state,
+        admin_subscribers,
+        admin_rate_limits,
+        admin_unbind_ip,
+        admin_remove_user,
+        admin_update_pipe,
+        admin_end_game,
+        admin_start_game,
+        admin_pause,
+        admin_resume,
+        admin_update_config,
+        admin_create_game,
+        admin_remove_game,
+        set_log_level,
+    ),
+    modifiers(&BearerToken)
+)]
+struct AdminApiDoc;
+
+#[get("/api/admin/openapi.json")]
+async fn admin_openapi_json(_admin: Admin) -> impl Responder {
+    HttpResponse::Ok().json(<AdminApiDoc as utoipa::OpenApi>::openapi())
+}
+
+/// Swagger UI for the api description, the UI itself comes from a CDN
+#[get("/api/docs")]
+async fn api_docs() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("api_docs.html"))
+}
+
+/// Optional file in the serve dir describing the viewer
+const VIEWER_MANIFEST: &str = "viewer.json";
+
+#[derive(Deserialize)]
+struct ViewerManifest {
+    log_schema_version: u32,
+}
+
+/// Fails if the viewer is missing or was built for another log format,
+/// so that a broken viewer is noticed before the game and not during the broadcast
+pub fn check_serve_dir(dir: &Path) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        dir.join("index.html").is_file(),
+        "No index.html in the serve dir {dir:?}, is the viewer built?",
+    );
+    let manifest_path = dir.join(VIEWER_MANIFEST);
+    if !manifest_path.exists() {
+        debug!("No viewer manifest in {dir:?}, skipping the log schema check");
+        return Ok(());
+    }
+    let file = std::fs::File::open(&manifest_path)
+        .with_context(|| format!("Failed to open {manifest_path:?}"))?;
+    let manifest: ViewerManifest = serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to parse {manifest_path:?}"))?;
+    check_viewer_manifest(&manifest, &format!("{dir:?}"))
+}
+
+fn check_viewer_manifest(manifest: &ViewerManifest, location: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        manifest.log_schema_version == LOG_SCHEMA_VERSION,
+        "Viewer in {location} reads log schema version {}, but the server writes version {LOG_SCHEMA_VERSION}",
+        manifest.log_schema_version,
+    );
+    Ok(())
+}
+
+/// Viewer built into the binary with the `embedded-viewer` feature
+#[cfg(feature = "embedded-viewer")]
+static EMBEDDED_VIEWER: include_dir::Dir =
+    include_dir::include_dir!("$CARGO_MANIFEST_DIR/frontend/dist");
+
+/// Where the viewer is served from, at the root of the server
+#[derive(Debug, Clone)]
+pub enum StaticFiles {
+    Dir(PathBuf),
+    /// Built from `frontend/dist` into the binary, see `--serve-embedded`
+    #[cfg(feature = "embedded-viewer")]
+    Embedded,
+}
+
+impl StaticFiles {
+    /// See [`check_serve_dir`]
+    pub fn check(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Dir(dir) => check_serve_dir(dir),
+            #[cfg(feature = "embedded-viewer")]
+            Self::Embedded => {
+                anyhow::ensure!(
+                    EMBEDDED_VIEWER.get_file("index.html").is_some(),
+                    "No index.html in the embedded viewer, was it built before the server?",
+                );
+                let Some(file) = EMBEDDED_VIEWER.get_file(VIEWER_MANIFEST) else {
+                    debug!("No manifest in the embedded viewer, skipping the log schema check");
+                    return Ok(());
+                };
+                let manifest = serde_json::from_slice(file.contents())
+                    .context("Failed to parse the manifest of the embedded viewer")?;
+                check_viewer_manifest(&manifest, "the binary")
+            }
+        }
+    }
+
+    /// Registers the files, paths of the viewer's own routes get its index.html.
+    /// Has to be configured after everything else, it matches every path.
+    pub fn configure(&self, config: &mut ServiceConfig) {
+        match self {
+            Self::Dir(dir) => {
+                let index = dir.join("index.html");
+                let fallback = actix_web::dev::fn_service(move |req: ServiceRequest| {
+                    let index = index.clone();
+                    async move {
+                        let (req, _) = req.into_parts();
+                        let response = if is_viewer_route(&req) {
+                            actix_files::NamedFile::open_async(index)
+                                .await?
+                                .into_response(&req)
+                        } else {
+                            HttpResponse::NotFound().finish()
+                        };
+                        Ok(ServiceResponse::new(req, response))
+                    }
+                });
+                config.service(
+                    actix_files::Files::new("/", dir)
+                        .index_file("index.html")
+                        .default_handler(fallback),
+                );
+            }
+            #[cfg(feature = "embedded-viewer")]
+            Self::Embedded => {
+                config.default_service(web::to(embedded_file));
+            }
+        }
+    }
+}
+
+/// Unknown paths that are left to the viewer, the api answers for itself
+fn is_viewer_route(req: &HttpRequest) -> bool {
+    matches!(
+        *req.method(),
+        actix_web::http::Method::GET | actix_web::http::Method::HEAD
+    ) && !req.path().starts_with("/api/")
+}
+
+#[cfg(feature = "embedded-viewer")]
+async fn embedded_file(req: HttpRequest) -> HttpResponse {
+    if !matches!(
+        *req.method(),
+        actix_web::http::Method::GET | actix_web::http::Method::HEAD
+    ) {
+        return HttpResponse::NotFound().finish();
+    }
+    let path = match req.path().trim_start_matches('/') {
+        "" => "index.html".to_owned(),
+        dir if dir.ends_with('/') => format!("{dir}index.html"),
+        path => path.to_owned(),
+    };
+    let file = EMBEDDED_VIEWER.get_file(&path).or_else(|| {
+        is_viewer_route(&req)
+            .then(|| EMBEDDED_VIEWER.get_file("index.html"))
+            .flatten()
+    });
+    let Some(file) = file else {
+        return HttpResponse::NotFound().finish();
+    };
+    let extension = file
+        .path()
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    HttpResponse::Ok()
+        .content_type(actix_files::file_extension_to_mime(extension))
+        .body(file.contents())
+}
+
+#[derive(Default)]
+pub struct Options {
+    pub static_files: Option<StaticFiles>,
+    /// Accept HTTP/2 with prior knowledge next to HTTP/1
+    pub http2: bool,
+    /// How long idle connections are kept open. Connections are closed after every
+    /// response by default, unless HTTP/2 is enabled.
+    pub keep_alive: Option<Duration>,
+    /// Number of worker threads, one per cpu core by default
+    pub workers: Option<usize>,
+    /// Time for a client to send the request head, actix default if not set
+    pub client_request_timeout: Option<Duration>,
+    /// Time for a client to acknowledge closing the connection, actix default if not set
+    pub client_disconnect_timeout: Option<Duration>,
+    pub enable_logs_api: bool,
+    /// Encoding of the `/logs` websocket frames, binary formats are sent as binary frames
+    pub log_format: LogFormat,
+    /// Serves a Swagger UI for `/api/openapi.json` at `/api/docs`
+    pub api_docs: bool,
+    /// Also serves the gRPC api of `proto/game.proto` on this address
+    pub grpc_addr: Option<SocketAddr>,
+    pub viewer_auth: Option<ViewerAuth>,
+    /// Enables the admin api
+    pub admin_token: Option<UserToken>,
+    /// Listeners bound next to the main ones, the admin api is then only served on them
+    pub admin_addrs: Vec<SocketAddr>,
+    /// Also listens on this Unix domain socket, a stale socket file is replaced
+    pub unix_socket: Option<PathBuf>,
+    /// Saves all game api calls
+    pub recorder: Option<Recorder>,
+    /// Writes every api call with its outcome, latency and source, for settling disputes
+    pub audit_log: Option<AuditLog>,
+    /// Records finished games, they are then served at `/api/history`
+    pub results_db: Option<Arc<ResultsDb>>,
+    /// Ladder accounts, served at `/api/account`
+    pub accounts: Option<Arc<Accounts>>,
+    /// Called once the server is listening
+    pub on_start: Option<Box<dyn FnOnce(Started)>>,
+    /// Reads the config again on SIGHUP, to be applied to the running game
+    pub reload_config: Option<Box<dyn Fn() -> anyhow::Result<model::Config>>>,
+}
+
+/// Server that is up and listening
+#[derive(Clone)]
+pub struct Started {
+    pub app: Arc<model::App>,
+    /// Addresses the server is bound to, useful when binding to port 0
+    pub addrs: Vec<SocketAddr>,
+    /// Address of the gRPC server if it was enabled
+    pub grpc_addr: Option<SocketAddr>,
+    pub handle: ServerHandle,
+}
+
+pub async fn run(
+    addr: impl ToSocketAddrs,
+    mut state: model::App,
+    time_to_run: Option<Duration>,
+    options: Options,
+) -> anyhow::Result<Arc<model::App>> {
+    let Options {
+        static_files,
+        http2,
+        keep_alive,
+        workers,
+        client_request_timeout,
+        client_disconnect_timeout,
+        enable_logs_api,
+        log_format,
+        api_docs: enable_api_docs,
+        grpc_addr,
+        viewer_auth,
+        admin_token,
+        admin_addrs,
+        unix_socket,
+        recorder,
+        audit_log,
+        results_db,
+        accounts,
+        on_start,
+        reload_config,
+    } = options;
+    if let Some(static_files) = &static_files {
+        static_files.check()?;
+    }
+    let recorder = recorder.map(web::Data::new);
+    let audit_log = audit_log.map(web::Data::new);
+    let viewer_auth = viewer_auth.map(web::Data::new);
+    // The gRPC server checks viewer tokens of log subscriptions itself
+    let grpc_viewer_auth = viewer_auth.clone().map(web::Data::into_inner);
+    let admin_token = admin_token.map(|token| web::Data::new(AdminToken(token)));
+    let admin_listeners =
+        (!admin_addrs.is_empty()).then(|| web::Data::new(AdminListeners::default()));
+    let chaos = state.config().chaos.clone().map(|chaos| {
+        warn!(target: "chaos", "Chaos mode is on, the api will be slow and flaky on purpose");
+        web::Data::new(ChaosInjector::new(chaos, state.seed()))
+    });
+    state.set_time_to_run(time_to_run);
+    if let Some(recorder) = &recorder {
+        recorder.start(&state, time_to_run)?;
+    }
+    let state = web::Data::new(state);
+    let results_db = results_db.map(web::Data::from);
+    let accounts = accounts.map(web::Data::from);
+    let lobby = web::Data::new(Lobby::new((*state.config()).clone(), results_db.clone()));
+    let server = HttpServer::new({
+        let state = state.clone();
+        let lobby = lobby.clone();
+        let results_db = results_db.clone();
+        let admin_listeners = admin_listeners.clone();
+        let recorder = recorder.clone();
+        let audit_log = audit_log.clone();
+        move || {
+            let mut app = App::new()
+                .wrap(from_fn(record_requests))
+                // Outside of the recorder, so that replays don't fail the same way
+                .wrap(from_fn(inject_chaos))
+                .wrap(from_fn(check_viewer_token))
+                .wrap(from_fn(shed_load))
+                .wrap(from_fn(limit_token_requests))
+                .wrap(from_fn(limit_ip_requests))
+                .wrap(from_fn(limit_ip_connections))
+                .wrap(from_fn(problem_details))
+                .wrap(from_fn(format_scores))
+                .wrap_fn(|req, srv| {
+                    let start = Instant::now();
+                    let endpoint = req
+                        .match_pattern()
+                        .filter(|pattern| pattern.starts_with("/api/"))
+                        .map(|pattern| format!("{} {pattern}", req.method()));
+                    let user = req
+                        .headers()
+                        .get(AUTHORIZATION)
+                        .and_then(|header| header.to_str().ok())
+                        .and_then(|header| header.strip_prefix("Bearer "))
+                        .map(str::to_owned);
+                    let round_trip = user.as_ref().and_then(|_| socket_round_trip(&req));
+                    let response = srv.call(req);
+                    async move {
+                        let response = response.await?;
+                        if let (Some(endpoint), Some(state)) = (
+                            endpoint,
+                            response.request().app_data::<web::Data<model::App>>(),
+                        ) {
+                            let error = response
+                                .response()
+                                .extensions()
+                                .get::<GameError>()
+                                .map(|error| format!("{:?}", error.0.code()));
+                            state.metrics().record(
+                                &endpoint,
+                                user.as_deref(),
+                                error.as_deref(),
+                                start.elapsed(),
+                            );
+                            if let (Some(user), Some(round_trip)) = (&user, round_trip) {
+                                state.metrics().record_round_trip(user, round_trip);
+                            }
+                        }
+                        Ok(response)
+                    }
+                })
+                // Outside of the limits, so that rejected requests are audited as well
+                .wrap(from_fn(audit_requests))
+                .wrap(from_fn(trace_requests))
+                // Before the other middleware, so that they see the state of the game
+                .wrap(from_fn(route_games))
+                .wrap(Condition::new(
+                    !state.config().cors_origins.is_empty(),
+                    cors(&state.config().cors_origins),
+                ))
+                .configure(|config| configure(config, state.clone()))
+                .app_data(lobby.clone())
+                .service(list_games);
+            if let Some(viewer_auth) = &viewer_auth {
+                app = app.app_data(viewer_auth.clone());
+            }
+            if let Some(recorder) = &recorder {
+                app = app.app_data(recorder.clone());
+            }
+            if let Some(audit_log) = &audit_log {
+                app = app.app_data(audit_log.clone());
+            }
+            if let Some(chaos) = &chaos {
+                app = app.app_data(chaos.clone());
+            }
+            if let Some(results_db) = &results_db {
+                app = app.app_data(results_db.clone());
+            }
+            if let Some(admin_listeners) = &admin_listeners {
+                app = app.app_data(admin_listeners.clone());
+            }
+            if let Some(accounts) = &accounts {
+                app = app.app_data(accounts.clone()).configure(configure_accounts);
+            }
+            if let Some(admin_token) = &admin_token {
+                app = app.app_data(admin_token.clone()).configure(configure_admin);
+            }
+            if enable_api_docs {
+                app = app.service(api_docs);
+            }
+            if enable_logs_api {
+                app = app
+                    .app_data(web::Data::new(log_format))
+                    .service(logs)
+                    .service(logs_sse)
+                    .service(logs_page)
+                    .service(pipe_history)
+                    .service(overlay)
+                    .service(scoreboard);
+            }
+            if let Some(static_files) = &static_files {
+                app = app.configure(|config| static_files.configure(config));
+            }
+            app
+        }
+    })
+    .on_connect({
+        let state = state.clone();
+        move |connection, data| {
+            let stream = connection.downcast_ref::<actix_web::rt::net::TcpStream>();
+            #[cfg(target_os = "linux")]
+            if let Some(stream) = stream {
+                use std::os::fd::AsRawFd;
+                data.insert(ConnectionSocket(stream.as_raw_fd()));
+            }
+            let ip = stream
+                .and_then(|stream| stream.peer_addr().ok())
+                .map(|addr| addr.ip());
+            if let Some(ip) = ip {
+                data.insert(ConnectionSlot::open(state.clone(), ip));
+            }
+        }
+    })
+    // Multiplexing is pointless if connections are closed after every response
+    .keep_alive(match keep_alive {
+        Some(timeout) => KeepAlive::Timeout(timeout),
+        None if http2 => KeepAlive::default(),
+        None => KeepAlive::Disabled,
+    });
+    let server = match workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    let server = match client_request_timeout {
+        Some(timeout) => server.client_request_timeout(timeout),
+        None => server,
+    };
+    let server = match client_disconnect_timeout {
+        Some(timeout) => server.client_disconnect_timeout(timeout),
+        None => server,
+    };
+    let mut server = if http2 {
+        server.bind_auto_h2c(addr)
+    } else {
+        server.bind(addr)
+    }
+    .context("Failed to bind server")?;
+    if let Some(admin_listeners) = &admin_listeners {
+        let public = server.addrs().len();
+        server = if http2 {
+            server.bind_auto_h2c(&admin_addrs[..])
+        } else {
+            server.bind(&admin_addrs[..])
+        }
+        .context("Failed to bind the admin listeners")?;
+        let _ = admin_listeners.0.set(server.addrs()[public..].to_vec());
+    }
+    // Unix sockets show up with a placeholder address
+    let addrs = server.addrs();
+    if let Some(path) = &unix_socket {
+        #[cfg(unix)]
+        {
+            remove_stale_socket(path)?;
+            server = server
+                .bind_uds(path)
+                .with_context(|| format!("Failed to bind unix socket {path:?}"))?;
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("Unix sockets are not supported on this platform");
+        info!("Listening on {path:?}");
+    }
+    let grpc_listener = match grpc_addr {
+        Some(addr) => Some(
+            tokio::net::TcpListener::bind(addr)
+                .await
+                .context("Failed to bind gRPC server")?,
+        ),
+        None => None,
+    };
+    let grpc_addr = grpc_listener
+        .as_ref()
+        .map(|listener| listener.local_addr())
+        .transpose()?;
+    let server = server.disable_signals().run();
+    let server_handle = server.handle();
+    if let Some(on_start) = on_start {
+        on_start(Started {
+            app: state.clone().into_inner(),
+            addrs,
+            grpc_addr,
+            handle: server_handle.clone(),
+        });
+    }
+    let server_future = spawn(server);
+    let scheduler = spawn({
+        let state = state.clone();
+        async move { state.run_scheduler().await }
+    });
+    let webhooks = spawn(crate::webhooks::run(state.clone().into_inner()));
+    let sweeper = spawn(sweep_rate_limits(state.clone(), audit_log.clone()));
+    let grpc = grpc_listener.map(|listener| {
+        let state = state.clone().into_inner();
+        spawn(async move {
+            if let Err(e) =
+                crate::grpc::serve(listener, state, enable_logs_api, grpc_viewer_auth).await
+            {
+                error!("gRPC server failed: {e:#}");
+            }
+        })
+    });
+    let signals = spawn(handle_signals(state.clone(), server_handle.clone()));
+    let reload =
+        reload_config.map(|reload_config| spawn(reload_on_hangup(state.clone(), reload_config)));
+    if time_to_run.is_none() {
+        info!("You can press Ctrl-C to stop the server");
+    }
+    match select(server_future, game_end(&state, time_to_run).boxed()).await {
+        Left((server, _end)) => {
+            if time_to_run.is_some() {
+                warn!("Server was shutdown before timeout was reached");
+            }
+            server??;
+        }
+        Right(((), server)) => {
+            if time_to_run.is_some() && !state.is_ended_early() {
+                info!("Time is up, shutting down the server");
+            }
+            state.announce_end().await;
+            game_over_grace(&state).await;
+            server_handle.stop(true).await;
+            server.await??;
+        }
+    }
+    info!("Server stopped");
+    if let Some(path) = &unix_socket {
+        if let Err(e) = std::fs::remove_file(path) {
+            debug!("Failed to remove the unix socket {path:?}: {e}");
+        }
+    }
+    if let Some(results_db) = &results_db {
+        results_db.save(&state, None).await;
+    }
+    if let Some(recorder) = &recorder {
+        recorder.finish(&state).await;
+    }
+    scheduler.abort();
+    webhooks.abort();
+    sweeper.abort();
+    signals.abort();
+    if let Some(reload) = reload {
+        reload.abort();
+    }
+    if let Some(grpc) = grpc {
+        grpc.abort();
+    }
+
+    Ok(state.into_inner())
+}
+
+/// Resolves once the game should end: its time is up and there are no rounds or overtime
+/// left, or it was ended early. Games without `time_to_run` only end early.
+pub(crate) async fn game_end(state: &model::App, time_to_run: Option<Duration>) {
+    if time_to_run.is_none() {
+        return state.wait_for_end().await;
+    }
+    // The end is not known before a game waiting for players starts
+    if let Left(((), _)) =
+        select(state.wait_for_end().boxed(), state.wait_for_start().boxed()).await
+    {
+        return;
+    }
+    loop {
+        // Games with rounds wake up at the end of every round to start the next one
+        let time_left = || state.round_time_left().or_else(|| state.time_left());
+        let wait = state.time_until_start() + time_left().unwrap_or_default();
+        select(sleep(wait).boxed(), state.wait_for_end().boxed()).await;
+        // Pauses move the end further away
+        let extended = time_left().is_some_and(|time| !time.is_zero());
+        if !extended && !state.end_round().await && !state.start_overtime().await {
+            return;
+        }
+    }
+}
+
+/// Keeps answering `GameOver` for a while, so that bots learn about the end
+/// instead of running into closed connections
+async fn game_over_grace(state: &model::App) {
+    let grace = Duration::from_secs_f64(state.config().game_over_grace_secs);
+    if !grace.is_zero() {
+        info!("Game is over, stopping the server in {grace:?}");
+        sleep(grace).await;
+    }
+}
+
+async fn wait_for_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        let terminated = terminate.recv().map(|_| Ok(()));
+        let (result, _) = select(signal::ctrl_c().boxed(), terminated.boxed())
+            .await
+            .factor_first();
+        result
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await
+}
+
+/// Removes a socket file left behind by a server that did not stop cleanly
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path).context("Failed to remove the stale unix socket")?;
+    }
+    Ok(())
+}
+
+/// Forgets ips and tokens that stopped making requests every minute, the failed
+/// authentication attempts are summarized in the audit log if there is one
+pub(crate) async fn sweep_rate_limits(
+    state: web::Data<model::App>,
+    audit_log: Option<web::Data<AuditLog>>,
+) {
+    loop {
+        sleep(Duration::from_secs(60)).await;
+        let failures = state.sweep_rate_limits();
+        if let Some(audit_log) = &audit_log {
+            if let Err(e) = audit_log.write_auth_failures(Some(state.game_time()), &failures) {
+                error!("Failed to write the audit log: {e}");
+            }
+        }
+    }
+}
+
+/// Ctrl-C and SIGTERM end the game as if the time ran out, so that results and logs
+/// are still written. A second signal stops the server without waiting for requests.
+async fn handle_signals(state: web::Data<model::App>, server_handle: ServerHandle) {
+    if let Err(e) = wait_for_signal().await {
+        error!("Failed to listen for signals: {e}");
+        return;
+    }
+    info!("Ending the game, send the signal again to stop immediately");
+    state.end_game().await;
+    if wait_for_signal().await.is_ok() {
+        warn!("Stopping the server immediately");
+        server_handle.stop(false).await;
+    }
+}
+
+/// Applies the config returned by `reload_config` to the game whenever SIGHUP is received
+async fn reload_on_hangup(
+    state: web::Data<model::App>,
+    reload_config: Box<dyn Fn() -> anyhow::Result<model::Config>>,
+) {
+    #[cfg(unix)]
+    {
+        let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {e}");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("Reloading the config");
+            match reload_config() {
+                Ok(config) => {
+                    if let Err(e) = state.update_config(config) {
+                        error!("Not reloading the config: {e}");
+                    }
+                }
+                Err(e) => error!("Failed to reload the config: {e:#}"),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (state, reload_config);
+    }
+}
+
+/// Game server running in the background of the current actix runtime,
+/// for embedding into test harnesses.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use itonecup_mobile::{model, server::GameServer};
+///
+/// let server = GameServer::builder()
+///     .config(model::Config {
+///         time_to_run: None,
+///         ..Default::default()
+///     })
+///     .users(["alice".parse()?, "bob".parse()?])
+///     .spawn()
+///     .await?;
+/// println!("Listening on {}", server.addr());
+/// let app = server.stop().await?;
+/// println!("Results: {:?}", app.results().await);
+/// # Ok(())
+/// # }
+/// ```
+pub struct GameServer {
+    started: Started,
+    task: JoinHandle<anyhow::Result<Arc<model::App>>>,
+}
+
+pub struct GameServerBuilder {
+    addr: SocketAddr,
+    config: model::Config,
+    users: Vec<UserToken>,
+    options: Options,
+}
+
+impl GameServer {
+    /// Listens on a random local port with the default config by default
+    pub fn builder() -> GameServerBuilder {
+        GameServerBuilder {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            config: Default::default(),
+            users: Vec::new(),
+            options: Default::default(),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.started.addrs[0]
+    }
+
+    /// Set when the server was started with `Options::grpc_addr`
+    pub fn grpc_addr(&self) -> Option<SocketAddr> {
+        self.started.grpc_addr
+    }
+
+    pub fn app(&self) -> &Arc<model::App> {
+        &self.started.app
+    }
+
+    /// Waits for the game to end by itself
+    pub async fn wait(self) -> anyhow::Result<Arc<model::App>> {
+        self.task.await?
+    }
+
+    /// Stops the server and returns the final state
+    pub async fn stop(self) -> anyhow::Result<Arc<model::App>> {
+        self.started.handle.stop(true).await;
+        self.wait().await
+    }
+}
+
+impl GameServerBuilder {
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// Game duration is taken from `config.time_to_run`, the server runs until stopped if unset
+    pub fn config(mut self, config: model::Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Allowed users, anyone is allowed if none are given (unless disabled in the config)
+    pub fn users(mut self, users: impl IntoIterator<Item = UserToken>) -> Self {
+        self.users = users.into_iter().collect();
+        self
+    }
+
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Starts the server, must be called within an actix runtime
+    pub async fn spawn(self) -> anyhow::Result<GameServer> {
+        let (sender, receiver) = oneshot::channel();
+        let user_on_start = self.options.on_start;
+        let options = Options {
+            on_start: Some(Box::new(move |started: Started| {
+                if let Some(on_start) = user_on_start {
+                    on_start(started.clone());
+                }
+                let _ = sender.send(started);
+            })),
+            ..self.options
+        };
+        let app = model::App::init(self.config, self.users);
+        let time_to_run = app.config().time_to_run.map(Duration::from_secs_f64);
+        let task = spawn(run(self.addr, app, time_to_run, options));
+        match receiver.await {
+            Ok(started) => Ok(GameServer { started, task }),
+            Err(_) => match task.await? {
+                Ok(_) => anyhow::bail!("Server stopped before starting"),
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::GameClient;
+    use actix_web::{rt::task::spawn_blocking, test};
+    use actix_web_httpauth::headers::authorization::Bearer;
+    use futures::channel::mpsc;
+
+    #[actix_web::test]
+    async fn test_log_frame_serialization_failure() {
+        struct Unserializable;
+        impl Serialize for Unserializable {
+            fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+                Err(serde::ser::Error::custom("broken entry"))
+            }
+        }
+        let frame: serde_json::Value = serde_json::from_str(&log_frame(&Unserializable)).unwrap();
+        assert_eq!(frame["error"], "SerializationFailed");
+        assert_eq!(frame["message"], "broken entry");
+    }
+
+    #[actix_web::test]
+    async fn test_check_serve_dir() {
+        let dir = std::env::temp_dir().join(format!("serve-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(check_serve_dir(&dir).is_err());
+        std::fs::write(dir.join("index.html"), "<html></html>").unwrap();
+        check_serve_dir(&dir).unwrap();
+        let manifest = |version: u32| format!(r#"{{"log_schema_version": {version}}}"#);
+        std::fs::write(dir.join(VIEWER_MANIFEST), manifest(LOG_SCHEMA_VERSION)).unwrap();
+        check_serve_dir(&dir).unwrap();
+        std::fs::write(dir.join(VIEWER_MANIFEST), manifest(LOG_SCHEMA_VERSION + 1)).unwrap();
+        let error = check_serve_dir(&dir).unwrap_err();
+        assert!(error.to_string().contains("log schema version"), "{error}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_viewer_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("index.html"), "<html></html>").unwrap();
+        std::fs::write(dir.path().join("app.js"), "main()").unwrap();
+        let static_files = StaticFiles::Dir(dir.path().to_owned());
+        let app =
+            test::init_service(App::new().configure(|config| static_files.configure(config))).await;
+        let get = |uri: &'static str| test::TestRequest::get().uri(uri).to_request();
+        let body = test::call_and_read_body(&app, get("/app.js")).await;
+        assert_eq!(body, "main()");
+        let body = test::call_and_read_body(&app, get("/games/3")).await;
+        assert_eq!(body, "<html></html>");
+        let resp = test::call_service(&app, get("/api/unknown")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = test::TestRequest::post().uri("/games/3").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_client_error());
+    }
+
+    #[actix_web::test]
+    async fn test_path_param() {
+        assert_eq!(
+            path_param("/api/pipe/{n}/value", "/api/pipe/3/value", "{n}"),
+            Some("3")
+        );
+        assert_eq!(path_param("/api/user", "/api/user", "{n}"), None);
+        let token = UserToken::from("alice".to_owned());
+        assert_eq!(token.digest().len(), 8);
+        assert_eq!(token.digest(), UserToken::from("alice".to_owned()).digest());
+        assert_ne!(token.digest(), UserToken::from("bob".to_owned()).digest());
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn test_java() {
+        crate::logger::init_for_tests();
+        spawn_blocking(|| {
+            let compilation_status = std::process::Command::new("javac")
+                .arg("java_test/Main.java")
+                .status()
+                .unwrap();
+            assert!(compilation_status.success());
+        })
+        .await
+        .unwrap();
+        let config = model::Config::default();
+        let app = run(
+            "127.0.0.1:8080",
+            model::App::init(config, vec![]),
+            Some(Duration::from_secs(2)),
+            Default::default(),
+        );
+        let client = async {
+            sleep(Duration::from_secs(1)).await; // Wait for server to start
+            let status = spawn_blocking(|| {
+                std::process::Command::new("java")
+                    .arg("java_test/Main")
+                    .status()
+                    .unwrap()
+            })
+            .await
+            .unwrap();
+            assert!(status.success());
+        };
+        let (app, ()) = futures::join!(app, client);
+        app.expect("App error");
+    }
+
+    #[actix_web::test]
+    async fn test_selftest() {
+        crate::logger::init_for_tests();
+        crate::selftest::run().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_embedded() {
+        crate::logger::init_for_tests();
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                ..Default::default()
+            })
+            .users([UserToken::from("player".to_owned())])
+            .spawn()
+            .await
+            .unwrap();
+        assert_ne!(server.addr().port(), 0);
+        let app = server.stop().await.unwrap();
+        assert_eq!(app.results().await["player"], 0);
+    }
+
+    #[actix_web::test]
+    async fn test_keep_alive() {
+        crate::logger::init_for_tests();
+        for (keep_alive, closes) in [(None, true), (Some(Duration::from_secs(5)), false)] {
+            let server = GameServer::builder()
+                .config(model::Config {
+                    time_to_run: None,
+                    ..Default::default()
+                })
+                .options(Options {
+                    keep_alive,
+                    workers: Some(1),
+                    ..Default::default()
+                })
+                .spawn()
+                .await
+                .unwrap();
+            let addr = server.addr();
+            let response = spawn_blocking(move || {
+                use std::io::{Read, Write};
+                let mut stream = std::net::TcpStream::connect(addr).unwrap();
+                let request = "GET /api/time HTTP/1.1\r\nHost: localhost\r\n\r\n";
+                stream.write_all(request.as_bytes()).unwrap();
+                stream.shutdown(std::net::Shutdown::Write).unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                response
+            })
+            .await
+            .unwrap();
+            assert!(response.starts_with("HTTP/1.1 200"));
+            let closed = response.to_lowercase().contains("connection: close");
+            assert_eq!(closed, closes, "{keep_alive:?}");
+            server.stop().await.unwrap();
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_ip_connection_limit() {
+        crate::logger::init_for_tests();
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                max_connections_per_ip: Some(1),
+                ..Default::default()
+            })
+            .options(Options {
+                workers: Some(1),
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.addr();
+        let [turned_away, allowed] = spawn_blocking(move || {
+            use std::io::{Read, Write};
+            let get = || {
+                let mut stream = std::net::TcpStream::connect(addr).unwrap();
+                let request = "GET /api/time HTTP/1.1\r\nHost: localhost\r\n\r\n";
+                stream.write_all(request.as_bytes()).unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                response
+            };
+            let idle = std::net::TcpStream::connect(addr).unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+            let turned_away = get();
+            drop(idle);
+            std::thread::sleep(Duration::from_millis(200));
+            [turned_away, get()]
+        })
+        .await
+        .unwrap();
+        assert!(turned_away.starts_with("HTTP/1.1 429"), "{turned_away}");
+        assert!(allowed.starts_with("HTTP/1.1 200"), "{allowed}");
+        server.stop().await.unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[actix_web::test]
+    async fn test_round_trip_stats() {
+        crate::logger::init_for_tests();
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                ..Default::default()
+            })
+            .users([UserToken::from("player".to_owned())])
+            .options(Options {
+                workers: Some(1),
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.addr();
+        let response = spawn_blocking(move || {
+            use std::io::{Read, Write};
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            let request = "GET /api/time HTTP/1.1\r\nHost: localhost\r\n\
+                Authorization: Bearer player\r\nConnection: close\r\n\r\n";
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        })
+        .await
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        let app = server.stop().await.unwrap();
+        let network = app.detailed_results().await.network;
+        let round_trip = network["player"].mean_round_trip.unwrap();
+        assert!(round_trip > 0.0 && round_trip < 1.0, "{round_trip}");
+    }
+
+    #[actix_web::test]
+    async fn test_token_ip_binding() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                bind_tokens_to_ip: true,
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AdminToken(UserToken::from(
+                    "admin".to_owned(),
+                ))))
+                .service(admin_unbind_ip)
+                .configure(|config| configure(config, state)),
+        )
+        .await;
+        let request = |ip: [u8; 4]| {
+            test::TestRequest::get()
+                .uri("/api/user")
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .peer_addr(SocketAddr::from((ip, 1234)))
+                .to_request()
+        };
+        let resp = test::call_service(&app, request([10, 0, 0, 1])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, request([10, 0, 0, 2])).await;
+        assert_eq!(body["code"], "TokenBoundToOtherIp");
+
+        let req = test::TestRequest::delete()
+            .uri("/api/admin/user/player/ip")
+            .append_header((AUTHORIZATION, Bearer::new("admin")))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["ip"], "10.0.0.1");
+        let resp = test::call_service(&app, request([10, 0, 0, 2])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, request([10, 0, 0, 1])).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn test_listeners() {
+        use std::io::{Read, Write};
+
+        fn get(mut stream: impl Read + Write, path: &str) -> String {
+            let request = format!(
+                "GET {path} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer admin\r\n\r\n"
+            );
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        }
+
+        crate::logger::init_for_tests();
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("game.sock");
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                ..Default::default()
+            })
+            .options(Options {
+                admin_token: Some(UserToken::from("admin".to_owned())),
+                admin_addrs: vec![SocketAddr::from(([127, 0, 0, 1], 0))],
+                unix_socket: Some(socket.clone()),
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let [public, admin] = server.started.addrs[..] else {
+            panic!("Expected two listeners: {:?}", server.started.addrs);
+        };
+        let responses = spawn_blocking(move || {
+            let tcp = |addr| std::net::TcpStream::connect(addr).unwrap();
+            [
+                get(tcp(public), "/api/admin/state"),
+                get(tcp(admin), "/api/admin/state"),
+                get(tcp(public), "/api/time"),
+                get(
+                    std::os::unix::net::UnixStream::connect(socket).unwrap(),
+                    "/api/time",
+                ),
+            ]
+        })
+        .await
+        .unwrap();
+        let statuses: Vec<_> = responses.iter().map(|response| &response[..12]).collect();
+        assert_eq!(
+            statuses,
+            [
+                "HTTP/1.1 404",
+                "HTTP/1.1 200",
+                "HTTP/1.1 200",
+                "HTTP/1.1 200"
+            ]
+        );
+        server.stop().await.unwrap();
+        assert!(!dir.path().join("game.sock").exists());
+    }
+
+    #[actix_web::test]
+    async fn test_game_ws() {
+        crate::logger::init_for_tests();
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                pipe_value_delay_secs: 0.0,
+                min_value: 100,
+                max_value: 100,
+                ..Default::default()
+            })
+            .users([UserToken::from("player".to_owned())])
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.addr().to_string();
+        let messages = actix_web::rt::task::spawn_blocking(move || {
+            let client = crate::http_client::Client::new(&addr, Some("player".to_owned()));
+            let mut websocket = client.websocket("/api/ws").unwrap();
+            // The current score comes first, without the history before the connection
+            let opened = websocket.receive().unwrap().unwrap();
+            let mut messages = vec![serde_json::from_str::<serde_json::Value>(&opened).unwrap()];
+            websocket
+                .send(r#"{"id": 1, "type": "collect", "pipe": 1}"#)
+                .unwrap();
+            websocket.send(r#"{"id": 2, "type": "value"}"#).unwrap();
+            while messages.len() < 4 {
+                let message = websocket.receive().unwrap().unwrap();
+                messages.push(serde_json::from_str::<serde_json::Value>(&message).unwrap());
+            }
+            messages
+        })
+        .await
+        .unwrap();
+        let find = |kind: &'static str| {
+            messages
+                .iter()
+                .filter(move |message| message["type"] == kind)
+        };
+        let response = find("response").next().unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["body"]["value"], 100);
+        assert_eq!(find("invalid").count(), 1);
+        let scores: Vec<_> = find("score").map(|message| &message["score"]).collect();
+        assert_eq!(scores, [0, 100]);
+        server.stop().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_log_subscribers() {
+        crate::logger::init_for_tests();
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                ..Default::default()
+            })
+            .users([UserToken::from("player".to_owned())])
+            .options(Options {
+                enable_logs_api: true,
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.addr().to_string();
+        let message = actix_web::rt::task::spawn_blocking(move || {
+            let client = crate::http_client::Client::new(&addr, None);
+            let mut websocket = client.websocket("/logs").unwrap();
+            websocket.receive().unwrap().unwrap()
+        })
+        .await
+        .unwrap();
+        let metrics = server.app().metrics();
+        let mut subscribers = metrics.subscribers();
+        for _ in 0..100 {
+            if !subscribers[0].connected {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+            subscribers = metrics.subscribers();
+        }
+        assert_eq!(subscribers.len(), 1);
+        assert!(!subscribers[0].connected);
+        assert!(subscribers[0].bytes_sent >= message.len() as u64);
+        server.stop().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_lobby() {
+        crate::logger::init_for_tests();
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                min_value: 100,
+                max_value: 100,
+                ..Default::default()
+            })
+            .users([UserToken::from("player".to_owned())])
+            .options(Options {
+                enable_logs_api: true,
+                admin_token: Some(UserToken::from("admin".to_owned())),
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.addr().to_string();
+        spawn_blocking(move || {
+            let client =
+                |token: &str| crate::http_client::Client::new(&addr, Some(token.to_owned()));
+            let new_game = serde_json::json!({ "id": "group-a", "users": ["alice"] });
+            let created = client("admin")
+                .request("POST", "/api/admin/games", Some(&new_game))
+                .unwrap();
+            assert_eq!(created.status, 200, "{}", created.body);
+            let again = client("admin")
+                .request("POST", "/api/admin/games", Some(&new_game))
+                .unwrap();
+            assert_eq!(again.status, 409);
+
+            let collected = client("alice")
+                .request("PUT", "/api/games/group-a/pipe/1", None)
+                .unwrap();
+            assert_eq!(collected.status, 200, "{}", collected.body);
+            // Users of one game are unknown in the others
+            let status =
+                |token: &str, path: &str| client(token).request("PUT", path, None).unwrap().status;
+            assert_eq!(status("alice", "/api/pipe/1"), 401);
+            assert_eq!(status("player", "/api/games/group-a/pipe/1"), 401);
+            assert_eq!(status("alice", "/api/games/group-b/pipe/1"), 404);
+
+            let games = client("alice").request("GET", "/api/games", None).unwrap();
+            let games: serde_json::Value = serde_json::from_str(&games.body).unwrap();
+            assert_eq!(games[0]["id"], "group-a");
+            assert_eq!(games[0]["players"], 1);
+
+            let mut websocket = client("alice")
+                .websocket("/api/games/group-a/logs?types=CollectEnd")
+                .unwrap();
+            let entry: serde_json::Value =
+                serde_json::from_str(&websocket.receive().unwrap().unwrap()).unwrap();
+            assert_eq!(entry["msg"]["user"], "alice");
+
+            let removed = client("admin")
+                .request("DELETE", "/api/admin/games/group-a", None)
+                .unwrap();
+            let results: serde_json::Value = serde_json::from_str(&removed.body).unwrap();
+            assert_eq!(results["alice"], 100);
+            assert_eq!(status("alice", "/api/games/group-a/pipe/1"), 404);
+        })
+        .await
+        .unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_logs_api_auth() {
+        crate::logger::init_for_tests();
+        let auth = ViewerAuth::new("secret");
+        let logs_token = auth.sign(Scope::Logs, Duration::from_secs(60));
+        let game_token = auth.sign_for(Scope::Logs, Some("group-a"), Duration::from_secs(60));
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                ..Default::default()
+            })
+            .users([UserToken::from("player".to_owned())])
+            .options(Options {
+                enable_logs_api: true,
+                viewer_auth: Some(auth),
+                admin_token: Some(UserToken::from("admin".to_owned())),
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.addr().to_string();
+        spawn_blocking(move || {
+            let client = |token: Option<&str>| {
+                crate::http_client::Client::new(&addr, token.map(str::to_owned))
+            };
+            let new_game = serde_json::json!({ "id": "group-a", "users": ["alice"] });
+            let created = client(Some("admin"))
+                .request("POST", "/api/admin/games", Some(&new_game))
+                .unwrap();
+            assert_eq!(created.status, 200, "{}", created.body);
+            let status = |token: Option<&str>, path: &str| {
+                client(token).request("GET", path, None).unwrap().status
+            };
+            for path in [
+                "/api/logs",
+                "/api/pipe/1/history",
+                "/api/overlay",
+                "/api/scoreboard",
+            ] {
+                assert_eq!(status(None, path), 403, "{path}");
+                assert_eq!(status(Some("player"), path), 403, "{path}");
+                assert_eq!(status(Some(&logs_token), path), 200, "{path}");
+                assert_eq!(status(Some(&game_token), path), 403, "{path}");
+            }
+            // Each game takes tokens of its own
+            for path in [
+                "/api/games/group-a/pipe/1/history",
+                "/api/games/group-a/scoreboard",
+            ] {
+                assert_eq!(status(Some(&logs_token), path), 403, "{path}");
+                assert_eq!(status(Some(&game_token), path), 200, "{path}");
+            }
+            let game_logs = |token: &str, game: &str| {
+                client(None)
+                    .request(
+                        "GET",
+                        &format!("/api/games/{game}/logs?token={token}"),
+                        None,
+                    )
+                    .unwrap()
+                    .status
+            };
+            assert_ne!(game_logs(&game_token, "group-a"), 403);
+            assert_eq!(game_logs(&logs_token, "group-a"), 403);
+            let other = serde_json::json!({ "id": "group-b" });
+            client(Some("admin"))
+                .request("POST", "/api/admin/games", Some(&other))
+                .unwrap();
+            assert_eq!(game_logs(&game_token, "group-b"), 403);
+        })
+        .await
+        .unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_game_viewer() {
+        crate::logger::init_for_tests();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("index.html"), "<html></html>").unwrap();
+        std::fs::write(dir.path().join("app.js"), "main()").unwrap();
+        let auth = ViewerAuth::new("secret");
+        let game_token = auth.sign_for(Scope::Viewer, Some("group-a"), Duration::from_secs(60));
+        let main_token = auth.sign(Scope::Viewer, Duration::from_secs(60));
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                ..Default::default()
+            })
+            .options(Options {
+                static_files: Some(StaticFiles::Dir(dir.path().to_owned())),
+                enable_logs_api: true,
+                viewer_auth: Some(auth),
+                admin_token: Some(UserToken::from("admin".to_owned())),
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.addr().to_string();
+        spawn_blocking(move || {
+            let client = |token: Option<&str>| {
+                crate::http_client::Client::new(&addr, token.map(str::to_owned))
+            };
+            let new_game = serde_json::json!({ "id": "group-a" });
+            client(Some("admin"))
+                .request("POST", "/api/admin/games", Some(&new_game))
+                .unwrap();
+            let get = |path: &str| client(None).request("GET", path, None).unwrap();
+            let redirect = get(&format!("/api/games/group-a/viewer?token={game_token}"));
+            assert_eq!(redirect.status, 308);
+            assert_eq!(
+                redirect.header("location"),
+                Some(format!("/api/games/group-a/viewer/?token={game_token}").as_str())
+            );
+            let index = get(&format!("/api/games/group-a/viewer/?token={game_token}"));
+            assert_eq!(index.status, 200);
+            assert_eq!(index.body, "<html></html>");
+            // Kept to the game, so that the viewer of another game keeps its own token
+            let cookie = index.header("set-cookie").unwrap();
+            assert!(cookie.contains("Path=/api/games/group-a/"), "{cookie}");
+            let script = get(&format!(
+                "/api/games/group-a/viewer/app.js?token={game_token}"
+            ));
+            assert_eq!(script.body, "main()");
+            let main = get(&format!("/api/games/group-a/viewer/?token={main_token}"));
+            assert_eq!(main.status, 403);
+            assert_eq!(get(&format!("/?token={game_token}")).status, 403);
+            assert_eq!(get("/api/games/group-b/viewer/").status, 404);
+        })
+        .await
+        .unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_log_filters() {
+        crate::logger::init_for_tests();
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                pipe_value_delay_secs: 0.0,
+                ..Default::default()
+            })
+            .users(["player", "other"].map(|token| UserToken::from(token.to_owned())))
+            .options(Options {
+                enable_logs_api: true,
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.addr().to_string();
+        let messages = actix_web::rt::task::spawn_blocking(move || {
+            let collect_pipe = |token: &str| {
+                crate::http_client::Client::new(&addr, Some(token.to_owned()))
+                    .request("PUT", "/api/pipe/1", None)
+                    .unwrap();
+            };
+            collect_pipe("player");
+            let client = crate::http_client::Client::new(&addr, None);
+            let mut websocket = client
+                .websocket("/logs?history=false&user=player&types=CollectEnd,UpdateUser")
+                .unwrap();
+            // Give the subscription time to get registered
+            std::thread::sleep(Duration::from_millis(100));
+            collect_pipe("other");
+            collect_pipe("player");
+            (0..2)
+                .map(|_| {
+                    let message = websocket.receive().unwrap().unwrap();
+                    serde_json::from_str::<serde_json::Value>(&message).unwrap()
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap();
+        for message in &messages {
+            assert_eq!(message["msg"]["user"], "player");
+        }
+        let types: Vec<_> = messages
+            .iter()
+            .map(|message| &message["msg"]["type"])
+            .collect();
+        assert_eq!(types, ["CollectEnd", "UpdateUser"]);
+        server.stop().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_logs_closed_after_game_over() {
+        crate::logger::init_for_tests();
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: Some(1.0),
+                start_delay_secs: 0.0,
+                game_over_grace_secs: 0.0,
+                ..Default::default()
+            })
+            .users(["a", "b"].map(|token| UserToken::from(token.to_owned())))
+            .options(Options {
+                enable_logs_api: true,
+                ..Default::default()
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.addr().to_string();
+        let messages = spawn_blocking(move || {
+            let client = crate::http_client::Client::new(&addr, None);
+            let mut websocket = client
+                .websocket("/logs?history=false&types=CollectEnd")
+                .unwrap();
+            let mut messages = Vec::new();
+            while let Some(message) = websocket.receive().unwrap() {
+                messages.push(serde_json::from_str::<serde_json::Value>(&message).unwrap());
+            }
+            messages
+        })
+        .await
+        .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["msg"]["type"], "GameOver");
+        let results = messages[0]["msg"]["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["rank"], 1);
+        server.wait().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_logs_page() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                history_limit: Some(3),
+                ..Default::default()
+            },
+            ["a", "b", "c", "d"].map(|token| UserToken::from(token.to_owned())),
+        ));
+        let auth = ViewerAuth::new("secret");
+        let token = auth.sign(Scope::Logs, Duration::from_secs(60));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(auth))
+                .configure(|config| configure(config, state.clone()))
+                .service(logs_page),
+        )
+        .await;
+        let len = state.history_len().await;
+        let request = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .append_header((AUTHORIZATION, format!("Bearer {token}")))
+                .to_request()
+        };
+        for token in ["", "?token=1.logs.00"] {
+            let uri = format!("/api/logs{token}");
+            let req = test::TestRequest::get().uri(&uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{uri}");
+        }
+        let page: crate::history::LogsResponse =
+            test::call_and_read_body_json(&app, request("/api/logs?limit=2")).await;
+        assert_eq!(page.first, len - 3);
+        assert_eq!(page.entries.len(), 2);
+        assert_eq!(page.next, len - 1);
+        let page: crate::history::LogsResponse = test::call_and_read_body_json(
+            &app,
+            request(&format!("/api/logs?offset={}", page.next)),
+        )
+        .await;
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.next, len);
+    }
+
+    #[actix_web::test]
+    async fn test_log_backpressure() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let player = UserToken::from("player".to_owned());
+        let state = model::App::init(
+            model::Config {
+                log_subscriber_buffer: 1,
+                ..Default::default()
+            },
+            vec![player.clone()],
+        );
+        let (dropping, mut dropping_receiver) = state.log_channel(Some(model::LogOverflow::Drop));
+        let (disconnecting, disconnecting_receiver) =
+            state.log_channel(Some(model::LogOverflow::Disconnect));
+        state.register_live_logs(dropping).await;
+        state.register_live_logs(disconnecting).await;
+        let pipe = PipeId::new(1).unwrap();
+        for _ in 0..3 {
+            state.collect(&player, pipe).await.unwrap();
+        }
+        // Nobody reads, yet the collects went through
+        assert_eq!(state.log_subscribers().await, 1);
+        let mut received = 0;
+        while dropping_receiver.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, 2);
+        let entries: Vec<_> = disconnecting_receiver.collect().await;
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_health() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                time_to_run: Some(60.0),
+                ..Default::default()
+            },
+            ["player".parse().unwrap()],
+        ));
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(check_viewer_token))
+                .app_data(web::Data::new(ViewerAuth::new("secret")))
+                .configure(|config| configure(config, state.clone())),
+        )
+        .await;
+        let request = test::TestRequest::get().uri("/healthz").to_request();
+        let health: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(health["state"], "running");
+        assert_eq!(health["players"], 1);
+        assert!(health["pipes"].as_u64().unwrap() > 0);
+        assert!(health["time_left"].as_f64().unwrap() > 0.0);
+        let request = test::TestRequest::get().uri("/readyz").to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::OK
+        );
+
+        state.end_game().await;
+        let request = test::TestRequest::get().uri("/readyz").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let health: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(health["state"], "ended");
+    }
+
+    #[actix_web::test]
+    async fn test_game_over() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                time_to_run: Some(60.0),
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                .await;
+        let request = test::TestRequest::get().uri("/api/game").to_request();
+        let game: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(game["state"], "running");
+        assert!(game["time_left"].as_f64().unwrap() > 59.0);
+
+        state.announce_end().await;
+        let request = test::TestRequest::put()
+            .uri("/api/pipe/1")
+            .append_header((AUTHORIZATION, Bearer::new("player")))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::GONE);
+        let error: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(error["error"], "GameOver");
+        let request = test::TestRequest::get().uri("/api/game").to_request();
+        let game: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(game["state"], "ended");
+    }
+
+    #[actix_web::test]
+    async fn test_start_delay() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let launched = tokio::time::Instant::now();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                start_delay_secs: 5.0,
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                ..Default::default()
+            },
+            ["first", "second"].map(|token| UserToken::from(token.to_owned())),
+        ));
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                .await;
+        // Status and completion second of a collect sent after `after` seconds
+        let collect_pipe = |token: &'static str, after: u64| {
+            let app = &app;
+            async move {
+                sleep(Duration::from_secs(after)).await;
+                let request = test::TestRequest::put()
+                    .uri("/api/pipe/1")
+                    .append_header((AUTHORIZATION, Bearer::new(token)))
+                    .to_request();
+                let resp = test::call_service(app, request).await;
+                (resp.status(), launched.elapsed().as_secs_f64().round())
+            }
+        };
+        let game_state = || async {
+            let request = test::TestRequest::get().uri("/api/game").to_request();
+            let game: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+            game["state"].clone()
+        };
+        assert_eq!(game_state().await, "starting");
+
+        // Unknown users are turned away at once instead of being held
+        let (first, second, intruder, late) = futures::join!(
+            collect_pipe("first", 0),
+            collect_pipe("second", 2),
+            collect_pipe("intruder", 1),
+            collect_pipe("first", 7),
+        );
+        assert_eq!(intruder, (StatusCode::UNAUTHORIZED, 1.0));
+        // Both early requests are released together when the game starts
+        assert_eq!(first, (StatusCode::OK, 5.0));
+        assert_eq!(second, (StatusCode::OK, 5.0));
+        assert_eq!(late, (StatusCode::OK, 7.0));
+        assert_eq!(game_state().await, "running");
+        assert!((state.game_time() - 2.0).abs() < 0.01);
+    }
+
+    #[actix_web::test]
+    async fn test_waiting_room() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                time_to_run: Some(60.0),
+                wait_for_players: Some(2),
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                ..Default::default()
+            },
+            ["first", "second", "third"].map(|token| UserToken::from(token.to_owned())),
+        ));
+        let (sender, mut receiver) = mpsc::unbounded();
+        state.register_logs(sender).await;
+        let scheduler = spawn({
+            let state = state.clone();
+            async move { state.run_scheduler().await }
+        });
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                .await;
+        let collect_pipe = |token: &str| {
+            let request = test::TestRequest::put()
+                .uri("/api/pipe/1")
+                .append_header((AUTHORIZATION, Bearer::new(token.to_owned())))
+                .to_request();
+            test::call_service(&app, request)
+        };
+
+        let first = collect_pipe("first").boxed_local();
+        // Held until another user comes
+        let first = match select(first, sleep(Duration::from_millis(100)).boxed_local()).await {
+            Left(_) => panic!("The game started with a single player"),
+            Right((_, first)) => first,
+        };
+        let request = test::TestRequest::get().uri("/api/game").to_request();
+        let game: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(game["state"], "starting");
+        assert_eq!(game["time_left"], 60.0);
+
+        let (first, second) = futures::join!(first, collect_pipe("second"));
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+        assert!(!state.is_waiting_for_players());
+        assert!(!state.start_game());
+        sleep(Duration::from_millis(10)).await;
+        scheduler.abort();
+        let mut started = Vec::new();
+        while let Ok(entry) = receiver.try_recv() {
+            if let model::LogMessage::GameStart { players } = entry.msg {
+                started.push(players);
+            }
+        }
+        assert_eq!(started, [3]);
+    }
+
+    #[actix_web::test]
+    async fn test_register() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                implicit_users: false,
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                ..Default::default()
+            },
+            vec![],
+        ));
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                .await;
+        let collect_pipe = |token: &str| {
+            let request = test::TestRequest::put()
+                .uri("/api/pipe/1")
+                .append_header((AUTHORIZATION, Bearer::new(token.to_owned())))
+                .to_request();
+            test::call_service(&app, request)
+        };
+        assert_eq!(
+            collect_pipe("made-up").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let request = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(serde_json::json!({"name": "  Plumbers "}))
+            .to_request();
+        let registered: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(registered["name"], "Plumbers");
+        let token = registered["token"].as_str().unwrap().to_owned();
+        assert_eq!(collect_pipe(&token).await.status(), StatusCode::OK);
+
+        let request = test::TestRequest::post().uri("/api/register").to_request();
+        let anonymous: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_ne!(anonymous["token"], registered["token"]);
+        assert!(anonymous.get("name").is_none());
+
+        let results = state.detailed_results().await;
+        assert_eq!(results.scores.len(), 2);
+        assert_eq!(results.profiles.len(), 1);
+        assert_eq!(results.profiles[&token].name.as_deref(), Some("Plumbers"));
+        assert_eq!(state.game_state().names[&token], "Plumbers");
+
+        let listed = web::Data::new(model::App::init(
+            model::Config::default(),
+            [UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, listed))).await;
+        let request = test::TestRequest::post().uri("/api/register").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_accounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let results_db = ResultsDb::open(&dir.path().join("results.sqlite")).unwrap();
+        let accounts = web::Data::new(Accounts::new(&results_db).unwrap());
+        let state = web::Data::new(model::App::init(model::Config::default(), vec![]));
+        let app = test::init_service(
+            App::new()
+                .configure(|config| configure(config, state.clone()))
+                .app_data(accounts)
+                .configure(configure_accounts),
+        )
+        .await;
+        let post = |uri: &str, password: &str| {
+            let request = test::TestRequest::post()
+                .uri(uri)
+                .set_json(serde_json::json!({"username": "plumber", "password": password}))
+                .to_request();
+            test::call_service(&app, request)
+        };
+        assert_eq!(
+            post("/api/account", "pipes-all-the-way").await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            post("/api/account", "pipes-all-the-way").await.status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            post("/api/account/token", "wrong-password").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let registered: serde_json::Value =
+            test::read_body_json(post("/api/account/token", "pipes-all-the-way").await).await;
+        assert_eq!(registered["name"], "plumber");
+        let token = registered["token"].as_str().unwrap().to_owned();
+
+        let request = test::TestRequest::get()
+            .uri("/api/account")
+            .append_header((AUTHORIZATION, Bearer::new(token.clone())))
+            .to_request();
+        let account: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(account["username"], "plumber");
+        assert_eq!(account["games"], serde_json::json!([]));
+        let request = test::TestRequest::get()
+            .uri("/api/account")
+            .append_header((AUTHORIZATION, Bearer::new("made-up")))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Bots exchange an API key for the token instead of signing in
+        let response = post("/api/account/key", "pipes-all-the-way").await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let key: serde_json::Value = test::read_body_json(response).await;
+        let exchange = |key: &str| {
+            let request = test::TestRequest::post()
+                .uri("/api/token")
+                .append_header((AUTHORIZATION, Bearer::new(key.to_owned())))
+                .to_request();
+            test::call_service(&app, request)
+        };
+        let exchanged: serde_json::Value =
+            test::read_body_json(exchange(key["key"].as_str().unwrap()).await).await;
+        assert_eq!(exchanged["token"], registered["token"]);
+        assert_eq!(
+            exchange("key-made-up").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(exchange(&token).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_operations() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                min_delay_secs: 0.05,
+                max_delay_secs: 0.05,
+                min_value: 100,
+                max_value: 100,
+                ..Default::default()
+            },
+            [UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                .await;
+        let collect_pipe = |uri: &str, key: &str| {
+            let request = test::TestRequest::put()
+                .uri(uri)
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .append_header((IDEMPOTENCY_KEY, key.to_owned()))
+                .to_request();
+            test::call_service(&app, request)
+        };
+
+        let response = collect_pipe("/api/pipe/1?wait=false", "first").await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(response.headers().get(OPERATION_ID).unwrap(), "1");
+        let started: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(started["status"], "pending");
+        // A retry waits for the first request instead of collecting again
+        let response = collect_pipe("/api/pipe/1", "first").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(OPERATION_ID).unwrap(), "1");
+        let collected: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(collected["value"], 100);
+        assert_eq!(
+            state
+                .user_state(&"player".parse().unwrap())
+                .await
+                .unwrap()
+                .score,
+            100
+        );
+
+        let request = test::TestRequest::get()
+            .uri("/api/operations/1")
+            .append_header((AUTHORIZATION, Bearer::new("player")))
+            .to_request();
+        let finished: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(finished["status"], "done");
+        assert_eq!(finished["response"]["value"], 100);
+
+        let response = collect_pipe("/api/pipe/2", "first").await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let response = collect_pipe("/api/pipe/2", "second").await;
+        assert_eq!(response.headers().get(OPERATION_ID).unwrap(), "2");
+        let request = test::TestRequest::get()
+            .uri("/api/operations/3")
+            .append_header((AUTHORIZATION, Bearer::new("player")))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_abandoned_collect() {
+        crate::logger::init_for_tests();
+        for commit in [false, true] {
+            let [player, saboteur] =
+                ["player", "saboteur"].map(|token| UserToken::from(token.to_owned()));
+            let state = web::Data::new(model::App::init(
+                model::Config {
+                    initial_score: 1000,
+                    min_delay_secs: 0.1,
+                    max_delay_secs: 0.1,
+                    min_value: 100,
+                    max_value: 100,
+                    commit_abandoned_collects: commit,
+                    ..Default::default()
+                },
+                [player.clone(), saboteur.clone()],
+            ));
+            let pipe = PipeId::new(1).unwrap();
+            state
+                .apply_modifier(&saboteur, pipe, model::Modifier::Slow)
+                .await
+                .unwrap();
+            let slow_uses = || async {
+                let pipes = state.list_pipes(&saboteur).await.unwrap();
+                pipes.pipes[0].modifiers[0].uses_left
+            };
+            let uses = slow_uses().await;
+            let app =
+                test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                    .await;
+            let request = test::TestRequest::put()
+                .uri("/api/pipe/1")
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .to_request();
+            let request = test::call_service(&app, request).boxed_local();
+            // The client goes away in the middle of the collect
+            match select(request, sleep(Duration::from_millis(50)).boxed_local()).await {
+                Left(_) => panic!("The collect finished early"),
+                Right((_, request)) => drop(request),
+            }
+            sleep(Duration::from_millis(300)).await;
+
+            let score = state.user_state(&player).await.unwrap().score;
+            let page = state.logs_page(0, 100).await;
+            let aborted = page
+                .entries
+                .iter()
+                .any(|entry| matches!(entry.msg, model::LogMessage::CollectAborted { .. }));
+            if commit {
+                assert_eq!(score, 1100);
+                assert!(!aborted);
+                assert_eq!(slow_uses().await, uses.map(|uses| uses - 1));
+            } else {
+                assert_eq!(score, 1000);
+                assert!(aborted);
+                assert_eq!(slow_uses().await, uses);
+            }
+            let actions = state.user_actions(&player).await.unwrap();
+            assert!(actions.actions.is_empty());
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_concurrency_limit() {
+        crate::logger::init_for_tests();
+        for (limit, expected) in [
+            (Some(1), [StatusCode::OK, StatusCode::FORBIDDEN]),
+            (None, [StatusCode::OK, StatusCode::OK]),
+        ] {
+            let state = web::Data::new(model::App::init(
+                model::Config {
+                    min_delay_secs: 0.1,
+                    max_delay_secs: 0.1,
+                    max_concurrent_actions: limit,
+                    ..Default::default()
+                },
+                [UserToken::from("player".to_owned())],
+            ));
+            let app =
+                test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                    .await;
+            let collect_pipe = |pipe: usize| {
+                let request = test::TestRequest::put()
+                    .uri(&format!("/api/pipe/{pipe}"))
+                    .append_header((AUTHORIZATION, Bearer::new("player")))
+                    .to_request();
+                test::call_service(&app, request)
+            };
+            let (first, second) = futures::join!(collect_pipe(1), collect_pipe(2));
+            let mut statuses = [first.status(), second.status()];
+            statuses.sort();
+            assert_eq!(statuses, expected, "{limit:?}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_concurrent_actions_by_type() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                min_delay_secs: 1.0,
+                max_delay_secs: 1.0,
+                pipe_value_delay_secs: 1.0,
+                max_concurrent_actions: Some(3),
+                max_concurrent_actions_by_type: [(model::Action::Collect, 1)].into(),
+                ..Default::default()
+            },
+            [UserToken::from("player".to_owned())],
+        ));
+        let app = test::init_service(App::new().configure(|config| configure(config, state))).await;
+        let collect_pipe = |pipe: usize| {
+            test::call_service(
+                &app,
+                test::TestRequest::put()
+                    .uri(&format!("/api/pipe/{pipe}"))
+                    .append_header((AUTHORIZATION, Bearer::new("player")))
+                    .to_request(),
+            )
+        };
+        let value_of = |pipe: usize| {
+            test::call_service(
+                &app,
+                test::TestRequest::get()
+                    .uri(&format!("/api/pipe/{pipe}/value"))
+                    .append_header((AUTHORIZATION, Bearer::new("player")))
+                    .to_request(),
+            )
+        };
+        // With a collect and a value in flight another collect hits the collect limit,
+        // the next value fits and the one after it hits the total limit
+        let late = async {
+            actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+            let second_collect = collect_pipe(2).await;
+            let (second_value, third_value) = futures::join!(value_of(1), async {
+                actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+                value_of(2).await
+            });
+            (second_collect, second_value, third_value)
+        };
+        let (first_collect, first_value, (second_collect, second_value, third_value)) =
+            futures::join!(collect_pipe(1), value_of(3), late);
+        assert_eq!(first_collect.status(), StatusCode::OK);
+        assert_eq!(first_value.status(), StatusCode::OK);
+        assert_eq!(second_collect.status(), StatusCode::FORBIDDEN);
+        assert_eq!(second_value.status(), StatusCode::OK);
+        assert_eq!(third_value.status(), StatusCode::FORBIDDEN);
+
+        // Finished actions give their slots back
+        let (collected, valued) = futures::join!(collect_pipe(2), value_of(1));
+        assert_eq!(collected.status(), StatusCode::OK);
+        assert_eq!(valued.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_auth_failure_summary() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let audit_log = web::Data::new(AuditLog::open(&path).unwrap());
+        let state = web::Data::new(model::App::init(
+            model::Config::default(),
+            [UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                .await;
+        for ip in ["10.0.0.1", "10.0.0.1", "10.0.0.2"] {
+            let request = test::TestRequest::get()
+                .uri("/api/user")
+                .peer_addr(format!("{ip}:1234").parse().unwrap())
+                .append_header((AUTHORIZATION, Bearer::new("scanner")))
+                .to_request();
+            let resp = test::call_service(&app, request).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        let sweeper = spawn(sweep_rate_limits(state.clone(), Some(audit_log)));
+        tokio::task::yield_now().await;
+        sleep(Duration::from_secs(61)).await;
+        let log = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2, "{log}");
+        assert_eq!(records[0]["method"], "SUMMARY");
+        assert_eq!(records[0]["ip"], "10.0.0.1");
+        assert_eq!(records[0]["body"]["attempts"], 2);
+        assert_eq!(records[1]["ip"], "10.0.0.2");
+        assert_eq!(records[1]["body"]["attempts"], 1);
+        // Only new attempts are summarized
+        sleep(Duration::from_secs(60)).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), log);
+        sweeper.abort();
+    }
+
+    #[actix_web::test]
+    async fn test_error_hints() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                implicit_users: false,
+                min_delay_secs: 2.0,
+                max_delay_secs: 2.0,
+                max_concurrent_actions: Some(1),
+                max_auth_failures_per_minute: 2,
+                error_statuses: [(model::ErrorCode::UserBusy, 429)].into(),
+                ..Default::default()
+            },
+            [UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                .await;
+        let collect_pipe = |token: &'static str| {
+            let request = test::TestRequest::put()
+                .uri("/api/pipe/1")
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .append_header((AUTHORIZATION, Bearer::new(token)))
+                .to_request();
+            test::call_service(&app, request)
+        };
+
+        // Busy until the collect in flight ends
+        let (first, busy) = futures::join!(collect_pipe("player"), collect_pipe("player"));
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(busy.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(busy.headers().get(RETRY_AFTER).unwrap(), "2");
+        let body: serde_json::Value = test::read_body_json(busy).await;
+        assert_eq!(body["error"], "UserBusy");
+
+        for _ in 0..2 {
+            let resp = collect_pipe("made-up").await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            let challenge = resp.headers().get(WWW_AUTHENTICATE).unwrap();
+            assert!(challenge
+                .to_str()
+                .unwrap()
+                .starts_with("Bearer error=\"invalid_token\""));
+        }
+        tokio::time::advance(Duration::from_secs(20)).await;
+        let resp = collect_pipe("made-up").await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "40");
+        assert!(resp.headers().get(WWW_AUTHENTICATE).is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_spectators() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                pipe_value_delay_secs: 60.0,
+                spectator_tokens: vec![UserToken::from("caster".to_owned())],
+                ..Default::default()
+            },
+            [UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                .await;
+        let call = |request: test::TestRequest| {
+            let request = request
+                .append_header((AUTHORIZATION, Bearer::new("caster")))
+                .to_request();
+            test::call_service(&app, request)
+        };
+        let response = call(test::TestRequest::get().uri("/api/pipe/1/value")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call(test::TestRequest::get().uri("/api/pipes/values?ids=1,2")).await;
+        let values: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(values["values"].as_array().unwrap().len(), 2);
+
+        for request in [
+            test::TestRequest::put().uri("/api/pipe/1"),
+            test::TestRequest::post()
+                .uri("/api/pipe/1/modifier")
+                .set_json(serde_json::json!({"type": "double"})),
+            test::TestRequest::get().uri("/api/user"),
+        ] {
+            let response = call(request).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            let body: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(body["error"], "SpectatorOnly");
+        }
+        assert_eq!(state.results().await.keys().collect::<Vec<_>>(), ["player"]);
+    }
+
+    #[actix_web::test]
+    async fn test_cors() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(model::Config::default(), vec![]));
+        let origins = ["https://dashboard.example".to_owned()];
+        let app = test::init_service(
+            App::new()
+                .wrap(cors(&origins))
+                .configure(|config| configure(config, state)),
+        )
+        .await;
+        let preflight = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/api/pipe/1")
+            .insert_header(("Origin", "https://dashboard.example"))
+            .insert_header(("Access-Control-Request-Method", "PUT"))
+            .insert_header(("Access-Control-Request-Headers", "authorization"))
+            .to_request();
+        let response = test::call_service(&app, preflight).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response
+                .headers()
+                .get("access-control-allow-origin")
+                .unwrap(),
+            "https://dashboard.example"
+        );
+        let request = test::TestRequest::get()
+            .uri("/api/time")
+            .insert_header(("Origin", "https://dashboard.example"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let exposed = response
+            .headers()
+            .get("access-control-expose-headers")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(exposed.contains("x-game-ends-in"), "{exposed}");
+        let request = test::TestRequest::get()
+            .uri("/api/time")
+            .insert_header(("Origin", "https://elsewhere.example"))
+            .to_request();
+        // Left for the browser to block
+        let response = test::call_service(&app, request).await;
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+    }
+
+    #[actix_web::test]
+    async fn test_openapi() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(model::Config::default(), vec![]));
+        let app = test::init_service(App::new().configure(|config| configure(config, state))).await;
+        let request = test::TestRequest::get()
+            .uri("/api/openapi.json")
+            .to_request();
+        let spec: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        let collect_op = &spec["paths"]["/api/pipe/{n}"]["put"];
+        assert_eq!(
+            collect_op["responses"]["200"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/CollectResponse"
+        );
+        assert_eq!(collect_op["security"][0]["token"], serde_json::json!([]));
+        let schemas = &spec["components"]["schemas"];
+        for name in [
+            "User",
+            "Modifier",
+            "ErrorCode",
+            "ErrorDetails",
+            "ShopResponse",
+            "HistoryResponse",
+        ] {
+            assert!(schemas[name].is_object(), "{name} is missing");
+        }
+        assert_eq!(spec["paths"].as_object().unwrap().len(), 22);
+        let history = &spec["paths"]["/api/user/history"]["get"]["parameters"];
+        assert_eq!(history.as_array().unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_logs_sse() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config::default(),
+            vec![UserToken::from("player".to_owned())],
+        ));
+        state.announce_end().await;
+        let app = test::init_service(
+            App::new()
+                .configure(|config| configure(config, state.clone()))
+                .service(logs_sse),
+        )
+        .await;
+        let read_events = |uri: &'static str| {
+            let app = &app;
+            async move {
+                let resp =
+                    test::call_service(app, test::TestRequest::get().uri(uri).to_request()).await;
+                assert_eq!(
+                    resp.headers().get(CONTENT_TYPE).unwrap(),
+                    "text/event-stream"
+                );
+                // Let the history get registered
+                sleep(Duration::from_millis(10)).await;
+                let mut body = Box::pin(resp.into_body());
+                let mut events = String::new();
+                while let Some(Ok(chunk)) =
+                    futures::future::poll_fn(|cx| body.as_mut().poll_next(cx))
+                        .now_or_never()
+                        .flatten()
+                {
+                    events.push_str(std::str::from_utf8(&chunk).unwrap());
+                }
+                events
+            }
+        };
+        let events = read_events("/logs/sse").await;
+        assert!(events.starts_with("data: {"));
+        assert!(events.ends_with("\n\n"));
+        assert!(events.contains("GameEnd"));
+        let events = read_events("/logs/sse?from=1e9").await;
+        assert!(events.is_empty());
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(state.log_subscribers().await, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_logs_token_scope() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(model::Config::default(), vec![]));
+        state.announce_end().await;
+        let auth = ViewerAuth::new("secret");
+        let logs_token = auth.sign(Scope::Logs, Duration::from_secs(60));
+        let viewer_token = auth.sign(Scope::Viewer, Duration::from_secs(60));
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(check_viewer_token))
+                .app_data(web::Data::new(auth))
+                .configure(|config| configure(config, state))
+                .service(logs_sse),
+        )
+        .await;
+        let status = |uri: String| {
+            let app = &app;
+            async move {
+                let request = test::TestRequest::get().uri(&uri).to_request();
+                test::call_service(app, request).await.status()
+            }
+        };
+        assert_eq!(status("/logs/sse".to_owned()).await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            status("/logs/sse?token=1.logs.00".to_owned()).await,
+            StatusCode::FORBIDDEN
+        );
+        for token in [&logs_token, &viewer_token] {
+            let uri = format!("/logs/sse?token={token}");
+            assert_eq!(status(uri).await, StatusCode::OK);
+        }
+        // Tokens for the logs alone don't open the viewer
+        let uri = format!("/index.html?token={logs_token}");
+        assert_eq!(status(uri).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_scheduler() {
+        use crate::scheduler::{Action, Scheduler};
+        crate::logger::init_for_tests();
+        let server = GameServer::builder()
+            .config(model::Config {
+                time_to_run: None,
+                min_delay_secs: 0.5,
+                max_delay_secs: 0.5,
+                ..Default::default()
+            })
+            .users([UserToken::from("player".to_owned())])
+            .spawn()
+            .await
+            .unwrap();
+        let client = || {
+            crate::http_client::Client::new(&server.addr().to_string(), Some("player".to_owned()))
+        };
+        let busy = spawn_blocking({
+            let client = client();
+            move || client.request("PUT", "/api/pipe/1", None).unwrap().status
+        });
+        let scheduled = spawn_blocking({
+            let mut scheduler = Scheduler::new(client());
+            move || {
+                std::thread::sleep(Duration::from_millis(100));
+                scheduler
+                    .act_when_free(&Action::new("PUT", "/api/pipe/2", None))
+                    .unwrap()
+                    .status
+            }
+        });
+        assert_eq!(busy.await.unwrap(), 200);
+        assert_eq!(scheduled.await.unwrap(), 200);
+        server.stop().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_run() {
+        crate::logger::init_for_tests();
+        for _ in 0..100 {
+            let config = model::Config {
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                pipe_value_delay_secs: 0.0,
+                min_value: 100,
+                max_value: 200,
+                game_over_grace_secs: 0.0,
+                ..Default::default()
+            };
+            run(
+                "127.0.0.1:1234",
+                model::App::init(config, vec![]),
+                Some(Duration::ZERO),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    #[actix_web::test]
+    async fn test() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                pipe_value_delay_secs: 0.0,
+                min_value: 100,
+                max_value: 200,
+                ..Default::default()
+            },
+            vec![],
+        ));
+        let app =
+            test::init_service(App::new().configure(move |config| configure(config, state))).await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get()
+            .uri("/api/pipe/1/value")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let auth = (AUTHORIZATION, Bearer::new("hello"));
+
+        let req = test::TestRequest::get()
+            .uri("/api/pipe/1/value")
+            .append_header(auth.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let _: model::PipeValueResponse = test::read_body_json(resp).await;
+
+        let req = test::TestRequest::put()
+            .uri("/api/pipe/2")
+            .append_header(auth.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let _: model::CollectResponse = test::read_body_json(resp).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/pipe/3/modifier")
+            .append_header(auth.clone())
+            .set_json(ApplyModifierInput {
+                modifier: model::Modifier::Double,
             })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let _: model::ApplyModifierResponse = test::read_body_json(resp).await;
+
+        for (token, uses_left) in [("hello", Some(5)), ("other", None)] {
+            let req = test::TestRequest::get()
+                .uri("/api/pipe/3/modifier")
+                .append_header((AUTHORIZATION, Bearer::new(token)))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let resp: model::PipeModifiersResponse = test::read_body_json(resp).await;
+            assert_eq!(resp.modifiers.len(), 1);
+            assert_eq!(resp.modifiers[0].uses_left, uses_left, "{token}");
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/pipes")
+            .append_header(auth.clone())
+            .to_request();
+        let resp: model::PipesResponse = test::call_and_read_body_json(&app, req).await;
+        let ids: Vec<usize> = resp.pipes.iter().map(|pipe| pipe.id.get()).collect();
+        assert_eq!(
+            ids,
+            (1..=model::Config::default().pipe_count).collect::<Vec<_>>()
+        );
+        assert_eq!(resp.pipes[2].modifiers.len(), 1);
+
+        let req = test::TestRequest::get()
+            .uri("/api/user/action")
+            .append_header(auth.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp: model::UserActionsResponse = test::read_body_json(resp).await;
+        assert!(resp.actions.is_empty());
+
+        let req = test::TestRequest::get().uri("/api/shop").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let _: model::ShopResponse = test::read_body_json(resp).await;
+    }
+
+    #[actix_web::test]
+    async fn test_status_codes() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                pipe_value_delay_secs: 0.0,
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(move |config| configure(config, state))).await;
+
+        let player = (AUTHORIZATION, Bearer::new("player"));
+        let intruder = (AUTHORIZATION, Bearer::new("intruder"));
+        let double = serde_json::json!({ "type": model::Modifier::Double });
+        // Request, expected status and expected error name for error responses
+        let cases = [
+            (
+                test::TestRequest::get().uri("/api/pipe/1/value"),
+                StatusCode::UNAUTHORIZED,
+                None,
+            ),
+            (
+                test::TestRequest::get()
+                    .uri("/api/pipe/1/value")
+                    .append_header(intruder.clone()),
+                StatusCode::UNAUTHORIZED,
+                Some("UserNotFound"),
+            ),
+            (
+                test::TestRequest::get()
+                    .uri("/api/pipe/100/value")
+                    .append_header(player.clone()),
+                StatusCode::NOT_FOUND,
+                Some("PipeNotFound"),
+            ),
+            (
+                test::TestRequest::put()
+                    .uri("/api/pipe/100")
+                    .append_header(player.clone()),
+                StatusCode::NOT_FOUND,
+                Some("PipeNotFound"),
+            ),
+            (
+                test::TestRequest::put()
+                    .uri("/api/pipe/0")
+                    .append_header(player.clone()),
+                StatusCode::NOT_FOUND,
+                Some("PipeNotFound"),
+            ),
+            (
+                test::TestRequest::post()
+                    .uri("/api/pipe/1/modifier")
+                    .append_header(player.clone())
+                    .set_json(&double),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Some("NotEnoughScore"),
+            ),
+            (
+                test::TestRequest::post()
+                    .uri("/api/pipe/1/modifier")
+                    .append_header(player.clone())
+                    .set_json(serde_json::json!({ "type": "Teleport" })),
+                StatusCode::BAD_REQUEST,
+                None,
+            ),
+            (
+                test::TestRequest::get()
+                    .uri("/api/user/action")
+                    .append_header(player.clone()),
+                StatusCode::OK,
+                None,
+            ),
+            (
+                test::TestRequest::get()
+                    .uri("/api/user/history")
+                    .append_header(player.clone()),
+                StatusCode::OK,
+                None,
+            ),
+            (
+                test::TestRequest::get().uri("/api/shop"),
+                StatusCode::OK,
+                None,
+            ),
+            (
+                test::TestRequest::get().uri("/api/time"),
+                StatusCode::OK,
+                None,
+            ),
+        ];
+        for (req, status, error) in cases {
+            let req = req.to_request();
+            let description = format!("{} {}", req.method(), req.path());
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{description}");
+            if let Some(error) = error {
+                let body: serde_json::Value = test::read_body_json(resp).await;
+                assert_eq!(body["error"], error, "{description}");
+            }
+        }
+    }
+
+    /// Calls every operation of the served api descriptions with and without credentials,
+    /// with existing and missing path parameters and with well formed and malformed bodies.
+    /// Every response has to be documented and every documented status has to come up
+    #[actix_web::test]
+    async fn test_openapi_contract() {
+        crate::logger::init_for_tests();
+        let config = model::Config {
+            initial_score: 1000,
+            min_delay_secs: 0.0,
+            max_delay_secs: 0.0,
+            pipe_value_delay_secs: 0.0,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let results_db = ResultsDb::open(&dir.path().join("results.sqlite")).unwrap();
+        let accounts = web::Data::new(Accounts::new(&results_db).unwrap());
+        let plumber = Credentials {
+            username: "plumber".to_owned(),
+            password: "pipes-all-the-way".to_owned(),
+        };
+        accounts.create(plumber).await.unwrap();
+        // A fresh server for every operation, so that ending the game breaks nothing else
+        let service = || async {
+            let state = web::Data::new(model::App::init(
+                config.clone(),
+                ["player", "victim"].map(|token| UserToken::from(token.to_owned())),
+            ));
+            let lobby = web::Data::new(Lobby::new(config.clone(), None));
+            lobby
+                .create(NewGame {
+                    id: "1".to_owned(),
+                    config: None,
+                    users: Vec::new(),
+                })
+                .unwrap();
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(AdminToken(UserToken::from(
+                        "admin".to_owned(),
+                    ))))
+                    .app_data(lobby)
+                    .configure(configure_admin)
+                    .app_data(accounts.clone())
+                    .configure(configure_accounts)
+                    .configure(|service_config| configure(service_config, state)),
+            )
+            .await
+        };
+        // Values of path and required query parameters that exist and that don't
+        let param_values = [
+            ("n", "1", "100"),
+            ("token", "victim", "nobody"),
+            ("id", "1", "999"),
+            ("ids", "1,2", "1,100"),
+        ];
+        let bodies = [
+            (
+                "post /api/pipe/{n}/modifier",
+                serde_json::json!({ "type": "shuffle" }),
+            ),
+            (
+                "post /api/register",
+                serde_json::json!({ "name": "Newcomer" }),
+            ),
+            (
+                "post /api/admin/users/import",
+                serde_json::json!([{ "token": "newcomer", "name": "Newcomer" }]),
+            ),
+            (
+                "post /api/account",
+                serde_json::json!({ "username": "plumber", "password": "pipes-all-the-way" }),
+            ),
+            (
+                "post /api/account/token",
+                serde_json::json!({ "username": "plumber", "password": "pipes-all-the-way" }),
+            ),
+            (
+                "post /api/account/key",
+                serde_json::json!({ "username": "plumber", "password": "pipes-all-the-way" }),
+            ),
+            ("put /api/admin/pipe/{n}", serde_json::json!({ "value": 5 })),
+            (
+                "post /api/admin/config",
+                serde_json::to_value(&config).unwrap(),
+            ),
+            ("post /api/admin/games", serde_json::json!({ "id": "2" })),
+            (
+                "put /api/admin/log_level",
+                serde_json::json!({ "target": "contract", "level": "debug" }),
+            ),
+        ];
+
+        let app = service().await;
+        let mut specs = Vec::new();
+        for (uri, token) in [
+            ("/api/openapi.json", "player"),
+            ("/api/admin/openapi.json", "admin"),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .append_header((AUTHORIZATION, Bearer::new("admin")))
+                .to_request();
+            let spec: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            specs.push((spec, token));
+        }
+
+        let mut unreached = Vec::new();
+        for (spec, token) in &specs {
+            for (template, item) in spec["paths"].as_object().unwrap() {
+                for (method, operation) in item.as_object().unwrap() {
+                    let name = format!("{method} {template}");
+                    let params = operation["parameters"]
+                        .as_array()
+                        .cloned()
+                        .unwrap_or_default();
+                    let uri = |exists: bool, flag: Option<&str>| {
+                        let mut path = template.clone();
+                        let mut query: Vec<String> = flag.into_iter().map(str::to_owned).collect();
+                        for param in &params {
+                            let param_name = param["name"].as_str().unwrap();
+                            let required = param["required"] == true;
+                            if param["in"] != "path" && !(param["in"] == "query" && required) {
+                                continue;
+                            }
+                            let (_, existing, missing) = param_values
+                                .iter()
+                                .find(|(name, ..)| *name == param_name)
+                                .unwrap_or_else(|| panic!("{name}: no value for {param_name}"));
+                            let value = if exists { existing } else { missing };
+                            if param["in"] == "path" {
+                                path = path.replace(&format!("{{{param_name}}}"), value);
+                            } else {
+                                query.push(format!("{param_name}={value}"));
+                            }
+                        }
+                        if query.is_empty() {
+                            path
+                        } else {
+                            format!("{path}?{}", query.join("&"))
+                        }
+                    };
+                    // Flags are tried both ways, other optional query parameters are left out
+                    let flags: Vec<Option<String>> = std::iter::once(None)
+                        .chain(
+                            params
+                                .iter()
+                                .filter(|param| {
+                                    param["in"] == "query" && param["schema"]["type"] == "boolean"
+                                })
+                                .flat_map(|param| {
+                                    ["true", "false"].map(|value| {
+                                        Some(format!("{}={value}", param["name"].as_str().unwrap()))
+                                    })
+                                }),
+                        )
+                        .collect();
+                    // Malformed bodies go first, the well formed ones are used last
+                    let bodies: Vec<Option<String>> = match operation.get("requestBody") {
+                        Some(_) => {
+                            let (_, body) = bodies
+                                .iter()
+                                .find(|(operation, _)| *operation == name)
+                                .unwrap_or_else(|| panic!("{name}: no request body"));
+                            vec![Some("{".to_owned()), Some(body.to_string())]
+                        }
+                        None => vec![None],
+                    };
+
+                    let app = service().await;
+                    let mut statuses = std::collections::BTreeSet::new();
+                    for auth in [None, Some("intruder"), Some(*token)] {
+                        for exists in [false, true] {
+                            for flag in &flags {
+                                for body in &bodies {
+                                    let uri = uri(exists, flag.as_deref());
+                                    let mut req = test::TestRequest::default()
+                                        .method(method.to_uppercase().parse().unwrap())
+                                        .uri(&uri);
+                                    if let Some(auth) = auth {
+                                        req = req.append_header((AUTHORIZATION, Bearer::new(auth)));
+                                    }
+                                    if let Some(body) = body {
+                                        req = req
+                                            .insert_header((CONTENT_TYPE, "application/json"))
+                                            .set_payload(body.clone());
+                                    }
+                                    let resp = test::call_service(&app, req.to_request()).await;
+                                    let status = resp.status();
+                                    let body = test::read_body(resp).await;
+                                    crate::selftest::conforms_to(spec, operation, status, &body)
+                                        .unwrap_or_else(|e| {
+                                            panic!("{method} {uri} as {auth:?}: {e}: {body:?}")
+                                        });
+                                    statuses.insert(status);
+                                }
+                            }
+                        }
+                    }
+                    for code in operation["responses"].as_object().unwrap().keys() {
+                        let reached = match code.strip_suffix("XX") {
+                            Some(class) => statuses
+                                .iter()
+                                .any(|status| status.as_str().starts_with(class)),
+                            None => statuses.iter().any(|status| status.as_str() == code),
+                        };
+                        if !reached {
+                            unreached.push(format!("{name} {code}"));
+                        }
+                    }
+                }
+            }
+        }
+        // These need another config or state than a game in progress with listed users
+        let expected = [
+            "get /api/account 200",
+            "post /api/account 201",
+            "post /api/account/key 401",
+            "post /api/account/token 401",
+            "get /api/operations/{id} 200",
+            "post /api/register 200",
+            "post /api/token 200",
+            "post /api/admin/config 422",
+            "post /api/admin/games 422",
+            "post /api/admin/start 200",
+            "get /api/history 200",
+            "get /api/history 500",
+        ];
+        assert_eq!(unreached, expected);
+    }
+
+    #[actix_web::test]
+    async fn test_bankruptcy() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                min_value: -5,
+                max_value: -5,
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                min_user_score: Some(-3),
+                bankruptcy_lockout_secs: 60.0,
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app = test::init_service(App::new().configure({
+            let state = state.clone();
+            move |config| configure(config, state)
+        }))
+        .await;
+        let collect_request = || {
+            test::TestRequest::put()
+                .uri("/api/pipe/1")
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .to_request()
+        };
+        let resp = test::call_service(&app, collect_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(state.results().await["player"], -3);
+        let resp = test::call_service(&app, collect_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "Bankrupt");
+        // Bankrupt users can still look at their score
+        let req = test::TestRequest::get()
+            .uri("/api/user")
+            .append_header((AUTHORIZATION, Bearer::new("player")))
+            .to_request();
+        let user: model::User = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(user.score, -3);
+    }
+
+    #[actix_web::test]
+    async fn test_user_actions() {
+        use actix_web::http::Method;
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                min_delay_secs: 2.0,
+                max_delay_secs: 2.0,
+                pipe_value_delay_secs: 1.0,
+                max_concurrent_actions: None,
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(move |config| configure(config, state))).await;
+        let auth = (AUTHORIZATION, Bearer::new("player"));
+        let request = |method: Method, uri: &str| {
+            test::TestRequest::default()
+                .method(method)
+                .uri(uri)
+                .append_header(auth.clone())
+                .to_request()
+        };
+        let list_actions = || async {
+            let resp = test::call_service(&app, request(Method::GET, "/api/user/action")).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let mut body: model::UserActionsResponse = test::read_body_json(resp).await;
+            body.actions.sort_by_key(|action| action.pipe_id);
+            body.actions
+        };
+        assert!(list_actions().await.is_empty());
+
+        let (collected, valued, in_flight) = futures::join!(
+            test::call_service(&app, request(Method::PUT, "/api/pipe/1")),
+            test::call_service(&app, request(Method::GET, "/api/pipe/2/value")),
+            async {
+                sleep(Duration::from_millis(500)).await;
+                list_actions().await
+            },
+        );
+        assert_eq!(collected.status(), StatusCode::OK);
+        assert_eq!(valued.status(), StatusCode::OK);
+        let summary: Vec<_> = in_flight
+            .iter()
+            .map(|action| (action.action, action.pipe_id.get()))
+            .collect();
+        assert_eq!(
+            summary,
+            [(model::Action::Collect, 1), (model::Action::PipeValue, 2)]
+        );
+        for (action, duration) in in_flight.iter().zip([2.0, 1.0]) {
+            assert!(action.started_at < 0.1, "{action:?}");
+            assert!(
+                (action.expected_completion - action.started_at - duration).abs() < 0.1,
+                "{action:?}"
+            );
+        }
+        // Finished actions are not listed
+        assert!(list_actions().await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_cancel_actions() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                min_delay_secs: 10.0,
+                max_delay_secs: 10.0,
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(move |config| configure(config, state))).await;
+        let auth = (AUTHORIZATION, Bearer::new("player"));
+        let collect_request = test::TestRequest::put()
+            .uri("/api/pipe/1")
+            .append_header(auth.clone())
+            .to_request();
+        let cancel_request = test::TestRequest::delete()
+            .uri("/api/user/action")
+            .append_header(auth)
+            .to_request();
+        let (collect_response, cancel_response) =
+            futures::join!(test::call_service(&app, collect_request), async {
+                sleep(Duration::from_millis(100)).await;
+                test::call_service(&app, cancel_request).await
+            },);
+        assert_eq!(cancel_response.status(), StatusCode::OK);
+        let body: model::AbortActionsResponse = test::read_body_json(cancel_response).await;
+        assert_eq!(body.aborted, 1);
+        assert_eq!(collect_response.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = test::read_body_json(collect_response).await;
+        assert_eq!(body["error"], "ActionAborted");
+    }
+
+    #[actix_web::test]
+    async fn test_score_format() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                pipe_value_delay_secs: 0.0,
+                min_value: 100,
+                max_value: 100,
+                ..Default::default()
+            },
+            vec![],
+        ));
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(format_scores))
+                .configure(move |config| configure(config, state)),
+        )
+        .await;
+        let value_request = |accept: &str| {
+            test::TestRequest::get()
+                .uri("/api/pipe/1/value")
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .append_header((ACCEPT, accept))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, value_request("application/json")).await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["value"], 100);
+
+        let resp = test::call_service(&app, value_request("application/json; scores=string")).await;
+        let body = test::read_body(resp).await;
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["value"], "100");
+        // Clients reading scores as strings get the same values back
+        let value: model::PipeValueResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value.value, 100);
+    }
+
+    #[actix_web::test]
+    async fn test_virtual_time() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let play = || async {
+            let state = web::Data::new(model::App::init(
+                model::Config {
+                    seed: Some(1),
+                    min_delay_secs: 10.0,
+                    max_delay_secs: 20.0,
+                    pipe_value_delay_secs: 5.0,
+                    ..Default::default()
+                },
+                vec![UserToken::from("player".to_owned())],
+            ));
+            let app = test::init_service(App::new().configure({
+                let state = state.clone();
+                move |config| configure(config, state)
+            }))
+            .await;
+            for pipe in 1..=3 {
+                let value_request = test::TestRequest::get()
+                    .uri(&format!("/api/pipe/{pipe}/value"))
+                    .append_header((AUTHORIZATION, Bearer::new("player")))
+                    .to_request();
+                test::call_service(&app, value_request).await;
+                let collect_request = test::TestRequest::put()
+                    .uri(&format!("/api/pipe/{pipe}"))
+                    .append_header((AUTHORIZATION, Bearer::new("player")))
+                    .to_request();
+                test::call_service(&app, collect_request).await;
+            }
+            (state.game_time(), state.results().await)
+        };
+        let start = std::time::Instant::now();
+        let (time, results) = play().await;
+        // Three value checks and three collects took at least 45 seconds of game time
+        assert!(time >= 45.0, "{time}");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let (_, replayed) = play().await;
+        assert_eq!(results, replayed);
+    }
+
+    #[actix_web::test]
+    async fn test_problem_details() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                error_statuses: [(model::ErrorCode::PipeNotFound, 410)].into(),
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(problem_details))
+                .configure(move |config| configure(config, state)),
+        )
+        .await;
+        let missing_pipe = |accept: &str| {
+            test::TestRequest::get()
+                .uri("/api/pipe/100/value")
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .append_header((ACCEPT, accept))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, missing_pipe("application/json")).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "PipeNotFound");
+
+        let resp = test::call_service(
+            &app,
+            missing_pipe("application/problem+json, application/json;q=0.5"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "application/problem+json"
+        );
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], 410);
+        assert_eq!(body["title"], "Pipe not found");
+        assert_eq!(body["instance"], "/api/pipe/100/value");
+        assert_eq!(body["error"], "PipeNotFound");
+    }
+
+    #[actix_web::test]
+    async fn test_time_scale() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                min_delay_secs: 10.0,
+                max_delay_secs: 10.0,
+                time_to_run: Some(300.0),
+                time_scale: 0.1,
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        assert_eq!(state.time_left(), Some(Duration::from_secs(30)));
+        let app = test::init_service(App::new().configure({
+            let state = state.clone();
+            move |config| configure(config, state)
+        }))
+        .await;
+        let request = test::TestRequest::put()
+            .uri("/api/pipe/1")
+            .append_header((AUTHORIZATION, Bearer::new("player")))
+            .to_request();
+        let resp = test::call_service(&app, request).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(
+            (state.game_time() - 1.0).abs() < 0.01,
+            "{}",
+            state.game_time()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_rounds() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                min_value: 5,
+                max_value: 5,
+                rounds: Some(model::Rounds {
+                    count: 2,
+                    duration_secs: 10.0,
+                    aggregation: model::RoundAggregation::Sum,
+                }),
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        assert_eq!(state.time_left(), Some(Duration::from_secs(20)));
+        let app = test::init_service(App::new().configure({
+            let state = state.clone();
+            move |config| configure(config, state)
+        }))
+        .await;
+        let collect_request = |pipe: usize| {
+            test::TestRequest::put()
+                .uri(&format!("/api/pipe/{pipe}"))
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .to_request()
+        };
+        test::call_service(&app, collect_request(1)).await;
+        assert!(state.end_round().await);
+        assert_eq!(state.time().round, Some(2));
+        assert_eq!(state.results().await["player"], 5);
+        test::call_service(&app, collect_request(1)).await;
+        test::call_service(&app, collect_request(2)).await;
+        assert!(!state.end_round().await);
+        assert_eq!(state.time_left(), Some(Duration::ZERO));
+        let round_scores: Vec<_> = state
+            .round_scores()
+            .iter()
+            .map(|scores| scores["player"])
+            .collect();
+        assert_eq!(round_scores, [5, 10]);
+        assert_eq!(state.results().await["player"], 15);
+    }
+
+    #[actix_web::test]
+    async fn test_pipe_events() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let play = |spawn: f64, retire: f64| async move {
+            let state = web::Data::new(model::App::init(
+                model::Config {
+                    time_to_run: Some(10.0),
+                    pipe_events_secs: Some(1.0),
+                    pipe_spawn_probability: spawn,
+                    pipe_retire_probability: retire,
+                    max_pipe_count: Some(5),
+                    ..Default::default()
+                },
+                vec![UserToken::from("player".to_owned())],
+            ));
+            state.run_scheduler().await;
+            state
+        };
+        let player = UserToken::from("player".to_owned());
+        let state = play(1.0, 0.0).await;
+        let ids: Vec<usize> = state
+            .list_pipes(&player)
             .await
+            .unwrap()
+            .pipes
+            .iter()
+            .map(|pipe| pipe.id.get())
+            .collect();
+        assert_eq!(ids, [1, 2, 3, 4, 5]);
+
+        let state = play(0.0, 1.0).await;
+        let pipes = state.list_pipes(&player).await.unwrap().pipes;
+        assert_eq!(pipes.len(), 1);
+        let retired = (1..=3)
+            .filter_map(PipeId::new)
+            .find(|&id| id != pipes[0].id)
             .unwrap();
-            assert!(status.success());
+        let app = test::init_service(App::new().configure({
+            let state = state.clone();
+            move |config| configure(config, state)
+        }))
+        .await;
+        let request = test::TestRequest::put()
+            .uri(&format!("/api/pipe/{retired}"))
+            .append_header((AUTHORIZATION, Bearer::new("player")))
+            .to_request();
+        let resp = test::call_service(&app, request).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let history = state.pipe_history(retired, 0, 100).await.unwrap();
+        assert!(!history.entries.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_global_events() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let play = |seed: u64| async move {
+            let state = web::Data::new(model::App::init(
+                model::Config {
+                    time_to_run: Some(10.0),
+                    seed: Some(seed),
+                    global_events: Some(model::GlobalEvents {
+                        mean_interval_secs: 1.0,
+                        effects: vec![model::GlobalEffect {
+                            name: "Flood".to_owned(),
+                            duration_secs: 100.0,
+                            value_multiplier: 2.0,
+                            delay_multiplier: 0.5,
+                            weight: 1.0,
+                        }],
+                    }),
+                    ..Default::default()
+                },
+                vec![UserToken::from("player".to_owned())],
+            ));
+            let (sender, mut receiver) = mpsc::unbounded();
+            state.register_logs(sender).await;
+            state.run_scheduler().await;
+            let mut starts = Vec::new();
+            while let Ok(entry) = receiver.try_recv() {
+                if let model::LogMessage::GlobalEvent { event } = entry.msg {
+                    starts.push(event.start_secs);
+                }
+            }
+            (state, starts)
+        };
+        let (state, starts) = play(1).await;
+        assert!(!starts.is_empty());
+        assert_eq!(play(1).await.1, starts);
+        assert_ne!(play(2).await.1, starts);
+
+        let app = test::init_service(App::new().configure(|config| configure(config, state))).await;
+        let req = test::TestRequest::get().uri("/api/events").to_request();
+        let response: model::GlobalEventsResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(response.events.len(), starts.len());
+        assert_eq!(response.events[0].effect.value_multiplier, 2.0);
+    }
+
+    #[actix_web::test]
+    async fn test_game_ending_notice() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                time_to_run: Some(30.0),
+                game_ending_notice_secs: 10.0,
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let (sender, mut receiver) = mpsc::unbounded();
+        state.register_logs(sender).await;
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                .await;
+        let ends_in = || async {
+            let request = test::TestRequest::get()
+                .uri("/api/user/action")
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .to_request();
+            let resp = test::call_service(&app, request).await;
+            resp.headers()
+                .get("x-game-ends-in")
+                .map(|value| value.to_str().unwrap().parse::<f64>().unwrap())
+                .unwrap()
+        };
+        assert!((ends_in().await - 30.0).abs() < 0.01);
+        tokio::time::advance(Duration::from_millis(4500)).await;
+        assert!((ends_in().await - 25.5).abs() < 0.01);
+
+        state.run_scheduler().await;
+        let endings: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok())
+            .filter_map(|entry| match entry.msg {
+                model::LogMessage::GameEnding { seconds_left } => Some((entry.time, seconds_left)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(endings.len(), 1, "{endings:?}");
+        let (time, seconds_left) = endings[0];
+        assert!((time - 20.0).abs() < 0.01, "{time}");
+        assert!((seconds_left - 10.0).abs() < 0.01, "{seconds_left}");
+    }
+
+    #[actix_web::test]
+    async fn test_market_events() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let event =
+            |cost_multiplier: f64, start_secs: f64, duration_secs: f64| model::MarketEvent {
+                modifier: model::Modifier::Shuffle,
+                cost_multiplier,
+                start_secs,
+                duration_secs,
+            };
+        let state = model::App::init(
+            model::Config {
+                time_to_run: Some(10.0),
+                market_events: vec![event(0.5, 1.0, 2.0), event(2.0, 2.0, 3.0)],
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        );
+        let base = state.config().modifier_cost(model::Modifier::Shuffle);
+        let (sender, mut receiver) = mpsc::unbounded();
+        state.register_logs(sender).await;
+        state.run_scheduler().await;
+        let mut windows = Vec::new();
+        while let Ok(entry) = receiver.try_recv() {
+            match entry.msg {
+                model::LogMessage::MarketEvent { cost, .. } => {
+                    windows.push(("start", entry.time.round(), cost))
+                }
+                model::LogMessage::MarketEventEnd { cost, .. } => {
+                    windows.push(("end", entry.time.round(), cost))
+                }
+                _ => {}
+            }
+        }
+        // The windows overlap, each boundary sees the events active right after it
+        assert_eq!(
+            windows,
+            [
+                ("start", 1.0, base / 2),
+                ("start", 2.0, base),
+                ("end", 3.0, base * 2),
+                ("end", 5.0, base),
+            ]
+        );
+        assert!(state.shop().events.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_value_drift() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let config = model::Config {
+            time_to_run: Some(100.0),
+            value_drift_interval_secs: Some(1.0),
+            value_drift_amount: -1,
+            ..Default::default()
+        };
+        let min_value = config.min_value;
+        let state = model::App::init(config, vec![UserToken::from("player".to_owned())]);
+        let (sender, mut receiver) = mpsc::unbounded();
+        state.register_logs(sender).await;
+        state.run_scheduler().await;
+        let mut values = std::collections::BTreeMap::new();
+        while let Ok(entry) = receiver.try_recv() {
+            if let model::LogMessage::UpdatePipe { id, state } = entry.msg {
+                values.insert(id, state.value);
+            }
+        }
+        assert!(!values.is_empty());
+        assert!(values.values().all(|&value| value == min_value));
+    }
+
+    #[actix_web::test]
+    async fn test_streaks() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = model::App::init(
+            model::Config {
+                min_value: 5,
+                max_value: 5,
+                streak_step: Some(1.0),
+                max_streak_multiplier: 2.5,
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        );
+        let (sender, mut receiver) = mpsc::unbounded();
+        state.register_logs(sender).await;
+        let player = UserToken::from("player".to_owned());
+        let pipe = PipeId::new(1).unwrap();
+        for _ in 0..3 {
+            state.collect(&player, pipe).await.unwrap();
+        }
+        let missing = PipeId::new(100).unwrap();
+        assert!(state.collect(&player, missing).await.is_err());
+        state.collect(&player, pipe).await.unwrap();
+        let mut updates = Vec::new();
+        while let Ok(entry) = receiver.try_recv() {
+            if let model::LogMessage::UpdateUser { state, change, .. } = entry.msg {
+                updates.push((state.streak, change.map(|change| change.delta)));
+            }
+        }
+        assert_eq!(
+            updates,
+            [
+                (Some(0), None),
+                (Some(1), Some(5)),
+                (Some(2), Some(10)),
+                (Some(3), Some(13)),
+                (Some(0), None),
+                (Some(1), Some(5)),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_double_min_policy() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let player = UserToken::from("player".to_owned());
+        let pipe = PipeId::new(1).unwrap();
+        // Values of two collects after applying Double and Min once
+        let play = |policy: model::DoubleMinPolicy| {
+            let player = player.clone();
+            async move {
+                let state = model::App::init(
+                    model::Config {
+                        min_value: 3,
+                        max_value: 3,
+                        double_uses: 1,
+                        min_uses: 1,
+                        double_min_policy: policy,
+                        initial_score: 1000,
+                        ..Default::default()
+                    },
+                    vec![player.clone()],
+                );
+                for modifier in [model::Modifier::Double, model::Modifier::Min] {
+                    state.apply_modifier(&player, pipe, modifier).await.unwrap();
+                }
+                let mut values = Vec::new();
+                for _ in 0..2 {
+                    values.push(state.collect(&player, pipe).await.unwrap().value);
+                }
+                values
+            }
+        };
+        assert_eq!(play(model::DoubleMinPolicy::MinWins).await, [3, 3]);
+        assert_eq!(play(model::DoubleMinPolicy::KeepDouble).await, [3, 6]);
+        assert_eq!(play(model::DoubleMinPolicy::DoubleMin).await, [6, 3]);
+    }
+
+    #[actix_web::test]
+    async fn test_podium() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let scores = [("a", 10), ("b", 20), ("c", 20), ("d", 5)];
+        let state = model::App::init(
+            model::Config {
+                podium_ceremony: true,
+                podium_size: 3,
+                initial_scores: scores
+                    .iter()
+                    .map(|&(user, score)| (UserToken::from(user.to_owned()), score))
+                    .collect(),
+                ..Default::default()
+            },
+            scores.map(|(user, _)| UserToken::from(user.to_owned())),
+        );
+        let (sender, mut receiver) = mpsc::unbounded();
+        state.register_logs(sender).await;
+        state.announce_end().await;
+        let mut ended = false;
+        let mut podium = Vec::new();
+        let mut results = Vec::new();
+        while let Ok(entry) = receiver.try_recv() {
+            assert!(
+                results.is_empty(),
+                "Nothing is logged after the game is over"
+            );
+            match entry.msg {
+                model::LogMessage::GameEnd => ended = true,
+                model::LogMessage::Podium { rank, user, score } => {
+                    assert!(ended);
+                    podium.push((rank, user, score));
+                }
+                model::LogMessage::GameOver { results: standings } => results = standings,
+                _ => {}
+            }
+        }
+        assert_eq!(
+            podium,
+            [(3, "a", 10), (1, "b", 20), (1, "c", 20)].map(|(rank, user, score)| (
+                rank,
+                UserToken::from(user.to_owned()),
+                score
+            ))
+        );
+        let ranks: Vec<_> = results
+            .into_iter()
+            .map(|standing| (standing.rank, standing.user))
+            .collect();
+        assert_eq!(
+            ranks,
+            [(1, "b"), (1, "c"), (3, "a"), (4, "d")]
+                .map(|(rank, user)| (rank, UserToken::from(user.to_owned())))
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_action_cooldown() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                pipe_value_delay_secs: 1.0,
+                action_cooldowns_secs: [(model::Action::PipeValue, 5.0)].into(),
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app = test::init_service(App::new().configure(|config| configure(config, state))).await;
+        let request_pipe = |pipe: usize| {
+            test::TestRequest::get()
+                .uri(&format!("/api/pipe/{pipe}/value"))
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .to_request()
+        };
+        let request = || request_pipe(1);
+        // Failed actions leave the cooldown unused
+        let resp = test::call_service(&app, request_pipe(100)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(&app, request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "4");
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "Cooldown");
+        assert!((body["retry_after_secs"].as_f64().unwrap() - 4.0).abs() < 0.01);
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        let resp = test::call_service(&app, request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_post_collect_lockout() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                min_delay_secs: 1.0,
+                max_delay_secs: 1.0,
+                post_collect_lockout_secs: 3.0,
+                ..Default::default()
+            },
+            ["player", "rival"].map(|token| UserToken::from(token.to_owned())),
+        ));
+        let app = test::init_service(App::new().configure(|config| configure(config, state))).await;
+        let collect_pipe = |token: &str, pipe: usize| {
+            test::call_service(
+                &app,
+                test::TestRequest::put()
+                    .uri(&format!("/api/pipe/{pipe}"))
+                    .append_header((AUTHORIZATION, Bearer::new(token.to_owned())))
+                    .to_request(),
+            )
+        };
+        assert_eq!(collect_pipe("player", 1).await.status(), StatusCode::OK);
+
+        // Locked for everyone, the collector included, counted from the end of the collect
+        for token in ["rival", "player"] {
+            let resp = collect_pipe(token, 1).await;
+            assert_eq!(resp.status(), StatusCode::CONFLICT);
+            assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "3");
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["error"], "PipeLocked");
+            assert_eq!(body["details"]["pipe_id"], 1);
+        }
+        // Other pipes are not affected, collecting one takes a second
+        assert_eq!(collect_pipe("rival", 2).await.status(), StatusCode::OK);
+        let resp = collect_pipe("rival", 1).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "2");
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(collect_pipe("rival", 1).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_misconduct_policy() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                initial_score: 100,
+                pipe_value_delay_secs: 0.0,
+                misconduct_policy: Some(model::MisconductPolicy {
+                    errors: vec![model::ErrorCode::PipeNotFound],
+                    threshold: 3,
+                    window_secs: 10.0,
+                    penalty: 30,
+                    lockout_secs: 5.0,
+                }),
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                .await;
+        let request = |pipe: usize| {
+            test::TestRequest::get()
+                .uri(&format!("/api/pipe/{pipe}/value"))
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .to_request()
         };
-        let (app, ()) = futures::join!(app, client);
-        app.expect("App error");
+        for _ in 0..3 {
+            let resp = test::call_service(&app, request(1000)).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+        let token = UserToken::from("player".to_owned());
+        assert_eq!(state.user_state(&token).await.unwrap().score, 70);
+
+        let resp = test::call_service(&app, request(1)).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "5");
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "MisconductLockout");
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        let resp = test::call_service(&app, request(1)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let results = state.detailed_results().await;
+        assert_eq!(results.players["player"].misconduct, 3);
+        assert_eq!(results.players["player"].misconduct_penalties, 1);
     }
 
     #[actix_web::test]
-    async fn test_run() {
+    async fn test_dynamic_pricing() {
         crate::logger::init_for_tests();
-        for _ in 0..100 {
-            let config = model::Config {
-                min_delay_secs: 0.0,
-                max_delay_secs: 0.0,
-                pipe_value_delay_secs: 0.0,
-                min_value: 100,
-                max_value: 200,
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                initial_score: 1000,
+                reverse_cost: 100,
+                dynamic_pricing: Some(model::DynamicPricing {
+                    purchase_multiplier: 2.0,
+                    half_life_secs: 10.0,
+                    max_multiplier: None,
+                }),
                 ..Default::default()
-            };
-            run(
-                "127.0.0.1:1234",
-                model::App::init(config, vec![]),
-                Some(Duration::ZERO),
-                None::<&str>,
-                false,
-            )
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(|config| configure(config, state.clone())))
+                .await;
+        let reverse = || {
+            test::TestRequest::post()
+                .uri("/api/pipe/1/modifier")
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .set_json(serde_json::json!({"type": "reverse"}))
+                .to_request()
+        };
+        let get_prices = || test::TestRequest::get().uri("/api/prices").to_request();
+        for _ in 0..2 {
+            let resp = test::call_service(&app, reverse()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let token = UserToken::from("player".to_owned());
+        assert_eq!(state.user_state(&token).await.unwrap().score, 700);
+        let resp: model::PricesResponse = test::call_and_read_body_json(&app, get_prices()).await;
+        assert!(resp.dynamic);
+        assert_eq!(resp.prices[&model::Modifier::Reverse].cost, 400);
+        assert_eq!(
+            resp.prices[&model::Modifier::Reverse].demand_multiplier,
+            4.0
+        );
+        assert_eq!(resp.prices[&model::Modifier::Double].demand_multiplier, 1.0);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let resp: model::PricesResponse = test::call_and_read_body_json(&app, get_prices()).await;
+        assert_eq!(resp.prices[&model::Modifier::Reverse].cost, 250);
+        let page = state.logs_page(0, 100).await;
+        let updates = page
+            .entries
+            .iter()
+            .filter(|entry| matches!(entry.msg, model::LogMessage::UpdatePrices { .. }))
+            .count();
+        assert_eq!(updates, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_pipe_ownership() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let client = crate::testing::start(
+            model::Config {
+                min_value: 10,
+                max_value: 10,
+                ownership: Some(model::Ownership {
+                    secs: 30.0,
+                    share: 0.5,
+                }),
+                ..crate::testing::instant_config()
+            },
+            ["first", "second"].map(|token| UserToken::from(token.to_owned())),
+        )
+        .await;
+        let owner = |value: model::PipeValueResponse| value.owner.map(|owner| owner.yours);
+
+        assert_eq!(client.collect("first", 1).await.unwrap().value, 10);
+        assert_eq!(
+            owner(client.pipe_value("second", 1).await.unwrap()),
+            Some(false)
+        );
+        assert_eq!(client.collect("second", 1).await.unwrap().value, 5);
+        assert_eq!(client.collect("second", 1).await.unwrap().value, 10);
+        assert_eq!(
+            owner(client.pipe_value("second", 1).await.unwrap()),
+            Some(true)
+        );
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(owner(client.pipe_value("first", 1).await.unwrap()), None);
+        assert_eq!(client.collect("first", 1).await.unwrap().value, 10);
+    }
+
+    #[actix_web::test]
+    async fn test_custom_modifiers() {
+        crate::logger::init_for_tests();
+        let custom_modifiers = serde_json::json!([
+            {"name": "jackpot", "cost": 5, "uses": 2, "effect": "value_override", "value": 100},
+            {"name": "triple", "cost": 5, "effect": "value_multiplier", "multiplier": 3},
+            {"name": "flip", "cost": 1, "effect": "direction_flip"},
+        ]);
+        let client = crate::testing::start(
+            model::Config {
+                initial_score: 100,
+                custom_modifiers: serde_json::from_value(custom_modifiers).unwrap(),
+                ..crate::testing::instant_config()
+            },
+            [UserToken::from("player".to_owned())],
+        )
+        .await;
+        let custom = |name: &'static str| model::Modifier::Custom(name.try_into().unwrap());
+
+        client
+            .apply_modifier("player", 1, custom("triple"))
             .await
             .unwrap();
+        client
+            .apply_modifier("player", 1, custom("jackpot"))
+            .await
+            .unwrap();
+        assert_eq!(client.collect("player", 1).await.unwrap().value, 300);
+        assert_eq!(client.collect("player", 1).await.unwrap().value, 100);
+        let error = client
+            .apply_modifier("player", 1, custom("unknown"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, Some(model::ErrorCode::ModifierDisabled));
+
+        let mut entries = client.subscribe_logs().await;
+        client
+            .apply_modifier("player", 1, custom("flip"))
+            .await
+            .unwrap();
+        let mut pipes = Vec::new();
+        while let Ok(entry) = entries.try_recv() {
+            if let model::LogMessage::UpdatePipe { state, .. } = entry.msg {
+                pipes.push(state);
+            }
         }
+        let [.., before, after] = &pipes[..] else {
+            panic!("Pipe updates missing from the log");
+        };
+        assert_eq!(after.direction, before.direction.inverse());
+        assert!(after.modifiers.is_empty());
+
+        let token = UserToken::from("player".to_owned());
+        let score = client.state().user_state(&token).await.unwrap().score;
+        assert_eq!(score, 100 - 5 - 5 + 300 + 100 - 1);
+        let resp: model::PricesResponse = client
+            .call("player", test::TestRequest::get().uri("/api/prices"))
+            .await
+            .unwrap();
+        assert_eq!(resp.prices[&custom("jackpot")].cost, 5);
+        assert_eq!(resp.prices[&model::Modifier::Slow].cost, 40);
+
+        let invalid = model::Config {
+            custom_modifiers: serde_json::from_value(serde_json::json!([
+                {"name": "slow", "cost": 1, "effect": "delay_reroll"},
+                {"name": "triple", "cost": 1, "effect": "value_multiplier", "multiplier": 0},
+            ]))
+            .unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(invalid.validate().len(), 2);
     }
 
     #[actix_web::test]
-    async fn test() {
+    async fn test_error_details() {
         crate::logger::init_for_tests();
         let state = web::Data::new(model::App::init(
             model::Config {
-                min_delay_secs: 0.0,
-                max_delay_secs: 0.0,
-                pipe_value_delay_secs: 0.0,
-                min_value: 100,
-                max_value: 200,
+                initial_score: 5,
                 ..Default::default()
             },
-            vec![],
+            vec![UserToken::from("player".to_owned())],
         ));
-        let app =
-            test::init_service(App::new().configure(move |config| configure(config, state))).await;
+        let cost = state.modifier_cost(model::Modifier::Double);
+        let app = test::init_service(App::new().configure(|config| configure(config, state))).await;
+        let request = test::TestRequest::post()
+            .uri("/api/pipe/1/modifier")
+            .append_header((AUTHORIZATION, Bearer::new("player")))
+            .set_json(serde_json::json!({"type": "double"}))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["code"], "NotEnoughScore");
+        assert_eq!(body["error"], "NotEnoughScore");
+        assert_eq!(
+            body["message"],
+            format!("Not enough score, {cost} needed but only 5 left")
+        );
+        assert_eq!(
+            body["details"],
+            serde_json::json!({"required_score": cost, "score": 5})
+        );
 
-        let req = test::TestRequest::get().uri("/").to_request();
-        let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let request = test::TestRequest::get()
+            .uri("/api/pipe/1000/value")
+            .append_header((AUTHORIZATION, Bearer::new("player")))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["code"], "PipeNotFound");
+        assert_eq!(body["details"], serde_json::json!({"pipe_id": 1000}));
 
-        let req = test::TestRequest::get()
-            .uri("/api/pipe/1/value")
+        let request = test::TestRequest::get()
+            .uri("/api/pipe/zero/value")
+            .append_header((AUTHORIZATION, Bearer::new("player")))
             .to_request();
-        let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["code"], "PipeNotFound");
+        assert_eq!(body["details"], serde_json::json!({}));
+    }
 
-        let auth = (AUTHORIZATION, Bearer::new("hello"));
+    #[actix_web::test]
+    async fn test_ip_rate_limit() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                max_requests_per_ip_per_minute: Some(2),
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(limit_ip_requests))
+                .configure(|config| configure(config, state)),
+        )
+        .await;
+        let request = |ip: [u8; 4]| {
+            test::TestRequest::get()
+                .uri("/api/time")
+                .peer_addr(SocketAddr::from((ip, 1234)))
+                .to_request()
+        };
+        for _ in 0..2 {
+            let resp = test::call_service(&app, request([10, 0, 0, 1])).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = test::call_service(&app, request([10, 0, 0, 1])).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let resp = test::call_service(&app, request([10, 0, 0, 2])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_token_rate_limit() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                max_requests_per_token_per_second: Some(2),
+                ..Default::default()
+            },
+            vec![
+                UserToken::from("spammer".to_owned()),
+                UserToken::from("player".to_owned()),
+            ],
+        ));
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(limit_token_requests))
+                .app_data(web::Data::new(AdminToken(UserToken::from(
+                    "admin".to_owned(),
+                ))))
+                .service(admin_rate_limits)
+                .configure(|config| configure(config, state)),
+        )
+        .await;
+        let request = |token: &str| {
+            test::TestRequest::get()
+                .uri("/api/user")
+                .append_header((AUTHORIZATION, Bearer::new(token.to_owned())))
+                .to_request()
+        };
+        for _ in 0..2 {
+            let resp = test::call_service(&app, request("spammer")).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = test::call_service(&app, request("spammer")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "1");
+        let resp = test::call_service(&app, request("player")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        tokio::time::advance(Duration::from_millis(1100)).await;
+        let resp = test::call_service(&app, request("spammer")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // Unknown tokens are not counted, they fail authentication instead
+        for _ in 0..3 {
+            let resp = test::call_service(&app, request("made-up")).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
 
         let req = test::TestRequest::get()
-            .uri("/api/pipe/1/value")
-            .append_header(auth.clone())
+            .uri("/api/admin/rate_limits")
+            .append_header((AUTHORIZATION, Bearer::new("admin")))
             .to_request();
-        let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(), StatusCode::OK);
-        let _: model::PipeValueResponse = test::read_body_json(resp).await;
+        let stats: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(stats["spammer"]["requests"], 4);
+        assert_eq!(stats["spammer"]["rejected"], 1);
+        assert_eq!(stats["player"]["rejected"], 0);
+        assert!(stats.get("made-up").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_set_log_level() {
+        use tracing::{enabled, Level};
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            Default::default(),
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AdminToken(UserToken::from(
+                    "admin".to_owned(),
+                ))))
+                .service(set_log_level)
+                .configure(|config| configure(config, state)),
+        )
+        .await;
+        let set_level = |level: &str| {
+            let request = test::TestRequest::put()
+                .uri("/api/admin/log_level")
+                .append_header((AUTHORIZATION, Bearer::new("admin")))
+                .set_json(serde_json::json!({"target": "log_level_probe", "level": level}))
+                .to_request();
+            test::call_service(&app, request)
+        };
+        assert!(!enabled!(target: "log_level_probe", Level::DEBUG));
+
+        assert_eq!(set_level("verbose").await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(set_level("debug").await.status(), StatusCode::OK);
+        assert!(enabled!(target: "log_level_probe", Level::DEBUG));
+        assert!(!enabled!(target: "log_level_probe", Level::TRACE));
+        // Only the given target is affected
+        assert!(!enabled!(target: "log_level_other", Level::DEBUG));
+
+        // A later level of the same target replaces the earlier one
+        assert_eq!(set_level("off").await.status(), StatusCode::OK);
+        assert!(!enabled!(target: "log_level_probe", Level::ERROR));
+    }
+
+    #[actix_web::test]
+    async fn test_update_config() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                time_scale: 0.5,
+                ..Default::default()
+            },
+            vec![UserToken::from("player".to_owned())],
+        ));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AdminToken(UserToken::from(
+                    "admin".to_owned(),
+                ))))
+                .service(admin_update_config)
+                .configure(|config| configure(config, state)),
+        )
+        .await;
+        let update = |config: model::Config| {
+            test::TestRequest::post()
+                .uri("/api/admin/config")
+                .append_header((AUTHORIZATION, Bearer::new("admin")))
+                .set_json(config)
+                .to_request()
+        };
+
+        let config = model::Config {
+            time_scale: 0.5,
+            peek_cost: 42,
+            ..Default::default()
+        };
+        let reported: model::Config =
+            test::call_and_read_body_json(&app, update(config.clone())).await;
+        let req = test::TestRequest::get().uri("/api/shop").to_request();
+        let resp: model::ShopResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.peek_cost, 42);
+        // The reported config is already scaled and is taken back as it is
+        assert_eq!(reported.time_scale, 1.0);
+        assert_eq!(reported.max_delay_secs, config.max_delay_secs * 0.5);
+        let again: model::Config =
+            test::call_and_read_body_json(&app, update(reported.clone())).await;
+        assert_eq!(
+            serde_json::to_value(&again).unwrap(),
+            serde_json::to_value(&reported).unwrap()
+        );
 
+        let resp = test::call_service(
+            &app,
+            update(model::Config {
+                pipe_count: config.pipe_count + 1,
+                peek_cost: 7,
+                ..config.clone()
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("pipe_count"));
+        // Fields are only changed at runtime if the game reads them again
+        let resp = test::call_service(
+            &app,
+            update(model::Config {
+                webhook_secret: Some("secret".to_owned()),
+                ..config.clone()
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("webhook_secret"));
+        let resp = test::call_service(
+            &app,
+            update(model::Config {
+                peek_cost: -7,
+                ..config.clone()
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let req = test::TestRequest::get().uri("/api/shop").to_request();
+        let resp: model::ShopResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.peek_cost, 42);
+
+        // Negative durations would panic once the game gets to them
+        for (field, invalid) in [
+            (
+                "action_cooldowns_secs",
+                model::Config {
+                    action_cooldowns_secs: [(model::Action::Collect, -1.0)].into(),
+                    ..config.clone()
+                },
+            ),
+            (
+                "podium_delay_secs",
+                model::Config {
+                    podium_delay_secs: -1.0,
+                    ..config.clone()
+                },
+            ),
+        ] {
+            let resp = test::call_service(&app, update(invalid)).await;
+            assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY, "{field}");
+            let body = test::read_body(resp).await;
+            assert!(std::str::from_utf8(&body).unwrap().contains(field));
+        }
         let req = test::TestRequest::put()
-            .uri("/api/pipe/2")
-            .append_header(auth.clone())
+            .uri("/api/pipe/1")
+            .append_header((AUTHORIZATION, Bearer::new("player")))
             .to_request();
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
-        let _: model::CollectResponse = test::read_body_json(resp).await;
+    }
+
+    #[actix_web::test]
+    async fn test_pipe_values() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let play = |delay: model::BatchDelay| async move {
+            let state = web::Data::new(model::App::init(
+                model::Config {
+                    pipe_value_delay_secs: 1.0,
+                    pipe_values_delay: delay,
+                    ..Default::default()
+                },
+                vec![UserToken::from("player".to_owned())],
+            ));
+            let app =
+                test::init_service(App::new().configure(|config| configure(config, state))).await;
+            let request = |ids: &str| {
+                test::TestRequest::get()
+                    .uri(&format!("/api/pipes/values?ids={ids}"))
+                    .append_header((AUTHORIZATION, Bearer::new("player")))
+                    .to_request()
+            };
+            let resp = test::call_service(&app, request("1,x")).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+            let start = tokio::time::Instant::now();
+            let response: model::PipeValuesResponse =
+                test::call_and_read_body_json(&app, request("3,1,2")).await;
+            let ids: Vec<usize> = response
+                .values
+                .iter()
+                .map(|value| value.pipe_id.get())
+                .collect();
+            assert_eq!(ids, [3, 1, 2]);
+            start.elapsed().as_secs_f64().round()
+        };
+        assert_eq!(play(model::BatchDelay::PerPipe).await, 3.0);
+        assert_eq!(play(model::BatchDelay::Once).await, 1.0);
+    }
+
+    #[actix_web::test]
+    async fn test_player_stats() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let player = UserToken::from("player".to_owned());
+        let state = model::App::init(
+            model::Config {
+                min_value: 5,
+                max_value: 5,
+                initial_score: 100,
+                ..Default::default()
+            },
+            vec![player.clone()],
+        );
+        let pipe = PipeId::new(1).unwrap();
+        state
+            .apply_modifier(&player, pipe, model::Modifier::Reverse)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            state.collect(&player, pipe).await.unwrap();
+        }
+        let missing = PipeId::new(100).unwrap();
+        assert!(state.collect(&player, missing).await.is_err());
+        let results = state.detailed_results().await;
+        let stats = &results.players["player"];
+        assert_eq!(stats.collects, 2);
+        assert_eq!(stats.modifiers_bought, 1);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.earned, 10);
+        assert_eq!(stats.spent, state.config().reverse_cost);
+        assert!(stats.time_blocked > 0.0);
+        assert_eq!(stats.error_streak, 1);
+        assert!(stats.last_success_secs.is_some());
+        assert_eq!(results.scores["player"], 100 + stats.earned - stats.spent);
+    }
+
+    #[actix_web::test]
+    async fn test_overlay() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let [thief, victim] =
+            ["secret-thief", "secret-victim"].map(|token| UserToken::from(token.to_owned()));
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                initial_score: 100,
+                min_value: 10,
+                max_value: 10,
+                ..Default::default()
+            },
+            [thief.clone(), victim.clone()],
+        ));
+        let app = test::init_service(
+            App::new()
+                .configure({
+                    let state = state.clone();
+                    move |config| configure(config, state)
+                })
+                .service(overlay),
+        )
+        .await;
+        let poll = || async {
+            let req = test::TestRequest::get().uri("/api/overlay").to_request();
+            let body = test::call_and_read_body(&app, req).await;
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(!body.contains("secret"), "{body}");
+            serde_json::from_str::<model::OverlayResponse>(&body).unwrap()
+        };
+        let scores = |response: &model::OverlayResponse| {
+            response
+                .top
+                .iter()
+                .map(|player| (player.user.clone(), player.score))
+                .collect::<Vec<_>>()
+        };
+        let cached = poll().await;
+        assert_eq!(cached.top.len(), 2);
+        assert!(cached.top.iter().all(|player| player.score == 100));
+
+        state
+            .apply_modifier(&thief, PipeId::new(1).unwrap(), model::Modifier::Steal)
+            .await
+            .unwrap();
+        // Polling within a second gets the same overlay
+        assert_eq!(scores(&poll().await), scores(&cached));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let steal_cost = state.config().steal_cost;
+        assert!(scores(&poll().await).contains(&(thief.digest(), 100 - steal_cost)));
+
+        state
+            .collect(&victim, PipeId::new(1).unwrap())
+            .await
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let refreshed = poll().await;
+        assert_eq!(
+            scores(&refreshed),
+            [
+                (victim.digest(), 105),
+                (thief.digest(), 100 - steal_cost + 5)
+            ]
+        );
+        let event = refreshed.last_event.unwrap();
+        assert!(
+            matches!(event.msg, model::LogMessage::ScoreStolen { user, victim: robbed, .. }
+                if user == thief.digest() && robbed == victim.digest())
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_display_names() {
+        crate::logger::init_for_tests();
+        let [first, second, third] =
+            ["first", "second", "third"].map(|token| UserToken::from(token.to_owned()));
+        let profile = model::UserProfile {
+            name: Some("Alpha".to_owned()),
+            team: None,
+            color: Some("#ff0000".to_owned()),
+            ghost: false,
+        };
+        let state = model::App::init(
+            model::Config {
+                initial_score: 100,
+                initial_scores: [(third.clone(), 50)].into(),
+                user_profiles: [(first.clone(), profile)].into(),
+                ..Default::default()
+            },
+            [first.clone(), second.clone(), third.clone()],
+        );
+        state
+            .import_users(vec![model::UserRecord {
+                token: second.clone(),
+                profile: model::UserProfile {
+                    name: Some("Beta".to_owned()),
+                    ..Default::default()
+                },
+            }])
+            .await;
+        assert_eq!(
+            state.user_state(&first).await.unwrap().name.unwrap(),
+            "Alpha"
+        );
+
+        let standings = state.scoreboard();
+        let lines: Vec<_> = standings
+            .iter()
+            .map(|entry| (entry.rank, entry.player.name.as_deref(), entry.player.score))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (1, Some("Alpha"), 100),
+                (1, Some("Beta"), 100),
+                (3, None, 50)
+            ]
+        );
+        assert_eq!(standings[0].player.color.as_deref(), Some("#ff0000"));
+        assert_eq!(state.overlay().top[1].name.as_deref(), Some("Beta"));
+        let results = state.detailed_results().await;
+        assert_eq!(results.profiles.len(), 2);
+        assert_eq!(results.profiles["second"].name.as_deref(), Some("Beta"));
+    }
+
+    #[actix_web::test]
+    async fn test_checkpoint() {
+        crate::logger::init_for_tests();
+        // Times in the checkpoint are floats, they survive json exactly when they are whole
+        tokio::time::pause();
+        let config = || model::Config {
+            min_delay_secs: 0.0,
+            max_delay_secs: 0.0,
+            streak_step: Some(0.5),
+            ..Default::default()
+        };
+        let users = || [UserToken::from("player".to_owned())];
+        let state = web::Data::new(model::App::init(config(), users()));
+        let app = test::init_service(App::new().configure({
+            let state = state.clone();
+            move |config| configure(config, state)
+        }))
+        .await;
+        for pipe in 1..=2 {
+            let request = test::TestRequest::put()
+                .uri(&format!("/api/pipe/{pipe}"))
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .to_request();
+            test::call_service(&app, request).await;
+        }
+        let checkpoint = serde_json::to_string(&state.checkpoint().await).unwrap();
+
+        let mut restored = model::App::init(config(), users());
+        restored.restore(serde_json::from_str(&checkpoint).unwrap());
+        assert_eq!(restored.results().await, state.results().await);
+        assert_eq!(restored.history_len().await, state.history_len().await);
+        assert_eq!(restored.seed(), state.seed());
+        let stats = &restored.player_stats().await["player"];
+        assert_eq!(stats.collects, 2);
+        assert_eq!(
+            serde_json::to_value(stats).unwrap(),
+            serde_json::to_value(&state.player_stats().await["player"]).unwrap()
+        );
+        let user = restored
+            .user_state(&UserToken::from("player".to_owned()))
+            .await
+            .unwrap();
+        assert!(user.last_collect_secs.is_some());
+        // Random choices go on from where the game was
+        let checkpoint: serde_json::Value = serde_json::from_str(&checkpoint).unwrap();
+        assert!(checkpoint["rng"].is_object());
+        assert_eq!(
+            serde_json::to_value(restored.checkpoint().await.rng).unwrap(),
+            checkpoint["rng"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_shield() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                initial_score: 100,
+                ..Default::default()
+            },
+            ["owner", "other"].map(|token| UserToken::from(token.to_owned())),
+        ));
+        let app =
+            test::init_service(App::new().configure(move |config| configure(config, state))).await;
+        let apply = |user: &str, modifier: &str| {
+            test::TestRequest::post()
+                .uri("/api/pipe/1/modifier")
+                .append_header((AUTHORIZATION, Bearer::new(user.to_owned())))
+                .set_json(serde_json::json!({ "type": modifier }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, apply("owner", "shield")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, apply("other", "double")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "PipeShielded");
+        let resp = test::call_service(&app, apply("owner", "double")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_disabled_modifiers() {
+        crate::logger::init_for_tests();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                initial_score: 100,
+                enabled_modifiers: vec![model::Modifier::Double, model::Modifier::Reverse],
+                ..Default::default()
+            },
+            [UserToken::from("player".to_owned())],
+        ));
+        let app =
+            test::init_service(App::new().configure(move |config| configure(config, state))).await;
+        let apply = |modifier: &str| {
+            test::TestRequest::post()
+                .uri("/api/pipe/1/modifier")
+                .append_header((AUTHORIZATION, Bearer::new("player")))
+                .set_json(serde_json::json!({ "type": modifier }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, apply("slow")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "ModifierDisabled");
+        let resp = test::call_service(&app, apply("double")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri("/api/shop").to_request();
+        let response: model::ShopResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            response.modifiers.into_keys().collect::<Vec<_>>(),
+            [model::Modifier::Double, model::Modifier::Reverse]
+        );
+    }
 
+    #[actix_web::test]
+    async fn test_steal() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                initial_score: 100,
+                min_value: 10,
+                max_value: 10,
+                ..Default::default()
+            },
+            ["thief", "victim"].map(|token| UserToken::from(token.to_owned())),
+        ));
+        let app = test::init_service(App::new().configure({
+            let state = state.clone();
+            move |config| configure(config, state)
+        }))
+        .await;
         let req = test::TestRequest::post()
-            .uri("/api/pipe/3/modifier")
-            .append_header(auth.clone())
-            .set_json(ApplyModifierInput {
-                modifier: model::Modifier::Double,
-            })
+            .uri("/api/pipe/1/modifier")
+            .append_header((AUTHORIZATION, Bearer::new("thief")))
+            .set_json(serde_json::json!({ "type": "steal" }))
             .to_request();
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
-        let _: model::ApplyModifierResponse = test::read_body_json(resp).await;
+        let victim_collect = || {
+            test::TestRequest::put()
+                .uri("/api/pipe/1")
+                .append_header((AUTHORIZATION, Bearer::new("victim")))
+                .to_request()
+        };
+        let response: model::CollectResponse =
+            test::call_and_read_body_json(&app, victim_collect()).await;
+        assert_eq!(response.value, 5);
+        // The modifier is used up by the first collect
+        let response: model::CollectResponse =
+            test::call_and_read_body_json(&app, victim_collect()).await;
+        assert_eq!(response.value, 10);
+        let results = state.results().await;
+        assert_eq!(results["thief"], 100 - 40 + 5);
+        assert_eq!(results["victim"], 100 + 5 + 10);
+    }
+
+    #[actix_web::test]
+    async fn test_insurance_refund() {
+        crate::logger::init_for_tests();
+        tokio::time::pause();
+        let state = web::Data::new(model::App::init(
+            model::Config {
+                initial_score: 100,
+                min_value: 10,
+                max_value: 10,
+                insurance_threshold: 6,
+                ..Default::default()
+            },
+            ["thief", "victim"].map(|token| UserToken::from(token.to_owned())),
+        ));
+        let app = test::init_service(App::new().configure({
+            let state = state.clone();
+            move |config| configure(config, state)
+        }))
+        .await;
+        for (user, modifier) in [("thief", "steal"), ("victim", "insurance")] {
+            let req = test::TestRequest::post()
+                .uri("/api/pipe/1/modifier")
+                .append_header((AUTHORIZATION, Bearer::new(user)))
+                .set_json(serde_json::json!({ "type": modifier }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{modifier}");
+        }
+        let victim_collect = || {
+            test::TestRequest::put()
+                .uri("/api/pipe/1")
+                .append_header((AUTHORIZATION, Bearer::new("victim")))
+                .to_request()
+        };
+        // Half of the stolen 5 is refunded, apart from what the collect paid
+        let response: serde_json::Value =
+            test::call_and_read_body_json(&app, victim_collect()).await;
+        assert_eq!(response, serde_json::json!({ "value": 5, "refund": 3 }));
+        // Nothing to refund when the payout is good
+        let response: model::CollectResponse =
+            test::call_and_read_body_json(&app, victim_collect()).await;
+        assert_eq!((response.value, response.refund), (10, 0));
+        let results = state.results().await;
+        assert_eq!(results["victim"], 100 - 20 + 5 + 3 + 10);
+    }
+
+    #[actix_web::test]
+    async fn test_admin_token_matches() {
+        let admin = AdminToken(UserToken::from("admin-secret".to_owned()));
+        let token = |token: &str| UserToken::from(token.to_owned());
+        assert!(admin.matches(&token("admin-secret")));
+        for other in ["", "admin", "admin-secreT", "admin-secret2"] {
+            assert!(!admin.matches(&token(other)), "{other}");
+        }
     }
 }
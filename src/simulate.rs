@@ -0,0 +1,525 @@
+//! Bots with simple strategies playing against an in-process game, for balancing configs.
+//! The bots call the game directly instead of going through HTTP.
+
+use crate::{
+    model::{self, Modifier, PipeId, Score, UserToken},
+    record, server,
+};
+use actix_web::rt::{
+    spawn,
+    task::{yield_now, JoinHandle},
+    time::sleep,
+};
+use anyhow::Context;
+use futures::{channel::mpsc, StreamExt};
+use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, Rng, SeedableRng};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{debug, info};
+
+/// Pause of a bot after a failed move, so that it does not spin on errors
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// What a bot knows when choosing its next move
+pub struct View<'a> {
+    pub score: Score,
+    pub pipes: &'a [PipeId],
+    /// Last value seen of every pipe, from collects and value probes
+    pub known_values: &'a BTreeMap<PipeId, Score>,
+    pub shop: &'a model::ShopResponse,
+}
+
+pub enum Move {
+    Collect(PipeId),
+    ProbeValue(PipeId),
+    ApplyModifier(PipeId, Modifier),
+}
+
+/// Decision making of a bot, the simulator carries out the moves
+pub trait Strategy {
+    /// Waits a bit and asks again if there is nothing to do
+    fn next_move(&mut self, view: &View, rng: &mut StdRng) -> Option<Move>;
+}
+
+/// Collects random pipes and sometimes buys a random modifier
+pub struct RandomStrategy;
+
+impl Strategy for RandomStrategy {
+    fn next_move(&mut self, view: &View, rng: &mut StdRng) -> Option<Move> {
+        let &pipe_id = view.pipes.choose(rng)?;
+        let modifier = *Modifier::ALL.choose(rng)?;
+        let affordable = view
+            .shop
+            .modifiers
+            .get(&modifier)
+            .is_some_and(|item| item.cost <= view.score);
+        if affordable && rng.gen_bool(0.1) {
+            Some(Move::ApplyModifier(pipe_id, modifier))
+        } else {
+            Some(Move::Collect(pipe_id))
+        }
+    }
+}
+
+/// Tries every pipe once, then keeps collecting the best one seen
+pub struct GreedyStrategy;
+
+impl Strategy for GreedyStrategy {
+    fn next_move(&mut self, view: &View, _rng: &mut StdRng) -> Option<Move> {
+        let unknown = view
+            .pipes
+            .iter()
+            .find(|pipe_id| !view.known_values.contains_key(pipe_id));
+        let pipe_id = unknown.copied().or_else(|| best_known(view))?;
+        Some(Move::Collect(pipe_id))
+    }
+}
+
+/// Checks the values of the pipes in turn and collects the best one every few moves
+#[derive(Default)]
+pub struct ValueProberStrategy {
+    probes: usize,
+    probes_since_collect: usize,
+}
+
+impl ValueProberStrategy {
+    const PROBES_BETWEEN_COLLECTS: usize = 2;
+}
+
+impl Strategy for ValueProberStrategy {
+    fn next_move(&mut self, view: &View, _rng: &mut StdRng) -> Option<Move> {
+        if view.pipes.is_empty() {
+            return None;
+        }
+        if self.probes_since_collect >= Self::PROBES_BETWEEN_COLLECTS {
+            if let Some(pipe_id) = best_known(view) {
+                self.probes_since_collect = 0;
+                return Some(Move::Collect(pipe_id));
+            }
+        }
+        let pipe_id = view.pipes[self.probes % view.pipes.len()];
+        self.probes += 1;
+        self.probes_since_collect += 1;
+        Some(Move::ProbeValue(pipe_id))
+    }
+}
+
+fn best_known(view: &View) -> Option<PipeId> {
+    view.known_values
+        .iter()
+        .filter(|(pipe_id, _)| view.pipes.contains(pipe_id))
+        .max_by_key(|(_, &value)| value)
+        .map(|(&pipe_id, _)| pipe_id)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum StrategyKind {
+    Random,
+    Greedy,
+    ValueProber,
+}
+
+impl StrategyKind {
+    pub const ALL: [StrategyKind; 3] = [
+        StrategyKind::Random,
+        StrategyKind::Greedy,
+        StrategyKind::ValueProber,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StrategyKind::Random => "random",
+            StrategyKind::Greedy => "greedy",
+            StrategyKind::ValueProber => "value-prober",
+        }
+    }
+
+    pub fn build(self) -> Box<dyn Strategy> {
+        match self {
+            StrategyKind::Random => Box::new(RandomStrategy),
+            StrategyKind::Greedy => Box::new(GreedyStrategy),
+            StrategyKind::ValueProber => Box::new(ValueProberStrategy::default()),
+        }
+    }
+}
+
+pub struct Options {
+    pub bots: usize,
+    /// Given to the bots in turn
+    pub strategies: Vec<StrategyKind>,
+    pub games: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct StrategySummary {
+    pub bots: usize,
+    pub mean_score: f64,
+    #[serde(with = "crate::serde_score")]
+    pub best_score: Score,
+    /// Games in which a bot with the strategy had the top score, ties count for everyone
+    pub wins: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GameSummary {
+    pub seed: u64,
+    pub scores: model::Results,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub games: Vec<GameSummary>,
+    pub strategies: BTreeMap<&'static str, StrategySummary>,
+}
+
+/// Plays the games one after another, game `n` is seeded with the config seed plus `n`
+pub async fn run(config: model::Config, options: &Options) -> anyhow::Result<Summary> {
+    anyhow::ensure!(
+        config.time_to_run.is_some(),
+        "Simulated games need time_to_run"
+    );
+    anyhow::ensure!(!options.strategies.is_empty(), "No strategies to play");
+    let bots = lineup(options);
+    let base_seed = config.seed.unwrap_or_else(|| thread_rng().gen());
+    let mut games = Vec::with_capacity(options.games);
+    for index in 0..options.games {
+        let seed = base_seed.wrapping_add(index as u64);
+        info!(
+            "Simulating game {} of {} with seed {seed}",
+            index + 1,
+            options.games
+        );
+        let config = model::Config {
+            seed: Some(seed),
+            ..config.clone()
+        };
+        let scores = play(config, &bots, seed).await.results().await;
+        games.push(GameSummary { seed, scores });
+    }
+
+    let mut strategies: BTreeMap<&'static str, StrategySummary> = BTreeMap::new();
+    let mut totals: BTreeMap<&'static str, Score> = BTreeMap::new();
+    for (_, kind) in &bots {
+        let summary = strategies.entry(kind.name()).or_insert(StrategySummary {
+            best_score: Score::MIN,
+            ..Default::default()
+        });
+        summary.bots += 1;
+    }
+    for game in &games {
+        let top = game.scores.values().max().copied();
+        let mut winners = BTreeSet::new();
+        for (token, kind) in &bots {
+            let score = game.scores[token];
+            let summary = strategies.get_mut(kind.name()).unwrap();
+            summary.best_score = summary.best_score.max(score);
+            *totals.entry(kind.name()).or_default() += score;
+            if Some(score) == top {
+                winners.insert(kind.name());
+            }
+        }
+        for name in winners {
+            strategies.get_mut(name).unwrap().wins += 1;
+        }
+    }
+    for (name, summary) in &mut strategies {
+        let plays = summary.bots * games.len();
+        summary.mean_score =
+            totals.get(name).copied().unwrap_or_default() as f64 / plays.max(1) as f64;
+    }
+    Ok(Summary { games, strategies })
+}
+
+/// Names and strategies of the bots
+fn lineup(options: &Options) -> Vec<(String, StrategyKind)> {
+    (0..options.bots)
+        .map(|index| {
+            let kind = options.strategies[index % options.strategies.len()];
+            (format!("{}-{}", kind.name(), index + 1), kind)
+        })
+        .collect()
+}
+
+/// Plays a single game with the seed of the config on the virtual clock and keeps its log.
+/// The same seed plays out the same way, so the outcomes of two configs can be compared with
+/// [`record::diff`].
+pub async fn trace(config: model::Config, options: &Options) -> anyhow::Result<record::Replay> {
+    anyhow::ensure!(
+        config.time_to_run.is_some(),
+        "Simulated games need time_to_run"
+    );
+    anyhow::ensure!(!options.strategies.is_empty(), "No strategies to play");
+    let seed = config.seed.context("Traced games have to be seeded")?;
+    let bots = lineup(options);
+    record::on_virtual_clock(move || async move {
+        let app = play(config, &bots, seed).await;
+        // The whole log is replayed to new subscribers
+        let (sender, receiver) = mpsc::unbounded();
+        app.register_logs(sender.clone()).await;
+        app.unregister_logs(sender).await;
+        Ok(record::Replay {
+            results: app.results().await,
+            log: receiver.collect().await,
+            mismatches: 0,
+        })
+    })
+    .await
+}
+
+/// Returns the game once it is over
+async fn play(
+    config: model::Config,
+    bots: &[(String, StrategyKind)],
+    seed: u64,
+) -> Arc<model::App> {
+    let tokens = bots.iter().map(|(token, _)| UserToken::from(token.clone()));
+    let mut app = model::App::init(config, tokens);
+    let time_to_run = app.config().time_to_run.map(Duration::from_secs_f64);
+    app.set_time_to_run(time_to_run);
+    let app = Arc::new(app);
+    let scheduler = spawn({
+        let app = app.clone();
+        async move { app.run_scheduler().await }
+    });
+    let tasks = spawn_bots(&app, bots, seed);
+    server::game_end(&app, time_to_run).await;
+    app.announce_end().await;
+    scheduler.abort();
+    for task in tasks {
+        task.abort();
+    }
+    app
+}
+
+/// Bots playing until the game is over, their tokens have to be users of the game.
+/// Bot `n` plays with the seed plus `n`
+pub fn spawn_bots(
+    app: &Arc<model::App>,
+    bots: &[(String, StrategyKind)],
+    seed: u64,
+) -> Vec<JoinHandle<()>> {
+    bots.iter()
+        .enumerate()
+        .map(|(index, (token, kind))| {
+            let rng = StdRng::seed_from_u64(seed.wrapping_add(index as u64));
+            spawn(play_bot(
+                app.clone(),
+                token.clone().into(),
+                kind.build(),
+                rng,
+            ))
+        })
+        .collect()
+}
+
+/// Adds bots for the slots of `ghost_players` that real players left empty, the strategies
+/// take turns. Ghosts of a restored game are picked up again instead of adding new ones
+pub async fn join_ghosts(app: &Arc<model::App>) -> Vec<JoinHandle<()>> {
+    let Some(slots) = app.config().ghost_players else {
+        return Vec::new();
+    };
+    let profiles = app.profiles().await;
+    let ghosts = profiles.values().filter(|profile| profile.ghost).count();
+    let players = app.user_count() - ghosts;
+    let bots: Vec<_> = (0..slots.saturating_sub(players))
+        .map(|index| {
+            let kind = StrategyKind::ALL[index % StrategyKind::ALL.len()];
+            (format!("ghost-{}-{}", kind.name(), index + 1), kind)
+        })
+        .collect();
+    if bots.is_empty() {
+        return Vec::new();
+    }
+    info!("{} ghosts join {players} players", bots.len());
+    let records = bots
+        .iter()
+        .map(|(token, _)| model::UserRecord {
+            token: UserToken::from(token.clone()),
+            profile: model::UserProfile {
+                name: Some(token.clone()),
+                ghost: true,
+                ..Default::default()
+            },
+        })
+        .collect();
+    app.import_users(records).await;
+    spawn_bots(app, &bots, app.seed())
+}
+
+async fn pipe_ids(app: &model::App, token: &UserToken) -> Vec<PipeId> {
+    match app.list_pipes(token).await {
+        Ok(response) => response.pipes.into_iter().map(|pipe| pipe.id).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+async fn play_bot(
+    app: Arc<model::App>,
+    token: UserToken,
+    mut strategy: Box<dyn Strategy>,
+    mut rng: StdRng,
+) {
+    app.wait_for_start().await;
+    let mut pipes = pipe_ids(&app, &token).await;
+    let mut known_values = BTreeMap::new();
+    while !app.is_over() {
+        let Ok(user) = app.user_state(&token).await else {
+            return;
+        };
+        let shop = app.shop();
+        let view = View {
+            score: user.score,
+            pipes: &pipes,
+            known_values: &known_values,
+            shop: &shop,
+        };
+        let Some(next) = strategy.next_move(&view, &mut rng) else {
+            sleep(RETRY_DELAY).await;
+            continue;
+        };
+        let (pipe_id, result) = match next {
+            Move::Collect(pipe_id) => {
+                let result = app.collect(&token, pipe_id).await;
+                (pipe_id, result.map(|response| Some(response.value)))
+            }
+            Move::ProbeValue(pipe_id) => {
+                let result = app.pipe_value(&token, pipe_id).await;
+                (pipe_id, result.map(|response| Some(response.value)))
+            }
+            Move::ApplyModifier(pipe_id, modifier) => {
+                let result = app.apply_modifier(&token, pipe_id, modifier).await;
+                (pipe_id, result.map(|_| None))
+            }
+        };
+        match result {
+            Ok(Some(value)) => {
+                known_values.insert(pipe_id, value);
+            }
+            Ok(None) => {}
+            Err(model::Error::GameOver) => return,
+            Err(model::Error::PipeNotFound { .. }) => {
+                known_values.remove(&pipe_id);
+                pipes = pipe_ids(&app, &token).await;
+            }
+            Err(error) => {
+                debug!("{token:?} failed on pipe {pipe_id}: {error}");
+                sleep(RETRY_DELAY).await;
+            }
+        }
+        yield_now().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_simulate() {
+        crate::logger::init_for_tests();
+        let config = model::Config {
+            time_to_run: Some(1.0),
+            start_delay_secs: 0.0,
+            min_delay_secs: 0.01,
+            max_delay_secs: 0.02,
+            pipe_value_delay_secs: 0.01,
+            seed: Some(7),
+            ..Default::default()
+        };
+        let options = Options {
+            bots: 6,
+            strategies: vec![
+                StrategyKind::Random,
+                StrategyKind::Greedy,
+                StrategyKind::ValueProber,
+            ],
+            games: 2,
+        };
+        let summary = run(config, &options).await.unwrap();
+        assert_eq!(summary.games.len(), 2);
+        assert_eq!(summary.games[1].seed, 8);
+        assert_eq!(summary.games[0].scores.len(), 6);
+        assert!(summary.games[0].scores.contains_key("value-prober-3"));
+        assert_eq!(summary.strategies.len(), 3);
+        let wins: usize = summary
+            .strategies
+            .values()
+            .map(|summary| summary.wins)
+            .sum();
+        assert!(wins >= 2);
+        for summary in summary.strategies.values() {
+            assert_eq!(summary.bots, 2);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_ghosts() {
+        crate::logger::init_for_tests();
+        let config = model::Config {
+            time_to_run: Some(5.0),
+            start_delay_secs: 0.0,
+            min_delay_secs: 0.01,
+            max_delay_secs: 0.01,
+            ghost_players: Some(3),
+            ..Default::default()
+        };
+        let app = Arc::new(model::App::init(
+            config,
+            [UserToken::from("player".to_owned())],
+        ));
+        let ghosts = join_ghosts(&app).await;
+        assert_eq!(ghosts.len(), 2);
+        let results = app.detailed_results().await;
+        assert_eq!(results.scores.len(), 3);
+        assert!(results.profiles["ghost-random-1"].ghost);
+        assert!(results.profiles["ghost-greedy-2"].ghost);
+        assert!(!results.profiles.contains_key("player"));
+        // The ghosts play and are flagged in the log
+        sleep(Duration::from_millis(200)).await;
+        let (sender, receiver) = mpsc::unbounded();
+        app.register_logs(sender.clone()).await;
+        app.unregister_logs(sender).await;
+        let log: Vec<model::LogEntry> = receiver.collect().await;
+        let ghost_updates = log.iter().filter(|entry| {
+            matches!(&entry.msg, model::LogMessage::UpdateUser { state, .. } if state.ghost)
+        });
+        assert!(ghost_updates.count() > 2);
+        // A restored game plays on with the ghosts it had, instead of adding more
+        let rejoined = join_ghosts(&app).await;
+        assert_eq!(rejoined.len(), 2);
+        assert_eq!(app.user_count(), 3);
+        for ghost in ghosts.into_iter().chain(rejoined) {
+            ghost.abort();
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_trace() {
+        crate::logger::init_for_tests();
+        let config = model::Config {
+            time_to_run: Some(5.0),
+            start_delay_secs: 0.0,
+            seed: Some(3),
+            ..Default::default()
+        };
+        let options = Options {
+            bots: 3,
+            strategies: StrategyKind::ALL.to_vec(),
+            games: 1,
+        };
+        let a = trace(config.clone(), &options).await.unwrap();
+        let b = trace(config.clone(), &options).await.unwrap();
+        assert!(a.log.len() > 10);
+        assert_eq!(record::diff(&a, &b), Vec::<String>::new());
+        let other = model::Config {
+            min_value: config.max_value,
+            ..config
+        };
+        let other = trace(other, &options).await.unwrap();
+        assert!(!record::diff(&a, &other).is_empty());
+    }
+}
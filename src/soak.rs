@@ -0,0 +1,203 @@
+//! Long running mode that drives the server with simulated players, periodically inspects it
+//! and ends the game as soon as it degrades
+
+use itonecup_mobile::{
+    model::{self, UserToken},
+    simulate::{self, StrategyKind},
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{error, info};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Latency increases below this are never reported
+const MIN_REPORTED_LATENCY_SECS: f64 = 0.1;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct Options {
+    /// Run for this many hours while checking the server for leaks and lag
+    #[clap(long = "soak")]
+    pub hours: Option<f64>,
+    #[clap(long = "soak-max-rss-mb", default_value = "1024")]
+    pub max_rss_mb: f64,
+    /// How many times the worst p99 latency may grow compared to the first check
+    #[clap(long = "soak-max-latency-drift", default_value = "5")]
+    pub max_latency_drift: f64,
+    /// Simulated players joining the game to keep it busy, on top of the real ones
+    #[clap(long = "soak-bots", default_value = "8")]
+    pub bots: usize,
+    /// Log entries a subscriber may have waiting before it counts as stuck
+    #[clap(long = "soak-max-log-backlog", default_value = "512")]
+    pub max_log_backlog: usize,
+}
+
+/// Resident memory of the process, only available on linux
+fn rss_mb() -> Option<f64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: f64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096.0 / 1024.0 / 1024.0)
+}
+
+fn worst_p99_latency(app: &model::App) -> Option<f64> {
+    app.metrics()
+        .snapshot()
+        .endpoints
+        .values()
+        .map(|stats| stats.p99_latency)
+        .max_by(f64::total_cmp)
+}
+
+/// Adds the simulated players to the game, the strategies take turns
+async fn join_bots(
+    app: &Arc<model::App>,
+    count: usize,
+) -> Vec<actix_web::rt::task::JoinHandle<()>> {
+    let bots: Vec<_> = (0..count)
+        .map(|index| {
+            let kind = StrategyKind::ALL[index % StrategyKind::ALL.len()];
+            (format!("soak-{}-{}", kind.name(), index + 1), kind)
+        })
+        .collect();
+    let records = bots
+        .iter()
+        .map(|(token, _)| model::UserRecord {
+            token: UserToken::from(token.clone()),
+            profile: model::UserProfile {
+                name: Some(token.clone()),
+                ..Default::default()
+            },
+        })
+        .collect();
+    app.import_users(records).await;
+    simulate::spawn_bots(app, &bots, app.seed())
+}
+
+/// Problems found in a single inspection of the server
+async fn check(
+    app: &model::App,
+    options: &Options,
+    baseline_latency: &mut Option<f64>,
+) -> Vec<String> {
+    let rss = rss_mb();
+    let latency = worst_p99_latency(app);
+    let backlogs = app.log_backlogs().await;
+    info!(
+        "Soak check: rss {rss:?} MB, {} log entries, log subscriber backlogs {backlogs:?}, \
+         worst p99 latency {latency:?}",
+        app.history_len().await,
+    );
+    let mut problems = Vec::new();
+    if let Some(rss) = rss.filter(|&rss| rss > options.max_rss_mb) {
+        problems.push(format!(
+            "rss is {rss:.1} MB, more than {} MB",
+            options.max_rss_mb
+        ));
+    }
+    if let Some(latency) = latency {
+        let baseline = *baseline_latency.get_or_insert(latency);
+        if latency > MIN_REPORTED_LATENCY_SECS && latency > baseline * options.max_latency_drift {
+            problems.push(format!(
+                "p99 latency grew from {baseline:.3}s to {latency:.3}s"
+            ));
+        }
+    }
+    if let Some(&backlog) = backlogs.iter().max() {
+        if backlog > options.max_log_backlog {
+            problems.push(format!(
+                "a log subscriber has {backlog} entries waiting, more than {}",
+                options.max_log_backlog
+            ));
+        }
+    }
+    problems
+}
+
+/// Plays and checks the server until the game ends, problems found are added to `failures`.
+/// The game is ended at the first problem, there is no point in waiting for the rest of it
+pub async fn monitor(app: Arc<model::App>, options: &Options, failures: Arc<Mutex<Vec<String>>>) {
+    let bots = join_bots(&app, options.bots).await;
+    let mut baseline_latency = None;
+    while app.time_left().is_none_or(|time_left| !time_left.is_zero()) {
+        actix_web::rt::time::sleep(CHECK_INTERVAL).await;
+        let problems = check(&app, options, &mut baseline_latency).await;
+        if problems.is_empty() {
+            continue;
+        }
+        for problem in &problems {
+            error!("Soak check failed: {problem}");
+        }
+        failures.lock().unwrap().extend(problems);
+        app.end_game().await;
+        break;
+    }
+    for bot in bots {
+        bot.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(max_log_backlog: usize) -> Options {
+        Options {
+            hours: Some(1.0),
+            max_rss_mb: f64::INFINITY,
+            max_latency_drift: 5.0,
+            bots: 3,
+            max_log_backlog,
+        }
+    }
+
+    fn game(minutes: f64) -> Arc<model::App> {
+        let mut app = model::App::init(
+            model::Config {
+                start_delay_secs: 0.0,
+                ..Default::default()
+            },
+            [UserToken::from("player".to_owned())],
+        );
+        app.set_time_to_run(Some(Duration::from_secs_f64(minutes * 60.0)));
+        Arc::new(app)
+    }
+
+    #[actix_web::test]
+    async fn test_healthy_soak() {
+        tokio::time::pause();
+        let app = game(3.0);
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        monitor(app.clone(), &options(512), failures.clone()).await;
+        assert!(failures.lock().unwrap().is_empty());
+        assert!(!app.is_ended_early());
+        let results = app.results().await;
+        assert_eq!(results.len(), 4);
+        // The bots played, the idle player did not
+        assert_eq!(results["player"], app.config().initial_score);
+        assert!(
+            results
+                .iter()
+                .any(|(user, &score)| user.starts_with("soak-")
+                    && score != app.config().initial_score)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_stuck_subscriber() {
+        tokio::time::pause();
+        let app = game(60.0);
+        // Subscribed and never read
+        let (subscriber, _receiver) = app.log_channel(Some(model::LogOverflow::Drop));
+        app.register_live_logs(subscriber).await;
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let started = tokio::time::Instant::now();
+        monitor(app.clone(), &options(10), failures.clone()).await;
+        // Failed at the first check instead of playing the hour out
+        assert!(started.elapsed() < CHECK_INTERVAL * 2);
+        assert!(app.is_ended_early());
+        let failures = failures.lock().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].contains("more than 10"), "{failures:?}");
+    }
+}
@@ -0,0 +1,241 @@
+//! Warm standby of a primary that saves its state with --save-state. The standby keeps reading
+//! the saved state and takes over the game from it when promoted through the admin api,
+//! listening on its own addresses so that clients only need to re-resolve DNS
+
+use super::parse_interval_secs;
+use actix_web::{
+    get, post,
+    rt::{spawn, time::sleep},
+    web, App, HttpRequest, HttpResponse, HttpServer,
+};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use anyhow::Context;
+use futures::channel::oneshot;
+use itonecup_mobile::model::{self, UserToken};
+use std::{
+    borrow::Borrow,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, clap::Args)]
+#[group(id = "standby_options")]
+pub struct Options {
+    /// Stand by for a primary saving its state to this file with --save-state and take over
+    /// the game when promoted with `POST /api/admin/promote`, which needs --admin-token
+    #[clap(long = "standby-of", conflicts_with = "restore_state")]
+    pub state_file: Option<PathBuf>,
+    /// How often the saved state of the primary is checked for changes
+    #[clap(
+        long = "standby-poll-interval-secs",
+        default_value = "1",
+        value_parser = parse_interval_secs
+    )]
+    pub poll_interval: Duration,
+}
+
+struct Standby {
+    admin_token: UserToken,
+    /// Only these listeners serve the promotion if set, like the admin api of the game
+    admin_listeners: OnceLock<Vec<SocketAddr>>,
+    /// Latest state of the primary that could be read
+    latest: Mutex<Option<model::Checkpoint>>,
+    promoted: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl Standby {
+    fn new(admin_token: UserToken) -> (web::Data<Self>, oneshot::Receiver<()>) {
+        let (sender, receiver) = oneshot::channel();
+        let standby = Self {
+            admin_token,
+            admin_listeners: OnceLock::new(),
+            latest: Mutex::new(None),
+            promoted: Mutex::new(Some(sender)),
+        };
+        (web::Data::new(standby), receiver)
+    }
+}
+
+fn read_state(path: &Path) -> anyhow::Result<model::Checkpoint> {
+    let file = std::fs::File::open(path).context("Failed to open the saved state")?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .context("Failed to parse the saved state")
+}
+
+/// Reads the state again whenever the primary replaces the file
+async fn follow(standby: web::Data<Standby>, path: PathBuf, interval: Duration) {
+    let mut read_modified: Option<SystemTime> = None;
+    loop {
+        let modified = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified.is_some() && modified != read_modified {
+            match read_state(&path) {
+                Ok(checkpoint) => {
+                    debug!("Read the state of the primary at {:.1}s", checkpoint.time);
+                    *standby.latest.lock().unwrap() = Some(checkpoint);
+                    read_modified = modified;
+                }
+                Err(e) => warn!("Failed to read the state of the primary: {e:#}"),
+            }
+        }
+        sleep(interval).await;
+    }
+}
+
+/// Live, so that the standby is not restarted while it waits
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("standby")
+}
+
+/// Never ready, so that no traffic is routed to the standby before it takes over
+#[get("/readyz")]
+async fn readyz() -> HttpResponse {
+    HttpResponse::ServiceUnavailable().body("standby")
+}
+
+#[post("/api/admin/promote")]
+async fn promote(req: HttpRequest, standby: web::Data<Standby>, auth: BearerAuth) -> HttpResponse {
+    let other_listener = standby
+        .admin_listeners
+        .get()
+        .is_some_and(|addrs| !addrs.contains(&req.app_config().local_addr()));
+    if other_listener {
+        return HttpResponse::NotFound().body("Not found");
+    }
+    let expected: &String = standby.admin_token.borrow();
+    if !bool::from(subtle::ConstantTimeEq::ct_eq(
+        expected.as_bytes(),
+        auth.token().as_bytes(),
+    )) {
+        warn!("Someone tried to promote the standby with incorrect token");
+        return HttpResponse::Forbidden().body("Admin token required");
+    }
+    if standby.latest.lock().unwrap().is_none() {
+        return HttpResponse::Conflict().body("No state of the primary has been read yet");
+    }
+    if let Some(promoted) = standby.promoted.lock().unwrap().take() {
+        info!("Promoted, taking over the game");
+        let _ = promoted.send(());
+    }
+    HttpResponse::Accepted().body("Taking over the game")
+}
+
+fn configure(config: &mut web::ServiceConfig) {
+    config.service(healthz).service(readyz).service(promote);
+}
+
+/// Stands by until promoted and returns the state to continue the game from,
+/// or nothing if stopped with Ctrl-C before that
+pub async fn wait(
+    options: &Options,
+    state_file: &Path,
+    addrs: &[SocketAddr],
+    admin_addrs: &[SocketAddr],
+    admin_token: UserToken,
+) -> anyhow::Result<Option<model::Checkpoint>> {
+    let (standby, promoted) = Standby::new(admin_token);
+    let mut server = HttpServer::new({
+        let standby = standby.clone();
+        move || App::new().app_data(standby.clone()).configure(configure)
+    })
+    .workers(1)
+    .bind(addrs)
+    .context("Failed to bind the standby server")?;
+    if !admin_addrs.is_empty() {
+        let public = server.addrs().len();
+        server = server
+            .bind(admin_addrs)
+            .context("Failed to bind the standby admin listeners")?;
+        let _ = standby
+            .admin_listeners
+            .set(server.addrs()[public..].to_vec());
+    }
+    let server = server.run();
+    let handle = server.handle();
+    let server = spawn(server);
+    let follower = spawn(follow(
+        standby.clone(),
+        state_file.to_owned(),
+        options.poll_interval,
+    ));
+    info!("Standing by for the primary saving its state to {state_file:?}");
+    let promoted = match futures::future::select(promoted, server).await {
+        futures::future::Either::Left((promoted, _)) => {
+            // Waiting for the promotion response before the game takes over the addresses
+            handle.stop(true).await;
+            promoted.is_ok()
+        }
+        futures::future::Either::Right(_) => false,
+    };
+    follower.abort();
+    if !promoted {
+        info!("Stopped before being promoted");
+        return Ok(None);
+    }
+    // The primary may have saved once more since the last poll
+    let latest = standby.latest.lock().unwrap().take();
+    let checkpoint = match read_state(state_file) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            warn!("Taking over from the last state read, failed to read it again: {e:#}");
+            latest.context("No state of the primary to take over from")?
+        }
+    };
+    info!("Taking over the game at {:.1}s", checkpoint.time);
+    Ok(Some(checkpoint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+
+    #[actix_web::test]
+    async fn test_promote() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let (standby, mut promoted) = Standby::new(UserToken::from("admin".to_owned()));
+        let service =
+            test::init_service(App::new().app_data(standby.clone()).configure(configure)).await;
+        let promotion = |token: &str| {
+            test::TestRequest::post()
+                .uri("/api/admin/promote")
+                .insert_header(("Authorization", format!("Bearer {token}")))
+                .to_request()
+        };
+
+        let response = test::call_service(&service, promotion("player")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&service, promotion("admin")).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let request = test::TestRequest::get().uri("/readyz").to_request();
+        let response = test::call_service(&service, request).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let app = model::App::init(
+            model::Config {
+                start_delay_secs: 0.0,
+                ..Default::default()
+            },
+            [UserToken::from("player".to_owned())],
+        );
+        let checkpoint = app.checkpoint().await;
+        crate::json::write_atomic(&path, &checkpoint, crate::json::Format::Compact).unwrap();
+        let follower = spawn(follow(standby.clone(), path, Duration::from_millis(10)));
+        while standby.latest.lock().unwrap().is_none() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        follower.abort();
+        assert!(promoted.try_recv().unwrap().is_none());
+        let response = test::call_service(&service, promotion("admin")).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(promoted.try_recv().unwrap(), Some(()));
+        let response = test::call_service(&service, promotion("admin")).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+}
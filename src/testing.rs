@@ -0,0 +1,401 @@
+//! In-process harness for testing bots and the server, no sockets are opened.
+//!
+//! ```ignore
+//! use testing::GameClient;
+//!
+//! let client = testing::start(testing::instant_config(), [UserToken::from("bot".to_owned())]).await;
+//! let value = client.pipe_value("bot", 1).await?.value;
+//! ```
+//!
+//! Bots written against [`GameClient`] can also be tested offline against a [`MockClient`].
+
+use crate::{
+    model::{self, Score},
+    server,
+};
+use actix_http::Request;
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    http::{header::AUTHORIZATION, StatusCode},
+    test, web, App,
+};
+use futures::channel::mpsc;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::sleep;
+
+/// Actions finish right away and the game runs until the test is done
+pub fn instant_config() -> model::Config {
+    model::Config {
+        min_delay_secs: 0.0,
+        max_delay_secs: 0.0,
+        pipe_value_delay_secs: 0.0,
+        peek_delay_secs: 0.0,
+        start_delay_secs: 0.0,
+        post_collect_lockout_secs: 0.0,
+        time_to_run: None,
+        ..Default::default()
+    }
+}
+
+/// Failed api call
+#[derive(Debug, thiserror::Error)]
+#[error("{status}: {message}")]
+pub struct ApiError {
+    pub status: StatusCode,
+    /// Only set for errors of the game, not for requests that did not reach it
+    pub code: Option<model::ErrorCode>,
+    pub message: String,
+}
+
+/// Game api as a bot sees it, served in-process by [`TestClient`] or canned by [`MockClient`],
+/// so strategies written against it can be tested either way
+#[allow(async_fn_in_trait)]
+pub trait GameClient {
+    async fn collect(&self, user: &str, pipe_id: usize)
+        -> Result<model::CollectResponse, ApiError>;
+
+    async fn pipe_value(
+        &self,
+        user: &str,
+        pipe_id: usize,
+    ) -> Result<model::PipeValueResponse, ApiError>;
+
+    async fn apply_modifier(
+        &self,
+        user: &str,
+        pipe_id: usize,
+        modifier: model::Modifier,
+    ) -> Result<model::ApplyModifierResponse, ApiError>;
+}
+
+/// Game api served in-process, every request is made as the user given to it
+pub struct TestClient<S> {
+    app: S,
+    state: web::Data<model::App>,
+}
+
+/// Starts a game, anyone may play if no users are given (unless disabled in the config)
+pub async fn start(
+    config: model::Config,
+    users: impl IntoIterator<Item = model::UserToken>,
+) -> TestClient<
+    impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error>,
+> {
+    let state = web::Data::new(model::App::init(config, users));
+    let app = test::init_service(App::new().configure({
+        let state = state.clone();
+        move |config| server::configure(config, state)
+    }))
+    .await;
+    TestClient { app, state }
+}
+
+impl<S, B> TestClient<S>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    /// The game itself, for looking behind the api
+    pub fn state(&self) -> &model::App {
+        &self.state
+    }
+
+    /// Every log entry of the game, starting with the ones logged so far
+    pub async fn subscribe_logs(&self) -> mpsc::UnboundedReceiver<model::LogEntry> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.state.register_logs(sender).await;
+        receiver
+    }
+
+    /// Sends any request as the user, for endpoints without a method of their own
+    pub async fn call<T: DeserializeOwned>(
+        &self,
+        user: &str,
+        request: test::TestRequest,
+    ) -> Result<T, ApiError> {
+        let request = request
+            .insert_header((AUTHORIZATION, format!("Bearer {user}")))
+            .to_request();
+        let response = test::call_service(&self.app, request).await;
+        let status = response.status();
+        let body = test::try_read_body(response).await.map_err(|_| ApiError {
+            status,
+            code: None,
+            message: "Failed to read the response".to_owned(),
+        })?;
+        if status.is_success() {
+            return serde_json::from_slice(&body).map_err(|e| ApiError {
+                status,
+                code: None,
+                message: format!("Unexpected response: {e}"),
+            });
+        }
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            code: model::ErrorCode,
+            message: String,
+        }
+        Err(match serde_json::from_slice::<ErrorBody>(&body) {
+            Ok(error) => ApiError {
+                status,
+                code: Some(error.code),
+                message: error.message,
+            },
+            Err(_) => ApiError {
+                status,
+                code: None,
+                message: String::from_utf8_lossy(&body).into_owned(),
+            },
+        })
+    }
+}
+
+impl<S, B> GameClient for TestClient<S>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    async fn collect(
+        &self,
+        user: &str,
+        pipe_id: usize,
+    ) -> Result<model::CollectResponse, ApiError> {
+        self.call(
+            user,
+            test::TestRequest::put().uri(&format!("/api/pipe/{pipe_id}")),
+        )
+        .await
+    }
+
+    async fn pipe_value(
+        &self,
+        user: &str,
+        pipe_id: usize,
+    ) -> Result<model::PipeValueResponse, ApiError> {
+        let request = test::TestRequest::get().uri(&format!("/api/pipe/{pipe_id}/value"));
+        self.call(user, request).await
+    }
+
+    async fn apply_modifier(
+        &self,
+        user: &str,
+        pipe_id: usize,
+        modifier: model::Modifier,
+    ) -> Result<model::ApplyModifierResponse, ApiError> {
+        let request = test::TestRequest::post()
+            .uri(&format!("/api/pipe/{pipe_id}/modifier"))
+            .set_json(serde_json::json!({ "type": modifier }));
+        self.call(user, request).await
+    }
+}
+
+/// Part of the api a call goes to, for scripting errors of a [`MockClient`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Collect,
+    PipeValue,
+    ApplyModifier,
+}
+
+/// Canned game api for testing bots without a game: pipes are worth fixed values, errors
+/// are returned as scripted and every call takes `latency`. Collecting does not change
+/// the values and modifiers have no effect.
+#[derive(Default)]
+pub struct MockClient {
+    values: HashMap<usize, Score>,
+    latency: Duration,
+    errors: Mutex<HashMap<Endpoint, VecDeque<model::Error>>>,
+    calls: Mutex<Vec<(String, Endpoint, usize)>>,
+}
+
+impl MockClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pipes without a value are not found
+    pub fn with_value(mut self, pipe_id: usize, value: Score) -> Self {
+        self.values.insert(pipe_id, value);
+        self
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// The next call to the endpoint fails with the error, the status is the default one
+    /// of the server. Errors scripted for the same endpoint are returned in order.
+    pub fn fail_next(self, endpoint: Endpoint, error: model::Error) -> Self {
+        self.errors
+            .lock()
+            .unwrap()
+            .entry(endpoint)
+            .or_default()
+            .push_back(error);
+        self
+    }
+
+    /// User, endpoint and pipe of every call made so far
+    pub fn calls(&self) -> Vec<(String, Endpoint, usize)> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    async fn call(
+        &self,
+        user: &str,
+        endpoint: Endpoint,
+        pipe_id: usize,
+    ) -> Result<Score, ApiError> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push((user.to_owned(), endpoint, pipe_id));
+        sleep(self.latency).await;
+        let scripted = self
+            .errors
+            .lock()
+            .unwrap()
+            .get_mut(&endpoint)
+            .and_then(VecDeque::pop_front);
+        let value = self.values.get(&pipe_id).copied();
+        let error = scripted.or_else(|| {
+            value.is_none().then(|| model::Error::PipeNotFound {
+                pipe_id: model::PipeId::new(pipe_id),
+            })
+        });
+        match error {
+            Some(error) => Err(ApiError {
+                status: server::default_status(error.code()),
+                code: Some(error.code()),
+                message: error.to_string(),
+            }),
+            None => Ok(value.unwrap()),
+        }
+    }
+}
+
+impl GameClient for MockClient {
+    async fn collect(
+        &self,
+        user: &str,
+        pipe_id: usize,
+    ) -> Result<model::CollectResponse, ApiError> {
+        let value = self.call(user, Endpoint::Collect, pipe_id).await?;
+        Ok(model::CollectResponse { value, refund: 0 })
+    }
+
+    async fn pipe_value(
+        &self,
+        user: &str,
+        pipe_id: usize,
+    ) -> Result<model::PipeValueResponse, ApiError> {
+        let value = self.call(user, Endpoint::PipeValue, pipe_id).await?;
+        Ok(model::PipeValueResponse { value, owner: None })
+    }
+
+    async fn apply_modifier(
+        &self,
+        user: &str,
+        pipe_id: usize,
+        _modifier: model::Modifier,
+    ) -> Result<model::ApplyModifierResponse, ApiError> {
+        self.call(user, Endpoint::ApplyModifier, pipe_id).await?;
+        Ok(model::ApplyModifierResponse {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_client() {
+        crate::logger::init_for_tests();
+        let client = start(
+            model::Config {
+                reverse_cost: 1_000_000,
+                ..instant_config()
+            },
+            [model::UserToken::from("bot".to_owned())],
+        )
+        .await;
+        let mut logs = client.subscribe_logs().await;
+        let value = client.pipe_value("bot", 1).await.unwrap().value;
+        let collected = client.collect("bot", 1).await.unwrap().value;
+        assert_eq!(collected, value);
+
+        let error = client
+            .apply_modifier("bot", 1, model::Modifier::Reverse)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, Some(model::ErrorCode::NotEnoughScore));
+        let error = client.collect("bot", 1000).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+        assert_eq!(error.code, Some(model::ErrorCode::PipeNotFound));
+
+        let mut collected = false;
+        while let Ok(entry) = logs.try_recv() {
+            collected |= matches!(entry.msg, model::LogMessage::CollectEnd { .. });
+        }
+        assert!(collected);
+    }
+
+    /// Collects the most valuable of the pipes, the way a bot under test would
+    async fn collect_best(
+        client: &impl GameClient,
+        user: &str,
+        pipes: &[usize],
+    ) -> Result<Score, ApiError> {
+        let mut best = None;
+        for &pipe_id in pipes {
+            let value = client.pipe_value(user, pipe_id).await?.value;
+            if best.is_none_or(|(_, best_value)| value > best_value) {
+                best = Some((pipe_id, value));
+            }
+        }
+        let (pipe_id, _) = best.expect("No pipes to collect");
+        Ok(client.collect(user, pipe_id).await?.value)
+    }
+
+    #[actix_web::test]
+    async fn test_mock_client() {
+        tokio::time::pause();
+        let client = MockClient::new()
+            .with_value(1, 10)
+            .with_value(2, 30)
+            .with_latency(Duration::from_millis(100))
+            .fail_next(Endpoint::Collect, model::Error::GamePaused);
+
+        let started = tokio::time::Instant::now();
+        let error = collect_best(&client, "bot", &[1, 2]).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error.code, Some(model::ErrorCode::GamePaused));
+        assert_eq!(collect_best(&client, "bot", &[1, 2]).await.unwrap(), 30);
+        // Three calls per attempt
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(600) && elapsed < Duration::from_millis(700),
+            "{elapsed:?}"
+        );
+        let collected: Vec<_> = client
+            .calls()
+            .into_iter()
+            .filter(|(_, endpoint, _)| *endpoint == Endpoint::Collect)
+            .map(|(_, _, pipe_id)| pipe_id)
+            .collect();
+        assert_eq!(collected, [2, 2]);
+
+        let Err(error) = client.pipe_value("bot", 3).await else {
+            panic!("Pipe 3 should not be found");
+        };
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+        assert_eq!(error.code, Some(model::ErrorCode::PipeNotFound));
+    }
+}
@@ -0,0 +1,248 @@
+//! Rendering saved game logs into videos
+//!
+//! Frames are drawn into a raw RGB buffer and piped into `ffmpeg`, which has to be installed.
+//! Left part of a frame shows pipe values (highlighted while collected),
+//! right part is the score race with one bar per user.
+
+use crate::{
+    model::{LogMessage, Score},
+    replay::{self, State},
+};
+use anyhow::Context;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Options {
+    #[clap(long, default_value = "30", value_parser = clap::value_parser!(u32).range(1..))]
+    pub fps: u32,
+    /// Even, as `yuv420p` needs
+    #[clap(long, default_value = "1280", value_parser = parse_dimension)]
+    pub width: usize,
+    /// Even, as `yuv420p` needs
+    #[clap(long, default_value = "720", value_parser = parse_dimension)]
+    pub height: usize,
+    /// Game seconds per video second
+    #[clap(long, default_value = "1", value_parser = crate::parse_speed)]
+    pub speed: f64,
+}
+
+fn parse_dimension(arg: &str) -> Result<usize, String> {
+    let pixels: usize = arg.parse().map_err(|e| format!("{e}"))?;
+    if pixels > 0 && pixels.is_multiple_of(2) {
+        Ok(pixels)
+    } else {
+        Err(format!(
+            "expected a positive even number of pixels, got {arg}"
+        ))
+    }
+}
+
+type Color = [u8; 3];
+
+const BACKGROUND: Color = [24, 24, 32];
+const PIPE: Color = [64, 128, 255];
+const COLLECTED: Color = [255, 208, 64];
+const NEGATIVE: Color = [224, 64, 64];
+
+struct Frame {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Frame {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height * 3],
+        }
+    }
+
+    fn fill(&mut self, color: Color) {
+        for pixel in self.pixels.chunks_exact_mut(3) {
+            pixel.copy_from_slice(&color);
+        }
+    }
+
+    fn fill_rect(&mut self, x: f64, y: f64, width: f64, height: f64, color: Color) {
+        let clamp = |value: f64, max: usize| (value.max(0.0) as usize).min(max);
+        let (x0, x1) = (clamp(x, self.width), clamp(x + width, self.width));
+        let (y0, y1) = (clamp(y, self.height), clamp(y + height, self.height));
+        for y in y0..y1 {
+            let row = y * self.width * 3;
+            for pixel in self.pixels[row + x0 * 3..row + x1 * 3].chunks_exact_mut(3) {
+                pixel.copy_from_slice(&color);
+            }
+        }
+    }
+}
+
+fn hue_color(hue: f64) -> Color {
+    let channel = |offset: f64| {
+        let k = (offset + hue * 6.0) % 6.0;
+        let value = 1.0 - (k.min(4.0 - k).clamp(0.0, 1.0));
+        (value * 255.0) as u8
+    };
+    [channel(5.0), channel(3.0), channel(1.0)]
+}
+
+struct Scale {
+    min_value: Score,
+    max_value: Score,
+    max_score: u64,
+    user_colors: BTreeMap<replay::User, Color>,
+}
+
+fn render(frame: &mut Frame, state: &State, scale: &Scale) {
+    frame.fill(BACKGROUND);
+    let width = frame.width as f64;
+    let height = frame.height as f64;
+    let margin = 16.0;
+
+    let pipes_width = width * 0.4;
+    let pipe_count = state.pipes.len().max(1) as f64;
+    let slot = (pipes_width - margin) / pipe_count;
+    for (index, (id, pipe)) in state.pipes.iter().enumerate() {
+        // The bounds are still the sentinels if no pipe was ever updated
+        let range = scale.max_value.saturating_sub(scale.min_value).max(1) as f64;
+        let fraction = (pipe.value.saturating_sub(scale.min_value) as f64 / range).clamp(0.0, 1.0);
+        let bar_height = (height - 2.0 * margin) * (0.05 + 0.95 * fraction);
+        let x = margin + index as f64 * slot;
+        let collected = state.collecting.values().any(|pipe_id| pipe_id == id);
+        let color = if collected { COLLECTED } else { PIPE };
+        frame.fill_rect(
+            x,
+            height - margin - bar_height,
+            slot * 0.8,
+            bar_height,
+            color,
+        );
+        for modifier in 0..pipe.modifiers.len() {
+            let size = slot * 0.8 / 4.0;
+            frame.fill_rect(
+                x + modifier as f64 * size,
+                margin,
+                size * 0.8,
+                size * 0.8,
+                NEGATIVE,
+            );
+        }
+    }
+
+    let mut scores: Vec<_> = state.scores.iter().collect();
+    scores.sort_by(|(_, a), (_, b)| b.cmp(a));
+    let left = pipes_width + margin;
+    let bars_width = width - left - margin;
+    let row = ((height - 2.0 * margin) / scores.len().max(1) as f64).min(64.0);
+    for (index, (user, &score)) in scores.into_iter().enumerate() {
+        let fraction = (score.unsigned_abs() as f64 / scale.max_score.max(1) as f64).min(1.0);
+        let color = if score < 0 {
+            NEGATIVE
+        } else {
+            scale.user_colors[user]
+        };
+        frame.fill_rect(
+            left,
+            margin + index as f64 * row,
+            (bars_width * fraction).max(2.0),
+            row * 0.8,
+            color,
+        );
+    }
+}
+
+pub fn export(
+    log: impl AsRef<Path>,
+    out: impl AsRef<Path>,
+    options: &Options,
+) -> anyhow::Result<()> {
+    let entries = replay::read_log(log)?;
+    let mut scale = Scale {
+        min_value: Score::MAX,
+        max_value: Score::MIN,
+        max_score: 0,
+        user_colors: BTreeMap::new(),
+    };
+    for entry in &entries {
+        match &entry.msg {
+            LogMessage::UpdatePipe { state, .. } => {
+                scale.min_value = scale.min_value.min(state.value);
+                scale.max_value = scale.max_value.max(state.value);
+            }
+            LogMessage::UpdateUser { user, state, .. } => {
+                scale.max_score = scale.max_score.max(state.score.unsigned_abs());
+                scale.user_colors.insert(user.clone(), BACKGROUND);
+            }
+            _ => {}
+        }
+    }
+    let user_count = scale.user_colors.len() as f64;
+    for (index, color) in scale.user_colors.values_mut().enumerate() {
+        *color = hue_color(index as f64 / user_count);
+    }
+
+    let duration = entries.last().map_or(0.0, |entry| entry.time);
+    let frames = (duration / options.speed * options.fps as f64).ceil() as usize + 1;
+    let mut ffmpeg = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error"])
+        .args(["-f", "rawvideo", "-pix_fmt", "rgb24"])
+        .args(["-s", &format!("{}x{}", options.width, options.height)])
+        .args(["-r", &options.fps.to_string()])
+        .args(["-i", "-", "-pix_fmt", "yuv420p"])
+        .arg(out.as_ref())
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to start ffmpeg")?;
+    let mut input = ffmpeg.stdin.take().unwrap();
+    let mut state = State::default();
+    let mut entries = entries.iter().peekable();
+    let mut frame = Frame::new(options.width, options.height);
+    let sent = (0..frames).try_for_each(|index| {
+        let time = index as f64 / options.fps as f64 * options.speed;
+        while let Some(entry) = entries.next_if(|entry| entry.time <= time) {
+            state.apply(entry);
+        }
+        render(&mut frame, &state, &scale);
+        input.write_all(&frame.pixels)
+    });
+    std::mem::drop(input);
+    // A failed ffmpeg closes its input early, its own error says why
+    let output = ffmpeg.wait_with_output()?;
+    anyhow::ensure!(
+        output.status.success(),
+        "ffmpeg failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    sent.context("Failed to send frame to ffmpeg")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extreme_scores() {
+        let state = State {
+            scores: [("a".to_owned(), Score::MIN), ("b".to_owned(), Score::MAX)].into(),
+            ..Default::default()
+        };
+        let scale = Scale {
+            min_value: Score::MAX,
+            max_value: Score::MIN,
+            max_score: Score::MIN.unsigned_abs(),
+            user_colors: [("a".to_owned(), PIPE), ("b".to_owned(), COLLECTED)].into(),
+        };
+        let mut frame = Frame::new(320, 180);
+        render(&mut frame, &state, &scale);
+        assert!(frame.pixels.chunks_exact(3).any(|pixel| pixel == NEGATIVE));
+        assert_eq!(parse_dimension("720"), Ok(720));
+        assert!(parse_dimension("721").is_err());
+    }
+}
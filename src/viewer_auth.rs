@@ -0,0 +1,173 @@
+//! Short-lived signed tokens for sharing the viewer and the logs stream
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Only the `/logs` streams, websocket and server-sent events
+    Logs,
+    /// Static viewer files and the `/logs` streams
+    Viewer,
+}
+
+impl Scope {
+    fn allows(self, required: Scope) -> bool {
+        self == Scope::Viewer || self == required
+    }
+}
+
+pub struct ViewerAuth {
+    secret: Vec<u8>,
+}
+
+impl ViewerAuth {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    fn mac(&self, expires: u64, scope: Scope, game: Option<&str>) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        match game {
+            Some(game) => mac.update(format!("{expires}:{scope:?}:{game}").as_bytes()),
+            None => mac.update(format!("{expires}:{scope:?}").as_bytes()),
+        }
+        mac
+    }
+
+    /// Creates a token valid for the given time
+    pub fn sign(&self, scope: Scope, ttl: Duration) -> String {
+        self.sign_for(scope, None, ttl)
+    }
+
+    /// Creates a token for a lobby game, or for the main game without one.
+    /// Each game is a realm of its own, a token opens no other game
+    pub fn sign_for(&self, scope: Scope, game: Option<&str>, ttl: Duration) -> String {
+        let expires = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let scope_name = serde_json::to_value(scope).unwrap();
+        let game_part = game.map(|game| format!("{game}.")).unwrap_or_default();
+        format!(
+            "{expires}.{}.{game_part}{}",
+            scope_name.as_str().unwrap(),
+            hex::encode(self.mac(expires, scope, game).finalize().into_bytes()),
+        )
+    }
+
+    pub fn verify(&self, token: &str, required: Scope) -> bool {
+        self.verify_for(token, required, None)
+    }
+
+    /// Checks a token for the lobby game, or for the main game without one
+    pub fn verify_for(&self, token: &str, required: Scope, game: Option<&str>) -> bool {
+        // Game ids have no dots, so tokens of lobby games have one part more
+        let parts: Vec<&str> = token.split('.').collect();
+        let (expires, scope, token_game, signature) = match parts[..] {
+            [expires, scope, signature] => (expires, scope, None, signature),
+            [expires, scope, game, signature] => (expires, scope, Some(game), signature),
+            _ => return false,
+        };
+        if token_game != game {
+            return false;
+        }
+        let Ok(expires) = expires.parse::<u64>() else {
+            return false;
+        };
+        let Ok(scope) = serde_json::from_value::<Scope>(scope.into()) else {
+            return false;
+        };
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        if self
+            .mac(expires, scope, game)
+            .verify_slice(&signature)
+            .is_err()
+        {
+            return false;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        now < expires && scope.allows(required)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn test_scopes() {
+        let auth = ViewerAuth::new("secret");
+        let logs = auth.sign(Scope::Logs, HOUR);
+        assert!(auth.verify(&logs, Scope::Logs));
+        assert!(!auth.verify(&logs, Scope::Viewer));
+        let viewer = auth.sign(Scope::Viewer, HOUR);
+        assert!(auth.verify(&viewer, Scope::Viewer));
+        assert!(auth.verify(&viewer, Scope::Logs));
+        // The scope is signed, so it can't be widened
+        let widened = logs.replacen(".logs.", ".viewer.", 1);
+        assert!(!auth.verify(&widened, Scope::Viewer));
+    }
+
+    #[test]
+    fn test_game_realms() {
+        let auth = ViewerAuth::new("secret");
+        let game = auth.sign_for(Scope::Viewer, Some("group-a"), HOUR);
+        assert!(auth.verify_for(&game, Scope::Logs, Some("group-a")));
+        assert!(!auth.verify_for(&game, Scope::Logs, Some("group-b")));
+        assert!(!auth.verify(&game, Scope::Logs));
+        let main = auth.sign(Scope::Viewer, HOUR);
+        assert!(!auth.verify_for(&main, Scope::Logs, Some("group-a")));
+        // The game is signed, so a token can't be moved to another one
+        let moved = game.replacen(".group-a.", ".group-b.", 1);
+        assert!(!auth.verify_for(&moved, Scope::Logs, Some("group-b")));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let auth = ViewerAuth::new("secret");
+        assert!(!auth.verify(&auth.sign(Scope::Logs, Duration::ZERO), Scope::Logs));
+        // Extending the expiry breaks the signature
+        let token = auth.sign(Scope::Logs, Duration::ZERO);
+        let (expires, rest) = token.split_once('.').unwrap();
+        let extended = format!("{}.{rest}", expires.parse::<u64>().unwrap() + 3600);
+        assert!(!auth.verify(&extended, Scope::Logs));
+    }
+
+    #[test]
+    fn test_tampered_and_malformed() {
+        let auth = ViewerAuth::new("secret");
+        let token = auth.sign(Scope::Viewer, HOUR);
+        assert!(!ViewerAuth::new("other").verify(&token, Scope::Viewer));
+        let mut tampered = token.clone().into_bytes();
+        let last = tampered.last_mut().unwrap();
+        *last = if *last == b'0' { b'1' } else { b'0' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(!auth.verify(&tampered, Scope::Viewer));
+        let (head, _) = token.rsplit_once('.').unwrap();
+        for malformed in [
+            String::new(),
+            "garbage".to_owned(),
+            head.to_owned(),
+            format!("{head}."),
+            format!("{head}.not-hex"),
+            format!("soon.viewer.{}", token.rsplit_once('.').unwrap().1),
+            token.replacen(".viewer.", ".admin.", 1),
+        ] {
+            assert!(!auth.verify(&malformed, Scope::Viewer), "{malformed}");
+        }
+    }
+}
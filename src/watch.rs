@@ -0,0 +1,355 @@
+//! Live terminal dashboard of a running game, fed by the `/logs` stream
+
+use itonecup_mobile::{
+    http_client::{Client, WebSocket},
+    model::{LogEntry, LogMessage, Observation},
+    replay::{self, State},
+};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::Stylize,
+    text::Line,
+    widgets::{Block, List, Row, Table},
+    DefaultTerminal, Frame,
+};
+use std::{collections::VecDeque, sync::mpsc, time::Duration};
+
+const RECENT_EVENTS: usize = 10;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+fn describe(entry: &LogEntry<replay::User>) -> Option<String> {
+    let description = match &entry.msg {
+        LogMessage::CollectStart {
+            user,
+            pipe_id,
+            delay,
+        } => format!("{user} started collecting pipe #{pipe_id} ({delay:?})"),
+        LogMessage::CollectEnd { user, pipe_id } => {
+            format!("{user} collected pipe #{pipe_id}")
+        }
+        LogMessage::InsurancePayout {
+            user,
+            pipe_id,
+            refund,
+        } => format!("{user} got {refund} insurance refund for pipe #{pipe_id}"),
+        LogMessage::ScoreStolen {
+            user,
+            victim,
+            pipe_id,
+            amount,
+        } => format!("{user} stole {amount} from {victim} at pipe #{pipe_id}"),
+        LogMessage::MarketEvent {
+            modifier,
+            cost_multiplier,
+            duration,
+            ..
+        } => format!("{modifier:?} costs x{cost_multiplier} for {duration:?}"),
+        LogMessage::MarketEventEnd { modifier, cost } => {
+            format!("{modifier:?} costs {cost} again")
+        }
+        LogMessage::GameEnding { seconds_left } => {
+            format!("Game ends in {seconds_left:.1}s")
+        }
+        LogMessage::Bankrupt {
+            user, locked_until, ..
+        } => match locked_until {
+            Some(time) => format!("{user} went bankrupt, locked out until {time:.1}s"),
+            None => format!("{user} went bankrupt"),
+        },
+        LogMessage::ActionFailed {
+            user,
+            action,
+            pipe_id,
+            reason,
+        } => format!("{user} failed {action:?} on pipe #{pipe_id}: {reason:?}"),
+        LogMessage::PhaseStarted { phase } => format!(
+            "Phase {:?}: costs x{}, values x{}",
+            phase.name, phase.cost_multiplier, phase.value_multiplier
+        ),
+        LogMessage::GlobalEvent { event } => format!(
+            "{}: values x{}, delays x{} for {:.1}s",
+            event.effect.name,
+            event.effect.value_multiplier,
+            event.effect.delay_multiplier,
+            event.effect.duration_secs
+        ),
+        LogMessage::Overtime {
+            tied_score,
+            seconds_left,
+        } => format!("Tied at {tied_score}, overtime! Game ends in {seconds_left:.1}s"),
+        LogMessage::ActionAborted {
+            user,
+            action,
+            pipe_id,
+            reason,
+        } => format!("{user}'s {action:?} on pipe #{pipe_id} aborted ({reason:?})"),
+        LogMessage::CollectAborted { user, pipe_id, .. } => {
+            format!("{user} went away while collecting pipe #{pipe_id}, rolled back")
+        }
+        LogMessage::UserIdle { user, idle_secs } => {
+            format!("{user} made no requests for {idle_secs:.1}s")
+        }
+        LogMessage::ValueObserved {
+            user,
+            pipe_id,
+            observation,
+        } => match observation {
+            Observation::PipeValue { value } => {
+                format!("{user} learned that pipe #{pipe_id} is worth {value}")
+            }
+            Observation::Peek { bucket } => {
+                format!("{user} peeked {bucket:?} value at pipe #{pipe_id}")
+            }
+        },
+        LogMessage::UserRemoved { user } => format!("{user} was removed from the game"),
+        LogMessage::UserRegistered { user, name } => match name {
+            Some(name) => format!("{name} joined as {user}"),
+            None => format!("{user} joined"),
+        },
+        LogMessage::RemovePipe { id } => format!("Pipe #{id} was retired"),
+        LogMessage::GamePaused => "Game paused".to_owned(),
+        LogMessage::GameResumed { paused_secs } => {
+            format!("Game resumed after {paused_secs:.1}s")
+        }
+        LogMessage::RoundStart {
+            round,
+            duration_secs,
+        } => format!("Round {round} started, lasts {duration_secs:.1}s"),
+        LogMessage::RoundEnd { round } => format!("Round {round} ended"),
+        LogMessage::GameStart { players } => format!("Game started with {players} players"),
+        LogMessage::GameEnd => "Game over".to_owned(),
+        LogMessage::GameOver { results } => {
+            let standings: Vec<String> = results
+                .iter()
+                .map(|standing| format!("#{} {} {}", standing.rank, standing.user, standing.score))
+                .collect();
+            format!("Final standings: {}", standings.join(", "))
+        }
+        LogMessage::Podium { rank, user, score } => format!("#{rank}: {user} with {score}"),
+        LogMessage::UpdatePipe { .. }
+        | LogMessage::UpdatePrices { .. }
+        | LogMessage::UpdateUser { .. }
+        | LogMessage::RankChanged { .. } => return None,
+    };
+    Some(format!("[{:>7.2}] {description}", entry.time))
+}
+
+fn draw(frame: &mut Frame, state: &State, events: &VecDeque<String>, closed: bool) {
+    let [header, tables, recent] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(4),
+        Constraint::Length(RECENT_EVENTS as u16 + 2),
+    ])
+    .areas(frame.area());
+    let [pipes_area, scores_area] =
+        Layout::horizontal([Constraint::Percentage(70), Constraint::Percentage(30)]).areas(tables);
+
+    let mut title = format!("Game time: {:.1}s", state.time);
+    if closed {
+        title.push_str(" | Connection closed, press q to quit");
+    }
+    frame.render_widget(Line::from(title).bold(), header);
+
+    let pipes = state.pipes.iter().map(|(id, pipe)| {
+        let mut modifiers: Vec<String> = pipe
+            .modifiers
+            .iter()
+            .map(|(modifier, uses)| match pipe.applied_by.get(modifier) {
+                Some(application) => format!("{modifier:?}:{uses}({})", application.user),
+                None => format!("{modifier:?}:{uses}"),
+            })
+            .collect();
+        modifiers.sort();
+        let collecting: Vec<&str> = state
+            .collecting
+            .iter()
+            .filter(|(_, pipe_id)| *pipe_id == id)
+            .map(|(user, _)| user.as_str())
+            .collect();
+        Row::new([
+            format!("#{id}"),
+            pipe.value.to_string(),
+            format!("{:.2}s", pipe.base_delay.as_secs_f64()),
+            format!("{:?}", pipe.direction),
+            modifiers.join(" "),
+            collecting.join(", "),
+        ])
+    });
+    let pipes = Table::new(
+        pipes,
+        [
+            Constraint::Length(5),
+            Constraint::Length(7),
+            Constraint::Length(8),
+            Constraint::Length(9),
+            Constraint::Fill(2),
+            Constraint::Fill(1),
+        ],
+    )
+    .header(
+        Row::new([
+            "Pipe",
+            "Value",
+            "Delay",
+            "Direction",
+            "Modifiers",
+            "Collecting",
+        ])
+        .bold(),
+    )
+    .block(Block::bordered().title("Pipes"));
+    frame.render_widget(pipes, pipes_area);
+
+    let mut scores: Vec<_> = state.scores.iter().collect();
+    scores.sort_by(|(_, a), (_, b)| b.cmp(a));
+    let scores = scores.into_iter().map(|(user, score)| {
+        let name = state.names.get(user).unwrap_or(user);
+        Row::new([name.clone(), score.to_string()])
+    });
+    let scores = Table::new(scores, [Constraint::Fill(1), Constraint::Length(7)])
+        .header(Row::new(["User", "Score"]).bold())
+        .block(Block::bordered().title("Scores"));
+    frame.render_widget(scores, scores_area);
+
+    let events = List::new(events.iter().map(String::as_str))
+        .block(Block::bordered().title("Recent events"));
+    frame.render_widget(events, recent);
+}
+
+/// Reads the log stream on its own thread, the entries are sent to `entries` until the
+/// connection closes or the receiver is gone
+fn stream_logs(
+    mut websocket: WebSocket,
+    entries: mpsc::Sender<anyhow::Result<LogEntry<replay::User>>>,
+) {
+    loop {
+        let entry = match websocket.receive() {
+            Ok(Some(message)) => replay::parse_entry(&message),
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+        let failed = entry.is_err();
+        if entries.send(entry).is_err() || failed {
+            return;
+        }
+    }
+}
+
+fn quit_requested() -> std::io::Result<bool> {
+    if !event::poll(REDRAW_INTERVAL)? {
+        return Ok(false);
+    }
+    Ok(match event::read()? {
+        Event::Key(key) if key.kind == KeyEventKind::Press => {
+            matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL))
+        }
+        _ => false,
+    })
+}
+
+fn show(
+    terminal: &mut DefaultTerminal,
+    entries: mpsc::Receiver<anyhow::Result<LogEntry<replay::User>>>,
+) -> anyhow::Result<()> {
+    let mut state = State::default();
+    let mut events = VecDeque::new();
+    let mut closed = false;
+    loop {
+        loop {
+            let entry = match entries.try_recv() {
+                Ok(entry) => entry?,
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    closed = true;
+                    break;
+                }
+            };
+            if let Some(event) = describe(&entry) {
+                if events.len() == RECENT_EVENTS {
+                    events.pop_front();
+                }
+                events.push_back(event);
+            }
+            state.apply(&entry);
+        }
+        terminal.draw(|frame| draw(frame, &state, &events, closed))?;
+        if quit_requested()? {
+            return Ok(());
+        }
+    }
+}
+
+pub fn run(url: &str, viewer_token: Option<String>) -> anyhow::Result<()> {
+    let client = Client::new(url, None);
+    let path = match viewer_token {
+        Some(token) => format!("/logs?token={token}"),
+        None => "/logs".to_owned(),
+    };
+    let websocket = client.websocket(&path)?;
+    let (sender, entries) = mpsc::channel();
+    std::thread::spawn(move || stream_logs(websocket, sender));
+    let mut terminal = ratatui::init();
+    let result = show(&mut terminal, entries);
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itonecup_mobile::model::{Modifier, Pipe, PipeDirection, PipeId};
+    use ratatui::{backend::TestBackend, Terminal};
+
+    #[test]
+    fn test_draw() {
+        let mut state = State {
+            time: 12.5,
+            ..Default::default()
+        };
+        let pipe = Pipe {
+            value: 42,
+            base_delay: Duration::from_millis(1500),
+            direction: PipeDirection::Up,
+            modifiers: [(Modifier::Insurance, 2)].into(),
+            applied_by: Default::default(),
+            insurance: Default::default(),
+            locked_until: None,
+            owner: None,
+        };
+        let id = PipeId::new(7).unwrap();
+        state.pipes.insert(id, pipe);
+        state.collecting.insert("alice".to_owned(), id);
+        state.scores.insert("alice".to_owned(), 90);
+        state.scores.insert("bob".to_owned(), 150);
+        state.names.insert("bob".to_owned(), "Bobby".to_owned());
+        let events = VecDeque::from(["[  12.00] alice collected pipe #3".to_owned()]);
+
+        let mut terminal = Terminal::new(TestBackend::new(120, 30)).unwrap();
+        terminal
+            .draw(|frame| draw(frame, &state, &events, true))
+            .unwrap();
+        let lines: Vec<String> = terminal
+            .backend()
+            .buffer()
+            .content
+            .chunks(120)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+        let row = |needle: &str| {
+            lines
+                .iter()
+                .position(|line| line.contains(needle))
+                .unwrap_or_else(|| panic!("{needle} not drawn:\n{}", lines.join("\n")))
+        };
+        assert_eq!(row("Game time: 12.5s | Connection closed"), 0);
+        let pipe_row = &lines[row("#7")];
+        assert!(pipe_row.contains("42") && pipe_row.contains("1.50s"));
+        assert!(pipe_row.contains("Up") && pipe_row.contains("Insurance:2"));
+        assert!(pipe_row.contains("alice"));
+        // The best score comes first, under the display name
+        assert!(row("Bobby") < row("90"));
+        row("alice collected pipe #3");
+    }
+}
@@ -0,0 +1,301 @@
+//! Notifying users with http callbacks when their score crosses configured milestones,
+//! and operators when a user goes idle
+
+use crate::{
+    http_client::{self, Client},
+    model::{self, LogMessage, Score, UserToken},
+};
+use actix_web::rt::task::spawn_blocking;
+use futures::{channel::mpsc, StreamExt};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tracing::{debug, error, warn};
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Payload {
+    pub time: f64,
+    pub milestone: Score,
+    pub direction: Direction,
+    pub score: Score,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IdlePayload {
+    pub time: f64,
+    pub user: UserToken,
+    pub idle_secs: f64,
+}
+
+fn crossed(old: Score, new: Score, milestone: Score) -> Option<Direction> {
+    if old < milestone && milestone <= new {
+        Some(Direction::Up)
+    } else if new < milestone && milestone <= old {
+        Some(Direction::Down)
+    } else {
+        None
+    }
+}
+
+/// Time a receiver gets to accept the connection and to answer,
+/// so that receivers that never answer don't hold on to a thread
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts the payload, signed with `X-Signature: sha256=<hex hmac of the body>` if a secret is set
+fn send(
+    url: &str,
+    secret: Option<&str>,
+    payload: &impl Serialize,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let body = serde_json::to_string(payload)?;
+    let signature = secret.map(|secret| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(body.as_bytes());
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    });
+    let mut headers = Vec::new();
+    if let Some(signature) = &signature {
+        headers.push(("X-Signature", signature.as_str()));
+    }
+    let (host, path) = http_client::split_url(url);
+    let response = Client::new(host, None)
+        .with_timeout(timeout)
+        .request_with_headers("POST", path, &headers, &body)?;
+    anyhow::ensure!(
+        (200..300).contains(&response.status),
+        "Webhook responded with {}",
+        response.status,
+    );
+    Ok(())
+}
+
+/// Calls the webhook in the background, slow receivers must not delay other notifications
+fn notify(url: String, secret: Option<String>, payload: impl Serialize + Send + 'static) {
+    actix_web::rt::spawn(async move {
+        let result = spawn_blocking({
+            let url = url.clone();
+            move || send(&url, secret.as_deref(), &payload, TIMEOUT)
+        });
+        match result.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to call webhook {url:?}: {e:#}"),
+            Err(e) => error!("Webhook task failed: {e}"),
+        }
+    });
+}
+
+/// Watches the log for the whole game, does nothing if no webhooks are configured
+pub async fn run(app: Arc<model::App>) {
+    let config = app.config();
+    let milestones = !config.webhooks.is_empty() && !config.score_milestones.is_empty();
+    if !milestones && config.idle_webhook.is_none() {
+        return;
+    }
+    let (sender, mut receiver) = mpsc::unbounded();
+    app.register_logs(sender).await;
+    let mut scores: HashMap<UserToken, Score> = HashMap::new();
+    while let Some(entry) = receiver.next().await {
+        match entry.msg {
+            LogMessage::UpdateUser { user, state, .. } if milestones => {
+                let old = scores
+                    .insert(user.clone(), state.score)
+                    .unwrap_or_else(|| config.initial_user(&user).score);
+                let Some(url) = config.webhooks.get(&user) else {
+                    continue;
+                };
+                for &milestone in &config.score_milestones {
+                    let Some(direction) = crossed(old, state.score, milestone) else {
+                        continue;
+                    };
+                    debug!("{user:?} crossed {milestone} going {direction:?}");
+                    let payload = Payload {
+                        time: entry.time,
+                        milestone,
+                        direction,
+                        score: state.score,
+                    };
+                    notify(url.clone(), config.webhook_secret.clone(), payload);
+                }
+            }
+            LogMessage::UserIdle { user, idle_secs } => {
+                let Some(url) = &config.idle_webhook else {
+                    continue;
+                };
+                let payload = IdlePayload {
+                    time: entry.time,
+                    user,
+                    idle_secs,
+                };
+                notify(url.clone(), config.webhook_secret.clone(), payload);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    /// Accepts a single request and returns its headers and body
+    fn receive(
+        listener: TcpListener,
+        respond: bool,
+    ) -> std::thread::JoinHandle<(Vec<String>, String)> {
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                headers.push(line.trim().to_owned());
+            }
+            let length = headers
+                .iter()
+                .find_map(|header| header.strip_prefix("Content-Length: "))
+                .map_or(0, |length| length.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            if respond {
+                reader
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                    .unwrap();
+            } else {
+                // Keeps the connection open without answering
+                std::thread::sleep(Duration::from_secs(1));
+            }
+            (headers, String::from_utf8(body).unwrap())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_milestones() {
+        crate::logger::init_for_tests();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/milestone", listener.local_addr().unwrap());
+        let received = std::thread::spawn(move || {
+            let mut bodies: Vec<serde_json::Value> = (0..2)
+                .map(|_| {
+                    let listener = listener.try_clone().unwrap();
+                    let (_, body) = receive(listener, true).join().unwrap();
+                    serde_json::from_str(&body).unwrap()
+                })
+                .collect();
+            bodies.sort_by(|a, b| {
+                a["time"]
+                    .as_f64()
+                    .unwrap()
+                    .total_cmp(&b["time"].as_f64().unwrap())
+            });
+            bodies
+        });
+        let player = UserToken::from("player".to_owned());
+        let app = Arc::new(model::App::init(
+            model::Config {
+                initial_score: 100,
+                min_delay_secs: 0.0,
+                max_delay_secs: 0.0,
+                double_cost: 50,
+                score_milestones: vec![120, 500],
+                webhooks: [(player.clone(), url)].into(),
+                ..Default::default()
+            },
+            [player.clone(), UserToken::from("other".to_owned())],
+        ));
+        let watcher = actix_web::rt::spawn(run(app.clone()));
+        actix_web::rt::task::yield_now().await;
+        let pipe = |id| model::PipeId::new(id).unwrap();
+        let value = |value| model::PipeUpdate {
+            value: Some(value),
+            direction: None,
+        };
+        app.update_pipe(pipe(1), value(50)).await.unwrap();
+        // 100 to 150 goes past 120, the other user has no webhook
+        app.collect(&player, pipe(1)).await.unwrap();
+        app.collect(&UserToken::from("other".to_owned()), pipe(1))
+            .await
+            .unwrap();
+        // 150 to 100 goes back below it
+        app.apply_modifier(&player, pipe(2), model::Modifier::Double)
+            .await
+            .unwrap();
+        let bodies = spawn_blocking(|| received.join().unwrap()).await.unwrap();
+        let summary: Vec<_> = bodies
+            .iter()
+            .map(|body| {
+                (
+                    body["milestone"].clone(),
+                    body["direction"].clone(),
+                    body["score"].clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (120.into(), "up".into(), 150.into()),
+                (120.into(), "down".into(), 100.into())
+            ]
+        );
+        watcher.abort();
+    }
+
+    #[test]
+    fn test_signature() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/score", listener.local_addr().unwrap());
+        let received = receive(listener, true);
+        let payload = Payload {
+            time: 1.5,
+            milestone: 100,
+            direction: Direction::Up,
+            score: 120,
+        };
+        send(&url, Some("secret"), &payload, TIMEOUT).unwrap();
+        let (headers, body) = received.join().unwrap();
+        assert_eq!(headers[0], "POST /hooks/score HTTP/1.1");
+        assert_eq!(body, serde_json::to_string(&payload).unwrap());
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body.as_bytes());
+        let signature = format!(
+            "X-Signature: sha256={}",
+            hex::encode(mac.finalize().into_bytes())
+        );
+        assert!(headers.contains(&signature), "{headers:?}");
+    }
+
+    #[test]
+    fn test_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let received = receive(listener, false);
+        let start = std::time::Instant::now();
+        let payload = IdlePayload {
+            time: 1.0,
+            user: UserToken::from("player".to_owned()),
+            idle_secs: 30.0,
+        };
+        assert!(send(&url, None, &payload, Duration::from_millis(200)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        received.join().unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+//! Log replay for viewers running in the browser, build it with `wasm-pack build wasm`.
+//!
+//! Log entries go in and the state comes out as json strings, the same json the server writes
+//! to its logs, so viewers keep parsing it with `JSON.parse`.
+
+use pipes_engine::replay;
+use wasm_bindgen::prelude::*;
+
+fn js_error(error: anyhow::Error) -> JsError {
+    JsError::new(&format!("{error:#}"))
+}
+
+/// Parses a log line, users come back as strings whether the log has tokens or numeric ids
+#[wasm_bindgen(js_name = parseEntry)]
+pub fn parse_entry(line: &str) -> Result<String, JsError> {
+    let entry = replay::parse_entry(line).map_err(js_error)?;
+    Ok(serde_json::to_string(&entry)?)
+}
+
+/// Game state rebuilt from the log lines applied so far
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct ReplayState(replay::State);
+
+#[wasm_bindgen]
+impl ReplayState {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn apply(&mut self, line: &str) -> Result<(), JsError> {
+        let entry = replay::parse_entry(line).map_err(js_error)?;
+        self.0.apply(&entry);
+        Ok(())
+    }
+
+    /// Game time of the last applied entry
+    #[wasm_bindgen(getter)]
+    pub fn time(&self) -> f64 {
+        self.0.time
+    }
+
+    /// Pipes, scores and the rest of [`replay::State`]
+    #[wasm_bindgen(js_name = toJson)]
+    pub fn to_json(&self) -> Result<String, JsError> {
+        Ok(serde_json::to_string(&self.0)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let line = r#"{"time":1.5,"msg":{"type":"UpdateUser","user":7,"score":12}}"#;
+        let entry: serde_json::Value = serde_json::from_str(&parse_entry(line).unwrap()).unwrap();
+        assert_eq!(entry["msg"]["user"], "7");
+
+        let mut state = ReplayState::new();
+        state.apply(line).unwrap();
+        assert_eq!(state.time(), 1.5);
+        let state: serde_json::Value = serde_json::from_str(&state.to_json().unwrap()).unwrap();
+        assert_eq!(state["scores"], serde_json::json!({ "7": 12 }));
+    }
+}